@@ -0,0 +1,624 @@
+//! What an editor needs to know about a Lox file without running it:
+//! diagnostics, where every variable and function is declared and used,
+//! an outline, and how to color each token
+
+use crate::grammar::*;
+use crate::lexer::{Lexer, Span, Token, Type};
+use crate::parser::Parser;
+use crate::resolver;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem the command line would report for the file
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub token: Token,
+    pub message: String,
+    pub severity: Severity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Function,
+    Class,
+    Method,
+}
+
+/// One declaration and every use of it that can be found statically.
+/// Methods are only ever reached through properties, so they have no
+/// references.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    /// The name where it is declared
+    pub declaration: Token,
+    /// Uses of the name in source order. A global declared again counts as
+    /// a use of the first declaration.
+    pub references: Vec<Token>,
+    /// How the declaration reads, like `fun add(a, b)`. Parameters show the
+    /// function they belong to.
+    pub detail: String,
+}
+
+impl Symbol {
+    pub fn name(&self) -> &str {
+        self.declaration.name()
+    }
+
+    /// Whether `offset` is on the declaration or one of the references
+    fn contains(&self, offset: usize) -> bool {
+        let touches = |token: &Token| token.span.start <= offset && offset <= token.span.end;
+        touches(&self.declaration) || self.references.iter().any(touches)
+    }
+}
+
+/// A function or class in the outline of a file
+#[derive(Debug, Clone)]
+pub struct Outline {
+    /// Index into `Analysis::symbols`
+    pub symbol: usize,
+    /// From the `fun` or `class` keyword to the closing brace
+    pub span: Span,
+    /// Methods of a class and functions declared inside a function
+    pub children: Vec<Outline>,
+}
+
+/// How a token should be colored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Highlight {
+    Keyword,
+    Comment,
+    String,
+    Number,
+    Operator,
+    Variable,
+    Parameter,
+    Function,
+    Class,
+    Method,
+    Property,
+}
+
+/// Everything found out about one version of a file. Code with syntax
+/// errors still gets symbols for the statements that did parse, but
+/// resolver diagnostics are only given for code that parses.
+#[derive(Debug)]
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    pub symbols: Vec<Symbol>,
+    pub outline: Vec<Outline>,
+    /// Every token, comments included
+    tokens: Vec<Token>,
+}
+
+impl Analysis {
+    pub fn new(src: &str) -> Self {
+        let tokens: Vec<Token> = Lexer::with_comments(src).collect();
+        let code = tokens
+            .iter()
+            .filter(|token| token.kind != Type::Comment)
+            .cloned()
+            .collect();
+        let (program, errors) = Parser::new(code).parse_recovering();
+
+        let mut diagnostics: Vec<Diagnostic> = errors
+            .iter()
+            .map(|err| Diagnostic {
+                token: err.token().clone(),
+                message: err.to_string(),
+                severity: Severity::Error,
+            })
+            .collect();
+        if errors.is_empty() {
+            let resolution = resolver::resolve(&program);
+            diagnostics.extend(resolution.errors.iter().map(|err| Diagnostic {
+                token: err.token().clone(),
+                message: err.to_string(),
+                severity: Severity::Error,
+            }));
+            diagnostics.extend(resolution.warnings.iter().map(|warning| Diagnostic {
+                token: warning.token().clone(),
+                message: warning.to_string(),
+                severity: Severity::Warning,
+            }));
+        }
+        diagnostics.sort_by_key(|diagnostic| diagnostic.token.span.start);
+
+        let mut binder = Binder {
+            tokens: &tokens,
+            symbols: Vec::new(),
+            scopes: Vec::new(),
+            globals: HashMap::new(),
+            unbound: Vec::new(),
+        };
+        let outline = binder.stmts(&program);
+        let symbols = binder.finish();
+        Self {
+            diagnostics,
+            symbols,
+            outline,
+            tokens,
+        }
+    }
+
+    /// The symbol whose declaration or reference is under `offset`
+    pub fn symbol_at(&self, offset: usize) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.contains(offset))
+    }
+
+    /// Every token worth coloring, in source order. Identifiers are colored
+    /// by what they refer to.
+    pub fn highlights(&self) -> Vec<(Span, Highlight)> {
+        let mut names = HashMap::new();
+        for symbol in self.symbols.iter() {
+            names.insert(symbol.declaration.span.start, symbol.kind);
+            for reference in symbol.references.iter() {
+                names.insert(reference.span.start, symbol.kind);
+            }
+        }
+        let mut after_dot = false;
+        let mut highlights = Vec::new();
+        for token in self.tokens.iter() {
+            let highlight = match &token.kind {
+                Type::Identifier { .. } => Some(match names.get(&token.span.start) {
+                    Some(SymbolKind::Variable) => Highlight::Variable,
+                    Some(SymbolKind::Parameter) => Highlight::Parameter,
+                    Some(SymbolKind::Function) => Highlight::Function,
+                    Some(SymbolKind::Class) => Highlight::Class,
+                    Some(SymbolKind::Method) => Highlight::Method,
+                    None if after_dot => Highlight::Property,
+                    None => Highlight::Variable,
+                }),
+                Type::String { .. } | Type::UnterminatedString { .. } => Some(Highlight::String),
+                Type::Int { .. } | Type::Float { .. } => Some(Highlight::Number),
+                Type::Comment | Type::UnterminatedComment => Some(Highlight::Comment),
+                Type::And
+                | Type::Catch
+                | Type::Class
+                | Type::Else
+                | Type::False
+                | Type::Fun
+                | Type::For
+                | Type::If
+                | Type::Import
+                | Type::Nil
+                | Type::Or
+                | Type::Print
+                | Type::Return
+                | Type::Super
+                | Type::This
+                | Type::Throw
+                | Type::True
+                | Type::Try
+                | Type::Var
+                | Type::While => Some(Highlight::Keyword),
+                Type::Minus
+                | Type::Plus
+                | Type::Slash
+                | Type::Star
+                | Type::Bang
+                | Type::BangEq
+                | Type::Eq
+                | Type::EqEq
+                | Type::Gt
+                | Type::Ge
+                | Type::Lt
+                | Type::Le => Some(Highlight::Operator),
+                _ => None,
+            };
+            if let Some(highlight) = highlight {
+                highlights.push((token.span, highlight));
+            }
+            if token.kind != Type::Comment {
+                after_dot = token.kind == Type::Dot;
+            }
+        }
+        highlights
+    }
+}
+
+/// Matches every name in a program to its declaration, following the same
+/// scoping rules as the resolver
+struct Binder<'a> {
+    tokens: &'a [Token],
+    symbols: Vec<Symbol>,
+    /// Local scopes, innermost last, mapping names to symbols
+    scopes: Vec<HashMap<String, usize>>,
+    globals: HashMap<String, usize>,
+    /// Names that were not local where they were used. Globals can be
+    /// declared after the functions that use them, so these are bound once
+    /// the whole program has been seen.
+    unbound: Vec<Token>,
+}
+
+impl Binder<'_> {
+    fn finish(mut self) -> Vec<Symbol> {
+        for token in std::mem::take(&mut self.unbound) {
+            if let Some(&idx) = self.globals.get(token.name()) {
+                self.symbols[idx].references.push(token);
+            }
+        }
+        for symbol in self.symbols.iter_mut() {
+            symbol.references.sort_by_key(|token| token.span.start);
+        }
+        self.symbols
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Vec<Outline> {
+        let mut outline = Vec::new();
+        for stmt in stmts.iter() {
+            self.stmt(stmt, &mut outline);
+        }
+        outline
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, outline: &mut Vec<Outline>) {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.expr(expr),
+            Stmt::Var(name, _, initializer) => {
+                self.declare(name, SymbolKind::Variable, format!("var {}", name.name()));
+                if let Some(expr) = initializer {
+                    self.expr(expr);
+                }
+            }
+            Stmt::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                for stmt in stmts.iter() {
+                    self.stmt(stmt, outline);
+                }
+                self.scopes.pop();
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expr(condition);
+                self.stmt(then_branch, outline);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch, outline);
+                }
+            }
+            Stmt::While(condition, body, _) => {
+                self.expr(condition);
+                self.stmt(body, outline);
+            }
+            Stmt::Function(decl) => {
+                let detail = format!("fun {}", signature(decl));
+                let symbol = self.declare(&decl.name, SymbolKind::Function, detail.clone());
+                let children = self.function(decl, &detail);
+                outline.push(self.outline(symbol, &decl.name, children));
+            }
+            Stmt::Return(_, value) => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+            Stmt::Throw(_, value) => self.expr(value),
+            Stmt::Import(path, name, _) => {
+                let detail = format!("import \"{}\" as {}", Stmt::import_path(path), name.name());
+                self.declare(name, SymbolKind::Variable, detail);
+            }
+            Stmt::Try(_, body, name, handler) => {
+                self.scopes.push(HashMap::new());
+                for stmt in body.iter() {
+                    self.stmt(stmt, outline);
+                }
+                self.scopes.pop();
+                self.scopes.push(HashMap::new());
+                let detail = format!("catch ({})", name.name());
+                self.declare(name, SymbolKind::Variable, detail);
+                for stmt in handler.iter() {
+                    self.stmt(stmt, outline);
+                }
+                self.scopes.pop();
+            }
+            Stmt::Class(decl) => {
+                let mut detail = format!("class {}", decl.name.name());
+                if let Some(Expr::Variable(superclass, _)) = &decl.superclass {
+                    detail = format!("{} < {}", detail, superclass.name());
+                }
+                let symbol = self.declare(&decl.name, SymbolKind::Class, detail);
+                if let Some(superclass) = &decl.superclass {
+                    self.expr(superclass);
+                }
+                let mut methods = Vec::new();
+                for method in decl.methods.iter() {
+                    let detail = format!("{}.{}", decl.name.name(), signature(method));
+                    let idx = self.add_symbol(&method.name, SymbolKind::Method, detail.clone());
+                    let children = self.function(method, &detail);
+                    methods.push(self.outline(idx, &method.name, children));
+                }
+                outline.push(self.outline(symbol, &decl.name, methods));
+            }
+        }
+    }
+
+    /// Binds the parameters and body of a function and returns the
+    /// functions declared in it
+    fn function(&mut self, decl: &FunctionDecl, detail: &str) -> Vec<Outline> {
+        self.scopes.push(HashMap::new());
+        for param in decl.params.iter() {
+            self.declare(param, SymbolKind::Parameter, detail.to_string());
+        }
+        let children = self.stmts(&decl.body);
+        self.scopes.pop();
+        children
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(..) | Expr::This(..) | Expr::Super(..) => {}
+            Expr::UnaryExpr(_, _, expr) | Expr::Grouping(expr) | Expr::Get(expr, _) => {
+                self.expr(expr)
+            }
+            Expr::BinaryExpr(left, _, _, right) | Expr::Logical(left, _, right) => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Variable(name, _) => self.reference(name),
+            Expr::Assign(name, value, _) => {
+                self.expr(value);
+                self.reference(name);
+            }
+            Expr::Set(object, _, value) => {
+                self.expr(value);
+                self.expr(object);
+            }
+            Expr::Call(callee, _, args) => {
+                self.expr(callee);
+                args.iter().for_each(|arg| self.expr(arg));
+            }
+            Expr::List(_, elements) => elements.iter().for_each(|element| self.expr(element)),
+            Expr::Map(_, entries) => {
+                for (key, value) in entries.iter() {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+            Expr::Index(object, _, index) => {
+                self.expr(object);
+                self.expr(index);
+            }
+            Expr::SetIndex(object, _, index, value) => {
+                self.expr(object);
+                self.expr(index);
+                self.expr(value);
+            }
+        }
+    }
+
+    fn add_symbol(&mut self, name: &Token, kind: SymbolKind, detail: String) -> usize {
+        self.symbols.push(Symbol {
+            kind,
+            declaration: name.clone(),
+            references: Vec::new(),
+            detail,
+        });
+        self.symbols.len() - 1
+    }
+
+    /// Adds a symbol to the innermost scope, or to the globals at the top
+    /// level, and returns its index
+    fn declare(&mut self, name: &Token, kind: SymbolKind, detail: String) -> usize {
+        if self.scopes.is_empty() {
+            if let Some(&idx) = self.globals.get(name.name()) {
+                self.symbols[idx].references.push(name.clone());
+                return idx;
+            }
+        }
+        let idx = self.add_symbol(name, kind, detail);
+        match self.scopes.last_mut() {
+            Some(scope) => scope.insert(name.name().to_string(), idx),
+            None => self.globals.insert(name.name().to_string(), idx),
+        };
+        idx
+    }
+
+    fn reference(&mut self, name: &Token) {
+        let local = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name.name()).copied());
+        match local {
+            Some(idx) => self.symbols[idx].references.push(name.clone()),
+            None => self.unbound.push(name.clone()),
+        }
+    }
+
+    /// The outline entry for a declared function or class, which runs from
+    /// the keyword before its name to the brace closing its body
+    fn outline(&self, symbol: usize, name: &Token, children: Vec<Outline>) -> Outline {
+        let mut span = name.span;
+        if let Ok(idx) = self
+            .tokens
+            .binary_search_by_key(&name.span.start, |token| token.span.start)
+        {
+            if let Some(keyword) = idx.checked_sub(1).map(|idx| &self.tokens[idx]) {
+                if keyword.kind == Type::Fun || keyword.kind == Type::Class {
+                    span.start = keyword.span.start;
+                    span.column = keyword.span.column;
+                }
+            }
+            let mut depth = 0;
+            let body = self.tokens[idx..]
+                .iter()
+                .skip_while(|token| token.kind != Type::LBrace);
+            for token in body {
+                match token.kind {
+                    Type::LBrace => depth += 1,
+                    Type::RBrace => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    span.end = token.span.end;
+                    break;
+                }
+            }
+        }
+        Outline {
+            symbol,
+            span,
+            children,
+        }
+    }
+}
+
+/// `name(a, b)`
+fn signature(decl: &FunctionDecl) -> String {
+    let params: Vec<&str> = decl.params.iter().map(|param| param.name()).collect();
+    format!("{}({})", decl.name.name(), params.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The symbol at the first occurrence of `needle`
+    fn symbol<'a>(analysis: &'a Analysis, src: &str, needle: &str) -> &'a Symbol {
+        analysis.symbol_at(src.find(needle).unwrap()).unwrap()
+    }
+
+    fn lines(src: &str, tokens: &[Token]) -> Vec<String> {
+        tokens
+            .iter()
+            .map(|token| format!("{}:{}", token.line + 1, token.lexeme(src)))
+            .collect()
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let messages = |src: &str| -> Vec<String> {
+            Analysis::new(src)
+                .diagnostics
+                .iter()
+                .map(|diagnostic| format!("{:?} {}", diagnostic.severity, diagnostic.message))
+                .collect()
+        };
+        assert_eq!(
+            messages("print 1\nvar x = @;"),
+            vec![
+                "Error Expect ';' after value.",
+                "Error Unexpected character."
+            ]
+        );
+        assert_eq!(
+            messages("{ var a = 1; var a = 2; print a; }\n{ var b; }"),
+            vec![
+                "Error Already a variable with this name in this scope.",
+                "Warning Local variable 'b' is never used."
+            ]
+        );
+        assert!(messages("// fine\nprint 1;").is_empty());
+    }
+
+    #[test]
+    fn test_references() {
+        let src = "\
+fun count(n) {
+  var total = n;
+  { var n = 2; total = total + n; }
+  return total + n;
+}
+print count(1);
+var later = f();
+fun f() { return later; }";
+        let analysis = Analysis::new(src);
+
+        let count = symbol(&analysis, src, "count(1)");
+        assert_eq!(count.kind, SymbolKind::Function);
+        assert_eq!(count.detail, "fun count(n)");
+        assert_eq!(lines(src, &count.references), vec!["6:count"]);
+
+        // the parameter and the shadowing local are separate symbols
+        let param = symbol(&analysis, src, "n)");
+        assert_eq!(param.kind, SymbolKind::Parameter);
+        assert_eq!(lines(src, &param.references), vec!["2:n", "4:n"]);
+        let local = symbol(&analysis, src, "n = 2");
+        assert_eq!(lines(src, &local.references), vec!["3:n"]);
+
+        let total = symbol(&analysis, src, "total");
+        assert_eq!(
+            lines(src, &total.references),
+            vec!["3:total", "3:total", "4:total"]
+        );
+
+        // globals can be used before they are declared
+        let f = symbol(&analysis, src, "f()");
+        assert_eq!(f.declaration.line + 1, 8);
+        let later = symbol(&analysis, src, "later;");
+        assert_eq!(later.declaration.span.start, src.find("later").unwrap());
+
+        assert!(analysis.symbol_at(src.find("print").unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_outline() {
+        let src = "\
+class A < B {
+  init(x) { fun helper() {} }
+  go() {}
+}
+fun main() {}
+var x = 1;";
+        let analysis = Analysis::new(src);
+        let names = |outline: &[Outline]| -> Vec<String> {
+            outline
+                .iter()
+                .map(|item| analysis.symbols[item.symbol].detail.clone())
+                .collect()
+        };
+        assert_eq!(names(&analysis.outline), vec!["class A < B", "fun main()"]);
+        let class = &analysis.outline[0];
+        assert_eq!(names(&class.children), vec!["A.init(x)", "A.go()"]);
+        assert_eq!(names(&class.children[0].children), vec!["fun helper()"]);
+        assert_eq!(
+            &src[class.span.start..class.span.end],
+            &src[..src.find("\nfun").unwrap()]
+        );
+        assert_eq!(
+            &src[class.children[1].span.start..class.children[1].span.end],
+            "go() {}"
+        );
+    }
+
+    #[test]
+    fn test_highlights() {
+        let src = "fun f(a) { return a.b + 1; } // done\nprint f(\"s\");";
+        let highlights: Vec<String> = Analysis::new(src)
+            .highlights()
+            .iter()
+            .map(|(span, highlight)| format!("{}={:?}", &src[span.start..span.end], highlight))
+            .collect();
+        assert_eq!(
+            highlights,
+            vec![
+                "fun=Keyword",
+                "f=Function",
+                "a=Parameter",
+                "return=Keyword",
+                "a=Parameter",
+                "b=Property",
+                "+=Operator",
+                "1=Number",
+                "// done=Comment",
+                "print=Keyword",
+                "f=Function",
+                "\"s\"=String"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_broken_code() {
+        let src = "fun f() {}\nvar x = ;\nf();";
+        let analysis = Analysis::new(src);
+        assert_eq!(analysis.diagnostics.len(), 1);
+        let f = symbol(&analysis, src, "f()");
+        assert_eq!(lines(src, &f.references), vec!["3:f"]);
+    }
+}
@@ -1,59 +1,214 @@
-use crate::evaluation::evaluate;
-use crate::lexer::{Lexer, Token};
-use crate::parser::Parser;
+use crate::debugger::Session;
+use crate::repl::Repl;
+use rlox::error::LoxError;
+use rlox::formatter;
+use rlox::grammar::Stmt;
+use rlox::wasm;
+use rlox::{Interpreter, Options};
 use std::fs;
-use std::io::{self, Write};
+use std::io;
+use std::path::Path;
+use std::process;
 
+/// The command line driver. Runs scripts and REPL input on an
+/// `Interpreter` and reports what goes wrong on stderr.
 pub struct Lox {
     had_error: bool,
+    had_runtime_error: bool,
+    interpreter: Interpreter,
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Lox {
     pub fn new() -> Self {
-        Self { had_error: false }
+        Self::with_options(Options::default())
     }
 
-    fn run(&self, source: &str) {
-        let tokens: Vec<Token> = Lexer::new(source).collect();
-        let mut parser = Parser::new(tokens);
-        let tree = parser.parse();
-        println!("{:?}", tree);
-        if let Ok(tree) = tree {
-            let result = evaluate(&tree);
-            println!("Result is {:?}", result);
+    pub fn with_options(options: Options) -> Self {
+        Self {
+            had_error: false,
+            had_runtime_error: false,
+            interpreter: Interpreter::with_options(options),
         }
     }
 
-    pub fn error(&mut self, line: i32, msg: &str) {
-        self.report(line, "", msg);
+    pub fn options(&self) -> Options {
+        self.interpreter.options()
     }
 
-    fn report(&mut self, line: i32, location: &str, msg: &str) {
-        eprintln!("[line {}] Error {}: {}", line, location, msg);
-        self.had_error = true;
+    /// Forgets errors reported so far, so the next input starts clean
+    pub fn clear_errors(&mut self) {
+        self.had_error = false;
+        self.had_runtime_error = false;
+    }
+
+    pub fn run(&mut self, source: &str) {
+        if let Some(program) = self.parse(source) {
+            self.execute(source, &program);
+        }
+    }
+
+    /// Parses `source`, reporting any syntax errors
+    pub fn parse(&mut self, source: &str) -> Option<Vec<Stmt>> {
+        match self.interpreter.parse(source) {
+            Ok(program) => Some(program),
+            Err(err) => {
+                self.report(source, Err(err));
+                None
+            }
+        }
+    }
+
+    /// Resolves and runs a program parsed from `source`. State left behind
+    /// by earlier programs, such as globals, is still visible.
+    pub fn execute(&mut self, source: &str, program: &[Stmt]) {
+        let result = self.interpreter.execute(program);
+        self.report(source, result);
+    }
+
+    /// Prints pending warnings and then the error, if there is one
+    fn report(&mut self, source: &str, result: Result<(), LoxError>) {
+        for warning in self.interpreter.take_warnings() {
+            eprint!("{}", warning.report(source));
+        }
+        if let Err(err) = result {
+            eprint!("{}", err.report(source));
+            match err.exit_code() {
+                70 => self.had_runtime_error = true,
+                _ => self.had_error = true,
+            }
+        }
+    }
+
+    pub fn run_script(&mut self, path: &str) {
+        let contents = read_script(path);
+        self.interpreter.set_path(Path::new(path));
+        self.run(&contents);
+        if let (true, Some(stats)) = (self.options().log_gc, self.interpreter.gc_stats()) {
+            eprintln!(
+                "-- gc summary: {} collections, freed {} objects ({} bytes)",
+                stats.collections, stats.objects_freed, stats.bytes_freed
+            );
+        }
+        self.exit_on_error();
     }
 
-    pub fn run_script(&self, path: &str) {
-        let contents = fs::read_to_string(path).expect("Cannot find script path");
+    /// Runs a script under the debugger, which takes its commands from
+    /// stdin
+    pub fn run_debug(&mut self, path: &str) {
+        let contents = read_script(path);
+        let session = Session::new(&contents, io::stdin().lock(), io::stdout());
+        self.interpreter.set_debugger(session);
+        self.interpreter.set_path(Path::new(path));
         self.run(&contents);
+        self.exit_on_error();
     }
 
-    pub fn run_repl(&mut self) {
-        loop {
-            let mut line = String::new();
-            print!(">");
-            io::stdout().flush().unwrap();
-            if let Ok(bytes) = io::stdin().read_line(&mut line) {
-                if bytes == 0 {
-                    println!("Bye!");
-                    break;
+    /// Compiles a script to a WebAssembly module written next to it, as
+    /// binary `.wasm` or with `text` as `.wat`
+    pub fn run_build(&mut self, path: &str, text: bool) {
+        let contents = read_script(path);
+        if let Some(program) = self.parse(&contents) {
+            match self.interpreter.compile_wasm(&program) {
+                Ok(module) => {
+                    let (output, bytes) = if text {
+                        (Path::new(path).with_extension("wat"), module.into_bytes())
+                    } else {
+                        (
+                            Path::new(path).with_extension("wasm"),
+                            wasm::assemble(&module),
+                        )
+                    };
+                    self.report(&contents, Ok(()));
+                    if let Err(err) = fs::write(&output, bytes) {
+                        eprintln!("Cannot write {}: {}", output.display(), err);
+                        self.had_error = true;
+                    }
                 }
-                self.run(&line.trim());
-                self.had_error = false;
-            } else {
-                println!("Something went wrong!");
-                break;
+                Err(err) => self.report(&contents, Err(err)),
             }
         }
+        self.exit_on_error();
     }
+
+    /// Type checks a script and reports what it finds, without running it
+    pub fn run_check(&mut self, path: &str) {
+        let contents = read_script(path);
+        if let Some(program) = self.parse(&contents) {
+            let result = self.interpreter.check(&program);
+            self.report(&contents, result);
+        }
+        self.exit_on_error();
+    }
+
+    /// Exits with the code for the worst error reported so far, if any
+    fn exit_on_error(&self) {
+        if self.had_error {
+            process::exit(65);
+        }
+        if self.had_runtime_error {
+            process::exit(70);
+        }
+    }
+
+    /// Formats each file in place, or with `check` only reports the ones
+    /// that are not formatted. Exits with 1 if `check` found any, 65 if a
+    /// file does not parse, 66 if one can't be read and 74 if one can't be
+    /// written. The first file that fails decides.
+    pub fn run_fmt(&mut self, paths: &[String], check: bool) {
+        let mut unformatted = false;
+        let mut failed = None;
+        for path in paths.iter() {
+            let source = match fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(err) => {
+                    eprintln!("Cannot read {}: {}", path, err);
+                    failed.get_or_insert(66);
+                    continue;
+                }
+            };
+            let formatted = match formatter::format(&source) {
+                Ok(formatted) => formatted,
+                Err(errors) => {
+                    self.report(&source, Err(LoxError::Parse(errors)));
+                    failed.get_or_insert(65);
+                    continue;
+                }
+            };
+            if formatted == source {
+                continue;
+            }
+            if check {
+                println!("{} is not formatted", path);
+                unformatted = true;
+            } else if let Err(err) = fs::write(path, formatted) {
+                eprintln!("Cannot write {}: {}", path, err);
+                failed.get_or_insert(74);
+            }
+        }
+        if let Some(code) = failed {
+            process::exit(code);
+        }
+        if unformatted {
+            process::exit(1);
+        }
+    }
+
+    pub fn run_repl(self) {
+        Repl::new(self).run();
+    }
+}
+
+/// The source of the script at `path`. One that can't be read exits with
+/// 66, the sysexits code for a missing input.
+fn read_script(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Cannot read {}: {}", path, err);
+        process::exit(66);
+    })
 }
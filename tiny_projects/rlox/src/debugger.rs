@@ -0,0 +1,331 @@
+use rlox::evaluation::{Debugger, Environment, Frame};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::io::{BufRead, Write};
+use std::process;
+use std::rc::Rc;
+
+const PROMPT: &str = "(debug) ";
+
+const HELP: &str = "\
+break <line>    stop before <line> runs, or list breakpoints without <line>
+delete <line>   remove the breakpoint on <line>
+continue        run until the next breakpoint
+step            run one statement, stepping into calls
+next            run one statement, stepping over calls
+out             run until the current function returns
+locals          print the variables in scope, apart from globals
+globals         print every global variable
+print <name>    print the variable <name>
+backtrace       print the call stack
+help            show this message
+quit            stop the program";
+
+/// When to stop next, apart from breakpoints
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resume {
+    /// At the very next statement
+    Step,
+    /// At the next statement at most this many calls deep
+    Next(usize),
+    /// At the next statement fewer than this many calls deep
+    Out(usize),
+    /// Only at breakpoints
+    Continue,
+}
+
+/// `rlox debug`, which stops a script before its first statement and then
+/// takes commands from `input`. When the input runs out the script runs to
+/// the end.
+pub struct Session<R, W> {
+    lines: Vec<String>,
+    input: R,
+    out: W,
+    breakpoints: BTreeSet<i32>,
+    resume: Resume,
+    /// Call depth and line of the last statement, so a breakpoint stops
+    /// once when its line is reached, not at every statement on it
+    last: Option<(usize, i32)>,
+}
+
+impl<R: BufRead, W: Write> Session<R, W> {
+    pub fn new(source: &str, input: R, out: W) -> Self {
+        Self {
+            lines: source.lines().map(str::to_string).collect(),
+            input,
+            out,
+            breakpoints: BTreeSet::new(),
+            resume: Resume::Step,
+            last: None,
+        }
+    }
+
+    /// Reads commands until one lets the program carry on
+    fn prompt(&mut self, stack: &[Frame]) {
+        loop {
+            let _ = write!(self.out, "{}", PROMPT);
+            let _ = self.out.flush();
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    self.breakpoints.clear();
+                    self.resume = Resume::Continue;
+                    return;
+                }
+                Ok(_) => {}
+            }
+            if self.command(line.trim(), stack) {
+                return;
+            }
+        }
+    }
+
+    /// Runs a command, returns true when the program should carry on
+    fn command(&mut self, line: &str, stack: &[Frame]) -> bool {
+        let (name, arg) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], line[idx..].trim()),
+            None => (line, ""),
+        };
+        self.resume = match name {
+            "step" | "s" => Resume::Step,
+            "next" | "n" => Resume::Next(stack.len()),
+            "out" | "o" => Resume::Out(stack.len()),
+            "continue" | "c" => Resume::Continue,
+            "quit" | "q" => process::exit(0),
+            _ => {
+                self.inspect(name, arg, stack);
+                return false;
+            }
+        };
+        true
+    }
+
+    /// Runs a command that leaves the program paused
+    fn inspect(&mut self, name: &str, arg: &str, stack: &[Frame]) {
+        let frame = match stack.last() {
+            Some(frame) => frame,
+            None => return,
+        };
+        match name {
+            "break" | "b" if arg.is_empty() => {
+                let lines: Vec<String> = self
+                    .breakpoints
+                    .iter()
+                    .map(|line| (line + 1).to_string())
+                    .collect();
+                if lines.is_empty() {
+                    self.print("No breakpoints.");
+                } else {
+                    self.print(&format!("Breakpoints at lines {}.", lines.join(", ")));
+                }
+            }
+            "break" | "b" => {
+                if let Some(line) = self.line_number(arg) {
+                    self.breakpoints.insert(line);
+                    self.print(&format!("Breakpoint at line {}.", line + 1));
+                }
+            }
+            "delete" | "d" => {
+                if let Some(line) = self.line_number(arg) {
+                    if self.breakpoints.remove(&line) {
+                        self.print(&format!("Removed the breakpoint at line {}.", line + 1));
+                    } else {
+                        self.print(&format!("No breakpoint at line {}.", line + 1));
+                    }
+                }
+            }
+            "locals" | "l" => {
+                let scopes = scopes(&frame.env);
+                self.print_variables(&scopes[..scopes.len() - 1]);
+            }
+            "globals" | "g" => {
+                let scopes = scopes(&frame.env);
+                self.print_variables(&scopes[scopes.len() - 1..]);
+            }
+            "print" | "p" => {
+                let value = scopes(&frame.env)
+                    .iter()
+                    .find_map(|scope| Environment::get_at(scope, 0, arg));
+                match value {
+                    Some(value) => self.print(&format!("{} = {}", arg, value.repr())),
+                    None => self.print(&format!("Undefined variable '{}'.", arg)),
+                }
+            }
+            "backtrace" | "bt" => {
+                for frame in stack.iter().rev() {
+                    self.show(frame);
+                }
+            }
+            "help" | "h" => self.print(HELP),
+            "" => {}
+            _ => self.print(&format!("Unknown command {}, try help", name)),
+        }
+    }
+
+    fn print(&mut self, text: &str) {
+        let _ = writeln!(self.out, "{}", text);
+    }
+
+    /// Where a frame is, and the source line it is on
+    fn show(&mut self, frame: &Frame) {
+        let function = match &frame.function {
+            Some(name) => format!("{}()", name),
+            None => "script".to_string(),
+        };
+        let source = self
+            .lines
+            .get(frame.line as usize)
+            .map_or("", |line| line.trim());
+        let text = format!("[line {}] in {}\n    {}", frame.line + 1, function, source);
+        self.print(&text);
+    }
+
+    /// A 1-based line number from the command line, 0-based like the tokens
+    fn line_number(&mut self, arg: &str) -> Option<i32> {
+        match arg.parse::<i32>() {
+            Ok(line) if line >= 1 => Some(line - 1),
+            _ => {
+                self.print("Expected a line number.");
+                None
+            }
+        }
+    }
+
+    /// Prints `name = value` for every variable in `scopes`, innermost
+    /// first, leaving out the ones shadowed by an inner scope
+    fn print_variables(&mut self, scopes: &[Rc<RefCell<Environment>>]) {
+        let mut seen = HashSet::new();
+        let mut lines = Vec::new();
+        for scope in scopes.iter() {
+            for (name, value) in scope.borrow().variables() {
+                if seen.insert(name.clone()) {
+                    lines.push(format!("{} = {}", name, value.repr()));
+                }
+            }
+        }
+        if lines.is_empty() {
+            lines.push("Nothing defined.".to_string());
+        }
+        self.print(&lines.join("\n"));
+    }
+}
+
+impl<R: BufRead, W: Write> Debugger for Session<R, W> {
+    fn before_statement(&mut self, stack: &[Frame]) {
+        let frame = match stack.last() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let here = (stack.len(), frame.line);
+        let stop = match self.resume {
+            Resume::Step => true,
+            Resume::Next(depth) => stack.len() <= depth,
+            Resume::Out(depth) => stack.len() < depth,
+            Resume::Continue => false,
+        } || (self.breakpoints.contains(&frame.line) && self.last != Some(here));
+        self.last = Some(here);
+        if stop {
+            self.show(frame);
+            self.prompt(stack);
+        }
+    }
+}
+
+/// `env` and every scope around it, ending with the globals
+fn scopes(env: &Rc<RefCell<Environment>>) -> Vec<Rc<RefCell<Environment>>> {
+    let mut scopes = vec![env.clone()];
+    let mut scope = env.borrow().enclosing();
+    while let Some(enclosing) = scope {
+        scope = enclosing.borrow().enclosing();
+        scopes.push(enclosing);
+    }
+    scopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlox::{Interpreter, SharedBuffer};
+
+    const SCRIPT: &str = "\
+fun square(n) {
+  var result = n * n;
+  return result;
+}
+var total = 0;
+for (var i = 1; i <= 3; i = i + 1) {
+  total = total + square(i);
+}
+print total;
+";
+
+    /// Runs `SCRIPT` with the debugger reading `commands`, and returns what
+    /// the debugger and the script printed
+    fn debug(commands: &'static str) -> String {
+        let out = SharedBuffer::new();
+        let mut lox = Interpreter::new();
+        lox.set_output(out.clone());
+        lox.set_debugger(Session::new(SCRIPT, commands.as_bytes(), out.clone()));
+        lox.run(SCRIPT).unwrap();
+        out.take().replace(PROMPT, "")
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let output = debug("break 2\nc\nlocals\nbt\nc\np n\ndelete 2\nb\nc\n");
+        assert_eq!(
+            output,
+            "\
+[line 1] in script
+    fun square(n) {
+Breakpoint at line 2.
+[line 2] in square()
+    var result = n * n;
+n = 1
+[line 2] in square()
+    var result = n * n;
+[line 7] in script
+    total = total + square(i);
+[line 2] in square()
+    var result = n * n;
+n = 2
+Removed the breakpoint at line 2.
+No breakpoints.
+14
+"
+        );
+    }
+
+    #[test]
+    fn test_stepping() {
+        let output = debug("n\nn\nn\nn\ns\ns\nglobals\no\nl\n");
+        assert_eq!(
+            output,
+            "\
+[line 1] in script
+    fun square(n) {
+[line 5] in script
+    var total = 0;
+[line 6] in script
+    for (var i = 1; i <= 3; i = i + 1) {
+[line 6] in script
+    for (var i = 1; i <= 3; i = i + 1) {
+[line 7] in script
+    total = total + square(i);
+[line 2] in square()
+    var result = n * n;
+[line 3] in square()
+    return result;
+clock = <native fn>
+io = <module io>
+math = <module math>
+square = <fn square>
+total = 0
+[line 6] in script
+    for (var i = 1; i <= 3; i = i + 1) {
+i = 1
+14
+"
+        );
+    }
+}
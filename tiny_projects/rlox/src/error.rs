@@ -0,0 +1,674 @@
+use crate::lexer::{LexError, Token, Type};
+use crate::typecheck::LoxType;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// A specific token was required here, the message says which
+    Expected {
+        token: Token,
+        msg: &'static str,
+    },
+    /// Nothing that can start an expression was found
+    ExpectedExpression(Token),
+    InvalidAssignmentTarget(Token),
+    TooManyArguments(Token),
+    TooManyParameters(Token),
+    TooManyElements(Token),
+    /// Past `parser::MAX_NESTING`
+    TooDeeplyNested(Token),
+    UnexpectedCharacter(Token),
+    /// A token the lexer rejected for some other reason than a stray
+    /// character, reported against the text as written
+    InvalidToken(Token, LexError),
+    UnterminatedString(Token),
+    UnterminatedComment(Token),
+}
+
+impl ParseError {
+    pub fn token(&self) -> &Token {
+        match self {
+            ParseError::Expected { token, .. } => token,
+            ParseError::ExpectedExpression(token)
+            | ParseError::InvalidAssignmentTarget(token)
+            | ParseError::TooManyArguments(token)
+            | ParseError::TooManyParameters(token)
+            | ParseError::TooManyElements(token)
+            | ParseError::TooDeeplyNested(token)
+            | ParseError::UnexpectedCharacter(token)
+            | ParseError::UnterminatedString(token)
+            | ParseError::UnterminatedComment(token)
+            | ParseError::InvalidToken(token, _) => token,
+        }
+    }
+
+    /// Errors found by the lexer are not reported against a lexeme
+    pub fn is_lexical(&self) -> bool {
+        matches!(
+            self,
+            ParseError::UnexpectedCharacter(_)
+                | ParseError::UnterminatedString(_)
+                | ParseError::UnterminatedComment(_)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Expected { msg, .. } => write!(f, "{}", msg),
+            ParseError::ExpectedExpression(_) => write!(f, "Expect expression."),
+            ParseError::InvalidAssignmentTarget(_) => write!(f, "Invalid assignment target."),
+            ParseError::TooManyArguments(_) => write!(f, "Can't have more than 255 arguments."),
+            ParseError::TooManyParameters(_) => {
+                write!(f, "Can't have more than 255 parameters.")
+            }
+            ParseError::TooManyElements(_) => write!(f, "Can't have more than 255 elements."),
+            ParseError::TooDeeplyNested(_) => {
+                write!(f, "Can't nest more than 256 levels deep.")
+            }
+            ParseError::UnexpectedCharacter(_) => write!(f, "Unexpected character."),
+            ParseError::InvalidToken(_, LexError::TooLarge) => write!(f, "Number is too large."),
+            ParseError::InvalidToken(_, LexError::Escape) => write!(f, "Invalid escape sequence."),
+            ParseError::InvalidToken(..) => write!(f, "Invalid number."),
+            ParseError::UnterminatedString(_) => write!(f, "Unterminated string."),
+            ParseError::UnterminatedComment(_) => write!(f, "Unterminated comment."),
+        }
+    }
+}
+
+/// Mistakes the resolver can find without running the program
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveError {
+    ReadInOwnInitializer(Token),
+    ReturnFromTopLevel(Token),
+    AlreadyDeclared(Token),
+    ReturnFromInitializer(Token),
+    ThisOutsideClass(Token),
+    SuperOutsideClass(Token),
+    SuperWithoutSuperclass(Token),
+    InheritFromSelf(Token),
+    ImportNotTopLevel(Token),
+}
+
+impl ResolveError {
+    pub fn token(&self) -> &Token {
+        match self {
+            ResolveError::ReadInOwnInitializer(token)
+            | ResolveError::ReturnFromTopLevel(token)
+            | ResolveError::AlreadyDeclared(token)
+            | ResolveError::ReturnFromInitializer(token)
+            | ResolveError::ThisOutsideClass(token)
+            | ResolveError::SuperOutsideClass(token)
+            | ResolveError::SuperWithoutSuperclass(token)
+            | ResolveError::InheritFromSelf(token)
+            | ResolveError::ImportNotTopLevel(token) => token,
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::ReadInOwnInitializer(_) => {
+                write!(f, "Can't read local variable in its own initializer.")
+            }
+            ResolveError::ReturnFromTopLevel(_) => write!(f, "Can't return from top-level code."),
+            ResolveError::AlreadyDeclared(_) => {
+                write!(f, "Already a variable with this name in this scope.")
+            }
+            ResolveError::ReturnFromInitializer(_) => {
+                write!(f, "Can't return a value from an initializer.")
+            }
+            ResolveError::ThisOutsideClass(_) => write!(f, "Can't use 'this' outside of a class."),
+            ResolveError::SuperOutsideClass(_) => {
+                write!(f, "Can't use 'super' outside of a class.")
+            }
+            ResolveError::SuperWithoutSuperclass(_) => {
+                write!(f, "Can't use 'super' in a class with no superclass.")
+            }
+            ResolveError::InheritFromSelf(_) => write!(f, "A class can't inherit from itself."),
+            ResolveError::ImportNotTopLevel(_) => {
+                write!(f, "Can only import at the top level of a file.")
+            }
+        }
+    }
+}
+
+/// An `import` whose module can't be loaded, reported at its path
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportError {
+    /// The file could not be read, with the reason the system gave
+    Unreadable {
+        token: Token,
+        path: String,
+        reason: String,
+    },
+    /// The file is already being loaded further out. `chain` runs from
+    /// the first file loaded through every import to this one.
+    Cycle { token: Token, chain: Vec<String> },
+    /// The host's `ImportPolicy` does not let the file be loaded
+    NotAllowed { token: Token, path: String },
+}
+
+impl ImportError {
+    pub fn token(&self) -> &Token {
+        match self {
+            ImportError::Unreadable { token, .. }
+            | ImportError::Cycle { token, .. }
+            | ImportError::NotAllowed { token, .. } => token,
+        }
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Unreadable { path, reason, .. } => {
+                write!(f, "Can't read module '{}': {}.", path, reason)
+            }
+            ImportError::Cycle { chain, .. } => {
+                write!(f, "Import cycle: {}.", chain.join(" -> "))
+            }
+            ImportError::NotAllowed { path, .. } => {
+                write!(f, "Not allowed to import '{}'.", path)
+            }
+        }
+    }
+}
+
+/// Type mistakes `--check` finds, see `typecheck`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeError {
+    /// An annotation that names no type
+    UnknownType(Token),
+    /// A unary operator and the type it can't take
+    Operand { token: Token, found: LoxType },
+    /// A binary operator and the types it can't take
+    Operands {
+        token: Token,
+        left: LoxType,
+        right: LoxType,
+    },
+    /// A value that doesn't fit its annotation, reported at the value
+    Mismatch {
+        token: Token,
+        expected: LoxType,
+        found: LoxType,
+    },
+    WrongArity {
+        token: Token,
+        expected: usize,
+        got: usize,
+    },
+}
+
+impl TypeError {
+    pub fn token(&self) -> &Token {
+        match self {
+            TypeError::UnknownType(token)
+            | TypeError::Operand { token, .. }
+            | TypeError::Operands { token, .. }
+            | TypeError::Mismatch { token, .. }
+            | TypeError::WrongArity { token, .. } => token,
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::UnknownType(token) => write!(f, "Unknown type '{}'.", token.name()),
+            TypeError::Operand { found, .. } => {
+                write!(f, "Operand must be a number, not {}.", found)
+            }
+            TypeError::Operands { token, left, right } if token.kind == Type::Plus => write!(
+                f,
+                "Operands must be two numbers or two strings, not {} and {}.",
+                left, right
+            ),
+            TypeError::Operands { left, right, .. } => {
+                write!(f, "Operands must be numbers, not {} and {}.", left, right)
+            }
+            TypeError::Mismatch {
+                expected, found, ..
+            } => write!(f, "Expected {} but found {}.", expected, found),
+            TypeError::WrongArity { expected, got, .. } => {
+                write!(f, "Expected {} arguments but got {}.", expected, got)
+            }
+        }
+    }
+}
+
+/// Limits of the bytecode format that a program ran into while compiling,
+/// and what the wasm backend can't lower
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    TooManyConstants(Token),
+    TooManyLocals(Token),
+    TooManyUpvalues(Token),
+    LoopTooLarge(Token),
+    JumpTooLarge(Token),
+    /// Says what kind of thing the backend has no lowering for
+    Unsupported(Token, &'static str),
+    WrongArity {
+        token: Token,
+        expected: usize,
+        got: usize,
+    },
+}
+
+impl CompileError {
+    pub fn token(&self) -> &Token {
+        match self {
+            CompileError::TooManyConstants(token)
+            | CompileError::TooManyLocals(token)
+            | CompileError::TooManyUpvalues(token)
+            | CompileError::LoopTooLarge(token)
+            | CompileError::JumpTooLarge(token)
+            | CompileError::Unsupported(token, _)
+            | CompileError::WrongArity { token, .. } => token,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::TooManyConstants(_) => write!(f, "Too many constants in one chunk."),
+            CompileError::TooManyLocals(_) => write!(f, "Too many local variables in function."),
+            CompileError::TooManyUpvalues(_) => {
+                write!(f, "Too many closure variables in function.")
+            }
+            CompileError::LoopTooLarge(_) => write!(f, "Loop body too large."),
+            CompileError::JumpTooLarge(_) => write!(f, "Too much code to jump over."),
+            CompileError::Unsupported(_, what) => write!(f, "Can't compile {} to wasm.", what),
+            CompileError::WrongArity { expected, got, .. } => {
+                write!(f, "Expected {} arguments but got {}.", expected, got)
+            }
+        }
+    }
+}
+
+/// Suspicious code that is still allowed to run
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    UnusedLocal(Token),
+    /// Found by the optimizer, which leaves it for the runtime to raise
+    DivisionByZero(Token),
+}
+
+impl Warning {
+    pub fn token(&self) -> &Token {
+        match self {
+            Warning::UnusedLocal(token) | Warning::DivisionByZero(token) => token,
+        }
+    }
+}
+
+impl Warning {
+    /// The warning as the command line prints it
+    pub fn report(&self, src: &str) -> String {
+        let token = self.token();
+        format!(
+            "[line {}] Warning at '{}': {}\n{}",
+            token.line + 1,
+            token.lexeme(src),
+            self,
+            snippet(src, token)
+        )
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::UnusedLocal(token) => {
+                write!(f, "Local variable '{}' is never used.", token.name())
+            }
+            Warning::DivisionByZero(_) => write!(f, "Division by zero."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    OperandMustBeNumber(Token),
+    OperandsMustBeNumbers(Token),
+    OperandsMustBeNumbersOrStrings(Token),
+    /// Dividing by an int or float zero, rather than making an infinity
+    /// or NaN
+    DivisionByZero(Token),
+    UndefinedVariable(Token),
+    NotCallable(Token),
+    ArityMismatch {
+        token: Token,
+        expected: usize,
+        got: usize,
+    },
+    StackOverflow(Token),
+    UndefinedProperty(Token),
+    OnlyInstancesHaveProperties(Token),
+    OnlyInstancesHaveFields(Token),
+    SuperclassMustBeClass(Token),
+    NotIndexable(Token),
+    NotIndexAssignable(Token),
+    IndexMustBeInteger(Token),
+    IndexOutOfRange(Token),
+    /// A map key that is not a string, number, boolean or nil
+    InvalidKey(Token),
+    /// Indexing a map with a key it does not have, shown as `print` would
+    /// show it inside a map
+    UndefinedKey {
+        token: Token,
+        key: String,
+    },
+    /// The host's step budget ran out
+    StepLimitExceeded(Token),
+    /// A native function rejected its arguments
+    Native {
+        token: Token,
+        msg: String,
+    },
+    /// A `throw` nothing caught, with the value shown as `print` would
+    Thrown {
+        token: Token,
+        value: String,
+    },
+}
+
+impl RuntimeError {
+    pub fn token(&self) -> &Token {
+        match self {
+            RuntimeError::OperandMustBeNumber(token)
+            | RuntimeError::OperandsMustBeNumbers(token)
+            | RuntimeError::OperandsMustBeNumbersOrStrings(token)
+            | RuntimeError::DivisionByZero(token)
+            | RuntimeError::UndefinedVariable(token)
+            | RuntimeError::NotCallable(token)
+            | RuntimeError::ArityMismatch { token, .. }
+            | RuntimeError::StackOverflow(token)
+            | RuntimeError::UndefinedProperty(token)
+            | RuntimeError::OnlyInstancesHaveProperties(token)
+            | RuntimeError::OnlyInstancesHaveFields(token)
+            | RuntimeError::SuperclassMustBeClass(token)
+            | RuntimeError::NotIndexable(token)
+            | RuntimeError::NotIndexAssignable(token)
+            | RuntimeError::IndexMustBeInteger(token)
+            | RuntimeError::IndexOutOfRange(token)
+            | RuntimeError::InvalidKey(token)
+            | RuntimeError::UndefinedKey { token, .. }
+            | RuntimeError::StepLimitExceeded(token)
+            | RuntimeError::Native { token, .. }
+            | RuntimeError::Thrown { token, .. } => token,
+        }
+    }
+
+    /// Whether a `catch` can stop the error. Running out of steps is the
+    /// host's decision, so the program never gets to see it.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, RuntimeError::StepLimitExceeded(_))
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::OperandMustBeNumber(_) => write!(f, "Operand must be a number."),
+            RuntimeError::OperandsMustBeNumbers(_) => write!(f, "Operands must be numbers."),
+            RuntimeError::OperandsMustBeNumbersOrStrings(_) => {
+                write!(f, "Operands must be two numbers or two strings.")
+            }
+            RuntimeError::DivisionByZero(_) => write!(f, "Division by zero."),
+            RuntimeError::UndefinedVariable(token) => {
+                write!(f, "Undefined variable '{}'.", token.name())
+            }
+            RuntimeError::NotCallable(_) => write!(f, "Can only call functions and classes."),
+            RuntimeError::ArityMismatch { expected, got, .. } => {
+                write!(f, "Expected {} arguments but got {}.", expected, got)
+            }
+            RuntimeError::StackOverflow(_) => write!(f, "Stack overflow."),
+            RuntimeError::UndefinedProperty(token) => {
+                write!(f, "Undefined property '{}'.", token.name())
+            }
+            RuntimeError::OnlyInstancesHaveProperties(_) => {
+                write!(f, "Only instances have properties.")
+            }
+            RuntimeError::OnlyInstancesHaveFields(_) => write!(f, "Only instances have fields."),
+            RuntimeError::SuperclassMustBeClass(_) => write!(f, "Superclass must be a class."),
+            RuntimeError::NotIndexable(_) => {
+                write!(f, "Only lists, maps and strings can be indexed.")
+            }
+            RuntimeError::NotIndexAssignable(_) => {
+                write!(f, "Only list and map elements can be assigned.")
+            }
+            RuntimeError::IndexMustBeInteger(_) => write!(f, "Index must be an integer."),
+            RuntimeError::IndexOutOfRange(_) => write!(f, "Index out of range."),
+            RuntimeError::InvalidKey(_) => {
+                write!(f, "Map keys must be strings, numbers, booleans or nil.")
+            }
+            RuntimeError::UndefinedKey { key, .. } => write!(f, "Undefined key {}.", key),
+            RuntimeError::StepLimitExceeded(_) => write!(f, "Step limit exceeded."),
+            RuntimeError::Native { msg, .. } => write!(f, "{}", msg),
+            RuntimeError::Thrown { value, .. } => write!(f, "Uncaught error: {}", value),
+        }
+    }
+}
+
+/// Frames shown at each end of a long stack trace
+const TRACE_EDGE: usize = 8;
+
+/// A call an uncaught runtime error passed through on its way out
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// `None` for the top level of the script
+    pub function: Option<String>,
+    /// The line the frame was running when the error left it
+    pub line: i32,
+    /// The module the function was declared in, as numbered by the
+    /// backend's `run_module`, `None` for the program itself
+    pub module: Option<usize>,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line + 1, name),
+            None => write!(f, "[line {}] in script", self.line + 1),
+        }
+    }
+}
+
+/// Everything that can stop a program, from the stage that found it
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+    Parse(Vec<ParseError>),
+    Resolve(Vec<ResolveError>),
+    Compile(Vec<CompileError>),
+    Type(Vec<TypeError>),
+    /// The error and the calls it unwound, innermost first
+    Runtime(RuntimeError, Vec<TraceFrame>),
+    Import(ImportError),
+    /// Something went wrong in a file loaded by `import`, which has to be
+    /// reported against that file's source
+    Module {
+        path: String,
+        source: String,
+        error: Box<LoxError>,
+    },
+}
+
+impl LoxError {
+    /// What the command line exits with: 65 when the program could not
+    /// start and 70 when it failed while running
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LoxError::Runtime(..) => 70,
+            LoxError::Module { error, .. } => error.exit_code(),
+            _ => 65,
+        }
+    }
+
+    /// The errors as the command line prints them, each with a snippet of
+    /// `src`
+    pub fn report(&self, src: &str) -> String {
+        let at = |token: &Token| match token.kind {
+            Type::Eof => " at end".to_string(),
+            _ => format!(" at '{}'", token.lexeme(src)),
+        };
+        let compile_time = |token: &Token, location: String, msg: String| {
+            format!(
+                "[line {}] Error{}: {}\n{}",
+                token.line + 1,
+                location,
+                msg,
+                snippet(src, token)
+            )
+        };
+        match self {
+            LoxError::Parse(errors) => errors
+                .iter()
+                .map(|err| {
+                    let location = if err.is_lexical() {
+                        String::new()
+                    } else {
+                        at(err.token())
+                    };
+                    compile_time(err.token(), location, err.to_string())
+                })
+                .collect(),
+            LoxError::Resolve(errors) => errors
+                .iter()
+                .map(|err| compile_time(err.token(), at(err.token()), err.to_string()))
+                .collect(),
+            LoxError::Compile(errors) => errors
+                .iter()
+                .map(|err| compile_time(err.token(), at(err.token()), err.to_string()))
+                .collect(),
+            LoxError::Type(errors) => errors
+                .iter()
+                .map(|err| compile_time(err.token(), at(err.token()), err.to_string()))
+                .collect(),
+            LoxError::Runtime(err, trace) => {
+                let mut report = format!(
+                    "{}\n[line {}]\n{}",
+                    err,
+                    err.token().line + 1,
+                    snippet(src, err.token())
+                );
+                // a runaway recursion would bury the error under
+                // hundreds of identical frames
+                let elided = trace.len().saturating_sub(2 * TRACE_EDGE);
+                for (idx, frame) in trace.iter().enumerate() {
+                    if elided > 0 && idx == TRACE_EDGE {
+                        report.push_str(&format!("[... {} more calls]\n", elided));
+                    }
+                    if elided == 0 || idx < TRACE_EDGE || idx >= TRACE_EDGE + elided {
+                        report.push_str(&format!("{}\n", frame));
+                    }
+                }
+                report
+            }
+            LoxError::Import(err) => compile_time(err.token(), at(err.token()), err.to_string()),
+            LoxError::Module {
+                path,
+                source,
+                error,
+            } => format!("In {}:\n{}", path, error.report(source)),
+        }
+    }
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn line(token: &Token, msg: &dyn fmt::Display) -> String {
+            format!("[line {}] {}", token.line + 1, msg)
+        }
+        let lines: Vec<String> = match self {
+            LoxError::Parse(errors) => errors.iter().map(|err| line(err.token(), err)).collect(),
+            LoxError::Resolve(errors) => errors.iter().map(|err| line(err.token(), err)).collect(),
+            LoxError::Compile(errors) => errors.iter().map(|err| line(err.token(), err)).collect(),
+            LoxError::Type(errors) => errors.iter().map(|err| line(err.token(), err)).collect(),
+            LoxError::Runtime(err, _) => vec![line(err.token(), err)],
+            LoxError::Import(err) => vec![line(err.token(), err)],
+            LoxError::Module { path, error, .. } => vec![format!("In {}:\n{}", path, error)],
+        };
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+impl From<RuntimeError> for LoxError {
+    fn from(err: RuntimeError) -> Self {
+        LoxError::Runtime(err, Vec::new())
+    }
+}
+
+/// A value the host tried to define that the backend can't hold
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostError {
+    /// The VM takes numbers, strings, booleans, nil, natives, modules and
+    /// lists and maps of those, but not the tree-walker's functions,
+    /// classes and instances
+    Unsupported(String),
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::Unsupported(name) => write!(
+                f,
+                "Can't give '{}' to the VM, only numbers, strings, booleans, nil, natives, modules, lists and maps.",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HostError {}
+
+/// Renders the source line `token` is on with its span underlined, in the
+/// style of rustc
+pub fn snippet(src: &str, token: &Token) -> String {
+    let line_no = (token.line + 1).to_string();
+    let gutter = " ".repeat(line_no.len());
+    let line = src.lines().nth(token.line as usize).unwrap_or("");
+    let width = token
+        .lexeme(src)
+        .lines()
+        .next()
+        .map_or(0, |first| first.chars().count())
+        .max(1);
+    format!(
+        "{} |\n{} | {}\n{} | {}{}\n",
+        gutter,
+        line_no,
+        line,
+        gutter,
+        " ".repeat(token.span.column),
+        "^".repeat(width)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    #[test]
+    fn test_snippet() {
+        let src = "var a = 1;\nprint a + foo;";
+        let token = Lexer::new(src)
+            .find(|token| token.lexeme(src) == "foo")
+            .unwrap();
+        assert_eq!(
+            snippet(src, &token),
+            "  |\n2 | print a + foo;\n  |           ^^^\n"
+        );
+        assert_eq!(
+            RuntimeError::UndefinedVariable(token).to_string(),
+            "Undefined variable 'foo'."
+        );
+    }
+}
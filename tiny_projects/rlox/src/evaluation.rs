@@ -1,30 +1,1037 @@
+use crate::error::{RuntimeError, TraceFrame};
 use crate::grammar::*;
+use crate::lexer::Token;
+use crate::stdlib::{self, Method, Receiver};
+use crate::value::{
+    Key, LoxClass, LoxFunction, LoxInstance, Module, NativeFunction, OrderedMap, Value,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
 
-pub fn evaluate(expr: &Expr) -> Result<f64, ()> {
-    match expr {
-        Expr::Literal(lit) => match lit {
-            Literal::Float(val) => Ok(*val),
-            Literal::Int(val) => Ok((*val).into()),
-            _ => Err(()),
+/// A scope of variables. Blocks get their own environment that points back
+/// at the one they are nested in.
+#[derive(Default)]
+pub struct Environment {
+    values: HashMap<String, Value>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_enclosed(enclosing: Rc<RefCell<Environment>>) -> Self {
+        Self {
+            values: HashMap::new(),
+            enclosing: Some(enclosing),
+        }
+    }
+
+    pub fn define(&mut self, name: &str, value: Value) {
+        self.values.insert(name.to_string(), value);
+    }
+
+    /// The scope this one is nested in, `None` for the globals
+    pub fn enclosing(&self) -> Option<Rc<RefCell<Environment>>> {
+        self.enclosing.clone()
+    }
+
+    /// The variables defined directly in this scope, sorted by name
+    pub fn variables(&self) -> Vec<(String, Value)> {
+        let mut variables: Vec<(String, Value)> = self
+            .values
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        variables.sort_by(|a, b| a.0.cmp(&b.0));
+        variables
+    }
+
+    pub fn get(&self, name: &Token) -> Result<Value, RuntimeError> {
+        match (self.values.get(name.name()), &self.enclosing) {
+            (Some(value), _) => Ok(value.clone()),
+            (None, Some(enclosing)) => enclosing.borrow().get(name),
+            (None, None) => Err(RuntimeError::UndefinedVariable(name.clone())),
+        }
+    }
+
+    /// The environment `depth` hops out along the enclosing chain
+    fn ancestor(env: &Rc<RefCell<Environment>>, depth: usize) -> Rc<RefCell<Environment>> {
+        let mut env = env.clone();
+        for _ in 0..depth {
+            let enclosing = env
+                .borrow()
+                .enclosing
+                .clone()
+                .expect("resolver computed a depth deeper than the scope chain");
+            env = enclosing;
+        }
+        env
+    }
+
+    pub fn get_at(env: &Rc<RefCell<Environment>>, depth: usize, name: &str) -> Option<Value> {
+        Self::ancestor(env, depth)
+            .borrow()
+            .values
+            .get(name)
+            .cloned()
+    }
+
+    pub fn assign_at(
+        env: &Rc<RefCell<Environment>>,
+        depth: usize,
+        name: &Token,
+        value: Value,
+    ) -> Result<(), RuntimeError> {
+        match Self::ancestor(env, depth)
+            .borrow_mut()
+            .values
+            .get_mut(name.name())
+        {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(RuntimeError::UndefinedVariable(name.clone())),
+        }
+    }
+
+    pub fn assign(&mut self, name: &Token, value: Value) -> Result<(), RuntimeError> {
+        if let Some(slot) = self.values.get_mut(name.name()) {
+            *slot = value;
+            Ok(())
+        } else if let Some(enclosing) = &self.enclosing {
+            enclosing.borrow_mut().assign(name, value)
+        } else {
+            Err(RuntimeError::UndefinedVariable(name.clone()))
+        }
+    }
+}
+
+/// Why execution of a statement stopped before reaching its end
+#[derive(Debug)]
+pub enum Interrupt {
+    Return(Value),
+    Error(RuntimeError),
+}
+
+impl From<RuntimeError> for Interrupt {
+    fn from(err: RuntimeError) -> Self {
+        Interrupt::Error(err)
+    }
+}
+
+/// Deep enough for any reasonable recursion. Calls move on to a new stack
+/// segment when needed, so this is the limit whatever the host's stack.
+pub const MAX_CALL_DEPTH: usize = 512;
+
+/// A call in progress, as a debugger sees it
+pub struct Frame {
+    /// The function running, `None` for the top level
+    pub function: Option<String>,
+    /// Line of the statement this frame is running
+    pub line: i32,
+    /// Innermost scope of that statement
+    pub env: Rc<RefCell<Environment>>,
+}
+
+/// Gets control from the tree-walker before each statement runs
+pub trait Debugger {
+    /// `stack` holds every call in progress, innermost last
+    fn before_statement(&mut self, stack: &[Frame]);
+}
+
+/// Runs the syntax tree directly
+pub struct TreeWalker {
+    pub globals: Rc<RefCell<Environment>>,
+    /// Scope distances worked out by the resolver
+    locals: HashMap<ExprId, usize>,
+    depth: usize,
+    /// Where `print` writes
+    out: Box<dyn Write>,
+    /// Loop iterations and calls left before the program is stopped
+    steps_left: Option<u64>,
+    debugger: Option<Box<dyn Debugger>>,
+    /// Calls in progress, only tracked while a debugger is attached
+    frames: Vec<Frame>,
+    /// The value of the `throw` being unwound, which its error only shows
+    thrown: Option<Value>,
+    /// Functions the current error has left so far, innermost first, with
+    /// the line each was called from and the module each belongs to
+    unwound: Vec<(String, i32, Option<usize>)>,
+    /// The globals of every module `run_module` is running or has run
+    namespaces: Vec<Rc<RefCell<Environment>>>,
+    /// Every module `run_module` made, by the index it returned
+    modules: Vec<Rc<Module>>,
+    /// The module each `import` binds
+    imports: HashMap<ExprId, usize>,
+}
+
+impl Default for TreeWalker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TreeWalker {
+    pub fn new() -> Self {
+        Self {
+            globals: Rc::new(RefCell::new(Environment::new())),
+            locals: HashMap::new(),
+            depth: 0,
+            out: Box::new(io::stdout()),
+            steps_left: None,
+            debugger: None,
+            frames: Vec::new(),
+            thrown: None,
+            unwound: Vec::new(),
+            namespaces: Vec::new(),
+            modules: Vec::new(),
+            imports: HashMap::new(),
+        }
+    }
+
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Allows `limit` more loop iterations and calls, or any number with
+    /// `None`
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.steps_left = limit;
+    }
+
+    /// Hands control to `debugger` before every statement from now on
+    pub fn set_debugger(&mut self, debugger: Box<dyn Debugger>) {
+        self.debugger = Some(debugger);
+    }
+
+    fn step(&mut self, token: &Token) -> Result<(), RuntimeError> {
+        match &mut self.steps_left {
+            Some(0) => Err(RuntimeError::StepLimitExceeded(token.clone())),
+            Some(steps) => {
+                *steps -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Takes on the variable bindings from a resolver pass. Bindings from
+    /// earlier passes are kept, as closures from them may still be called.
+    pub fn add_locals(&mut self, locals: HashMap<ExprId, usize>) {
+        self.locals.extend(locals);
+    }
+
+    /// Takes on the modules `import` statements bind, as indices returned
+    /// by `run_module`
+    pub fn add_imports(&mut self, imports: HashMap<ExprId, usize>) {
+        self.imports.extend(imports);
+    }
+
+    /// Runs a program with globals of its own, which start out as
+    /// `prelude`. Afterwards the ones named in `exports` are collected into
+    /// a module called `name`, and its index is returned.
+    pub fn run_module(
+        &mut self,
+        program: &[Stmt],
+        name: &str,
+        prelude: &[(String, Value)],
+        exports: &[String],
+    ) -> Result<usize, RuntimeError> {
+        let mut globals = Environment::new();
+        for (member, value) in prelude.iter() {
+            globals.define(member, value.clone());
+        }
+        let globals = Rc::new(RefCell::new(globals));
+        self.namespaces.push(globals.clone());
+        let outer = std::mem::replace(&mut self.globals, globals.clone());
+        let result = program.iter().try_for_each(|stmt| self.run(stmt));
+        self.globals = outer;
+        if let Err(err) = result {
+            self.namespaces.pop();
+            return Err(err);
+        }
+        let members = exports
+            .iter()
+            .filter_map(|member| {
+                let value = Environment::get_at(&globals, 0, member)?;
+                Some((member.clone(), value))
+            })
+            .collect();
+        self.modules.push(Rc::new(Module {
+            name: name.to_string(),
+            members,
+        }));
+        Ok(self.modules.len() - 1)
+    }
+
+    fn look_up_variable(
+        &self,
+        name: &Token,
+        id: ExprId,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Result<Value, RuntimeError> {
+        match self.locals.get(&id) {
+            Some(depth) => Environment::get_at(env, *depth, name.name())
+                .ok_or_else(|| RuntimeError::UndefinedVariable(name.clone())),
+            None => self.globals.borrow().get(name),
+        }
+    }
+
+    /// Runs a top level statement in the global scope
+    pub fn run(&mut self, stmt: &Stmt) -> Result<(), RuntimeError> {
+        let globals = self.globals.clone();
+        match crate::ensure_stack(|| self.execute(stmt, &globals)) {
+            Ok(()) | Err(Interrupt::Return(_)) => Ok(()),
+            Err(Interrupt::Error(err)) => Err(err),
+        }
+    }
+
+    /// The calls `err` unwound on its way out of `run`, innermost first
+    pub fn take_trace(&mut self, err: &RuntimeError) -> Vec<TraceFrame> {
+        let mut line = err.token().line;
+        let mut trace = Vec::new();
+        for (function, call_line, module) in self.unwound.drain(..) {
+            trace.push(TraceFrame {
+                function: Some(function),
+                line,
+                module,
+            });
+            line = call_line;
+        }
+        trace.push(TraceFrame {
+            function: None,
+            line,
+            module: None,
+        });
+        trace
+    }
+
+    pub fn execute(
+        &mut self,
+        stmt: &Stmt,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Result<(), Interrupt> {
+        if self.debugger.is_some() {
+            self.pause(stmt, env);
+        }
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.evaluate(expr, env)?;
+            }
+            Stmt::Print(expr) => {
+                let value = self.evaluate(expr, env)?;
+                // like `println!`, minus the panic when stdout goes away
+                let _ = writeln!(self.out, "{}", value);
+            }
+            Stmt::Var(name, _, initializer) => {
+                let value = match initializer {
+                    Some(expr) => self.evaluate(expr, env)?,
+                    None => Value::Nil,
+                };
+                env.borrow_mut().define(name.name(), value);
+            }
+            Stmt::Block(statements) => {
+                let scope = Rc::new(RefCell::new(Environment::new_enclosed(env.clone())));
+                self.execute_block(statements, &scope)?;
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                if self.evaluate(condition, env)?.is_truthy() {
+                    self.execute(then_branch, env)?;
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch, env)?;
+                }
+            }
+            Stmt::While(condition, body, end) => {
+                while self.evaluate(condition, env)?.is_truthy() {
+                    self.step(end)?;
+                    self.execute(body, env)?;
+                }
+            }
+            Stmt::Function(decl) => {
+                let function = LoxFunction {
+                    decl: decl.clone(),
+                    closure: env.clone(),
+                    globals: self.globals.clone(),
+                    is_initializer: false,
+                };
+                env.borrow_mut()
+                    .define(decl.name.name(), Value::Function(Rc::new(function)));
+            }
+            Stmt::Return(_, value) => {
+                let value = match value {
+                    Some(expr) => self.evaluate(expr, env)?,
+                    None => Value::Nil,
+                };
+                return Err(Interrupt::Return(value));
+            }
+            Stmt::Throw(keyword, value) => {
+                let value = self.evaluate(value, env)?;
+                let err = RuntimeError::Thrown {
+                    token: keyword.clone(),
+                    value: value.to_string(),
+                };
+                self.thrown = Some(value);
+                return Err(err.into());
+            }
+            Stmt::Try(_, body, name, handler) => {
+                let scope = Rc::new(RefCell::new(Environment::new_enclosed(env.clone())));
+                let err = match self.execute_block(body, &scope) {
+                    Err(Interrupt::Error(err)) if err.is_catchable() => err,
+                    result => return result,
+                };
+                // a runtime error is caught as its message
+                let value = match err {
+                    RuntimeError::Thrown { .. } => self.thrown.take().unwrap_or(Value::Nil),
+                    err => Value::Str(err.to_string()),
+                };
+                self.unwound.clear();
+                let mut scope = Environment::new_enclosed(env.clone());
+                scope.define(name.name(), value);
+                self.execute_block(handler, &Rc::new(RefCell::new(scope)))?;
+            }
+            Stmt::Class(decl) => self.class_declaration(decl, env)?,
+            Stmt::Import(_, name, id) => {
+                let module = self.modules[self.imports[id]].clone();
+                env.borrow_mut().define(name.name(), Value::Module(module));
+            }
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self, stmt: &Stmt, env: &Rc<RefCell<Environment>>) {
+        let line = match stmt.line() {
+            Some(line) => line,
+            None => return,
+        };
+        if self.frames.is_empty() {
+            self.frames.push(Frame {
+                function: None,
+                line,
+                env: env.clone(),
+            });
+        }
+        if let Some(frame) = self.frames.last_mut() {
+            frame.line = line;
+            frame.env = env.clone();
+        }
+        if let Some(debugger) = &mut self.debugger {
+            debugger.before_statement(&self.frames);
+        }
+    }
+
+    fn class_declaration(
+        &mut self,
+        decl: &ClassDecl,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Result<(), RuntimeError> {
+        let superclass = match &decl.superclass {
+            Some(expr) => match self.evaluate(expr, env)? {
+                Value::Class(class) => Some(class),
+                _ => {
+                    let token = match expr {
+                        Expr::Variable(name, _) => name.clone(),
+                        _ => decl.name.clone(),
+                    };
+                    return Err(RuntimeError::SuperclassMustBeClass(token));
+                }
+            },
+            None => None,
+        };
+        env.borrow_mut().define(decl.name.name(), Value::Nil);
+
+        // methods of a subclass close over an extra scope holding `super`
+        let closure = match &superclass {
+            Some(superclass) => {
+                let mut scope = Environment::new_enclosed(env.clone());
+                scope.define("super", Value::Class(superclass.clone()));
+                Rc::new(RefCell::new(scope))
+            }
+            None => env.clone(),
+        };
+        let methods = decl
+            .methods
+            .iter()
+            .map(|method| {
+                let function = LoxFunction {
+                    decl: method.clone(),
+                    closure: closure.clone(),
+                    globals: self.globals.clone(),
+                    is_initializer: method.name.name() == "init",
+                };
+                (method.name.name().to_string(), Rc::new(function))
+            })
+            .collect();
+
+        let class = LoxClass {
+            name: decl.name.name().to_string(),
+            superclass,
+            methods,
+        };
+        env.borrow_mut()
+            .define(decl.name.name(), Value::Class(Rc::new(class)));
+        Ok(())
+    }
+
+    pub fn execute_block(
+        &mut self,
+        statements: &[Stmt],
+        env: &Rc<RefCell<Environment>>,
+    ) -> Result<(), Interrupt> {
+        for stmt in statements.iter() {
+            self.execute(stmt, env)?;
+        }
+        Ok(())
+    }
+
+    pub fn evaluate(
+        &mut self,
+        expr: &Expr,
+        env: &Rc<RefCell<Environment>>,
+    ) -> Result<Value, RuntimeError> {
+        match expr {
+            Expr::Literal(lit, _) => Ok(match lit {
+                Literal::String(val) => Value::Str(val.clone()),
+                Literal::Float(val) => Value::Float(*val),
+                Literal::Int(val) => Value::Int(*val),
+                Literal::True => Value::Bool(true),
+                Literal::False => Value::Bool(false),
+                Literal::Nil => Value::Nil,
+            }),
+            Expr::UnaryExpr(op, token, expr) => {
+                let val = self.evaluate(expr, env)?;
+                match op {
+                    UnaryOp::Not => Ok(Value::Bool(!val.is_truthy())),
+                    UnaryOp::Negative => {
+                        negate(val).ok_or_else(|| RuntimeError::OperandMustBeNumber(token.clone()))
+                    }
+                }
+            }
+            Expr::BinaryExpr(left, op, token, right) => {
+                let left_val = self.evaluate(left, env)?;
+                let right_val = self.evaluate(right, env)?;
+                binary(op, token, left_val, right_val)
+            }
+            Expr::Grouping(expr) => self.evaluate(expr, env),
+            Expr::Variable(token, id) => self.look_up_variable(token, *id, env),
+            Expr::Assign(name, expr, id) => {
+                let value = self.evaluate(expr, env)?;
+                match self.locals.get(id) {
+                    Some(depth) => Environment::assign_at(env, *depth, name, value.clone())?,
+                    None => self.globals.borrow_mut().assign(name, value.clone())?,
+                }
+                Ok(value)
+            }
+            Expr::Logical(left, op, right) => {
+                let left_val = self.evaluate(left, env)?;
+                // the result is whichever operand decided the outcome
+                match (op, left_val.is_truthy()) {
+                    (LogicalOp::Or, true) | (LogicalOp::And, false) => Ok(left_val),
+                    _ => self.evaluate(right, env),
+                }
+            }
+            Expr::Call(callee, paren, args) => {
+                let callee = self.evaluate(callee, env)?;
+                let mut arg_vals = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    arg_vals.push(self.evaluate(arg, env)?);
+                }
+                self.call(callee, paren, arg_vals)
+            }
+            Expr::Get(object, name) => match self.evaluate(object, env)? {
+                Value::Module(module) => module
+                    .members
+                    .get(name.name())
+                    .cloned()
+                    .ok_or_else(|| RuntimeError::UndefinedProperty(name.clone())),
+                Value::Instance(instance) => {
+                    let field = instance.borrow().fields.get(name.name()).cloned();
+                    if let Some(value) = field {
+                        return Ok(value);
+                    }
+                    let method = instance.borrow().class.find_method(name.name());
+                    match method {
+                        Some(method) => Ok(Value::Function(Rc::new(method.bind(instance)))),
+                        None => Err(RuntimeError::UndefinedProperty(name.clone())),
+                    }
+                }
+                receiver => bind_builtin(receiver, name.name())
+                    .ok_or_else(|| RuntimeError::OnlyInstancesHaveProperties(name.clone())),
+            },
+            Expr::Set(object, name, value) => match self.evaluate(object, env)? {
+                Value::Instance(instance) => {
+                    let value = self.evaluate(value, env)?;
+                    instance
+                        .borrow_mut()
+                        .fields
+                        .insert(name.name().to_string(), value.clone());
+                    Ok(value)
+                }
+                _ => Err(RuntimeError::OnlyInstancesHaveFields(name.clone())),
+            },
+            Expr::This(keyword, id) => match self.locals.get(id) {
+                Some(depth) => Ok(Environment::get_at(env, *depth, "this")
+                    .expect("resolver bound `this` to a method scope")),
+                None => Err(RuntimeError::UndefinedVariable(keyword.clone())),
+            },
+            Expr::Super(_, method, id) => {
+                let depth = *self
+                    .locals
+                    .get(id)
+                    .expect("resolver bound `super` to a subclass scope");
+                // `this` always lives in the scope just inside `super`
+                let superclass = Environment::get_at(env, depth, "super");
+                let object = Environment::get_at(env, depth - 1, "this");
+                match (superclass, object) {
+                    (Some(Value::Class(superclass)), Some(Value::Instance(object))) => {
+                        match superclass.find_method(method.name()) {
+                            Some(found) => Ok(Value::Function(Rc::new(found.bind(object)))),
+                            None => Err(RuntimeError::UndefinedProperty(method.clone())),
+                        }
+                    }
+                    _ => unreachable!("`super` and `this` are bound when methods are created"),
+                }
+            }
+            Expr::List(_, elements) => {
+                let mut items = Vec::with_capacity(elements.len());
+                for element in elements.iter() {
+                    items.push(self.evaluate(element, env)?);
+                }
+                Ok(Value::list(items))
+            }
+            Expr::Map(brace, entries) => {
+                let mut map = OrderedMap::new();
+                for (key, value) in entries.iter() {
+                    let key = self.evaluate(key, env)?;
+                    let key = Key::from_value(&key)
+                        .ok_or_else(|| RuntimeError::InvalidKey(brace.clone()))?;
+                    map.insert(key, self.evaluate(value, env)?);
+                }
+                Ok(Value::Map(Rc::new(RefCell::new(map))))
+            }
+            Expr::Index(object, bracket, index) => {
+                let object = self.evaluate(object, env)?;
+                let index = self.evaluate(index, env)?;
+                get_index(&object, &index, bracket)
+            }
+            Expr::SetIndex(object, bracket, index, value) => {
+                let object = self.evaluate(object, env)?;
+                let index = self.evaluate(index, env)?;
+                let value = self.evaluate(value, env)?;
+                set_index(&object, &index, value.clone(), bracket)?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn call(
+        &mut self,
+        callee: Value,
+        paren: &Token,
+        args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        let arity = match &callee {
+            Value::Function(fun) => fun.decl.params.len(),
+            Value::Native(native) => native.arity,
+            Value::Class(class) => class.arity(),
+            _ => return Err(RuntimeError::NotCallable(paren.clone())),
+        };
+        if args.len() != arity {
+            return Err(RuntimeError::ArityMismatch {
+                token: paren.clone(),
+                expected: arity,
+                got: args.len(),
+            });
+        }
+        self.step(paren)?;
+
+        match callee {
+            Value::Function(fun) => {
+                if self.depth >= MAX_CALL_DEPTH {
+                    return Err(RuntimeError::StackOverflow(paren.clone()));
+                }
+                let mut scope = Environment::new_enclosed(fun.closure.clone());
+                for (param, arg) in fun.decl.params.iter().zip(args) {
+                    scope.define(param.name(), arg);
+                }
+
+                let scope = Rc::new(RefCell::new(scope));
+                if self.debugger.is_some() {
+                    self.frames.push(Frame {
+                        function: Some(fun.decl.name.name().to_string()),
+                        line: fun.decl.name.line,
+                        env: scope.clone(),
+                    });
+                }
+                self.depth += 1;
+                let globals = std::mem::replace(&mut self.globals, fun.globals.clone());
+                let result = crate::ensure_stack(|| self.execute_block(&fun.decl.body, &scope));
+                self.globals = globals;
+                self.depth -= 1;
+                if self.debugger.is_some() {
+                    self.frames.pop();
+                }
+                let value = match result {
+                    Ok(()) => Value::Nil,
+                    Err(Interrupt::Return(value)) => value,
+                    Err(Interrupt::Error(err)) => {
+                        let module = self
+                            .namespaces
+                            .iter()
+                            .position(|globals| Rc::ptr_eq(globals, &fun.globals));
+                        self.unwound
+                            .push((fun.decl.name.name().to_string(), paren.line, module));
+                        return Err(err);
+                    }
+                };
+                if fun.is_initializer {
+                    // even a bare `return;` in `init` hands back the instance
+                    return Ok(Environment::get_at(&fun.closure, 0, "this")
+                        .expect("initializers are always bound"));
+                }
+                Ok(value)
+            }
+            Value::Class(class) => {
+                let instance = Rc::new(RefCell::new(LoxInstance::new(class.clone())));
+                if let Some(init) = class.find_method("init") {
+                    let init = Value::Function(Rc::new(init.bind(instance.clone())));
+                    self.call(init, paren, args)?;
+                }
+                Ok(Value::Instance(instance))
+            }
+            Value::Native(native) => (native.func)(&args).map_err(|msg| RuntimeError::Native {
+                token: paren.clone(),
+                msg,
+            }),
+            _ => unreachable!("callee was checked above"),
+        }
+    }
+}
+
+/// The method `name` of a string, list or map, as a function that remembers
+/// its receiver
+fn bind_builtin(receiver: Value, name: &str) -> Option<Value> {
+    let kind = match receiver {
+        Value::Str(_) => Receiver::Str,
+        Value::List(_) => Receiver::List,
+        Value::Map(_) => Receiver::Map,
+        _ => return None,
+    };
+    let method = Method::lookup(kind, name)?;
+    let native = NativeFunction {
+        name: method.name().to_string(),
+        arity: method.arity(),
+        func: Box::new(move |args| stdlib::call_method(&receiver, method, args)),
+    };
+    Some(Value::Native(Rc::new(native)))
+}
+
+/// Checks an index, as returned by `as_int`, against a list or string of
+/// `len` elements. On failure this returns the kind of error, both backends
+/// attach their own token.
+pub fn check_index(index: Option<i64>, len: usize) -> Result<usize, fn(Token) -> RuntimeError> {
+    match index {
+        None => Err(RuntimeError::IndexMustBeInteger),
+        Some(idx) if idx < 0 || idx as usize >= len => Err(RuntimeError::IndexOutOfRange),
+        Some(idx) => Ok(idx as usize),
+    }
+}
+
+fn position(index: &Value, len: usize, bracket: &Token) -> Result<usize, RuntimeError> {
+    check_index(index.as_int(), len).map_err(|err| err(bracket.clone()))
+}
+
+fn get_index(object: &Value, index: &Value, bracket: &Token) -> Result<Value, RuntimeError> {
+    match object {
+        Value::List(list) => {
+            let list = list.borrow();
+            Ok(list[position(index, list.len(), bracket)?].clone())
+        }
+        Value::Str(val) => {
+            let idx = position(index, stdlib::char_len(val), bracket)?;
+            Ok(Value::Str(
+                stdlib::char_at(val, idx as i64).expect("index was checked"),
+            ))
+        }
+        Value::Map(map) => {
+            let key =
+                Key::from_value(index).ok_or_else(|| RuntimeError::InvalidKey(bracket.clone()))?;
+            map.borrow()
+                .get(&key)
+                .cloned()
+                .ok_or_else(|| RuntimeError::UndefinedKey {
+                    token: bracket.clone(),
+                    key: index.repr(),
+                })
+        }
+        _ => Err(RuntimeError::NotIndexable(bracket.clone())),
+    }
+}
+
+fn set_index(
+    object: &Value,
+    index: &Value,
+    value: Value,
+    bracket: &Token,
+) -> Result<(), RuntimeError> {
+    match object {
+        Value::List(list) => {
+            let idx = position(index, list.borrow().len(), bracket)?;
+            list.borrow_mut()[idx] = value;
+        }
+        Value::Map(map) => {
+            let key =
+                Key::from_value(index).ok_or_else(|| RuntimeError::InvalidKey(bracket.clone()))?;
+            map.borrow_mut().insert(key, value);
+        }
+        _ => return Err(RuntimeError::NotIndexAssignable(bracket.clone())),
+    }
+    Ok(())
+}
+
+pub fn negate(val: Value) -> Option<Value> {
+    match val {
+        // Lox numbers are doubles at heart, so `-0` has to stay negative
+        Value::Int(0) => Some(Value::Float(-0.0)),
+        Value::Int(val) => Some(
+            val.checked_neg()
+                .map_or(Value::Float(-(val as f64)), Value::Int),
+        ),
+        Value::Float(val) => Some(Value::Float(-val)),
+        _ => None,
+    }
+}
+
+pub fn binary(
+    op: &BinaryOp,
+    token: &Token,
+    left: Value,
+    right: Value,
+) -> Result<Value, RuntimeError> {
+    match op {
+        BinaryOp::Eq => Ok(Value::Bool(left.is_equal(&right))),
+        BinaryOp::Ne => Ok(Value::Bool(!left.is_equal(&right))),
+        BinaryOp::Plus => match (left, right) {
+            (Value::Str(a), Value::Str(b)) => Ok(Value::Str(a + &b)),
+            (a, b) => arithmetic(op, &a, &b)
+                .ok_or_else(|| RuntimeError::OperandsMustBeNumbersOrStrings(token.clone())),
+        },
+        BinaryOp::Minus | BinaryOp::Times | BinaryOp::Divide => match arithmetic(op, &left, &right)
+        {
+            Some(_) if *op == BinaryOp::Divide && right.as_float() == Some(0.0) => {
+                Err(RuntimeError::DivisionByZero(token.clone()))
+            }
+            Some(value) => Ok(value),
+            None => Err(RuntimeError::OperandsMustBeNumbers(token.clone())),
         },
-        Expr::UnaryExpr(op, expr) => {
-            let val = evaluate(expr)?;
-            match op {
-                UnaryOp::Not => Err(()),
-                UnaryOp::Negative => Ok(-val),
+        BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Lt | BinaryOp::Le => {
+            match (left.as_float(), right.as_float()) {
+                (Some(a), Some(b)) => Ok(Value::Bool(match op {
+                    BinaryOp::Gt => a > b,
+                    BinaryOp::Ge => a >= b,
+                    BinaryOp::Lt => a < b,
+                    _ => a <= b,
+                })),
+                _ => Err(RuntimeError::OperandsMustBeNumbers(token.clone())),
             }
         }
-        Expr::BinaryExpr(left, op, right) => {
-            let left_val = evaluate(left)?;
-            let right_val = evaluate(right)?;
-            match op {
-                BinaryOp::Plus => Ok(left_val + right_val),
-                BinaryOp::Minus => Ok(left_val - right_val),
-                BinaryOp::Times => Ok(left_val * right_val),
-                BinaryOp::Divide => Ok(left_val / right_val),
-                _ => Err(()),
+    }
+}
+
+/// Ints stay ints for as long as the result fits, otherwise the operation
+/// is promoted to floating point. Returns `None` if either side is not a
+/// number.
+pub fn arithmetic(op: &BinaryOp, left: &Value, right: &Value) -> Option<Value> {
+    if let (Value::Int(a), Value::Int(b)) = (left, right) {
+        let exact = match op {
+            BinaryOp::Plus => a.checked_add(*b),
+            BinaryOp::Minus => a.checked_sub(*b),
+            BinaryOp::Times => a.checked_mul(*b),
+            BinaryOp::Divide => match a.checked_rem(*b) {
+                Some(0) => a.checked_div(*b),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(val) = exact {
+            return Some(Value::Int(val));
+        }
+    }
+
+    let a = left.as_float()?;
+    let b = right.as_float()?;
+    Some(Value::Float(match op {
+        BinaryOp::Plus => a + b,
+        BinaryOp::Minus => a - b,
+        BinaryOp::Times => a * b,
+        _ => a / b,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::thread;
+
+    fn eval(input: &str) -> Result<Value, String> {
+        eval_after("", input)
+    }
+
+    /// Runs `program` and then evaluates `input` in the resulting globals
+    fn eval_after(program: &str, input: &str) -> Result<Value, String> {
+        let mut interpreter = TreeWalker::new();
+        let tokens: Vec<Token> = Lexer::new(program).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        interpreter.add_locals(crate::resolver::resolve(&program).locals);
+        for stmt in program.iter() {
+            interpreter.run(stmt).map_err(|err| err.to_string())?;
+        }
+
+        let tokens: Vec<Token> = Lexer::new(input).collect();
+        let mut parser = Parser::new(tokens);
+        let expr = parser.parse().map_err(|err| err.to_string())?;
+        let globals = interpreter.globals.clone();
+        interpreter
+            .evaluate(&expr, &globals)
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let tests = [
+            ("1 + 2", Ok(Value::Int(3))),
+            ("1 + 2.5", Ok(Value::Float(3.5))),
+            ("8 / 2", Ok(Value::Int(4))),
+            ("3 / 2", Ok(Value::Float(1.5))),
+            ("2147483647 + 1", Ok(Value::Int(2147483648))),
+            (
+                "9223372036854775807 + 1",
+                Ok(Value::Float(9223372036854775808.0)),
+            ),
+            ("-0", Ok(Value::Float(-0.0))),
+            ("\"a\" + \"b\"", Ok(Value::Str("ab".to_string()))),
+            (
+                "\"a\" + 1",
+                Err("Operands must be two numbers or two strings.".to_string()),
+            ),
+            ("nil * 1", Err("Operands must be numbers.".to_string())),
+            ("-\"a\"", Err("Operand must be a number.".to_string())),
+        ];
+        for (input, expected) in tests.iter() {
+            assert_eq!(eval(input), *expected);
+        }
+    }
+
+    #[test]
+    fn test_comparison_and_equality() {
+        let tests = [
+            ("\"a\" == \"a\"", Value::Bool(true)),
+            ("1 == 1.0", Value::Bool(true)),
+            ("nil == false", Value::Bool(false)),
+            ("nil != nil", Value::Bool(false)),
+            ("1 < 2.5", Value::Bool(true)),
+            ("2 >= 2", Value::Bool(true)),
+            ("!nil", Value::Bool(true)),
+            ("!0", Value::Bool(false)),
+        ];
+        for (input, expected) in tests.iter() {
+            assert_eq!(eval(input), Ok(expected.clone()));
+        }
+        assert_eq!(eval("0 / 0"), Err("Division by zero.".to_string()));
+        assert_eq!(eval("1.5 / -0.0"), Err("Division by zero.".to_string()));
+        assert_eq!(
+            eval("nil / 0"),
+            Err("Operands must be numbers.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let program = "
+            fun makeCounter() {
+                var i = 0;
+                fun count() {
+                    i = i + 1;
+                    return i;
+                }
+                return count;
+            }
+            var counter = makeCounter();
+            counter();
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            fun forever() { return forever(); }
+        ";
+        // running out the call depth needs more than a test thread's stack
+        thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(move || {
+                let tests = [
+                    ("counter()", Ok(Value::Int(2))),
+                    ("fib(10)", Ok(Value::Int(55))),
+                    (
+                        "fib(1, 2)",
+                        Err("Expected 1 arguments but got 2.".to_string()),
+                    ),
+                    (
+                        "counter()()",
+                        Err("Can only call functions and classes.".to_string()),
+                    ),
+                    ("forever()", Err("Stack overflow.".to_string())),
+                ];
+                for (input, expected) in tests.iter() {
+                    assert_eq!(eval_after(program, input), *expected);
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_classes() {
+        let program = "
+            class Point {
+                init(x, y) { this.x = x; this.y = y; }
+                sum() { return this.x + this.y; }
+            }
+            class Point3 < Point {
+                init(x, y, z) { super.init(x, y); this.z = z; }
+                sum() { return super.sum() + this.z; }
             }
+            var p = Point3(1, 2, 3);
+            var sum = p.sum;
+        ";
+        let tests = [
+            ("p.sum()", Ok(Value::Int(6))),
+            ("sum()", Ok(Value::Int(6))),
+            ("p.init(4, 5, 6).z", Ok(Value::Int(6))),
+            ("p.x = 10", Ok(Value::Int(10))),
+            ("p.w", Err("Undefined property 'w'.".to_string())),
+            ("sum.x", Err("Only instances have properties.".to_string())),
+            (
+                "Point(1)",
+                Err("Expected 2 arguments but got 1.".to_string()),
+            ),
+        ];
+        for (input, expected) in tests.iter() {
+            assert_eq!(eval_after(program, input), *expected);
         }
-        Expr::Grouping(expr) => evaluate(expr),
+        assert_eq!(
+            eval_after(program, "p").map(|val| val.to_string()),
+            Ok("Point3 instance".to_string())
+        );
+        assert_eq!(
+            eval_after("var a = 1; class B < a {}", "nil"),
+            Err("Superclass must be a class.".to_string())
+        );
     }
 }
@@ -0,0 +1,330 @@
+use crate::error::ParseError;
+use crate::lexer::{Lexer, Token, Type};
+use crate::parser::Parser;
+
+const INDENT: &str = "  ";
+
+/// Canonical layout of `source`, comments included. Only valid programs
+/// are formatted, anything else fails with the parse errors.
+pub fn format(source: &str) -> Result<String, Vec<ParseError>> {
+    let tokens: Vec<Token> = Lexer::new(source).collect();
+    Parser::new(tokens).parse_program()?;
+
+    let tokens: Vec<Token> = Lexer::with_comments(source)
+        .filter(|token| token.kind != Type::Eof)
+        .collect();
+    let mut formatter = Formatter {
+        src: source,
+        out: String::new(),
+        depth: 0,
+        parens: 0,
+        braces: Vec::new(),
+        newline: false,
+        last_code: None,
+        unary_minus: false,
+    };
+    for (idx, token) in tokens.iter().enumerate() {
+        let prev = idx.checked_sub(1).map(|idx| &tokens[idx]);
+        let next = tokens[idx + 1..]
+            .iter()
+            .find(|token| token.kind != Type::Comment);
+        if token.kind == Type::Comment {
+            formatter.comment(prev, token, tokens.get(idx + 1));
+        } else {
+            formatter.token(prev, token, next);
+        }
+    }
+    if !formatter.out.is_empty() {
+        formatter.out.push('\n');
+    }
+    Ok(formatter.out)
+}
+
+/// Writes tokens back out one at a time, deciding only the whitespace
+/// between them
+struct Formatter<'a> {
+    src: &'a str,
+    out: String,
+    depth: usize,
+    /// Open parentheses. Inside them `;` separates the parts of a `for`
+    /// header instead of ending a line.
+    parens: usize,
+    /// Open braces, `true` for map literals, which stay on one line
+    braces: Vec<bool>,
+    /// The next token goes on a new line
+    newline: bool,
+    /// Last token that is not a comment, what spacing depends on
+    last_code: Option<Token>,
+    /// The last token was a `-` that negates rather than subtracts
+    unary_minus: bool,
+}
+
+impl Formatter<'_> {
+    /// Line the token ends on, later than it starts for multiline strings
+    /// and comments
+    fn end_line(&self, token: &Token) -> i32 {
+        token.line + token.lexeme(self.src).matches('\n').count() as i32
+    }
+
+    /// Ends the current line and indents the next one. A blank line in the
+    /// source survives as one blank line, except at the edges of a block.
+    /// A statement broken by a comment continues one level deeper.
+    fn start_line(&mut self, prev: Option<&Token>, token: &Token) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+            let blank = match prev {
+                Some(prev) => token.line > self.end_line(prev) + 1 && prev.kind != Type::LBrace,
+                None => false,
+            };
+            if blank && token.kind != Type::RBrace {
+                self.out.push('\n');
+            }
+        }
+        let continuation = match &self.last_code {
+            Some(last) => !matches!(last.kind, Type::Semicolon | Type::LBrace | Type::RBrace),
+            None => false,
+        };
+        for _ in 0..self.depth + continuation as usize {
+            self.out.push_str(INDENT);
+        }
+        self.newline = false;
+    }
+
+    fn comment(&mut self, prev: Option<&Token>, token: &Token, next: Option<&Token>) {
+        let text = token.lexeme(self.src);
+        let trailing = match prev {
+            Some(prev) => self.end_line(prev) == token.line,
+            None => false,
+        };
+        if trailing {
+            // stays at the end of the line it was on
+            self.out.push(' ');
+        } else {
+            self.start_line(prev, token);
+        }
+        self.out.push_str(text);
+        let code_follows_on_line = match next {
+            Some(next) => next.line == self.end_line(token),
+            None => false,
+        };
+        if text.starts_with("//") || !code_follows_on_line {
+            self.newline = true;
+        }
+    }
+
+    fn token(&mut self, prev: Option<&Token>, token: &Token, next: Option<&Token>) {
+        let after_open_brace = matches!(&self.last_code, Some(last) if last.kind == Type::LBrace);
+        let map = match token.kind {
+            Type::LBrace => matches!(&self.last_code, Some(last) if expects_operand(last)),
+            Type::RBrace => self.braces.last() == Some(&true),
+            _ => false,
+        };
+        let empty_block = token.kind == Type::RBrace && after_open_brace && !self.newline;
+        if token.kind == Type::RBrace && !empty_block && !map {
+            self.depth = self.depth.saturating_sub(1);
+        }
+
+        if self.newline || self.out.is_empty() {
+            self.start_line(prev, token);
+        } else if self.needs_space(prev, token) {
+            self.out.push(' ');
+        }
+        self.out.push_str(token.lexeme(self.src));
+
+        let next_kind = next.map(|next| &next.kind);
+        match token.kind {
+            Type::LBrace => {
+                self.braces.push(map);
+                // `{}` stays on one line
+                if !map && next_kind != Some(&Type::RBrace) {
+                    self.depth += 1;
+                    self.newline = true;
+                }
+            }
+            Type::RBrace => {
+                self.braces.pop();
+                if !map {
+                    self.newline = !matches!(next_kind, Some(Type::Else | Type::Catch));
+                }
+            }
+            Type::Semicolon if self.parens == 0 => self.newline = true,
+            Type::LParen => self.parens += 1,
+            Type::RParen => self.parens = self.parens.saturating_sub(1),
+            _ => {}
+        }
+        self.unary_minus = token.kind == Type::Minus
+            && !matches!(&self.last_code, Some(last) if ends_operand(last));
+        self.last_code = Some(token.clone());
+    }
+
+    fn needs_space(&self, prev: Option<&Token>, token: &Token) -> bool {
+        if let Some(Type::Comment) = prev.map(|prev| &prev.kind) {
+            return true;
+        }
+        let last = match &self.last_code {
+            Some(last) => last,
+            None => return false,
+        };
+        let in_map = self.braces.last() == Some(&true);
+        match token.kind {
+            Type::Comma | Type::Semicolon | Type::Dot | Type::RParen | Type::RBracket => {
+                return false
+            }
+            Type::Colon => return false,
+            // a call or an index
+            Type::LParen | Type::LBracket if ends_operand(last) => return false,
+            Type::RBrace if last.kind == Type::LBrace || in_map => return false,
+            _ => {}
+        }
+        match last.kind {
+            Type::LParen | Type::LBracket | Type::Dot | Type::Bang => false,
+            Type::LBrace => !in_map,
+            Type::Minus => !self.unary_minus,
+            _ => true,
+        }
+    }
+}
+
+/// Whether an expression can end with `token`, so that what follows is an
+/// infix operator or a call
+fn ends_operand(token: &Token) -> bool {
+    matches!(
+        token.kind,
+        Type::Identifier { .. }
+            | Type::String { .. }
+            | Type::Int { .. }
+            | Type::Float { .. }
+            | Type::True
+            | Type::False
+            | Type::Nil
+            | Type::This
+            | Type::RParen
+            | Type::RBracket
+    )
+}
+
+/// Whether `token` has to be followed by an operand, which makes a `{`
+/// after it a map literal rather than a block
+fn expects_operand(token: &Token) -> bool {
+    matches!(
+        token.kind,
+        Type::Eq
+            | Type::LParen
+            | Type::LBracket
+            | Type::Comma
+            | Type::Colon
+            | Type::Print
+            | Type::Return
+            | Type::Throw
+            | Type::Minus
+            | Type::Plus
+            | Type::Slash
+            | Type::Star
+            | Type::Bang
+            | Type::BangEq
+            | Type::EqEq
+            | Type::Gt
+            | Type::Ge
+            | Type::Lt
+            | Type::Le
+            | Type::And
+            | Type::Or
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_format() {
+        let source = "\
+// leading comment
+var  a=-1 ;fun add(x,y){return x+y;}   // trailing
+
+
+class B<A{init(){super.init( );this.n=0;}
+  empty(){}}
+for(var i=0;i<3;i=i+1)print add(i,-a);
+if(!a)print \"no\";else{print a-1;/* block */}
+print 1 + // one
+  2;
+{
+  /* between */
+  print 1;
+
+}
+var m={ \"a\" :[1,2 ],\"b\":{}} ;m [\"a\"][0]=-m[\"b\"] [ 0];
+";
+        let expected = "\
+// leading comment
+var a = -1;
+fun add(x, y) {
+  return x + y;
+} // trailing
+
+class B < A {
+  init() {
+    super.init();
+    this.n = 0;
+  }
+  empty() {}
+}
+for (var i = 0; i < 3; i = i + 1) print add(i, -a);
+if (!a) print \"no\";
+else {
+  print a - 1; /* block */
+}
+print 1 + // one
+  2;
+{
+  /* between */
+  print 1;
+}
+var m = {\"a\": [1, 2], \"b\": {}};
+m[\"a\"][0] = -m[\"b\"][0];
+";
+        assert_eq!(
+            format(source).map_err(|errs| errs.len()),
+            Ok(expected.to_string())
+        );
+        assert!(format("print ;").is_err());
+    }
+
+    fn program(source: &str) -> String {
+        let tokens: Vec<Token> = Lexer::new(source).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        program.iter().map(|stmt| stmt.to_string()).collect()
+    }
+
+    /// Formatting every valid test script keeps its meaning and a second
+    /// pass changes nothing
+    #[test]
+    fn test_round_trip() {
+        let mut dirs = vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/end-to-end")];
+        let mut checked = 0;
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let source = match fs::read_to_string(&path) {
+                    Ok(source) => source,
+                    Err(_) => continue,
+                };
+                let formatted = match format(&source) {
+                    Ok(formatted) => formatted,
+                    Err(_) => continue,
+                };
+                assert_eq!(program(&formatted), program(&source), "{:?}", path);
+                assert_eq!(format(&formatted).ok(), Some(formatted), "{:?}", path);
+                checked += 1;
+            }
+        }
+        assert!(checked > 100);
+    }
+}
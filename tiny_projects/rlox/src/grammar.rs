@@ -1,28 +1,162 @@
-#[derive(Debug, PartialEq)]
+use crate::lexer::{Token, Type};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Expression(Expr),
+    Print(Expr),
+    /// Name, type annotation and initializer
+    Var(Token, Option<Token>, Option<Expr>),
+    Block(Vec<Stmt>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    /// Condition, body and the last token of the body
+    While(Expr, Box<Stmt>, Token),
+    Function(Rc<FunctionDecl>),
+    Return(Token, Option<Expr>),
+    /// The `throw` keyword and the value thrown
+    Throw(Token, Expr),
+    /// The `try` keyword, body, the name the caught error is bound to and
+    /// the handler
+    Try(Token, Vec<Stmt>, Token, Vec<Stmt>),
+    Class(ClassDecl),
+    /// The path, a string token, the name the module is bound to and the
+    /// id its loaded module is looked up by
+    Import(Token, Token, ExprId),
+}
+
+/// Shared between the syntax tree and every closure created from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDecl {
+    pub name: Token,
+    pub params: Vec<Token>,
+    /// The type annotation of each parameter, if it has one
+    pub param_types: Vec<Option<Token>>,
+    pub return_type: Option<Token>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassDecl {
+    pub name: Token,
+    /// Always an `Expr::Variable` so the resolver can bind it like any other
+    pub superclass: Option<Expr>,
+    pub methods: Vec<Rc<FunctionDecl>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
-    Literal(Literal),
-    UnaryExpr(UnaryOp, Box<Expr>),
-    BinaryExpr(Box<Expr>, BinaryOp, Box<Expr>),
+    Literal(Literal, Token),
+    UnaryExpr(UnaryOp, Token, Box<Expr>),
+    BinaryExpr(Box<Expr>, BinaryOp, Token, Box<Expr>),
     Grouping(Box<Expr>),
+    Variable(Token, ExprId),
+    Assign(Token, Box<Expr>, ExprId),
+    Logical(Box<Expr>, LogicalOp, Box<Expr>),
+    /// Callee, closing parenthesis and arguments
+    Call(Box<Expr>, Token, Vec<Expr>),
+    /// Object and property name
+    Get(Box<Expr>, Token),
+    /// Object, property name and the value stored into it
+    Set(Box<Expr>, Token, Box<Expr>),
+    This(Token, ExprId),
+    /// The `super` keyword and the method looked up on the superclass
+    Super(Token, Token, ExprId),
+    /// Opening bracket and elements
+    List(Token, Vec<Expr>),
+    /// Opening brace and key-value pairs
+    Map(Token, Vec<(Expr, Expr)>),
+    /// Object, closing bracket and index
+    Index(Box<Expr>, Token, Box<Expr>),
+    /// Object, closing bracket, index and the value stored there
+    SetIndex(Box<Expr>, Token, Box<Expr>, Box<Expr>),
+}
+
+/// Identifies one variable expression or import so later passes can
+/// attach information to it in a side table
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ExprId(usize);
+
+impl ExprId {
+    /// Ids are unique for the life of the process, so trees parsed at
+    /// different times (like REPL lines) never collide
+    pub fn fresh() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Stmt {
+    /// The line the statement starts on. Blocks have none of their own,
+    /// only the statements in them do.
+    pub fn line(&self) -> Option<i32> {
+        match self {
+            Stmt::Expression(expr) | Stmt::Print(expr) | Stmt::If(expr, ..) => {
+                Some(expr.first_token().line)
+            }
+            Stmt::While(condition, ..) => Some(condition.first_token().line),
+            Stmt::Var(name, _, _) => Some(name.line),
+            Stmt::Function(decl) => Some(decl.name.line),
+            Stmt::Class(decl) => Some(decl.name.line),
+            Stmt::Import(path, ..) => Some(path.line),
+            Stmt::Return(keyword, _) | Stmt::Throw(keyword, _) | Stmt::Try(keyword, ..) => {
+                Some(keyword.line)
+            }
+            Stmt::Block(_) => None,
+        }
+    }
+
+    /// The text of the string token an `import` names its file with
+    pub fn import_path(path: &Token) -> &str {
+        match &path.kind {
+            Type::String { value } => value,
+            _ => unreachable!("the parser only takes a string as the path"),
+        }
+    }
+}
+
+impl Expr {
+    /// The leftmost token the tree keeps for the expression
+    pub fn first_token(&self) -> &Token {
+        match self {
+            Expr::Literal(_, token)
+            | Expr::UnaryExpr(_, token, _)
+            | Expr::Variable(token, _)
+            | Expr::Assign(token, ..)
+            | Expr::This(token, _)
+            | Expr::Super(token, ..)
+            | Expr::List(token, _)
+            | Expr::Map(token, _) => token,
+            Expr::Grouping(inner) => inner.first_token(),
+            Expr::BinaryExpr(left, ..)
+            | Expr::Logical(left, ..)
+            | Expr::Call(left, ..)
+            | Expr::Get(left, _)
+            | Expr::Set(left, ..)
+            | Expr::Index(left, ..)
+            | Expr::SetIndex(left, ..) => left.first_token(),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     String(String),
     Float(f64),
-    Int(i32),
+    Int(i64),
     True,
     False,
     Nil,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum UnaryOp {
     Negative,
     Not,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum BinaryOp {
     Eq,
     Ne,
@@ -34,5 +168,255 @@ pub enum BinaryOp {
     Minus,
     Times,
     Divide,
-    Assign,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+//----------------------------------------------------------------------
+// Lisp-style printing, e.g. `(* (- 123) (group 45.67))`
+//----------------------------------------------------------------------
+
+/// Writes `(name part part ...)`
+fn parenthesize(f: &mut fmt::Formatter, name: &str, parts: &[&dyn fmt::Display]) -> fmt::Result {
+    write!(f, "({}", name)?;
+    for part in parts.iter() {
+        write!(f, " {}", part)?;
+    }
+    write!(f, ")")
+}
+
+/// Renders `items` separated by spaces
+struct Spaced<'a, T>(&'a [T]);
+
+impl<T: fmt::Display> fmt::Display for Spaced<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, item) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", item)?;
+        }
+        Ok(())
+    }
+}
+
+struct Name<'a>(&'a Token);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.name())
+    }
+}
+
+impl fmt::Display for FunctionDecl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<Name> = self.params.iter().map(Name).collect();
+        write!(f, "(fun {} ({})", self.name.name(), Spaced(&params))?;
+        for stmt in self.body.iter() {
+            write!(f, " {}", stmt)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stmt::Expression(expr) => parenthesize(f, ";", &[expr]),
+            Stmt::Print(expr) => parenthesize(f, "print", &[expr]),
+            Stmt::Var(name, _, None) => parenthesize(f, "var", &[&Name(name)]),
+            Stmt::Var(name, _, Some(init)) => parenthesize(f, "var", &[&Name(name), init]),
+            Stmt::Block(stmts) => {
+                let stmts: Vec<&dyn fmt::Display> =
+                    stmts.iter().map(|stmt| stmt as &dyn fmt::Display).collect();
+                parenthesize(f, "block", &stmts)
+            }
+            Stmt::If(cond, then, None) => parenthesize(f, "if", &[cond, then]),
+            Stmt::If(cond, then, Some(other)) => parenthesize(f, "if", &[cond, then, other]),
+            Stmt::While(cond, body, _) => parenthesize(f, "while", &[cond, body]),
+            Stmt::Function(decl) => write!(f, "{}", decl),
+            Stmt::Return(_, None) => write!(f, "(return)"),
+            Stmt::Return(_, Some(value)) => parenthesize(f, "return", &[value]),
+            Stmt::Throw(_, value) => parenthesize(f, "throw", &[value]),
+            Stmt::Try(_, body, name, handler) => {
+                write!(f, "(try")?;
+                for stmt in body.iter() {
+                    write!(f, " {}", stmt)?;
+                }
+                write!(f, " (catch {}", name.name())?;
+                for stmt in handler.iter() {
+                    write!(f, " {}", stmt)?;
+                }
+                write!(f, "))")
+            }
+            Stmt::Class(decl) => {
+                write!(f, "(class {}", decl.name.name())?;
+                if let Some(superclass) = &decl.superclass {
+                    write!(f, " < {}", superclass)?;
+                }
+                for method in decl.methods.iter() {
+                    write!(f, " {}", method)?;
+                }
+                write!(f, ")")
+            }
+            Stmt::Import(path, name, _) => {
+                write!(
+                    f,
+                    "(import \"{}\" {})",
+                    Stmt::import_path(path),
+                    name.name()
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Literal(lit, _) => write!(f, "{}", lit),
+            Expr::UnaryExpr(op, _, operand) => parenthesize(f, &op.to_string(), &[operand]),
+            Expr::BinaryExpr(left, op, _, right) => {
+                parenthesize(f, &op.to_string(), &[left, right])
+            }
+            Expr::Grouping(expr) => parenthesize(f, "group", &[expr]),
+            Expr::Variable(name, _) => write!(f, "{}", Name(name)),
+            Expr::This(..) => write!(f, "this"),
+            Expr::Assign(name, value, _) => parenthesize(f, "=", &[&Name(name), value]),
+            Expr::Logical(left, op, right) => parenthesize(f, &op.to_string(), &[left, right]),
+            Expr::Call(callee, _, args) => {
+                let mut parts: Vec<&dyn fmt::Display> = vec![callee];
+                parts.extend(args.iter().map(|arg| arg as &dyn fmt::Display));
+                parenthesize(f, "call", &parts)
+            }
+            Expr::Get(object, name) => parenthesize(f, ".", &[object, &Name(name)]),
+            Expr::Set(object, name, value) => {
+                write!(f, "(= (. {} {}) {})", object, name.name(), value)
+            }
+            Expr::Super(_, method, _) => parenthesize(f, "super", &[&Name(method)]),
+            Expr::List(_, elements) => {
+                let elements: Vec<&dyn fmt::Display> = elements
+                    .iter()
+                    .map(|element| element as &dyn fmt::Display)
+                    .collect();
+                parenthesize(f, "list", &elements)
+            }
+            Expr::Map(_, entries) => {
+                let entries: Vec<&dyn fmt::Display> = entries
+                    .iter()
+                    .flat_map(|(key, value)| [key as &dyn fmt::Display, value])
+                    .collect();
+                parenthesize(f, "map", &entries)
+            }
+            Expr::Index(object, _, index) => parenthesize(f, "[]", &[object, index]),
+            Expr::SetIndex(object, _, index, value) => {
+                write!(f, "(= ([] {} {}) {})", object, index, value)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Literal::String(val) => write!(f, "\"{}\"", val),
+            // `{:?}` keeps the `.0` that tells floats from ints
+            Literal::Float(val) => write!(f, "{:?}", val),
+            Literal::Int(val) => write!(f, "{}", val),
+            Literal::True => write!(f, "true"),
+            Literal::False => write!(f, "false"),
+            Literal::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOp::Negative => "-",
+            UnaryOp::Not => "!",
+        })
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Plus => "+",
+            BinaryOp::Minus => "-",
+            BinaryOp::Times => "*",
+            BinaryOp::Divide => "/",
+        })
+    }
+}
+
+impl fmt::Display for LogicalOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogicalOp::And => "and",
+            LogicalOp::Or => "or",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::{Lexer, Token};
+    use crate::parser::Parser;
+
+    fn print(source: &str) -> String {
+        let tokens: Vec<Token> = Lexer::new(source).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        program
+            .iter()
+            .map(|stmt| stmt.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_print() {
+        let tests = [
+            ("-123 * (45.67);", "(; (* (- 123) (group 45.67)))"),
+            ("print 1.0 + \"a\";", "(print (+ 1.0 \"a\"))"),
+            (
+                "var a; a = !nil or true;",
+                "(var a) (; (= a (or (! nil) true)))",
+            ),
+            (
+                "if (a) f(1, 2); else { return; }",
+                "(if a (; (call f 1 2)) (block (return)))",
+            ),
+            ("while (x) x.y = x.z;", "(while x (; (= (. x y) (. x z))))"),
+            (
+                "class B < A { f(a, b) { return super.f(this); } }",
+                "(class B < A (fun f (a b) (return (call (super f) this))))",
+            ),
+            (
+                "var m = {\"a\": [1, 2]}; m[\"a\"][0] = m[\"b\"];",
+                "(var m (map \"a\" (list 1 2))) (; (= ([] ([] m \"a\") 0) ([] m \"b\")))",
+            ),
+            (
+                "try { throw 1; } catch (e) { print e; }",
+                "(try (throw 1) (catch e (print e)))",
+            ),
+            (
+                "import \"lib/util.lox\" as util;",
+                "(import \"lib/util.lox\" util)",
+            ),
+        ];
+        for (source, expected) in tests.iter() {
+            assert_eq!(print(source), *expected);
+        }
+    }
 }
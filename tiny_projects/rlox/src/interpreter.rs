@@ -0,0 +1,827 @@
+use crate::error::{HostError, ImportError, LoxError, RuntimeError, TraceFrame, Warning};
+use crate::evaluation::{Debugger, TreeWalker};
+use crate::grammar::{ExprId, Stmt};
+use crate::lexer::{Lexer, Token};
+use crate::optimizer;
+use crate::parser::Parser;
+use crate::resolver;
+use crate::stdlib;
+use crate::typecheck;
+use crate::value::{NativeFunction, Value};
+use crate::vm::object::GcStats;
+use crate::vm::Vm;
+use crate::wasm;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Which engine runs the program. Both take the same resolved syntax tree
+/// and must behave the same.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    TreeWalker,
+    Vm,
+}
+
+/// Settings picked on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub backend: Backend,
+    /// Print the bytecode of every chunk before running it
+    pub disassemble: bool,
+    /// Print the VM stack and each instruction as it runs
+    pub trace: bool,
+    /// Collect garbage before every allocation
+    pub stress_gc: bool,
+    /// Report each collection and a summary at exit
+    pub log_gc: bool,
+    /// Fold constants and drop dead branches before running
+    pub optimize: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            backend: Backend::TreeWalker,
+            disassemble: false,
+            trace: false,
+            stress_gc: false,
+            log_gc: false,
+            optimize: false,
+        }
+    }
+}
+
+enum Engine {
+    TreeWalker(Box<TreeWalker>),
+    Vm(Box<Vm>),
+}
+
+/// Which files `import` and `io.readFile` may read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ImportPolicy {
+    /// Any file the process can read, as on the command line
+    #[default]
+    Anywhere,
+    /// Only files inside this directory, once symbolic links and `..` are
+    /// resolved
+    Within(PathBuf),
+    /// No files at all
+    Disabled,
+}
+
+impl ImportPolicy {
+    /// Whether the file at `path` may be read. Paths outside the directory
+    /// are turned down before the file system is looked at, so a script
+    /// can't find out which files exist there.
+    pub fn allows(&self, path: &Path) -> bool {
+        match self {
+            ImportPolicy::Anywhere => true,
+            ImportPolicy::Disabled => false,
+            ImportPolicy::Within(root) => {
+                if !normalize(path).starts_with(normalize(root)) {
+                    return false;
+                }
+                // a symbolic link inside may still lead out
+                match (fs::canonicalize(path), fs::canonicalize(root)) {
+                    (Ok(file), Ok(root)) => file.starts_with(root),
+                    _ => true,
+                }
+            }
+        }
+    }
+}
+
+/// `path` made absolute, with `.` and `..` resolved by the names alone
+fn normalize(path: &Path) -> PathBuf {
+    let path = match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    normal
+}
+
+/// A Lox session for a host program. Globals defined by one `run` are seen
+/// by the next. Programs nested too deeply and runaway recursion are
+/// reported as errors on any thread, however little stack it has.
+///
+/// Every session starts with `clock` and the `math` and `io` modules. A
+/// host that should not let scripts read any file it likes sets an
+/// `ImportPolicy`, which both `import` and `io.readFile` follow.
+///
+/// `import "path" as name;` loads another file as a module before the
+/// program runs. Each file is run once, with globals of its own that start
+/// out as everything the host defined, and its top level definitions become
+/// the members of the module.
+///
+/// ```
+/// let out = rlox::SharedBuffer::new();
+/// let mut lox = rlox::Interpreter::new();
+/// lox.set_output(out.clone());
+/// lox.run("print 1 + 2;").unwrap();
+/// assert_eq!(out.take(), "3\n");
+/// ```
+pub struct Interpreter {
+    options: Options,
+    engine: Engine,
+    step_limit: Option<u64>,
+    /// Warnings from every run so far, until `take_warnings`
+    warnings: Vec<Warning>,
+    /// The file later programs come from, see `set_path`
+    path: Option<PathBuf>,
+    /// Shared with `io.readFile`
+    imports: Rc<RefCell<ImportPolicy>>,
+    /// Everything the host defined, which every module run by the
+    /// tree-walker starts out with. The VM keeps its own.
+    prelude: Vec<(String, Value)>,
+    /// Modules loaded so far by canonical path, as the backend numbers them
+    modules: HashMap<PathBuf, usize>,
+    /// The path and source of each module, in the backend's numbering
+    files: Vec<(String, String)>,
+    /// Files whose imports are being loaded, outermost first, by canonical
+    /// path and as the path was written
+    loading: Vec<(PathBuf, String)>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_options(Options::default())
+    }
+
+    pub fn with_options(options: Options) -> Self {
+        let engine = match options.backend {
+            Backend::TreeWalker => Engine::TreeWalker(Box::default()),
+            Backend::Vm => {
+                let mut vm = Vm::new();
+                vm.set_trace(options.trace);
+                vm.set_gc_options(options.stress_gc, options.log_gc);
+                Engine::Vm(Box::new(vm))
+            }
+        };
+        let imports = Rc::<RefCell<ImportPolicy>>::default();
+        let mut interpreter = Self {
+            options,
+            engine,
+            step_limit: None,
+            warnings: Vec::new(),
+            path: None,
+            imports: imports.clone(),
+            prelude: Vec::new(),
+            modules: HashMap::new(),
+            files: Vec::new(),
+            loading: Vec::new(),
+        };
+        interpreter.define_native("clock", 0, |_| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|err| err.to_string())?;
+            Ok(Value::Float(now.as_secs_f64()))
+        });
+        for module in stdlib::modules(imports) {
+            let name = module.name.clone();
+            interpreter
+                .define_global(&name, Value::Module(Rc::new(module)))
+                .expect("the standard library only holds natives");
+        }
+        interpreter
+    }
+
+    pub fn options(&self) -> Options {
+        self.options
+    }
+
+    /// Sends the output of `print` to `out` instead of stdout
+    pub fn set_output<W: Write + 'static>(&mut self, out: W) {
+        match &mut self.engine {
+            Engine::TreeWalker(interpreter) => interpreter.set_output(Box::new(out)),
+            Engine::Vm(vm) => vm.set_output(Box::new(out)),
+        }
+    }
+
+    /// Stops each later `run` with a runtime error once it has taken more
+    /// than `limit` steps. The tree-walker counts loop iterations and calls,
+    /// the VM counts instructions.
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    /// Imports in later programs are found relative to the directory of
+    /// `path`, the file they come from. Without one they are relative to
+    /// the working directory.
+    pub fn set_path(&mut self, path: &Path) {
+        self.path = Some(path.to_path_buf());
+    }
+
+    /// Decides which files later imports and `io.readFile` may read. Files
+    /// that are not allowed are never read, so their contents can't show up
+    /// in errors.
+    pub fn set_import_policy(&mut self, policy: ImportPolicy) {
+        *self.imports.borrow_mut() = policy;
+    }
+
+    /// Hands control to `debugger` before each statement. Only the
+    /// tree-walker can be debugged, the VM ignores this.
+    pub fn set_debugger<D: Debugger + 'static>(&mut self, debugger: D) {
+        if let Engine::TreeWalker(interpreter) = &mut self.engine {
+            interpreter.set_debugger(Box::new(debugger));
+        }
+    }
+
+    /// Exposes a host function to scripts as a global called `name`. Calls
+    /// with anything other than `arity` arguments are rejected before `func`
+    /// runs, and an `Err` from `func` becomes a runtime error.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, func: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let native = NativeFunction {
+            name: name.to_string(),
+            arity,
+            func: Box::new(func),
+        };
+        self.define_global(name, Value::Native(Rc::new(native)))
+            .expect("every backend takes natives");
+    }
+
+    /// Defines a global visible to every later `run` and every module
+    /// loaded after this. The VM copies lists and maps, and rejects
+    /// functions, classes and instances, which only the tree-walker has.
+    pub fn define_global(&mut self, name: &str, value: Value) -> Result<(), HostError> {
+        match &mut self.engine {
+            Engine::TreeWalker(interpreter) => {
+                match self.prelude.iter_mut().find(|(known, _)| known == name) {
+                    Some(entry) => entry.1 = value.clone(),
+                    None => self.prelude.push((name.to_string(), value.clone())),
+                }
+                interpreter.globals.borrow_mut().define(name, value);
+                Ok(())
+            }
+            Engine::Vm(vm) => vm.define_global(name, value),
+        }
+    }
+
+    /// The current value of the global `name`. With the VM, only numbers,
+    /// strings, booleans, nil and lists and maps of those can be read back,
+    /// as copies.
+    pub fn global(&mut self, name: &str) -> Option<Value> {
+        match &mut self.engine {
+            Engine::TreeWalker(interpreter) => {
+                crate::evaluation::Environment::get_at(&interpreter.globals, 0, name)
+            }
+            Engine::Vm(vm) => vm.global(name),
+        }
+    }
+
+    /// Parses, checks and runs `source`
+    pub fn run(&mut self, source: &str) -> Result<(), LoxError> {
+        let program = self.parse(source)?;
+        self.execute(&program)
+    }
+
+    pub fn parse(&self, source: &str) -> Result<Vec<Stmt>, LoxError> {
+        let tokens: Vec<Token> = Lexer::new(source).collect();
+        Parser::new(tokens).parse_program().map_err(LoxError::Parse)
+    }
+
+    /// Resolves and runs an already parsed program
+    pub fn execute(&mut self, program: &[Stmt]) -> Result<(), LoxError> {
+        let resolution = resolver::resolve(program);
+        self.warnings.extend(resolution.warnings);
+        if !resolution.errors.is_empty() {
+            return Err(LoxError::Resolve(resolution.errors));
+        }
+        match &mut self.engine {
+            Engine::TreeWalker(interpreter) => {
+                interpreter.add_locals(resolution.locals);
+                interpreter.set_step_limit(self.step_limit);
+            }
+            Engine::Vm(vm) => vm.set_step_limit(self.step_limit),
+        }
+        // the file itself counts as being loaded, in case a module imports it
+        let path = self.path.clone();
+        if let Some(path) = &path {
+            if let Ok(canonical) = fs::canonicalize(path) {
+                self.loading.push((canonical, path.display().to_string()));
+            }
+        }
+        let imports = self.load_imports(program, path.as_deref());
+        self.loading.clear();
+        let imports = imports?;
+
+        let optimized;
+        let program = if self.options.optimize {
+            let (program, warnings) = optimizer::optimize(program.to_vec());
+            self.warnings.extend(warnings);
+            optimized = program;
+            &optimized[..]
+        } else {
+            program
+        };
+        match &mut self.engine {
+            Engine::TreeWalker(interpreter) => {
+                interpreter.add_imports(imports);
+                if let Err(err) = program.iter().try_for_each(|stmt| interpreter.run(stmt)) {
+                    let trace = interpreter.take_trace(&err);
+                    return Err(self.runtime_error(err, trace));
+                }
+            }
+            Engine::Vm(vm) => {
+                vm.add_imports(imports);
+                let function = vm.compile(program).map_err(LoxError::Compile)?;
+                if self.options.disassemble {
+                    print!("{}", vm.disassemble(function));
+                }
+                if let Err(err) = vm.run(function) {
+                    let trace = vm.take_trace();
+                    return Err(self.runtime_error(err, trace));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A runtime error, reported against the module it was raised in if
+    /// that is not the program itself
+    fn runtime_error(&self, err: RuntimeError, trace: Vec<TraceFrame>) -> LoxError {
+        let file = trace
+            .first()
+            .and_then(|frame| frame.module)
+            .and_then(|module| self.files.get(module));
+        match file {
+            Some((path, source)) => LoxError::Module {
+                path: path.clone(),
+                source: source.clone(),
+                error: Box::new(LoxError::Runtime(err, trace)),
+            },
+            None => LoxError::Runtime(err, trace),
+        }
+    }
+
+    /// Loads the module of every `import` in `program`, which comes from the
+    /// file `importer`
+    fn load_imports(
+        &mut self,
+        program: &[Stmt],
+        importer: Option<&Path>,
+    ) -> Result<HashMap<ExprId, usize>, LoxError> {
+        let dir = importer
+            .and_then(Path::parent)
+            .unwrap_or_else(|| Path::new(""));
+        let mut imports = HashMap::new();
+        for stmt in program.iter() {
+            if let Stmt::Import(path, _, id) = stmt {
+                let module = self.import(path, &dir.join(Stmt::import_path(path)))?;
+                imports.insert(*id, module);
+            }
+        }
+        Ok(imports)
+    }
+
+    /// The module in the file at `path`, loading it unless that was done
+    /// before. `token` is the path as the importing file wrote it.
+    fn import(&mut self, token: &Token, path: &Path) -> Result<usize, LoxError> {
+        let shown = path.display().to_string();
+        let unreadable = |err: io::Error| {
+            LoxError::Import(ImportError::Unreadable {
+                token: token.clone(),
+                path: shown.clone(),
+                reason: err.to_string(),
+            })
+        };
+        let not_allowed = || {
+            LoxError::Import(ImportError::NotAllowed {
+                token: token.clone(),
+                path: shown.clone(),
+            })
+        };
+        if !self.imports.borrow().allows(path) {
+            return Err(not_allowed());
+        }
+        let canonical = fs::canonicalize(path).map_err(unreadable)?;
+        if let Some(&module) = self.modules.get(&canonical) {
+            return Ok(module);
+        }
+        if self.loading.iter().any(|(known, _)| *known == canonical) {
+            let mut chain: Vec<String> = self
+                .loading
+                .iter()
+                .map(|(_, shown)| shown.clone())
+                .collect();
+            chain.push(shown.clone());
+            let cycle = ImportError::Cycle {
+                token: token.clone(),
+                chain,
+            };
+            return Err(LoxError::Import(cycle));
+        }
+        let source = fs::read_to_string(&canonical).map_err(unreadable)?;
+
+        self.loading.push((canonical.clone(), shown.clone()));
+        let result = self.load_module(path, &source);
+        self.loading.pop();
+        let module = match result {
+            Ok(module) => module,
+            // already reported against the file further in
+            Err(error @ LoxError::Module { .. }) => return Err(error),
+            Err(error) => {
+                return Err(LoxError::Module {
+                    path: shown,
+                    source,
+                    error: Box::new(error),
+                })
+            }
+        };
+        self.modules.insert(canonical, module);
+        self.files.push((shown, source));
+        Ok(module)
+    }
+
+    /// Parses, resolves and runs the file at `path` as a module. Its
+    /// warnings are left for when it is run on its own.
+    fn load_module(&mut self, path: &Path, source: &str) -> Result<usize, LoxError> {
+        let program = self.parse(source)?;
+        let resolution = resolver::resolve(&program);
+        if !resolution.errors.is_empty() {
+            return Err(LoxError::Resolve(resolution.errors));
+        }
+        let imports = self.load_imports(&program, Some(path))?;
+        let exports = exports(&program);
+        let name = path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+        let program = if self.options.optimize {
+            optimizer::optimize(program).0
+        } else {
+            program
+        };
+        let prelude = &self.prelude;
+        let result = match &mut self.engine {
+            Engine::TreeWalker(interpreter) => {
+                interpreter.add_locals(resolution.locals);
+                interpreter.add_imports(imports);
+                interpreter
+                    .run_module(&program, &name, prelude, &exports)
+                    .map_err(|err| {
+                        let trace = interpreter.take_trace(&err);
+                        (err, trace)
+                    })
+            }
+            Engine::Vm(vm) => {
+                vm.add_imports(imports);
+                let function = vm.compile(&program).map_err(LoxError::Compile)?;
+                vm.run_module(function, &name, &exports)
+                    .map_err(|err| (err, vm.take_trace()))
+            }
+        };
+        result.map_err(|(err, trace)| self.runtime_error(err, trace))
+    }
+
+    /// Resolves and type checks an already parsed program, without running it
+    pub fn check(&mut self, program: &[Stmt]) -> Result<(), LoxError> {
+        let resolution = resolver::resolve(program);
+        self.warnings.extend(resolution.warnings);
+        if !resolution.errors.is_empty() {
+            return Err(LoxError::Resolve(resolution.errors));
+        }
+        let errors = typecheck::check(program);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(LoxError::Type(errors))
+        }
+    }
+
+    /// Resolves an already parsed program and lowers it to a WebAssembly
+    /// module in the text format, without running it
+    pub fn compile_wasm(&mut self, program: &[Stmt]) -> Result<String, LoxError> {
+        let resolution = resolver::resolve(program);
+        self.warnings.extend(resolution.warnings);
+        if !resolution.errors.is_empty() {
+            return Err(LoxError::Resolve(resolution.errors));
+        }
+        wasm::compile(program).map_err(LoxError::Compile)
+    }
+
+    /// Warnings found since the last call, such as unused locals
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Collection totals so far, when running on the VM
+    pub fn gc_stats(&self) -> Option<GcStats> {
+        match &self.engine {
+            Engine::TreeWalker(_) => None,
+            Engine::Vm(vm) => Some(vm.gc_stats()),
+        }
+    }
+}
+
+/// The names a module's own top level defines, which are its members
+fn exports(program: &[Stmt]) -> Vec<String> {
+    program
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Var(name, ..) | Stmt::Import(_, name, _) => Some(name),
+            Stmt::Function(decl) => Some(&decl.name),
+            Stmt::Class(decl) => Some(&decl.name),
+            _ => None,
+        })
+        .map(|name| name.name().to_string())
+        .collect()
+}
+
+/// A `Write` that keeps everything written to it. Clones share the same
+/// buffer, so a host can hand one to `Interpreter::set_output` and read
+/// the output through another.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything written so far, leaving the buffer empty
+    pub fn take(&self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut *self.0.borrow_mut())).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{RuntimeError, TraceFrame};
+    use crate::parser::MAX_NESTING;
+
+    fn backends() -> Vec<Interpreter> {
+        [Backend::TreeWalker, Backend::Vm]
+            .iter()
+            .map(|backend| {
+                Interpreter::with_options(Options {
+                    backend: *backend,
+                    ..Options::default()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_output_and_globals() {
+        for mut lox in backends() {
+            let out = SharedBuffer::new();
+            lox.set_output(out.clone());
+            lox.define_global("greeting", Value::Str("hi".to_string()))
+                .unwrap();
+            lox.define_native("twice", 1, |args| match &args[0] {
+                Value::Int(val) => Ok(Value::Int(val * 2)),
+                _ => Err("Expected an int.".to_string()),
+            });
+            lox.run("var x = twice(21); print greeting + \"!\";")
+                .unwrap();
+            lox.run("print x;").unwrap();
+            assert_eq!(out.take(), "hi!\n42\n");
+            assert_eq!(lox.global("x"), Some(Value::Int(42)));
+            assert_eq!(lox.global("missing"), None);
+
+            let err = lox.run("twice(nil);").unwrap_err();
+            assert_eq!(err.to_string(), "[line 1] Expected an int.");
+            assert_eq!(err.exit_code(), 70);
+            let err = lox.run("print ;").unwrap_err();
+            assert!(matches!(err, LoxError::Parse(_)));
+            assert_eq!(err.exit_code(), 65);
+        }
+    }
+
+    #[test]
+    fn test_vm_rejects_tree_walker_values() {
+        let mut tree_walker = Interpreter::new();
+        tree_walker.run("fun f() {}").unwrap();
+        let function = tree_walker.global("f").unwrap();
+        let mut vm = Interpreter::with_options(Options {
+            backend: Backend::Vm,
+            ..Options::default()
+        });
+        assert_eq!(
+            vm.define_global("f", function.clone()),
+            Err(HostError::Unsupported("f".to_string()))
+        );
+        assert!(vm.define_global("fs", Value::list(vec![function])).is_err());
+        assert!(matches!(vm.run("f;"), Err(LoxError::Runtime(..))));
+    }
+
+    #[test]
+    fn test_collections_and_modules() {
+        for mut lox in backends() {
+            let out = SharedBuffer::new();
+            lox.set_output(out.clone());
+            lox.define_global("items", Value::list(vec![Value::Int(1), Value::Nil]))
+                .unwrap();
+            lox.run("items.push(\"a\"); var m = {\"n\": math.sqrt(items.len() + 6)};")
+                .unwrap();
+            lox.run("print m;").unwrap();
+            assert_eq!(out.take(), "{\"n\": 3}\n");
+            assert_eq!(
+                lox.global("items").map(|items| items.to_string()),
+                Some("[1, nil, \"a\"]".to_string())
+            );
+
+            lox.define_global("io", Value::Nil).unwrap();
+            assert!(lox.run("io.readFile(\"Cargo.toml\");").is_err());
+        }
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let source = "class A {\n  init() { this.f(); }\n  f() { throw 1; }\n}\nfun make() {\n  return A();\n}\nmake();";
+        let frame = |function: Option<&str>, line| TraceFrame {
+            function: function.map(str::to_string),
+            line,
+            module: None,
+        };
+        for mut lox in backends() {
+            let err = lox.run(source).unwrap_err();
+            assert_eq!(
+                err,
+                LoxError::Runtime(
+                    RuntimeError::Thrown {
+                        token: Token::new(crate::lexer::Type::Throw, 2),
+                        value: "1".to_string(),
+                    },
+                    vec![
+                        frame(Some("f"), 2),
+                        frame(Some("init"), 1),
+                        frame(Some("make"), 5),
+                        frame(None, 7),
+                    ]
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_deep_programs() {
+        // the test thread has the default stack, far less than the CLI's
+        let fib = "fun f(n) { if (n > 0) return f(n - 1) + 1; return 0; }";
+        let nested = |depth| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        for optimize in [false, true].iter() {
+            for backend in [Backend::TreeWalker, Backend::Vm].iter() {
+                let mut lox = Interpreter::with_options(Options {
+                    backend: *backend,
+                    optimize: *optimize,
+                    ..Options::default()
+                });
+                let out = SharedBuffer::new();
+                lox.set_output(out.clone());
+                lox.run(fib).unwrap();
+                lox.run("print f(500);").unwrap();
+                lox.run(&nested(MAX_NESTING - 2)).unwrap();
+                assert_eq!(out.take(), "500\n1\n");
+
+                let err = lox.run("f(100000);").unwrap_err();
+                assert!(matches!(
+                    err,
+                    LoxError::Runtime(RuntimeError::StackOverflow(_), _)
+                ));
+                let err = lox.run(&nested(20_000)).unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    "[line 1] Can't nest more than 256 levels deep."
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_limit() {
+        for mut lox in backends() {
+            lox.set_step_limit(Some(10_000));
+            let err = lox.run("var i = 0;\nwhile (true) i = i + 1;").unwrap_err();
+            assert!(matches!(
+                err,
+                LoxError::Runtime(RuntimeError::StepLimitExceeded(_), _)
+            ));
+            assert_eq!(
+                err.report("var i = 0;\nwhile (true) i = i + 1;")
+                    .lines()
+                    .next(),
+                Some("Step limit exceeded.")
+            );
+            // the host's limit is not the program's to catch
+            let err = lox
+                .run("try { while (true) {} } catch (e) { print e; }")
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                LoxError::Runtime(RuntimeError::StepLimitExceeded(_), _)
+            ));
+            // the budget is per run, and enough for a short one
+            lox.run("fun f(n) { return n; } var x = f(1);").unwrap();
+            lox.run("for (var i = 0; i < 100; i = i + 1) {}").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_imports() {
+        let dir = std::env::temp_dir().join(format!("rlox-imports-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("lib.lox"),
+            "print \"loaded\";\nvar answer = greeting;\n",
+        )
+        .unwrap();
+        for mut lox in backends() {
+            let out = SharedBuffer::new();
+            lox.set_output(out.clone());
+            lox.set_path(&dir.join("main.lox"));
+            // modules start out with what the host defined
+            lox.define_global("greeting", Value::Int(42)).unwrap();
+            lox.run("import \"lib.lox\" as lib; print lib.answer;")
+                .unwrap();
+            assert_eq!(out.take(), "loaded\n42\n");
+            // a later run gets the same module without loading it again
+            lox.run("import \"./lib.lox\" as again; print again.answer;")
+                .unwrap();
+            assert_eq!(out.take(), "42\n");
+        }
+
+        let inner = dir.join("inner");
+        fs::create_dir_all(&inner).unwrap();
+        let not_allowed = |err: LoxError| match err {
+            LoxError::Import(ImportError::NotAllowed { path, .. }) => path,
+            err => panic!("unexpected error {}", err),
+        };
+        for mut lox in backends() {
+            let out = SharedBuffer::new();
+            lox.set_output(out.clone());
+            lox.set_path(&inner.join("main.lox"));
+            lox.define_global("greeting", Value::Int(42)).unwrap();
+            lox.set_import_policy(ImportPolicy::Within(inner.clone()));
+            let err = lox.run("import \"../lib.lox\" as lib;").unwrap_err();
+            assert!(not_allowed(err).ends_with("lib.lox"));
+            lox.set_import_policy(ImportPolicy::Disabled);
+            let err = lox.run("import \"../lib.lox\" as lib;").unwrap_err();
+            assert!(not_allowed(err).ends_with("lib.lox"));
+            // neither was read
+            assert_eq!(out.take(), "");
+            // a file that doesn't exist outside looks the same as one that does
+            lox.set_import_policy(ImportPolicy::Within(inner.clone()));
+            let err = lox.run("import \"../missing.lox\" as lib;").unwrap_err();
+            assert!(not_allowed(err).ends_with("missing.lox"));
+            // and `io` follows the same policy
+            let read = format!("io.readFile(\"{}\");", dir.join("lib.lox").display());
+            let err = lox.run(&read).unwrap_err();
+            assert!(err.to_string().contains("Not allowed to read file"));
+            let read = format!("io.readFile(\"{}\");", dir.join("missing.txt").display());
+            let err = lox.run(&read).unwrap_err();
+            assert!(err.to_string().contains("Not allowed to read file"));
+            lox.set_import_policy(ImportPolicy::Within(dir.clone()));
+            lox.run("import \"../lib.lox\" as lib; print lib.answer;")
+                .unwrap();
+            assert_eq!(out.take(), "loaded\n42\n");
+            let read = format!("print io.readFile(\"{}\");", dir.join("lib.lox").display());
+            lox.run(&read).unwrap();
+            assert!(out.take().starts_with("print \"loaded\";"));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_warnings() {
+        let mut lox = Interpreter::new();
+        lox.run("{ var unused = 1; }").unwrap();
+        let warnings = lox.take_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(lox.take_warnings().is_empty());
+    }
+}
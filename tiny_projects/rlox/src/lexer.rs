@@ -1,4 +1,4 @@
-use std::{iter::Peekable, str::Chars};
+use std::{iter::Peekable, ops::Range, str::Chars};
 
 //----------------------------------------------------------------------
 // Token
@@ -10,6 +10,9 @@ pub enum Type {
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -29,42 +32,158 @@ pub enum Type {
     Le,
 
     // Literals
-    Identifier { name: String },
-    String { value: String },
-    Int { value: i32 },
-    Float { value: f64 },
+    Identifier {
+        name: String,
+    },
+    String {
+        value: String,
+    },
+    Int {
+        value: i64,
+    },
+    Float {
+        value: f64,
+    },
 
     // Keywords
     And,
+    Catch,
     Class,
     Else,
     False,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
-    Invalid { value: String },
+    /// `value` is the offending text exactly as written
+    Invalid {
+        value: String,
+        error: LexError,
+    },
+    UnterminatedString {
+        value: String,
+    },
+    UnterminatedComment,
+    /// Only produced by `Lexer::with_comments`
+    Comment,
     Eof,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// Why a piece of source is not a token
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LexError {
+    /// A character that starts no token
+    Character,
+    /// Something that starts like a number but isn't one, like `1.2.3`
+    Number,
+    /// An integer that doesn't fit in 64 bits
+    TooLarge,
+    /// A string with an escape sequence that means nothing, like `"\q"`
+    Escape,
+}
+
+/// Where a token sits in the source. `start` and `end` are byte offsets,
+/// `column` counts characters from the start of the line.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The bytes of the source the token covers
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Token {
     pub kind: Type,
     pub line: i32,
+    pub span: Span,
 }
 
 impl Token {
     pub fn new(kind: Type, line: i32) -> Self {
-        Self { kind, line }
+        Self {
+            kind,
+            line,
+            span: Span::default(),
+        }
+    }
+
+    /// The name of an identifier, empty for every other kind of token
+    pub fn name(&self) -> &str {
+        match &self.kind {
+            Type::Identifier { name } => name,
+            _ => "",
+        }
+    }
+
+    /// The text of the token as it appears in `src`
+    pub fn lexeme<'a>(&self, src: &'a str) -> &'a str {
+        src.get(self.span.range()).unwrap_or("")
+    }
+}
+
+// The span is bookkeeping for diagnostics and does not change which token
+// this is
+impl PartialEq for Token {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.line == other.line
+    }
+}
+
+//----------------------------------------------------------------------
+// Character streams
+//----------------------------------------------------------------------
+
+/// What the matchers need from their input. Implemented for a plain
+/// `Peekable<Chars>` and for `Cursor`, which also tracks the byte offset.
+pub trait CharStream: Clone {
+    fn peek(&mut self) -> Option<&char>;
+    fn next(&mut self) -> Option<char>;
+}
+
+impl CharStream for Peekable<Chars<'_>> {
+    fn peek(&mut self) -> Option<&char> {
+        Peekable::peek(self)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        Iterator::next(self)
+    }
+}
+
+#[derive(Clone)]
+struct Cursor<'a> {
+    iter: Peekable<Chars<'a>>,
+    offset: usize,
+}
+
+impl CharStream for Cursor<'_> {
+    fn peek(&mut self) -> Option<&char> {
+        Peekable::peek(&mut self.iter)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let ch = Iterator::next(&mut self.iter)?;
+        self.offset += ch.len_utf8();
+        Some(ch)
     }
 }
 
@@ -73,17 +192,42 @@ impl Token {
 //----------------------------------------------------------------------
 
 pub struct Lexer<'a> {
-    iter: Peekable<Chars<'a>>,
+    src: &'a str,
+    iter: Cursor<'a>,
     line: i32,
     done: bool,
+    /// Produce `Type::Comment` tokens instead of skipping comments
+    comments: bool,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(src: &'a str) -> Self {
         Self {
-            iter: src.chars().peekable(),
+            src,
+            iter: Cursor {
+                iter: src.chars().peekable(),
+                offset: 0,
+            },
             line: 0,
             done: false,
+            comments: false,
+        }
+    }
+
+    /// A lexer that keeps comments, for tools that rewrite source
+    pub fn with_comments(src: &'a str) -> Self {
+        Self {
+            comments: true,
+            ..Self::new(src)
+        }
+    }
+
+    fn span_from(&self, start: usize) -> Span {
+        let line_start = self.src[..start].rfind('\n').map_or(0, |idx| idx + 1);
+        Span {
+            start,
+            end: self.iter.offset,
+            column: self.src[line_start..start].chars().count(),
         }
     }
 }
@@ -99,44 +243,66 @@ impl Iterator for Lexer<'_> {
         // consume as much as possible
         loop {
             consume_whitespace(&mut self.iter, &mut self.line);
-            if let None = consume_comments(&mut self.iter, &mut self.line) {
+            let start = self.iter.offset;
+            let line = self.line;
+            if consume_comments(&mut self.iter, &mut self.line).is_none() {
                 break;
             }
+
+            // a block comment that runs off the end of the file is reported
+            // as a token so the parser can turn it into a diagnostic
+            let comment = &self.src[start..self.iter.offset];
+            if comment.starts_with("/*") && (comment.len() < 4 || !comment.ends_with("*/")) {
+                let mut token = Token::new(Type::UnterminatedComment, line);
+                token.span = self.span_from(start);
+                return Some(token);
+            }
+            if self.comments {
+                let mut token = Token::new(Type::Comment, line);
+                token.span = self.span_from(start);
+                // a line comment ends before its newline
+                token.span.end = start + comment.trim_end().len();
+                return Some(token);
+            }
         }
 
-        if let Some(token) = match_single(&mut self.iter, self.line) {
-            Some(token)
+        let start = self.iter.offset;
+        let mut token = if let Some(token) = match_single(&mut self.iter, self.line) {
+            token
         } else if let Some(token) = match_single_double(&mut self.iter, self.line) {
-            Some(token)
+            token
         } else if let Some(token) = match_number_literal(&mut self.iter, self.line) {
-            Some(token)
+            token
         } else if let Some(token) = match_string_literal(&mut self.iter, self.line) {
-            Some(token)
+            token
         } else if let Some(token) = match_identifier_or_keyword(&mut self.iter, self.line) {
-            Some(token)
+            token
         } else if let Some(token) = match_invalid(&mut self.iter, self.line) {
-            Some(token)
+            token
         } else {
             self.done = true;
-            Some(Token::new(Type::Eof, self.line))
-        }
+            Token::new(Type::Eof, self.line)
+        };
+        token.span = self.span_from(start);
+        // multiline strings are the only tokens that can span lines
+        self.line += token.lexeme(self.src).matches('\n').count() as i32;
+        Some(token)
     }
 }
 
-fn match_invalid(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
-    if let Some(ch) = iter.next() {
-        Some(Token::new(
+fn match_invalid<S: CharStream>(iter: &mut S, line: i32) -> Option<Token> {
+    iter.next().map(|ch| {
+        Token::new(
             Type::Invalid {
                 value: ch.to_string(),
+                error: LexError::Character,
             },
             line,
-        ))
-    } else {
-        None
-    }
+        )
+    })
 }
 
-fn consume_whitespace(iter: &mut Peekable<Chars>, line: &mut i32) {
+fn consume_whitespace<S: CharStream>(iter: &mut S, line: &mut i32) {
     while let Some(ch) = iter.peek() {
         match ch {
             '\n' => {
@@ -153,7 +319,7 @@ fn consume_whitespace(iter: &mut Peekable<Chars>, line: &mut i32) {
     }
 }
 
-fn consume_inline_comment(iter: &mut Peekable<Chars>) {
+fn consume_inline_comment<S: CharStream>(iter: &mut S) {
     while let Some(next) = iter.next() {
         if next == '\n' {
             break;
@@ -161,7 +327,7 @@ fn consume_inline_comment(iter: &mut Peekable<Chars>) {
     }
 }
 
-fn consume_multiline_comment(iter: &mut Peekable<Chars>, line: &mut i32) {
+fn consume_multiline_comment<S: CharStream>(iter: &mut S, line: &mut i32) {
     // increment iterator to start search for end of comment block
     let _ = iter.next();
     let _ = iter.next();
@@ -184,7 +350,7 @@ fn consume_multiline_comment(iter: &mut Peekable<Chars>, line: &mut i32) {
     }
 }
 
-fn consume_comments(iter: &mut Peekable<Chars>, line: &mut i32) -> Option<()> {
+fn consume_comments<S: CharStream>(iter: &mut S, line: &mut i32) -> Option<()> {
     // Make a copy as there is no way to peek more than one character
     // without consuming characters
     let mut copy = iter.clone();
@@ -203,13 +369,16 @@ fn consume_comments(iter: &mut Peekable<Chars>, line: &mut i32) -> Option<()> {
     None
 }
 
-fn match_single(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
+fn match_single<S: CharStream>(iter: &mut S, line: i32) -> Option<Token> {
     if let Some(ch) = iter.peek() {
         let result = match ch {
             '(' => Some(Token::new(Type::LParen, line)),
             ')' => Some(Token::new(Type::RParen, line)),
             '{' => Some(Token::new(Type::LBrace, line)),
             '}' => Some(Token::new(Type::RBrace, line)),
+            '[' => Some(Token::new(Type::LBracket, line)),
+            ']' => Some(Token::new(Type::RBracket, line)),
+            ':' => Some(Token::new(Type::Colon, line)),
             ',' => Some(Token::new(Type::Comma, line)),
             '.' => Some(Token::new(Type::Dot, line)),
             '-' => Some(Token::new(Type::Minus, line)),
@@ -219,7 +388,7 @@ fn match_single(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
             '*' => Some(Token::new(Type::Star, line)),
             _ => None,
         };
-        if let Some(_) = result {
+        if result.is_some() {
             let _ = iter.next();
         };
         result
@@ -228,7 +397,7 @@ fn match_single(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
     }
 }
 
-fn match_single_double(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
+fn match_single_double<S: CharStream>(iter: &mut S, line: i32) -> Option<Token> {
     if let Some(ch) = iter.peek() {
         match ch {
             '!' => {
@@ -274,59 +443,84 @@ fn match_single_double(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
     }
 }
 
-fn match_number_literal(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
+fn match_number_literal<S: CharStream>(iter: &mut S, line: i32) -> Option<Token> {
     if let Some('0'..='9') = iter.peek() {
         // Can find a match
     } else {
         return None;
     }
 
+    // take everything that could be part of the number, then work out
+    // whether it is one
     let mut value = String::new();
-    let mut is_valid = true;
-    let mut is_floating_point = false;
-
-    while let Some(ch) = iter.peek() {
-        match ch {
-            '0'..='9' => value.push(iter.next().unwrap()),
-            '.' => {
-                if is_floating_point {
-                    // can't have two periods in a number eg 2..4 or 2.3.4
-                    is_valid = false;
-                } else {
-                    is_floating_point = true;
-                }
-                value.push(iter.next().unwrap())
+    while let Some(&ch) = iter.peek() {
+        let mut lookahead = iter.clone();
+        lookahead.next();
+        let digit_follows = matches!(lookahead.peek(), Some('0'..='9'));
+        let take = match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => true,
+            // a dot not followed by a digit is property access, like `1.foo`
+            '.' => digit_follows,
+            // the sign of an exponent, like `1e-3`
+            '+' | '-' => {
+                (value.ends_with('e') || value.ends_with('E'))
+                    && radix(&value).0 == 10
+                    && digit_follows
             }
-            'a'..='z' | 'A'..='Z' => {
-                is_valid = false;
-                value.push(iter.next().unwrap())
-            }
-            _ => break,
+            _ => false,
         };
+        if !take {
+            break;
+        }
+        value.push(ch);
+        iter.next();
     }
 
-    if value.is_empty() {
-        None
-    } else if !is_valid {
-        Some(Token::new(Type::Invalid { value }, line))
-    } else if is_floating_point {
-        Some(Token::new(
-            Type::Float {
-                value: value.parse().unwrap(),
-            },
-            line,
-        ))
-    } else {
-        Some(Token::new(
-            Type::Int {
-                value: value.parse().unwrap(),
-            },
-            line,
-        ))
+    let kind = match number(&value) {
+        Ok(kind) => kind,
+        Err(error) => Type::Invalid { value, error },
+    };
+    Some(Token::new(kind, line))
+}
+
+/// The base of a number literal and its digits after any `0x` or `0b`
+fn radix(text: &str) -> (u32, &str) {
+    match text.get(..2) {
+        Some("0x") | Some("0X") => (16, &text[2..]),
+        Some("0b") | Some("0B") => (2, &text[2..]),
+        _ => (10, text),
+    }
+}
+
+/// Reads a number literal. Ints can be written in hex or binary, floats
+/// can have an exponent, and `_` can separate the digits of either.
+fn number(text: &str) -> Result<Type, LexError> {
+    let (radix, digits) = radix(text);
+    let chars: Vec<char> = digits.chars().collect();
+    let separated = chars.iter().enumerate().all(|(idx, &ch)| {
+        ch != '_'
+            || (idx > 0
+                && chars[idx - 1].is_digit(radix)
+                && chars.get(idx + 1).is_some_and(|next| next.is_digit(radix)))
+    });
+    let digits: String = chars.into_iter().filter(|&ch| ch != '_').collect();
+    if !separated || digits.is_empty() {
+        return Err(LexError::Number);
+    }
+
+    if digits.chars().all(|ch| ch.is_digit(radix)) {
+        return i64::from_str_radix(&digits, radix)
+            .map(|value| Type::Int { value })
+            .map_err(|_| LexError::TooLarge);
+    }
+    match digits.parse::<f64>() {
+        Ok(value) if radix == 10 && value.is_finite() => Ok(Type::Float { value }),
+        Ok(_) if radix == 10 => Err(LexError::TooLarge),
+        _ => Err(LexError::Number),
     }
 }
 
-fn match_string_literal(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
+fn match_string_literal<S: CharStream>(iter: &mut S, line: i32) -> Option<Token> {
     match iter.peek() {
         Some('"') => {}
         _ => {
@@ -334,18 +528,71 @@ fn match_string_literal(iter: &mut Peekable<Chars>, line: i32) -> Option<Token>
         }
     };
 
+    // the string as written, for when an escape is invalid
+    let mut text = String::new();
     let mut value = String::new();
-    let _ = iter.next();
+    let mut is_valid = true;
+    text.push(iter.next().unwrap());
     while let Some(ch) = iter.next() {
-        if ch == '"' {
-            break;
+        text.push(ch);
+        match ch {
+            '"' if is_valid => return Some(Token::new(Type::String { value }, line)),
+            '"' => {
+                let error = LexError::Escape;
+                return Some(Token::new(Type::Invalid { value: text, error }, line));
+            }
+            '\\' => match match_escape(iter, &mut text) {
+                Some(ch) => value.push(ch),
+                None => is_valid = false,
+            },
+            _ => value.push(ch),
         }
-        value.push(ch);
     }
-    Some(Token::new(Type::String { value }, line))
+    Some(Token::new(Type::UnterminatedString { value }, line))
+}
+
+/// Reads the escape sequence after a backslash into `text`, and returns the
+/// character it stands for
+fn match_escape<S: CharStream>(iter: &mut S, text: &mut String) -> Option<char> {
+    let ch = iter.next()?;
+    text.push(ch);
+    match ch {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '"' => Some(ch),
+        'u' => {
+            if iter.peek() != Some(&'{') {
+                return None;
+            }
+            text.push(iter.next().unwrap());
+            let mut hex = String::new();
+            // stop at a quote so a bad escape doesn't swallow the end of
+            // the string
+            while let Some(&ch) = iter.peek() {
+                if ch == '"' {
+                    break;
+                }
+                text.push(iter.next().unwrap());
+                if ch == '}' {
+                    let is_hex = hex.chars().all(|ch| ch.is_ascii_hexdigit());
+                    if !is_hex || hex.is_empty() || hex.len() > 6 {
+                        return None;
+                    }
+                    return u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(std::char::from_u32);
+                }
+                hex.push(ch);
+            }
+            None
+        }
+        _ => None,
+    }
 }
 
-fn match_identifier_like(iter: &mut Peekable<Chars>) -> Option<(bool, String)> {
+fn match_identifier_like<S: CharStream>(iter: &mut S) -> Option<(bool, String)> {
     let mut value = String::new();
 
     while let Some(ch) = iter.peek() {
@@ -355,34 +602,39 @@ fn match_identifier_like(iter: &mut Peekable<Chars>) -> Option<(bool, String)> {
         };
     }
 
-    if let Some(first) = value.chars().nth(0) {
-        let valid = !('0'..='9').contains(&first);
+    if let Some(first) = value.chars().next() {
+        let valid = !first.is_ascii_digit();
         Some((valid, value))
     } else {
         None
     }
 }
 
-fn match_identifier_or_keyword(iter: &mut Peekable<Chars>, line: i32) -> Option<Token> {
+fn match_identifier_or_keyword<S: CharStream>(iter: &mut S, line: i32) -> Option<Token> {
     if let Some((is_valid, value)) = match_identifier_like(iter) {
         if !is_valid {
-            return Some(Token::new(Type::Invalid { value }, line));
+            let error = LexError::Number;
+            return Some(Token::new(Type::Invalid { value, error }, line));
         }
         let token = match value.as_str() {
             "and" => Token::new(Type::And, line),
+            "catch" => Token::new(Type::Catch, line),
             "class" => Token::new(Type::Class, line),
             "else" => Token::new(Type::Else, line),
             "false" => Token::new(Type::False, line),
             "fun" => Token::new(Type::Fun, line),
             "for" => Token::new(Type::For, line),
             "if" => Token::new(Type::If, line),
+            "import" => Token::new(Type::Import, line),
             "nil" => Token::new(Type::Nil, line),
             "or" => Token::new(Type::Or, line),
             "print" => Token::new(Type::Print, line),
             "return" => Token::new(Type::Return, line),
             "super" => Token::new(Type::Super, line),
             "this" => Token::new(Type::This, line),
+            "throw" => Token::new(Type::Throw, line),
             "true" => Token::new(Type::True, line),
+            "try" => Token::new(Type::Try, line),
             "var" => Token::new(Type::Var, line),
             "while" => Token::new(Type::While, line),
             _ => Token::new(Type::Identifier { name: value }, line),
@@ -473,6 +725,18 @@ mod tests {
             match_single(&mut iter, 0)
         );
 
+        let input = "[:]";
+        let mut iter = input.chars().peekable();
+        assert_eq!(
+            Some(Token::new(Type::LBracket, 0)),
+            match_single(&mut iter, 0)
+        );
+        assert_eq!(Some(Token::new(Type::Colon, 0)), match_single(&mut iter, 0));
+        assert_eq!(
+            Some(Token::new(Type::RBracket, 0)),
+            match_single(&mut iter, 0)
+        );
+
         let input = "@";
         let mut iter = input.chars().peekable();
         assert_eq!(None, match_single(&mut iter, 0));
@@ -518,7 +782,8 @@ mod tests {
         assert_eq!(
             Some(Token::new(
                 Type::Invalid {
-                    value: "123abc".to_owned()
+                    value: "123abc".to_owned(),
+                    error: LexError::Number,
                 },
                 0
             )),
@@ -540,12 +805,154 @@ mod tests {
         assert_eq!(
             Some(Token::new(
                 Type::Invalid {
-                    value: "1.2.3".to_owned()
+                    value: "1.2.3".to_owned(),
+                    error: LexError::Number,
+                },
+                0
+            )),
+            match_number_literal(&mut iter, 0)
+        );
+
+        // a trailing dot is left for property access
+        let input = "1.foo";
+        let mut iter = input.chars().peekable();
+        assert_eq!(
+            Some(Token::new(Type::Int { value: 1 }, 0)),
+            match_number_literal(&mut iter, 0)
+        );
+        assert_eq!(Some(&'.'), iter.peek());
+    }
+
+    /// Runs `match_number_literal` over all of `input`
+    fn number(input: &str) -> Type {
+        let mut iter = input.chars().peekable();
+        let token = match_number_literal(&mut iter, 0).unwrap();
+        assert_eq!(None, iter.peek());
+        token.kind
+    }
+
+    fn invalid(value: &str, error: LexError) -> Type {
+        Type::Invalid {
+            value: value.to_string(),
+            error,
+        }
+    }
+
+    #[test]
+    fn test_match_radix_literal() {
+        assert_eq!(number("0xff"), Type::Int { value: 255 });
+        assert_eq!(number("0XdeadBEEF"), Type::Int { value: 0xdead_beef });
+        assert_eq!(number("0b1010"), Type::Int { value: 10 });
+        assert_eq!(number("1_000_000"), Type::Int { value: 1_000_000 });
+        assert_eq!(number("0xff_ff"), Type::Int { value: 0xffff });
+        assert_eq!(number("9223372036854775807"), Type::Int { value: i64::MAX });
+
+        assert_eq!(number("0x"), invalid("0x", LexError::Number));
+        assert_eq!(number("0xfg"), invalid("0xfg", LexError::Number));
+        assert_eq!(number("0b102"), invalid("0b102", LexError::Number));
+        assert_eq!(number("1__0"), invalid("1__0", LexError::Number));
+        assert_eq!(number("10_"), invalid("10_", LexError::Number));
+        assert_eq!(number("0x_1"), invalid("0x_1", LexError::Number));
+        assert_eq!(
+            number("9223372036854775808"),
+            invalid("9223372036854775808", LexError::TooLarge)
+        );
+        assert_eq!(
+            number("0x1_0000_0000_0000_0000"),
+            invalid("0x1_0000_0000_0000_0000", LexError::TooLarge)
+        );
+
+        // a hex `e` is a digit, not an exponent
+        let mut iter = "0x1e-2".chars().peekable();
+        assert_eq!(
+            Some(Token::new(Type::Int { value: 30 }, 0)),
+            match_number_literal(&mut iter, 0)
+        );
+        assert_eq!(Some(&'-'), iter.peek());
+    }
+
+    #[test]
+    fn test_match_scientific_literal() {
+        assert_eq!(number("1e3"), Type::Float { value: 1000.0 });
+        assert_eq!(number("2.5E-3"), Type::Float { value: 0.0025 });
+        assert_eq!(number("1e+2"), Type::Float { value: 100.0 });
+        assert_eq!(number("1_000.000_1"), Type::Float { value: 1000.0001 });
+        assert_eq!(number("1e"), invalid("1e", LexError::Number));
+        assert_eq!(number("1e3e3"), invalid("1e3e3", LexError::Number));
+        assert_eq!(number("1e999"), invalid("1e999", LexError::TooLarge));
+
+        // the sign only belongs to the number when a digit follows it
+        let mut iter = "1e-x".chars().peekable();
+        assert_eq!(
+            Some(Token::new(
+                Type::Invalid {
+                    value: "1e".to_string(),
+                    error: LexError::Number
                 },
                 0
             )),
             match_number_literal(&mut iter, 0)
         );
+        assert_eq!(Some(&'-'), iter.peek());
+
+        let mut iter = "2-1".chars().peekable();
+        assert_eq!(
+            Some(Token::new(Type::Int { value: 2 }, 0)),
+            match_number_literal(&mut iter, 0)
+        );
+        assert_eq!(Some(&'-'), iter.peek());
+    }
+
+    /// Runs `match_string_literal` over `input`
+    fn string(input: &str) -> Type {
+        let mut iter = input.chars().peekable();
+        match_string_literal(&mut iter, 0).unwrap().kind
+    }
+
+    #[test]
+    fn test_match_string_escapes() {
+        let value = |value: &str| Type::String {
+            value: value.to_string(),
+        };
+        assert_eq!(string(r#""a\nb""#), value("a\nb"));
+        assert_eq!(string(r#""\t\r\0""#), value("\t\r\0"));
+        assert_eq!(string(r#""say \"hi\"""#), value("say \"hi\""));
+        assert_eq!(string(r#""back\\slash""#), value("back\\slash"));
+        assert_eq!(string(r#""\u{48}\u{e9}\u{1F600}""#), value("Hé😀"));
+
+        assert_eq!(
+            string(r#""a\qb" + 1"#),
+            invalid(r#""a\qb""#, LexError::Escape)
+        );
+        assert_eq!(
+            string(r#""\u{110000}""#),
+            invalid(r#""\u{110000}""#, LexError::Escape)
+        );
+        assert_eq!(string(r#""\u{}""#), invalid(r#""\u{}""#, LexError::Escape));
+        assert_eq!(string(r#""\u41""#), invalid(r#""\u41""#, LexError::Escape));
+        // an unfinished `\u{` stops at the closing quote
+        assert_eq!(
+            string(r#""\u{41""#),
+            invalid(r#""\u{41""#, LexError::Escape)
+        );
+
+        // an escaped quote doesn't end the string
+        assert_eq!(
+            string(r#""abc\""#),
+            Type::UnterminatedString {
+                value: "abc\"".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        let input = "print 0b12 + \"\\z\";";
+        let invalid: Vec<(&str, Range<usize>)> = Lexer::new(input)
+            .filter(|token| matches!(token.kind, Type::Invalid { .. }))
+            .map(|token| (token.lexeme(input), token.span.range()))
+            .collect();
+        assert_eq!(invalid, vec![("0b12", 6..10), ("\"\\z\"", 13..17)]);
     }
 
     #[test]
@@ -581,7 +988,8 @@ mod tests {
         assert_eq!(
             Some(Token::new(
                 Type::Invalid {
-                    value: "01tony".to_string()
+                    value: "01tony".to_string(),
+                    error: LexError::Number,
                 },
                 0
             )),
@@ -642,4 +1050,79 @@ mod tests {
         let result: Vec<Token> = Lexer::new(input).collect();
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_spans() {
+        let input = "var abc = \"x\ny\";\n  éa;";
+        let tokens: Vec<Token> = Lexer::new(input).collect();
+        let spans: Vec<(&str, i32, usize)> = tokens
+            .iter()
+            .map(|token| (token.lexeme(input), token.line, token.span.column))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("var", 0, 0),
+                ("abc", 0, 4),
+                ("=", 0, 8),
+                ("\"x\ny\"", 0, 10),
+                (";", 1, 2),
+                ("é", 2, 2),
+                ("a", 2, 3),
+                (";", 2, 4),
+                ("", 2, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated() {
+        let input = "\"abc";
+        let mut iter = input.chars().peekable();
+        assert_eq!(
+            Some(Token::new(
+                Type::UnterminatedString {
+                    value: "abc".to_string()
+                },
+                0
+            )),
+            match_string_literal(&mut iter, 0)
+        );
+
+        let kinds: Vec<Type> = Lexer::new("a /* b\n c */ d /* e")
+            .map(|token| token.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Type::Identifier {
+                    name: "a".to_string()
+                },
+                Type::Identifier {
+                    name: "d".to_string()
+                },
+                Type::UnterminatedComment,
+                Type::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = "a // one\n/* two\n */ b";
+        let tokens: Vec<(&str, i32)> = Lexer::with_comments(input)
+            .map(|token| (token.lexeme(input), token.line))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                ("a", 0),
+                ("// one", 0),
+                ("/* two\n */", 1),
+                ("b", 2),
+                ("", 2)
+            ]
+        );
+        assert_eq!(Lexer::new(input).count(), 3);
+    }
 }
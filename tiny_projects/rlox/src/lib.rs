@@ -0,0 +1,37 @@
+//! A Lox interpreter with a tree-walking and a bytecode backend, usable
+//! from other programs through `Interpreter`, and a compiler for part of
+//! the language to WebAssembly.
+
+pub mod analysis;
+pub mod error;
+pub mod evaluation;
+pub mod formatter;
+pub mod grammar;
+pub mod interpreter;
+pub mod lexer;
+pub mod optimizer;
+pub mod parser;
+pub mod resolver;
+pub mod stdlib;
+pub mod typecheck;
+pub mod value;
+pub mod vm;
+pub mod wasm;
+
+pub use error::LoxError;
+pub use interpreter::{Backend, ImportPolicy, Interpreter, Options, SharedBuffer};
+pub use value::Value;
+
+/// When less stack than this is left, recursion moves on to a new segment.
+/// Passes only check once per program or call, so this has to hold a whole
+/// walk over a tree nested `parser::MAX_NESTING` deep, in a debug build.
+const RED_ZONE: usize = 4 * 1024 * 1024;
+const STACK_SEGMENT: usize = 16 * 1024 * 1024;
+
+/// Runs `f`, on a new stack segment if the current one is nearly used up.
+/// The parser and the tree-walker recurse through this, so deep programs
+/// run into `parser::MAX_NESTING` and `evaluation::MAX_CALL_DEPTH` rather
+/// than the end of whatever stack the host gave them.
+pub(crate) fn ensure_stack<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(RED_ZONE, STACK_SEGMENT, f)
+}
@@ -0,0 +1,379 @@
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as LspNotification, PublishDiagnostics,
+};
+use lsp_types::request::{
+    DocumentSymbolRequest, GotoDefinition, HoverRequest, References, Request as LspRequest,
+    SemanticTokensFullRequest,
+};
+use lsp_types::{
+    DiagnosticSeverity, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ReferenceParams, SemanticToken, SemanticTokenType,
+    SemanticTokens, SemanticTokensFullOptions, SemanticTokensLegend, SemanticTokensOptions,
+    SemanticTokensParams, SemanticTokensResult, SemanticTokensServerCapabilities,
+    ServerCapabilities, SymbolKind as LspSymbolKind, TextDocumentPositionParams,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use rlox::analysis::{Analysis, Outline, Severity, Symbol, SymbolKind};
+use rlox::lexer::Span;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// Token types in the order of `Highlight`, so a highlight converts to its
+/// index in the legend with `as u32`
+const LEGEND: [SemanticTokenType; 11] = [
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::COMMENT,
+    SemanticTokenType::STRING,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::OPERATOR,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::CLASS,
+    SemanticTokenType::METHOD,
+    SemanticTokenType::PROPERTY,
+];
+
+/// `rlox lsp`, a language server talking JSON-RPC over stdin and stdout
+/// until the client asks it to exit
+pub fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (connection, io_threads) = Connection::stdio();
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    let mut server = Server {
+        connection,
+        documents: HashMap::new(),
+    };
+    server.main_loop()?;
+    // the writer thread only finishes once the connection is gone
+    drop(server);
+    io_threads.join()?;
+    Ok(())
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+            SemanticTokensOptions {
+                legend: SemanticTokensLegend {
+                    token_types: LEGEND.to_vec(),
+                    token_modifiers: Vec::new(),
+                },
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                ..SemanticTokensOptions::default()
+            },
+        )),
+        ..ServerCapabilities::default()
+    }
+}
+
+/// An open file and what is known about its current text
+struct Document {
+    text: String,
+    /// Byte offset of the start of every line
+    line_starts: Vec<usize>,
+    analysis: Analysis,
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        Self {
+            analysis: Analysis::new(&text),
+            line_starts,
+            text,
+        }
+    }
+
+    /// LSP positions count UTF-16 code units from the start of the line
+    fn position(&self, offset: usize) -> Position {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.line_starts[line];
+        let character = self.text[start..offset].encode_utf16().count();
+        Position::new(line as u32, character as u32)
+    }
+
+    fn offset(&self, position: Position) -> usize {
+        let start = match self.line_starts.get(position.line as usize) {
+            Some(&start) => start,
+            None => return self.text.len(),
+        };
+        let mut units = 0;
+        for (idx, ch) in self.text[start..].char_indices() {
+            if units >= position.character as usize || ch == '\n' {
+                return start + idx;
+            }
+            units += ch.len_utf16();
+        }
+        self.text.len()
+    }
+
+    fn range(&self, span: Span) -> Range {
+        Range::new(self.position(span.start), self.position(span.end))
+    }
+
+    fn symbol_at(&self, position: Position) -> Option<&Symbol> {
+        self.analysis.symbol_at(self.offset(position))
+    }
+}
+
+struct Server {
+    connection: Connection,
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    fn main_loop(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        while let Ok(msg) = self.connection.receiver.recv() {
+            match msg {
+                Message::Request(req) => {
+                    if self.connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    let response = self.handle_request(req);
+                    self.connection.sender.send(Message::Response(response))?;
+                }
+                Message::Notification(notification) => self.handle_notification(notification)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&self, req: Request) -> Response {
+        match req.method.as_str() {
+            GotoDefinition::METHOD => {
+                respond::<GotoDefinition>(req, |params| self.definition(params))
+            }
+            References::METHOD => respond::<References>(req, |params| self.references(params)),
+            HoverRequest::METHOD => respond::<HoverRequest>(req, |params| self.hover(params)),
+            DocumentSymbolRequest::METHOD => {
+                respond::<DocumentSymbolRequest>(req, |params| self.document_symbols(params))
+            }
+            SemanticTokensFullRequest::METHOD => {
+                respond::<SemanticTokensFullRequest>(req, |params| self.semantic_tokens(params))
+            }
+            method => Response::new_err(
+                req.id.clone(),
+                ErrorCode::MethodNotFound as i32,
+                format!("Unknown method {}", method),
+            ),
+        }
+    }
+
+    fn handle_notification(
+        &mut self,
+        notification: Notification,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = notification
+                    .extract::<lsp_types::DidOpenTextDocumentParams>(DidOpenTextDocument::METHOD)?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), Document::new(params.text_document.text));
+                self.publish_diagnostics(uri)
+            }
+            DidChangeTextDocument::METHOD => {
+                let params = notification.extract::<lsp_types::DidChangeTextDocumentParams>(
+                    DidChangeTextDocument::METHOD,
+                )?;
+                // only full syncing is offered, so the last change is the
+                // whole text
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    let uri = params.text_document.uri;
+                    self.documents
+                        .insert(uri.clone(), Document::new(change.text));
+                    self.publish_diagnostics(uri)?;
+                }
+                Ok(())
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = notification.extract::<lsp_types::DidCloseTextDocumentParams>(
+                    DidCloseTextDocument::METHOD,
+                )?;
+                self.documents.remove(&params.text_document.uri);
+                self.publish_diagnostics(params.text_document.uri)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends the diagnostics of a document, or clears them once it is closed
+    fn publish_diagnostics(&self, uri: Url) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let diagnostics = match self.documents.get(&uri) {
+            Some(doc) => doc
+                .analysis
+                .diagnostics
+                .iter()
+                .map(|diagnostic| lsp_types::Diagnostic {
+                    range: doc.range(diagnostic.token.span),
+                    severity: Some(match diagnostic.severity {
+                        Severity::Error => DiagnosticSeverity::ERROR,
+                        Severity::Warning => DiagnosticSeverity::WARNING,
+                    }),
+                    source: Some("rlox".to_string()),
+                    message: diagnostic.message.clone(),
+                    ..lsp_types::Diagnostic::default()
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+        self.connection
+            .sender
+            .send(Message::Notification(notification))?;
+        Ok(())
+    }
+
+    /// The document and the symbol under the cursor
+    fn lookup(&self, params: &TextDocumentPositionParams) -> Option<(&Document, &Symbol)> {
+        let doc = self.documents.get(&params.text_document.uri)?;
+        Some((doc, doc.symbol_at(params.position)?))
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let params = params.text_document_position_params;
+        let (doc, symbol) = self.lookup(&params)?;
+        let range = doc.range(symbol.declaration.span);
+        Some(GotoDefinitionResponse::Scalar(Location::new(
+            params.text_document.uri,
+            range,
+        )))
+    }
+
+    fn references(&self, params: ReferenceParams) -> Option<Vec<Location>> {
+        let include_declaration = params.context.include_declaration;
+        let params = params.text_document_position;
+        let (doc, symbol) = self.lookup(&params)?;
+        let declaration = Some(&symbol.declaration).filter(|_| include_declaration);
+        let locations = declaration
+            .into_iter()
+            .chain(symbol.references.iter())
+            .map(|token| Location::new(params.text_document.uri.clone(), doc.range(token.span)))
+            .collect();
+        Some(locations)
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let params = params.text_document_position_params;
+        let (doc, symbol) = self.lookup(&params)?;
+        let kind = match symbol.kind {
+            SymbolKind::Variable => "Variable",
+            SymbolKind::Parameter => "Parameter",
+            SymbolKind::Function => "Function",
+            SymbolKind::Class => "Class",
+            SymbolKind::Method => "Method",
+        };
+        let value = format!(
+            "```lox\n{}\n```\n{} declared on line {}",
+            symbol.detail,
+            kind,
+            symbol.declaration.line + 1
+        );
+        let offset = doc.offset(params.position);
+        let token = std::iter::once(&symbol.declaration)
+            .chain(symbol.references.iter())
+            .find(|token| token.span.start <= offset && offset <= token.span.end);
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: token.map(|token| doc.range(token.span)),
+        })
+    }
+
+    fn document_symbols(&self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let doc = self.documents.get(&params.text_document.uri)?;
+        let symbols = doc
+            .analysis
+            .outline
+            .iter()
+            .map(|item| document_symbol(doc, item))
+            .collect();
+        Some(DocumentSymbolResponse::Nested(symbols))
+    }
+
+    fn semantic_tokens(&self, params: SemanticTokensParams) -> Option<SemanticTokensResult> {
+        let doc = self.documents.get(&params.text_document.uri)?;
+        let mut data = Vec::new();
+        let mut previous = Position::new(0, 0);
+        for (span, highlight) in doc.analysis.highlights() {
+            // tokens may not span lines, so strings and comments that do are
+            // sent one line at a time
+            let mut start = span.start;
+            for line in doc.text[span.start..span.end].split('\n') {
+                let position = doc.position(start);
+                let length = line.encode_utf16().count() as u32;
+                start += line.len() + 1;
+                if length == 0 {
+                    continue;
+                }
+                let delta_line = position.line - previous.line;
+                let delta_start = if delta_line == 0 {
+                    position.character - previous.character
+                } else {
+                    position.character
+                };
+                data.push(SemanticToken {
+                    delta_line,
+                    delta_start,
+                    length,
+                    token_type: highlight as u32,
+                    token_modifiers_bitset: 0,
+                });
+                previous = position;
+            }
+        }
+        Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: None,
+            data,
+        }))
+    }
+}
+
+/// Answers a request with what `handler` makes of its parameters
+fn respond<R: LspRequest>(req: Request, handler: impl FnOnce(R::Params) -> R::Result) -> Response {
+    match serde_json::from_value(req.params) {
+        Ok(params) => Response::new_ok(req.id, handler(params)),
+        Err(err) => Response::new_err(req.id, ErrorCode::InvalidParams as i32, err.to_string()),
+    }
+}
+
+fn document_symbol(doc: &Document, item: &Outline) -> DocumentSymbol {
+    let symbol = &doc.analysis.symbols[item.symbol];
+    let kind = match symbol.kind {
+        SymbolKind::Class => LspSymbolKind::CLASS,
+        SymbolKind::Method => LspSymbolKind::METHOD,
+        _ => LspSymbolKind::FUNCTION,
+    };
+    // `deprecated` is itself deprecated but still has to be filled in
+    #[allow(deprecated)]
+    DocumentSymbol {
+        name: symbol.name().to_string(),
+        detail: Some(symbol.detail.clone()),
+        kind,
+        tags: None,
+        deprecated: None,
+        range: doc.range(item.span),
+        selection_range: doc.range(symbol.declaration.span),
+        children: Some(
+            item.children
+                .iter()
+                .map(|child| document_symbol(doc, child))
+                .collect(),
+        ),
+    }
+}
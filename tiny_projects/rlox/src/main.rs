@@ -1,19 +1,185 @@
 use std::env;
+use std::path::Path;
+use std::process;
+use std::thread;
 
-pub mod app;
-pub mod evaluation;
-pub mod grammar;
-pub mod lexer;
-pub mod parser;
+mod app;
+mod debugger;
+mod lsp;
+mod repl;
+
+/// Every Lox call recurses through the tree-walker on the host stack, so
+/// give it more room than the default main thread gets
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+const USAGE: &str = "\
+rlox [-O] [--vm] [--disassemble] [--trace] [--stress-gc] [--log-gc] <optional path to file>
+rlox --check <path to file>
+rlox fmt [--check] <paths to files>
+rlox debug <path to file>
+rlox build --target wasm [--wat] <path to file>
+rlox lsp";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut lox = app::Lox::new();
-    if args.len() > 2 {
-        println!("rlox <optional path to file>");
-    } else if args.len() == 2 {
-        lox.run_script(&args[1]);
-    } else {
-        lox.run_repl();
+    // `--target` takes a value, so this one can't be split into flags and
+    // arguments like the others
+    let argv: Vec<String> = env::args().skip(1).collect();
+    if is_subcommand(argv.first(), "build") {
+        return build(&argv[1..]);
+    }
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with('-'));
+    if is_subcommand(args.first(), "fmt") {
+        return fmt(&flags, &args[1..]);
+    }
+    if is_subcommand(args.first(), "lsp") {
+        return lsp(&flags);
+    }
+    if is_subcommand(args.first(), "debug") {
+        return debug(&flags, &args[1..]);
+    }
+    let mut options = rlox::Options::default();
+    let mut check = false;
+    for flag in flags.iter() {
+        match flag.as_str() {
+            "-O" => options.optimize = true,
+            "--check" => check = true,
+            "--vm" => options.backend = rlox::Backend::Vm,
+            // bytecode only exists in the VM, so these imply it
+            "--disassemble" => {
+                options.backend = rlox::Backend::Vm;
+                options.disassemble = true;
+            }
+            "--trace" => {
+                options.backend = rlox::Backend::Vm;
+                options.trace = true;
+            }
+            "--stress-gc" => {
+                options.backend = rlox::Backend::Vm;
+                options.stress_gc = true;
+            }
+            "--log-gc" => {
+                options.backend = rlox::Backend::Vm;
+                options.log_gc = true;
+            }
+            _ => {
+                eprintln!("Unknown option {}", flag);
+                usage();
+            }
+        }
+    }
+
+    if args.len() > 1 || (check && args.is_empty()) {
+        usage();
+    }
+    with_big_stack(move || {
+        let mut lox = app::Lox::with_options(options);
+        if check {
+            lox.run_check(&args[0]);
+        } else if args.len() == 1 {
+            lox.run_script(&args[0]);
+        } else {
+            lox.run_repl();
+        }
+    });
+}
+
+/// Whether `arg` is the subcommand `name`, rather than a script in the
+/// working directory that happens to have the same name
+fn is_subcommand(arg: Option<&String>, name: &str) -> bool {
+    arg.map(String::as_str) == Some(name) && !Path::new(name).is_file()
+}
+
+/// Reports a command line that makes no sense and exits with 64, the
+/// usage error code of sysexits
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(64);
+}
+
+/// Runs `f` on a thread with `STACK_SIZE` of stack and waits for it
+fn with_big_stack<F: FnOnce() + Send + 'static>(f: F) {
+    let child = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(f)
+        .expect("Cannot spawn interpreter thread");
+    child.join().expect("Interpreter thread panicked");
+}
+
+/// `rlox fmt`, which rewrites files in place or with `--check` only lists
+/// the ones that would change
+fn fmt(flags: &[String], paths: &[String]) {
+    let mut check = false;
+    for flag in flags.iter() {
+        match flag.as_str() {
+            "--check" => check = true,
+            _ => {
+                eprintln!("Unknown option {}", flag);
+                usage();
+            }
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+    app::Lox::new().run_fmt(paths, check);
+}
+
+/// `rlox lsp`, the language server. Editors often pass `--stdio`, which is
+/// the only transport there is anyway.
+fn lsp(flags: &[String]) {
+    if let Some(flag) = flags.iter().find(|flag| flag.as_str() != "--stdio") {
+        eprintln!("Unknown option {}", flag);
+        usage();
+    }
+    if let Err(err) = lsp::run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+/// `rlox debug`, which runs a script on the tree-walker under the debugger
+fn debug(flags: &[String], paths: &[String]) {
+    if let Some(flag) = flags.first() {
+        eprintln!("Unknown option {}", flag);
+        usage();
+    }
+    if paths.len() != 1 {
+        usage();
+    }
+    let path = paths[0].clone();
+    with_big_stack(move || app::Lox::new().run_debug(&path));
+}
+
+/// `rlox build`, which compiles a script for another runtime instead of
+/// running it. WebAssembly is the only target so far.
+fn build(args: &[String]) {
+    let mut target = None;
+    let mut text = false;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--target" => target = args.next().map(String::as_str),
+            "--wat" => text = true,
+            flag if flag.starts_with('-') => {
+                eprintln!("Unknown option {}", flag);
+                usage();
+            }
+            path => paths.push(path.to_string()),
+        }
+    }
+    match target {
+        Some("wasm") => {}
+        Some(target) => {
+            eprintln!("Unknown target {}", target);
+            usage();
+        }
+        None => usage(),
+    }
+    if paths.len() != 1 {
+        usage();
     }
+    let path = paths.remove(0);
+    with_big_stack(move || app::Lox::new().run_build(&path, text));
 }
@@ -0,0 +1,344 @@
+//! Optional pass between the resolver and the backends, turned on with `-O`
+
+use crate::error::Warning;
+use crate::evaluation::{binary, negate};
+use crate::grammar::*;
+use crate::lexer::Token;
+use crate::value::Value;
+use std::rc::Rc;
+
+/// Folds constant expressions and drops branches that can never run. The
+/// program must already be resolved: every variable expression that is
+/// kept keeps its id, so the resolver's bindings still apply.
+///
+/// Folding uses the same operations as the interpreters, so a folded
+/// program prints exactly what the original would. Anything that would
+/// fail at runtime, like `-"a"`, is left for the runtime to report.
+/// Division by a constant zero that can run is also returned as a warning.
+pub fn optimize(program: Vec<Stmt>) -> (Vec<Stmt>, Vec<Warning>) {
+    let mut optimizer = Optimizer {
+        warnings: Vec::new(),
+    };
+    let program = crate::ensure_stack(|| optimizer.stmts(program));
+    (program, optimizer.warnings)
+}
+
+struct Optimizer {
+    warnings: Vec<Warning>,
+}
+
+impl Optimizer {
+    fn stmts(&mut self, stmts: Vec<Stmt>) -> Vec<Stmt> {
+        stmts
+            .into_iter()
+            .filter_map(|stmt| self.stmt(stmt))
+            .collect()
+    }
+
+    /// `None` when nothing of the statement can ever run
+    fn stmt(&mut self, stmt: Stmt) -> Option<Stmt> {
+        Some(match stmt {
+            Stmt::Expression(expr) => Stmt::Expression(self.expr(expr)),
+            Stmt::Print(expr) => Stmt::Print(self.expr(expr)),
+            Stmt::Var(name, annotation, initializer) => {
+                Stmt::Var(name, annotation, initializer.map(|expr| self.expr(expr)))
+            }
+            Stmt::Block(stmts) => Stmt::Block(self.stmts(stmts)),
+            Stmt::If(condition, then_branch, else_branch) => {
+                let condition = self.condition(condition);
+                match constant(&condition) {
+                    Some(value) if value.is_truthy() => return self.stmt(*then_branch),
+                    Some(_) => return else_branch.and_then(|branch| self.stmt(*branch)),
+                    None => Stmt::If(
+                        condition,
+                        Box::new(self.body(*then_branch)),
+                        else_branch
+                            .and_then(|branch| self.stmt(*branch))
+                            .map(Box::new),
+                    ),
+                }
+            }
+            Stmt::While(condition, body, end) => {
+                let condition = self.condition(condition);
+                match constant(&condition) {
+                    Some(value) if !value.is_truthy() => return None,
+                    _ => Stmt::While(condition, Box::new(self.body(*body)), end),
+                }
+            }
+            Stmt::Function(decl) => Stmt::Function(self.function(decl)),
+            Stmt::Return(keyword, value) => {
+                Stmt::Return(keyword, value.map(|expr| self.expr(expr)))
+            }
+            Stmt::Throw(keyword, value) => Stmt::Throw(keyword, self.expr(value)),
+            Stmt::Try(keyword, body, name, handler) => {
+                let body = self.stmts(body);
+                Stmt::Try(keyword, body, name, self.stmts(handler))
+            }
+            Stmt::Import(path, name, id) => Stmt::Import(path, name, id),
+            Stmt::Class(decl) => Stmt::Class(ClassDecl {
+                methods: decl
+                    .methods
+                    .into_iter()
+                    .map(|method| self.function(method))
+                    .collect(),
+                ..decl
+            }),
+        })
+    }
+
+    /// A statement that has to stay, even if it does nothing
+    fn body(&mut self, stmt: Stmt) -> Stmt {
+        self.stmt(stmt).unwrap_or_else(|| Stmt::Block(Vec::new()))
+    }
+
+    fn function(&mut self, decl: Rc<FunctionDecl>) -> Rc<FunctionDecl> {
+        let decl = Rc::try_unwrap(decl).unwrap_or_else(|decl| (*decl).clone());
+        Rc::new(FunctionDecl {
+            body: self.stmts(decl.body),
+            ..decl
+        })
+    }
+
+    /// Only the truthiness of a condition matters, so `!!x` is just `x`
+    fn condition(&mut self, expr: Expr) -> Expr {
+        match self.expr(expr) {
+            Expr::UnaryExpr(UnaryOp::Not, token, inner) => match *inner {
+                Expr::UnaryExpr(UnaryOp::Not, _, operand) => *operand,
+                inner => Expr::UnaryExpr(UnaryOp::Not, token, Box::new(inner)),
+            },
+            expr => expr,
+        }
+    }
+
+    fn expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Literal(..) | Expr::Variable(..) | Expr::This(..) | Expr::Super(..) => expr,
+            Expr::Grouping(inner) => match self.expr(*inner) {
+                inner @ Expr::Literal(..) => inner,
+                inner => Expr::Grouping(Box::new(inner)),
+            },
+            Expr::UnaryExpr(op, token, operand) => {
+                let operand = self.expr(*operand);
+                self.unary(op, token, operand)
+            }
+            Expr::BinaryExpr(left, op, token, right) => {
+                let left = self.expr(*left);
+                let right = self.expr(*right);
+                if op == BinaryOp::Divide && is_zero(&right) {
+                    self.warnings.push(Warning::DivisionByZero(token.clone()));
+                }
+                match (constant(&left), constant(&right)) {
+                    (Some(a), Some(b)) => match binary(&op, &token, a, b) {
+                        Ok(value) => literal(value, token),
+                        Err(_) => Expr::BinaryExpr(Box::new(left), op, token, Box::new(right)),
+                    },
+                    _ => Expr::BinaryExpr(Box::new(left), op, token, Box::new(right)),
+                }
+            }
+            Expr::Logical(left, op, right) => {
+                let left = self.expr(*left);
+                // the result is whichever operand decided the outcome, and
+                // a right operand that never runs is not looked at
+                match (op, constant(&left).map(|value| value.is_truthy())) {
+                    (LogicalOp::Or, Some(true)) | (LogicalOp::And, Some(false)) => left,
+                    (_, Some(_)) => self.expr(*right),
+                    (_, None) => Expr::Logical(Box::new(left), op, Box::new(self.expr(*right))),
+                }
+            }
+            Expr::Assign(name, value, id) => Expr::Assign(name, Box::new(self.expr(*value)), id),
+            Expr::Call(callee, paren, args) => {
+                let callee = self.expr(*callee);
+                Expr::Call(Box::new(callee), paren, self.exprs(args))
+            }
+            Expr::Get(object, name) => Expr::Get(Box::new(self.expr(*object)), name),
+            Expr::Set(object, name, value) => {
+                let object = self.expr(*object);
+                Expr::Set(Box::new(object), name, Box::new(self.expr(*value)))
+            }
+            Expr::List(bracket, elements) => Expr::List(bracket, self.exprs(elements)),
+            Expr::Map(brace, entries) => Expr::Map(
+                brace,
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (self.expr(key), self.expr(value)))
+                    .collect(),
+            ),
+            Expr::Index(object, bracket, index) => {
+                let object = self.expr(*object);
+                Expr::Index(Box::new(object), bracket, Box::new(self.expr(*index)))
+            }
+            Expr::SetIndex(object, bracket, index, value) => {
+                let object = self.expr(*object);
+                let index = self.expr(*index);
+                Expr::SetIndex(
+                    Box::new(object),
+                    bracket,
+                    Box::new(index),
+                    Box::new(self.expr(*value)),
+                )
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        exprs.into_iter().map(|expr| self.expr(expr)).collect()
+    }
+
+    fn unary(&mut self, op: UnaryOp, token: Token, operand: Expr) -> Expr {
+        let folded = constant(&operand).and_then(|value| match op {
+            UnaryOp::Not => Some(Value::Bool(!value.is_truthy())),
+            UnaryOp::Negative => negate(value),
+        });
+        match (op, folded, operand) {
+            (_, Some(value), _) => literal(value, token),
+            // `!!x` is `x` when `x` is a boolean anyway
+            (UnaryOp::Not, None, Expr::UnaryExpr(UnaryOp::Not, _, inner)) if is_boolean(&inner) => {
+                *inner
+            }
+            (op, None, operand) => Expr::UnaryExpr(op, token, Box::new(operand)),
+        }
+    }
+}
+
+/// The value of a literal expression
+fn constant(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Literal(lit, _) => Some(match lit {
+            Literal::String(val) => Value::Str(val.clone()),
+            Literal::Float(val) => Value::Float(*val),
+            Literal::Int(val) => Value::Int(*val),
+            Literal::True => Value::Bool(true),
+            Literal::False => Value::Bool(false),
+            Literal::Nil => Value::Nil,
+        }),
+        _ => None,
+    }
+}
+
+/// A folded value, attributed to the operator that produced it
+fn literal(value: Value, token: Token) -> Expr {
+    let lit = match value {
+        Value::Str(val) => Literal::String(val),
+        Value::Float(val) => Literal::Float(val),
+        Value::Int(val) => Literal::Int(val),
+        Value::Bool(true) => Literal::True,
+        Value::Bool(false) => Literal::False,
+        Value::Nil => Literal::Nil,
+        other => unreachable!("operators on literals never produce {:?}", other),
+    };
+    Expr::Literal(lit, token)
+}
+
+fn is_zero(expr: &Expr) -> bool {
+    constant(expr).and_then(|value| value.as_float()) == Some(0.0)
+}
+
+/// Whether the expression always produces `true` or `false`
+fn is_boolean(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(Literal::True, _) | Expr::Literal(Literal::False, _) => true,
+        Expr::UnaryExpr(UnaryOp::Not, ..) => true,
+        Expr::BinaryExpr(_, op, ..) => matches!(
+            op,
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Lt | BinaryOp::Le
+        ),
+        Expr::Grouping(inner) => is_boolean(inner),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn optimize_source(source: &str) -> Result<String, Vec<String>> {
+        let tokens: Vec<Token> = Lexer::new(source).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        match optimize(program) {
+            (program, warnings) if warnings.is_empty() => Ok(program
+                .iter()
+                .map(|stmt| stmt.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")),
+            (_, warnings) => Err(warnings
+                .iter()
+                .map(|warning| format!("{} {}", warning.token().line + 1, warning))
+                .collect()),
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let tests = [
+            ("print 1 + 2 * 3;", "(print 7)"),
+            ("print (1 + 2) * -x;", "(print (* 3 (- x)))"),
+            ("print 1 < 2 and \"a\" + \"b\";", "(print \"ab\")"),
+            ("print nil or x;", "(print x)"),
+            ("print 2147483647 + 1;", "(print 2147483648)"),
+            (
+                "print 9223372036854775807 + 1;",
+                "(print 9.223372036854776e18)",
+            ),
+            ("print -\"a\";", "(print (- \"a\"))"),
+            ("print 1 + nil;", "(print (+ 1 nil))"),
+            ("print !!x;", "(print (! (! x)))"),
+            ("print !!(x < 1);", "(print (group (< x 1)))"),
+            ("print !!!x;", "(print (! x))"),
+        ];
+        for (source, expected) in tests.iter() {
+            assert_eq!(
+                optimize_source(source),
+                Ok(expected.to_string()),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_dead_code() {
+        let tests = [
+            ("if (false) print 1; else print 2;", "(print 2)"),
+            ("if (1 > 2) print 1;", ""),
+            ("if (!!x) print 1;", "(if x (print 1))"),
+            ("if (x) { if (true) print 1; }", "(if x (block (print 1)))"),
+            ("if (x) if (false) print 1;", "(if x (block))"),
+            ("while (nil) print 1;", ""),
+            (
+                "fun f() { while (!!x) if (nil) return; }",
+                "(fun f () (while x (block)))",
+            ),
+            (
+                "for (var i = 0; false; i = i + 1) print i;",
+                "(block (var i 0))",
+            ),
+        ];
+        for (source, expected) in tests.iter() {
+            assert_eq!(
+                optimize_source(source),
+                Ok(expected.to_string()),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        assert_eq!(
+            optimize_source("print 1 / 0;\nprint x / (1 - 1.0);"),
+            Err(vec![
+                "1 Division by zero.".to_string(),
+                "2 Division by zero.".to_string()
+            ])
+        );
+        assert!(optimize_source("print 1 / x; print 0 / 1;").is_ok());
+        // operands and branches that never run
+        assert_eq!(
+            optimize_source("print false and 1 / 0; if (nil) print 1 / 0;"),
+            Ok("(print false)".to_string())
+        );
+    }
+}
@@ -1,22 +1,111 @@
 #![allow(dead_code)]
+use crate::error::ParseError;
 use crate::grammar::*;
-use crate::lexer::{Token, Type};
+use crate::lexer::{LexError, Token, Type};
+use std::mem::discriminant;
+use std::rc::Rc;
+
+/// Calls and functions are limited to this many arguments so the bytecode
+/// can store the count in a single byte
+pub const MAX_ARGS: usize = 255;
+
+/// Expressions and statements can't nest deeper than this. Every pass after
+/// the parser recurses on the tree too, so this keeps all of them from
+/// running out of stack.
+pub const MAX_NESTING: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Function,
+    Method,
+}
 
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    errors: Vec<ParseError>,
+    /// Where in `tokens` literals the lexer rejected were taken out
+    rejected: Vec<usize>,
+    /// How many expressions and statements the current one is nested in
+    depth: usize,
 }
 
 impl Parser {
+    /// Tokens the lexer could not make sense of are pulled out here and
+    /// kept as diagnostics, the rest of the stream is parsed without them
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0 }
+        let mut errors = Vec::new();
+        let mut rejected = Vec::new();
+        let mut kept = Vec::new();
+        for token in tokens.into_iter() {
+            let err = match token.kind {
+                // a stray character is skipped like whitespace, the parser
+                // still has something to say about what is around it
+                Type::Invalid {
+                    error: LexError::Character,
+                    ..
+                } => {
+                    errors.push(ParseError::UnexpectedCharacter(token));
+                    continue;
+                }
+                Type::Invalid { error, .. } => ParseError::InvalidToken(token, error),
+                Type::UnterminatedString { .. } => ParseError::UnterminatedString(token),
+                Type::UnterminatedComment => ParseError::UnterminatedComment(token),
+                _ => {
+                    kept.push(token);
+                    continue;
+                }
+            };
+            errors.push(err);
+            rejected.push(kept.len());
+        }
+        Self {
+            tokens: kept,
+            current: 0,
+            errors,
+            rejected,
+            depth: 0,
+        }
     }
 
-    pub fn parse(&mut self) -> Result<Expr, ()> {
+    pub fn parse(&mut self) -> Result<Expr, ParseError> {
         self.current = 0; // reset at beginning
         self.expression()
     }
 
+    /// Parses every declaration in the token stream. On a syntax error the
+    /// parser skips ahead to the next statement boundary and carries on, so
+    /// all errors in the program are reported at once.
+    pub fn parse_program(&mut self) -> Result<Vec<Stmt>, Vec<ParseError>> {
+        match self.parse_recovering() {
+            (statements, errors) if errors.is_empty() => Ok(statements),
+            (_, errors) => Err(errors),
+        }
+    }
+
+    /// Like `parse_program`, but also keeps the statements that did parse
+    /// when there are errors, for tools that work on unfinished code
+    pub fn parse_recovering(&mut self) -> (Vec<Stmt>, Vec<ParseError>) {
+        self.current = 0; // reset at beginning
+        let mut statements = Vec::new();
+        while !self.is_at_end() {
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
+        }
+        (statements, std::mem::take(&mut self.errors))
+    }
+
+    fn is_at_end(&self) -> bool {
+        matches!(
+            self.peek(),
+            None | Some(Token {
+                kind: Type::Eof,
+                ..
+            })
+        )
+    }
+
     fn peek(&self) -> Option<Token> {
         let idx = self.current;
         if idx < self.tokens.len() {
@@ -26,6 +115,19 @@ impl Parser {
         }
     }
 
+    /// The token under the cursor, or an `Eof` if the tokens ran out
+    fn current(&self) -> Token {
+        self.peek().unwrap_or_else(|| {
+            let line = self.tokens.last().map_or(0, |token| token.line);
+            Token::new(Type::Eof, line)
+        })
+    }
+
+    /// The most recently consumed token
+    fn previous(&self) -> Token {
+        self.tokens[self.current.saturating_sub(1)].clone()
+    }
+
     fn next(&mut self) -> Option<Token> {
         let idx = self.current;
         if idx < self.tokens.len() {
@@ -36,115 +138,653 @@ impl Parser {
         }
     }
 
-    fn expression(&mut self) -> Result<Expr, ()> {
-        self.equality()
+    fn check(&self, kind: &Type) -> bool {
+        matches!(self.peek(), Some(token) if discriminant(&token.kind) == discriminant(kind))
+    }
+
+    /// Consumes the next token if it has the same kind as `kind`, ignoring
+    /// any payload carried by literals and identifiers
+    fn consume(&mut self, kind: Type, msg: &'static str) -> Result<Token, ParseError> {
+        if self.check(&kind) {
+            Ok(self.next().unwrap())
+        } else {
+            Err(ParseError::Expected {
+                token: self.current(),
+                msg,
+            })
+        }
+    }
+
+    /// Consumes the next token if it is one of the operators in `ops`
+    fn match_op<T: Copy>(&mut self, ops: &[(Type, T)]) -> Option<(T, Token)> {
+        let token = self.peek()?;
+        let (_, op) = ops.iter().find(|(kind, _)| *kind == token.kind)?;
+        let _ = self.next();
+        Some((*op, token))
+    }
+
+    /// Discards tokens until the start of the next statement
+    fn synchronize(&mut self) {
+        while let Some(token) = self.next() {
+            if token.kind == Type::Semicolon {
+                return;
+            }
+            match self.peek().map(|token| token.kind) {
+                Some(
+                    Type::Class
+                    | Type::Fun
+                    | Type::Var
+                    | Type::For
+                    | Type::If
+                    | Type::While
+                    | Type::Print
+                    | Type::Return
+                    | Type::Throw
+                    | Type::Try
+                    | Type::Import
+                    | Type::Eof,
+                )
+                | None => return,
+                _ => {}
+            }
+        }
+    }
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        let result = match self.current().kind {
+            Type::Var => {
+                let _ = self.next();
+                self.var_declaration()
+            }
+            Type::Class => {
+                let _ = self.next();
+                self.class_declaration()
+            }
+            Type::Fun => {
+                let _ = self.next();
+                self.function(FunctionKind::Function)
+                    .map(|decl| Stmt::Function(Rc::new(decl)))
+            }
+            Type::Import => {
+                let _ = self.next();
+                self.import_declaration()
+            }
+            _ => self.statement(),
+        };
+        match result {
+            Ok(stmt) => Some(stmt),
+            Err(err) => {
+                // a literal the lexer rejected was already reported, that
+                // it is missing here is not news
+                if !self.rejected.contains(&self.current) {
+                    self.errors.push(err);
+                }
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt, ParseError> {
+        let identifier = Type::Identifier {
+            name: String::new(),
+        };
+        let name = self.consume(identifier.clone(), "Expect class name.")?;
+        let superclass = if self.check(&Type::Lt) {
+            let _ = self.next();
+            let superclass = self.consume(identifier, "Expect superclass name.")?;
+            Some(Expr::Variable(superclass, ExprId::fresh()))
+        } else {
+            None
+        };
+
+        self.consume(Type::LBrace, "Expect '{' before class body.")?;
+        let mut methods = Vec::new();
+        while !self.check(&Type::RBrace) && !self.is_at_end() {
+            methods.push(Rc::new(self.function(FunctionKind::Method)?));
+        }
+        self.consume(Type::RBrace, "Expect '}' after class body.")?;
+        Ok(Stmt::Class(ClassDecl {
+            name,
+            superclass,
+            methods,
+        }))
+    }
+
+    fn function(&mut self, kind: FunctionKind) -> Result<FunctionDecl, ParseError> {
+        let (name_msg, paren_msg, body_msg) = match kind {
+            FunctionKind::Function => (
+                "Expect function name.",
+                "Expect '(' after function name.",
+                "Expect '{' before function body.",
+            ),
+            FunctionKind::Method => (
+                "Expect method name.",
+                "Expect '(' after method name.",
+                "Expect '{' before method body.",
+            ),
+        };
+        let name = self.consume(
+            Type::Identifier {
+                name: String::new(),
+            },
+            name_msg,
+        )?;
+
+        self.consume(Type::LParen, paren_msg)?;
+        let mut params = Vec::new();
+        let mut param_types = Vec::new();
+        if !self.check(&Type::RParen) {
+            loop {
+                if params.len() >= MAX_ARGS {
+                    self.errors
+                        .push(ParseError::TooManyParameters(self.current()));
+                }
+                params.push(self.consume(
+                    Type::Identifier {
+                        name: String::new(),
+                    },
+                    "Expect parameter name.",
+                )?);
+                param_types.push(self.annotation()?);
+                if !self.check(&Type::Comma) {
+                    break;
+                }
+                let _ = self.next();
+            }
+        }
+        self.consume(Type::RParen, "Expect ')' after parameters.")?;
+        let return_type = self.annotation()?;
+
+        self.consume(Type::LBrace, body_msg)?;
+        let body = self.block()?;
+        Ok(FunctionDecl {
+            name,
+            params,
+            param_types,
+            return_type,
+            body,
+        })
+    }
+
+    /// `import "path" as name;`, where `as` is an ordinary identifier
+    fn import_declaration(&mut self) -> Result<Stmt, ParseError> {
+        let path = self.consume(
+            Type::String {
+                value: String::new(),
+            },
+            "Expect module path after 'import'.",
+        )?;
+        if self.current().name() != "as" {
+            return Err(ParseError::Expected {
+                token: self.current(),
+                msg: "Expect 'as' after module path.",
+            });
+        }
+        let _ = self.next();
+        let identifier = Type::Identifier {
+            name: String::new(),
+        };
+        let name = self.consume(identifier, "Expect module name.")?;
+        self.consume(Type::Semicolon, "Expect ';' after import.")?;
+        Ok(Stmt::Import(path, name, ExprId::fresh()))
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt, ParseError> {
+        let name = self.consume(
+            Type::Identifier {
+                name: String::new(),
+            },
+            "Expect variable name.",
+        )?;
+        let annotation = self.annotation()?;
+        let initializer = if self.check(&Type::Eq) {
+            let _ = self.next();
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(Type::Semicolon, "Expect ';' after variable declaration.")?;
+        Ok(Stmt::Var(name, annotation, initializer))
+    }
+
+    /// The type after a `:` that may follow a variable, a parameter or a
+    /// parameter list. Types are checked by `typecheck`, the parser takes
+    /// any name.
+    fn annotation(&mut self) -> Result<Option<Token>, ParseError> {
+        if !self.check(&Type::Colon) {
+            return Ok(None);
+        }
+        let _ = self.next();
+        match self.current().kind {
+            Type::Identifier { .. } | Type::Nil => Ok(self.next()),
+            _ => Err(ParseError::Expected {
+                token: self.current(),
+                msg: "Expect type after ':'.",
+            }),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt, ParseError> {
+        self.nested(Self::statement_kind)
+    }
+
+    fn statement_kind(&mut self) -> Result<Stmt, ParseError> {
+        let kind = self.current().kind;
+        match kind {
+            Type::Print => {
+                let _ = self.next();
+                let value = self.expression()?;
+                self.consume(Type::Semicolon, "Expect ';' after value.")?;
+                Ok(Stmt::Print(value))
+            }
+            Type::LBrace => {
+                let _ = self.next();
+                Ok(Stmt::Block(self.block()?))
+            }
+            Type::If => {
+                let _ = self.next();
+                self.if_statement()
+            }
+            Type::While => {
+                let _ = self.next();
+                self.while_statement()
+            }
+            Type::For => {
+                let _ = self.next();
+                self.for_statement()
+            }
+            Type::Return => {
+                let keyword = self.next().unwrap();
+                let value = if self.check(&Type::Semicolon) {
+                    None
+                } else {
+                    Some(self.expression()?)
+                };
+                self.consume(Type::Semicolon, "Expect ';' after return value.")?;
+                Ok(Stmt::Return(keyword, value))
+            }
+            Type::Throw => {
+                let keyword = self.next().unwrap();
+                let value = self.expression()?;
+                self.consume(Type::Semicolon, "Expect ';' after thrown value.")?;
+                Ok(Stmt::Throw(keyword, value))
+            }
+            Type::Try => {
+                let keyword = self.next().unwrap();
+                self.try_statement(keyword)
+            }
+            _ => {
+                let expr = self.expression()?;
+                self.consume(Type::Semicolon, "Expect ';' after expression.")?;
+                Ok(Stmt::Expression(expr))
+            }
+        }
+    }
+
+    /// Parses the declarations of a block, the opening brace has already
+    /// been consumed
+    fn block(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut statements = Vec::new();
+        while !self.check(&Type::RBrace) && !self.is_at_end() {
+            if let Some(stmt) = self.declaration() {
+                statements.push(stmt);
+            }
+        }
+        self.consume(Type::RBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, ParseError> {
+        self.consume(Type::LParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(Type::RParen, "Expect ')' after if condition.")?;
+
+        let then_branch = Box::new(self.statement()?);
+        // an `else` binds to the nearest `if`
+        let else_branch = if self.check(&Type::Else) {
+            let _ = self.next();
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        Ok(Stmt::If(condition, then_branch, else_branch))
+    }
+
+    fn try_statement(&mut self, keyword: Token) -> Result<Stmt, ParseError> {
+        self.consume(Type::LBrace, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+        self.consume(Type::Catch, "Expect 'catch' after try block.")?;
+        self.consume(Type::LParen, "Expect '(' after 'catch'.")?;
+        let name = self.consume(
+            Type::Identifier {
+                name: String::new(),
+            },
+            "Expect error variable name.",
+        )?;
+        self.consume(Type::RParen, "Expect ')' after error variable.")?;
+        self.consume(Type::LBrace, "Expect '{' before catch body.")?;
+        let handler = self.block()?;
+        Ok(Stmt::Try(keyword, body, name, handler))
+    }
+
+    fn while_statement(&mut self) -> Result<Stmt, ParseError> {
+        self.consume(Type::LParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(Type::RParen, "Expect ')' after condition.")?;
+        let body = self.statement()?;
+        Ok(Stmt::While(condition, Box::new(body), self.previous()))
+    }
+
+    /// `for` has no node of its own, it is desugared into a `while` loop
+    /// wrapped in a block that scopes the initializer
+    fn for_statement(&mut self) -> Result<Stmt, ParseError> {
+        self.consume(Type::LParen, "Expect '(' after 'for'.")?;
+
+        let initializer = match self.current().kind {
+            Type::Semicolon => {
+                let _ = self.next();
+                None
+            }
+            Type::Var => {
+                let _ = self.next();
+                Some(self.var_declaration()?)
+            }
+            _ => {
+                let expr = self.clause()?;
+                self.consume(Type::Semicolon, "Expect ';' after expression.")?;
+                Some(Stmt::Expression(expr))
+            }
+        };
+
+        let condition = if self.check(&Type::Semicolon) {
+            // a missing condition loops forever, as if it were `true`
+            let token = Token {
+                kind: Type::True,
+                ..self.current()
+            };
+            Expr::Literal(Literal::True, token)
+        } else {
+            self.clause()?
+        };
+        self.consume(Type::Semicolon, "Expect ';' after loop condition.")?;
+
+        let increment = if self.check(&Type::RParen) {
+            None
+        } else {
+            Some(self.clause()?)
+        };
+        self.consume(Type::RParen, "Expect ')' after for clauses.")?;
+
+        let mut body = self.statement()?;
+        if let Some(increment) = increment {
+            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
+        }
+        body = Stmt::While(condition, Box::new(body), self.previous());
+        if let Some(initializer) = initializer {
+            body = Stmt::Block(vec![initializer, body]);
+        }
+        Ok(body)
+    }
+
+    /// A clause of a `for`, which stands where a statement would. Like a
+    /// statement it can't start with `{`, so there are no map literals.
+    fn clause(&mut self) -> Result<Expr, ParseError> {
+        if self.check(&Type::LBrace) {
+            return Err(ParseError::ExpectedExpression(self.current()));
+        }
+        self.expression()
+    }
+
+    fn expression(&mut self) -> Result<Expr, ParseError> {
+        self.nested(Self::assignment)
+    }
+
+    /// Runs `parse` one level of nesting deeper, unless that would go past
+    /// `MAX_NESTING`
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        if self.depth == MAX_NESTING {
+            return Err(ParseError::TooDeeplyNested(self.current()));
+        }
+        self.depth += 1;
+        let result = crate::ensure_stack(|| parse(self));
+        self.depth -= 1;
+        result
+    }
+
+    /// Assignment is right associative and the target is only known to be
+    /// one once the `=` shows up, so the left side is parsed as an ordinary
+    /// expression and then checked
+    fn assignment(&mut self) -> Result<Expr, ParseError> {
+        let expr = self.or()?;
+        if self.check(&Type::Eq) {
+            let equals = self.next().unwrap();
+            let value = self.nested(Self::assignment)?;
+            return match expr {
+                Expr::Variable(name, _) => Ok(Expr::Assign(name, Box::new(value), ExprId::fresh())),
+                Expr::Get(object, name) => Ok(Expr::Set(object, name, Box::new(value))),
+                Expr::Index(object, bracket, index) => {
+                    Ok(Expr::SetIndex(object, bracket, index, Box::new(value)))
+                }
+                _ => {
+                    // no need to synchronize, the parser is not confused
+                    self.errors
+                        .push(ParseError::InvalidAssignmentTarget(equals));
+                    Ok(expr)
+                }
+            };
+        }
+        Ok(expr)
     }
 
-    fn equality(&mut self) -> Result<Expr, ()> {
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.and()?;
+        while let Some((op, _)) = self.match_op(&[(Type::Or, LogicalOp::Or)]) {
+            expr = Expr::Logical(Box::new(expr), op, Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.equality()?;
+        while let Some((op, _)) = self.match_op(&[(Type::And, LogicalOp::And)]) {
+            expr = Expr::Logical(Box::new(expr), op, Box::new(self.equality()?));
+        }
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> Result<Expr, ParseError> {
         let mut expr = self.comparison()?;
-        while let Some(Token {
-            kind: Type::EqEq | Type::BangEq,
-            line: _,
-        }) = self.peek()
+        while let Some((op, op_token)) =
+            self.match_op(&[(Type::EqEq, BinaryOp::Eq), (Type::BangEq, BinaryOp::Ne)])
         {
-            let op_token = self.next().unwrap();
-            let op = match op_token.kind {
-                Type::EqEq => BinaryOp::Eq,
-                Type::BangEq => BinaryOp::Ne,
-                _ => panic!(),
-            };
-            expr = Expr::BinaryExpr(Box::new(expr), op, Box::new(self.comparison()?));
+            expr = Expr::BinaryExpr(Box::new(expr), op, op_token, Box::new(self.comparison()?));
         }
         Ok(expr)
     }
 
-    fn comparison(&mut self) -> Result<Expr, ()> {
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
         let mut term = self.term()?;
-        while let Some(Token {
-            kind: Type::Gt | Type::Ge | Type::Lt | Type::Le,
-            line: _,
-        }) = self.peek()
-        {
-            let op_token = self.next().unwrap();
-            let op = match op_token.kind {
-                Type::Gt => BinaryOp::Gt,
-                Type::Ge => BinaryOp::Ge,
-                Type::Lt => BinaryOp::Lt,
-                Type::Le => BinaryOp::Le,
-                _ => panic!(),
-            };
-            term = Expr::BinaryExpr(Box::new(term), op, Box::new(self.term()?));
+        while let Some((op, op_token)) = self.match_op(&[
+            (Type::Gt, BinaryOp::Gt),
+            (Type::Ge, BinaryOp::Ge),
+            (Type::Lt, BinaryOp::Lt),
+            (Type::Le, BinaryOp::Le),
+        ]) {
+            term = Expr::BinaryExpr(Box::new(term), op, op_token, Box::new(self.term()?));
         }
         Ok(term)
     }
 
-    fn term(&mut self) -> Result<Expr, ()> {
+    fn term(&mut self) -> Result<Expr, ParseError> {
         let mut factor = self.factor()?;
-        while let Some(Token {
-            kind: Type::Minus | Type::Plus,
-            line: _,
-        }) = self.peek()
+        while let Some((op, op_token)) =
+            self.match_op(&[(Type::Minus, BinaryOp::Minus), (Type::Plus, BinaryOp::Plus)])
         {
-            let op_token = self.next().unwrap();
-            let op = match op_token.kind {
-                Type::Plus => BinaryOp::Plus,
-                Type::Minus => BinaryOp::Minus,
-                _ => panic!(),
-            };
-            factor = Expr::BinaryExpr(Box::new(factor), op, Box::new(self.factor()?));
+            factor = Expr::BinaryExpr(Box::new(factor), op, op_token, Box::new(self.factor()?));
         }
         Ok(factor)
     }
 
-    fn factor(&mut self) -> Result<Expr, ()> {
+    fn factor(&mut self) -> Result<Expr, ParseError> {
         let mut unary = self.unary()?;
-        while let Some(Token {
-            kind: Type::Star | Type::Slash,
-            line: _,
-        }) = self.peek()
-        {
-            let op_token = self.next().unwrap();
-            let op = match op_token.kind {
-                Type::Star => BinaryOp::Times,
-                Type::Slash => BinaryOp::Divide,
-                _ => panic!(),
-            };
-            unary = Expr::BinaryExpr(Box::new(unary), op, Box::new(self.unary()?));
+        while let Some((op, op_token)) = self.match_op(&[
+            (Type::Star, BinaryOp::Times),
+            (Type::Slash, BinaryOp::Divide),
+        ]) {
+            unary = Expr::BinaryExpr(Box::new(unary), op, op_token, Box::new(self.unary()?));
         }
         Ok(unary)
     }
 
-    fn unary(&mut self) -> Result<Expr, ()> {
-        if let Some(Token {
-            kind: Type::Minus | Type::Bang,
-            line: _,
-        }) = self.peek()
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if let Some((op, op_token)) =
+            self.match_op(&[(Type::Minus, UnaryOp::Negative), (Type::Bang, UnaryOp::Not)])
         {
-            let op_token = self.next().unwrap();
-            let op = match op_token.kind {
-                Type::Minus => UnaryOp::Negative,
-                Type::Bang => UnaryOp::Not,
-                _ => panic!(),
-            };
-            Ok(Expr::UnaryExpr(op, Box::new(self.unary()?)))
+            Ok(Expr::UnaryExpr(
+                op,
+                op_token,
+                Box::new(self.nested(Self::unary)?),
+            ))
         } else {
-            self.primary()
+            self.call()
         }
     }
 
-    fn primary(&mut self) -> Result<Expr, ()> {
-        match self.next() {
-            Some(token) => match token.kind {
-                Type::Nil => Ok(Expr::Literal(Literal::Nil)),
-                Type::True => Ok(Expr::Literal(Literal::True)),
-                Type::False => Ok(Expr::Literal(Literal::False)),
-                Type::String { value } => Ok(Expr::Literal(Literal::String(value))),
-                Type::Float { value } => Ok(Expr::Literal(Literal::Float(value))),
-                Type::Int { value } => Ok(Expr::Literal(Literal::Int(value))),
-                _ => Err(()),
-            },
-            None => Err(()),
+    fn call(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.check(&Type::LParen) {
+                let _ = self.next();
+                expr = self.finish_call(expr)?;
+            } else if self.check(&Type::Dot) {
+                let _ = self.next();
+                let name = self.consume(
+                    Type::Identifier {
+                        name: String::new(),
+                    },
+                    "Expect property name after '.'.",
+                )?;
+                expr = Expr::Get(Box::new(expr), name);
+            } else if self.check(&Type::LBracket) {
+                let _ = self.next();
+                let index = self.expression()?;
+                let bracket = self.consume(Type::RBracket, "Expect ']' after index.")?;
+                expr = Expr::Index(Box::new(expr), bracket, Box::new(index));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, ParseError> {
+        let mut args = Vec::new();
+        if !self.check(&Type::RParen) {
+            loop {
+                if args.len() >= MAX_ARGS {
+                    self.errors
+                        .push(ParseError::TooManyArguments(self.current()));
+                }
+                args.push(self.expression()?);
+                if !self.check(&Type::Comma) {
+                    break;
+                }
+                let _ = self.next();
+            }
+        }
+        let paren = self.consume(Type::RParen, "Expect ')' after arguments.")?;
+        Ok(Expr::Call(Box::new(callee), paren, args))
+    }
+
+    /// Parses the elements of a list literal, the opening bracket has
+    /// already been consumed
+    fn list(&mut self, bracket: Token) -> Result<Expr, ParseError> {
+        let mut elements = Vec::new();
+        if !self.check(&Type::RBracket) {
+            loop {
+                if elements.len() >= MAX_ARGS {
+                    self.errors
+                        .push(ParseError::TooManyElements(self.current()));
+                }
+                elements.push(self.expression()?);
+                if !self.check(&Type::Comma) {
+                    break;
+                }
+                let _ = self.next();
+            }
         }
+        self.consume(Type::RBracket, "Expect ']' after list elements.")?;
+        Ok(Expr::List(bracket, elements))
+    }
+
+    /// Parses the entries of a map literal, the opening brace has already
+    /// been consumed
+    fn map(&mut self, brace: Token) -> Result<Expr, ParseError> {
+        let mut entries = Vec::new();
+        if !self.check(&Type::RBrace) {
+            loop {
+                if entries.len() >= MAX_ARGS {
+                    self.errors
+                        .push(ParseError::TooManyElements(self.current()));
+                }
+                let key = self.expression()?;
+                self.consume(Type::Colon, "Expect ':' after map key.")?;
+                entries.push((key, self.expression()?));
+                if !self.check(&Type::Comma) {
+                    break;
+                }
+                let _ = self.next();
+            }
+        }
+        self.consume(Type::RBrace, "Expect '}' after map entries.")?;
+        Ok(Expr::Map(brace, entries))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let token = self.current();
+        let expr = match &token.kind {
+            Type::Nil => Expr::Literal(Literal::Nil, token.clone()),
+            Type::True => Expr::Literal(Literal::True, token.clone()),
+            Type::False => Expr::Literal(Literal::False, token.clone()),
+            Type::String { value } => Expr::Literal(Literal::String(value.clone()), token.clone()),
+            Type::Float { value } => Expr::Literal(Literal::Float(*value), token.clone()),
+            Type::Int { value } => Expr::Literal(Literal::Int(*value), token.clone()),
+            Type::Identifier { .. } => Expr::Variable(token.clone(), ExprId::fresh()),
+            Type::This => Expr::This(token.clone(), ExprId::fresh()),
+            Type::Super => {
+                let _ = self.next();
+                self.consume(Type::Dot, "Expect '.' after 'super'.")?;
+                let method = self.consume(
+                    Type::Identifier {
+                        name: String::new(),
+                    },
+                    "Expect superclass method name.",
+                )?;
+                return Ok(Expr::Super(token, method, ExprId::fresh()));
+            }
+            Type::LParen => {
+                let _ = self.next();
+                let expr = self.expression()?;
+                self.consume(Type::RParen, "Expect ')' after expression.")?;
+                return Ok(Expr::Grouping(Box::new(expr)));
+            }
+            Type::LBracket => {
+                let _ = self.next();
+                return self.list(token);
+            }
+            // a statement or `for` clause starting with `{` is not an
+            // expression, so this is only reached inside one
+            Type::LBrace => {
+                let _ = self.next();
+                return self.map(token);
+            }
+            _ => return Err(ParseError::ExpectedExpression(token)),
+        };
+        let _ = self.next();
+        Ok(expr)
     }
 }
 
@@ -155,11 +795,29 @@ mod tests {
 
     #[test]
     fn test_primary() {
-        let tests = vec![
-            ("nil", Ok(Expr::Literal(Literal::Nil))),
+        let tests = [
+            (
+                "nil",
+                Ok(Expr::Literal(Literal::Nil, Token::new(Type::Nil, 0))),
+            ),
             (
                 "\"something\"",
-                Ok(Expr::Literal(Literal::String("something".to_string()))),
+                Ok(Expr::Literal(
+                    Literal::String("something".to_string()),
+                    Token::new(
+                        Type::String {
+                            value: "something".to_string(),
+                        },
+                        0,
+                    ),
+                )),
+            ),
+            (
+                "(nil)",
+                Ok(Expr::Grouping(Box::new(Expr::Literal(
+                    Literal::Nil,
+                    Token::new(Type::Nil, 0),
+                )))),
             ),
         ];
         for (input, expected) in tests.iter() {
@@ -171,19 +829,32 @@ mod tests {
 
     #[test]
     fn test_unary() {
-        let tests = vec![
+        let tests = [
             (
                 "-1",
                 Ok(Expr::UnaryExpr(
                     UnaryOp::Negative,
-                    Box::new(Expr::Literal(Literal::Int(1))),
+                    Token::new(Type::Minus, 0),
+                    Box::new(Expr::Literal(
+                        Literal::Int(1),
+                        Token::new(Type::Int { value: 1 }, 0),
+                    )),
                 )),
             ),
             (
                 "!\"val\"",
                 Ok(Expr::UnaryExpr(
                     UnaryOp::Not,
-                    Box::new(Expr::Literal(Literal::String("val".to_string()))),
+                    Token::new(Type::Bang, 0),
+                    Box::new(Expr::Literal(
+                        Literal::String("val".to_string()),
+                        Token::new(
+                            Type::String {
+                                value: "val".to_string(),
+                            },
+                            0,
+                        ),
+                    )),
                 )),
             ),
         ];
@@ -196,22 +867,34 @@ mod tests {
 
     #[test]
     fn test_factor() {
-        let tests = vec![
+        let tests = [
             (
                 "-1",
                 Ok(Expr::UnaryExpr(
                     UnaryOp::Negative,
-                    Box::new(Expr::Literal(Literal::Int(1))),
+                    Token::new(Type::Minus, 0),
+                    Box::new(Expr::Literal(
+                        Literal::Int(1),
+                        Token::new(Type::Int { value: 1 }, 0),
+                    )),
                 )),
             ),
             (
                 "1 * -1",
                 Ok(Expr::BinaryExpr(
-                    Box::new(Expr::Literal(Literal::Int(1))),
+                    Box::new(Expr::Literal(
+                        Literal::Int(1),
+                        Token::new(Type::Int { value: 1 }, 0),
+                    )),
                     BinaryOp::Times,
+                    Token::new(Type::Star, 0),
                     Box::new(Expr::UnaryExpr(
                         UnaryOp::Negative,
-                        Box::new(Expr::Literal(Literal::Int(1))),
+                        Token::new(Type::Minus, 0),
+                        Box::new(Expr::Literal(
+                            Literal::Int(1),
+                            Token::new(Type::Int { value: 1 }, 0),
+                        )),
                     )),
                 )),
             ),
@@ -225,28 +908,50 @@ mod tests {
 
     #[test]
     fn test_term() {
-        let tests = vec![
+        let tests = [
             (
                 "8 * 8 + 1",
                 Ok(Expr::BinaryExpr(
                     Box::new(Expr::BinaryExpr(
-                        Box::new(Expr::Literal(Literal::Int(8))),
+                        Box::new(Expr::Literal(
+                            Literal::Int(8),
+                            Token::new(Type::Int { value: 8 }, 0),
+                        )),
                         BinaryOp::Times,
-                        Box::new(Expr::Literal(Literal::Int(8))),
+                        Token::new(Type::Star, 0),
+                        Box::new(Expr::Literal(
+                            Literal::Int(8),
+                            Token::new(Type::Int { value: 8 }, 0),
+                        )),
                     )),
                     BinaryOp::Plus,
-                    Box::new(Expr::Literal(Literal::Int(1))),
+                    Token::new(Type::Plus, 0),
+                    Box::new(Expr::Literal(
+                        Literal::Int(1),
+                        Token::new(Type::Int { value: 1 }, 0),
+                    )),
                 )),
             ),
             (
                 "16 - 10 / 5",
                 Ok(Expr::BinaryExpr(
-                    Box::new(Expr::Literal(Literal::Int(16))),
+                    Box::new(Expr::Literal(
+                        Literal::Int(16),
+                        Token::new(Type::Int { value: 16 }, 0),
+                    )),
                     BinaryOp::Minus,
+                    Token::new(Type::Minus, 0),
                     Box::new(Expr::BinaryExpr(
-                        Box::new(Expr::Literal(Literal::Int(10))),
+                        Box::new(Expr::Literal(
+                            Literal::Int(10),
+                            Token::new(Type::Int { value: 10 }, 0),
+                        )),
                         BinaryOp::Divide,
-                        Box::new(Expr::Literal(Literal::Int(5))),
+                        Token::new(Type::Slash, 0),
+                        Box::new(Expr::Literal(
+                            Literal::Int(5),
+                            Token::new(Type::Int { value: 5 }, 0),
+                        )),
                     )),
                 )),
             ),
@@ -260,28 +965,50 @@ mod tests {
 
     #[test]
     fn test_comparison() {
-        let tests = vec![
+        let tests = [
             (
                 "8 * 8 > 1",
                 Ok(Expr::BinaryExpr(
                     Box::new(Expr::BinaryExpr(
-                        Box::new(Expr::Literal(Literal::Int(8))),
+                        Box::new(Expr::Literal(
+                            Literal::Int(8),
+                            Token::new(Type::Int { value: 8 }, 0),
+                        )),
                         BinaryOp::Times,
-                        Box::new(Expr::Literal(Literal::Int(8))),
+                        Token::new(Type::Star, 0),
+                        Box::new(Expr::Literal(
+                            Literal::Int(8),
+                            Token::new(Type::Int { value: 8 }, 0),
+                        )),
                     )),
                     BinaryOp::Gt,
-                    Box::new(Expr::Literal(Literal::Int(1))),
+                    Token::new(Type::Gt, 0),
+                    Box::new(Expr::Literal(
+                        Literal::Int(1),
+                        Token::new(Type::Int { value: 1 }, 0),
+                    )),
                 )),
             ),
             (
                 "16 <= 10 + 5",
                 Ok(Expr::BinaryExpr(
-                    Box::new(Expr::Literal(Literal::Int(16))),
+                    Box::new(Expr::Literal(
+                        Literal::Int(16),
+                        Token::new(Type::Int { value: 16 }, 0),
+                    )),
                     BinaryOp::Le,
+                    Token::new(Type::Le, 0),
                     Box::new(Expr::BinaryExpr(
-                        Box::new(Expr::Literal(Literal::Int(10))),
+                        Box::new(Expr::Literal(
+                            Literal::Int(10),
+                            Token::new(Type::Int { value: 10 }, 0),
+                        )),
                         BinaryOp::Plus,
-                        Box::new(Expr::Literal(Literal::Int(5))),
+                        Token::new(Type::Plus, 0),
+                        Box::new(Expr::Literal(
+                            Literal::Int(5),
+                            Token::new(Type::Int { value: 5 }, 0),
+                        )),
                     )),
                 )),
             ),
@@ -295,28 +1022,50 @@ mod tests {
 
     #[test]
     fn test_equality() {
-        let tests = vec![
+        let tests = [
             (
                 "8 * 8 == 1",
                 Ok(Expr::BinaryExpr(
                     Box::new(Expr::BinaryExpr(
-                        Box::new(Expr::Literal(Literal::Int(8))),
+                        Box::new(Expr::Literal(
+                            Literal::Int(8),
+                            Token::new(Type::Int { value: 8 }, 0),
+                        )),
                         BinaryOp::Times,
-                        Box::new(Expr::Literal(Literal::Int(8))),
+                        Token::new(Type::Star, 0),
+                        Box::new(Expr::Literal(
+                            Literal::Int(8),
+                            Token::new(Type::Int { value: 8 }, 0),
+                        )),
                     )),
                     BinaryOp::Eq,
-                    Box::new(Expr::Literal(Literal::Int(1))),
+                    Token::new(Type::EqEq, 0),
+                    Box::new(Expr::Literal(
+                        Literal::Int(1),
+                        Token::new(Type::Int { value: 1 }, 0),
+                    )),
                 )),
             ),
             (
                 "16 != 10 + 5",
                 Ok(Expr::BinaryExpr(
-                    Box::new(Expr::Literal(Literal::Int(16))),
+                    Box::new(Expr::Literal(
+                        Literal::Int(16),
+                        Token::new(Type::Int { value: 16 }, 0),
+                    )),
                     BinaryOp::Ne,
+                    Token::new(Type::BangEq, 0),
                     Box::new(Expr::BinaryExpr(
-                        Box::new(Expr::Literal(Literal::Int(10))),
+                        Box::new(Expr::Literal(
+                            Literal::Int(10),
+                            Token::new(Type::Int { value: 10 }, 0),
+                        )),
                         BinaryOp::Plus,
-                        Box::new(Expr::Literal(Literal::Int(5))),
+                        Token::new(Type::Plus, 0),
+                        Box::new(Expr::Literal(
+                            Literal::Int(5),
+                            Token::new(Type::Int { value: 5 }, 0),
+                        )),
                     )),
                 )),
             ),
@@ -327,4 +1076,213 @@ mod tests {
             assert_eq!(parser.equality(), *expected);
         }
     }
+
+    #[test]
+    fn test_program() {
+        let input = "var a = 1; var b; print a + 2; b;";
+        let expected = [
+            Stmt::Var(
+                Token::new(
+                    Type::Identifier {
+                        name: "a".to_string(),
+                    },
+                    0,
+                ),
+                None,
+                Some(Expr::Literal(
+                    Literal::Int(1),
+                    Token::new(Type::Int { value: 1 }, 0),
+                )),
+            ),
+            Stmt::Var(
+                Token::new(
+                    Type::Identifier {
+                        name: "b".to_string(),
+                    },
+                    0,
+                ),
+                None,
+                None,
+            ),
+        ];
+        let tokens: Vec<Token> = Lexer::new(input).collect();
+        let mut parser = Parser::new(tokens);
+        let program = parser.parse_program().unwrap();
+        assert_eq!(program[..2], expected[..]);
+        // variables carry unique ids, so only their names are compared
+        match &program[2..] {
+            [Stmt::Print(Expr::BinaryExpr(left, BinaryOp::Plus, _, right)), Stmt::Expression(Expr::Variable(b, _))] =>
+            {
+                assert!(matches!(left.as_ref(), Expr::Variable(a, _) if a.name() == "a"));
+                assert_eq!(
+                    right.as_ref(),
+                    &Expr::Literal(Literal::Int(2), Token::new(Type::Int { value: 2 }, 0))
+                );
+                assert_eq!(b.name(), "b");
+            }
+            other => panic!("unexpected statements {:?}", other),
+        }
+
+        let tokens: Vec<Token> = Lexer::new("print 1").collect();
+        let mut parser = Parser::new(tokens);
+        assert_eq!(
+            parser.parse_program(),
+            Err(vec![ParseError::Expected {
+                token: Token::new(Type::Eof, 0),
+                msg: "Expect ';' after value."
+            }])
+        );
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let input = "print 1 2; var = 3; print @ 4; var a = 1;\nprint \"oops";
+        let tokens: Vec<Token> = Lexer::new(input).collect();
+        let mut parser = Parser::new(tokens);
+        let errors: Vec<String> = parser
+            .parse_program()
+            .unwrap_err()
+            .iter()
+            .map(|err| err.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "Unexpected character.",
+                "Unterminated string.",
+                "Expect ';' after value.",
+                "Expect variable name.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assignment() {
+        let ident = |name: &str| {
+            Token::new(
+                Type::Identifier {
+                    name: name.to_string(),
+                },
+                0,
+            )
+        };
+        let tokens: Vec<Token> = Lexer::new("a = b = c or d").collect();
+        let mut parser = Parser::new(tokens);
+        match parser.expression() {
+            Ok(Expr::Assign(a, value, _)) => {
+                assert_eq!(a, ident("a"));
+                match *value {
+                    Expr::Assign(b, value, _) => {
+                        assert_eq!(b, ident("b"));
+                        assert!(matches!(*value, Expr::Logical(_, LogicalOp::Or, _)));
+                    }
+                    other => panic!("unexpected expression {:?}", other),
+                }
+            }
+            other => panic!("unexpected expression {:?}", other),
+        }
+
+        let tokens: Vec<Token> = Lexer::new("a + b = c;").collect();
+        let mut parser = Parser::new(tokens);
+        assert_eq!(
+            parser.parse_program(),
+            Err(vec![ParseError::InvalidAssignmentTarget(Token::new(
+                Type::Eq,
+                0
+            ))])
+        );
+    }
+
+    #[test]
+    fn test_for_desugaring() {
+        let tokens: Vec<Token> = Lexer::new("for (var i = 0; i < 1; i = i + 1) print i;").collect();
+        let mut parser = Parser::new(tokens);
+        let program = parser.parse_program().unwrap();
+        match program.as_slice() {
+            [Stmt::Block(outer)] => match outer.as_slice() {
+                [Stmt::Var(_, _, Some(_)), Stmt::While(_, body, _)] => match body.as_ref() {
+                    Stmt::Block(inner) => assert!(matches!(
+                        inner.as_slice(),
+                        [Stmt::Print(_), Stmt::Expression(Expr::Assign(..))]
+                    )),
+                    other => panic!("unexpected loop body {:?}", other),
+                },
+                other => panic!("unexpected block {:?}", other),
+            },
+            other => panic!("unexpected program {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_class() {
+        let tokens: Vec<Token> =
+            Lexer::new("class A < B { init(x) { this.x = x; } } super.f(a.b.c);").collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        match program.as_slice() {
+            [Stmt::Class(decl), Stmt::Expression(Expr::Call(callee, _, args))] => {
+                assert_eq!(decl.name.name(), "A");
+                assert!(
+                    matches!(&decl.superclass, Some(Expr::Variable(name, _)) if name.name() == "B")
+                );
+                assert_eq!(decl.methods.len(), 1);
+                assert!(matches!(
+                    decl.methods[0].body.as_slice(),
+                    [Stmt::Expression(Expr::Set(object, _, _))] if matches!(**object, Expr::This(..))
+                ));
+                assert!(
+                    matches!(callee.as_ref(), Expr::Super(_, method, _) if method.name() == "f")
+                );
+                assert!(matches!(
+                    args.as_slice(),
+                    [Expr::Get(object, name)] if name.name() == "c" && matches!(**object, Expr::Get(..))
+                ));
+            }
+            other => panic!("unexpected program {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_collections() {
+        let tokens: Vec<Token> =
+            Lexer::new("var a = [1, [], {}]; a[0] = {\"k\": a[1], 2: nil}; { }").collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        match program.as_slice() {
+            [Stmt::Var(_, _, Some(Expr::List(_, elements))), Stmt::Expression(Expr::SetIndex(_, bracket, _, value)), Stmt::Block(_)] =>
+            {
+                assert!(matches!(
+                    elements.as_slice(),
+                    [Expr::Literal(..), Expr::List(_, empty), Expr::Map(_, none)] if empty.is_empty() && none.is_empty()
+                ));
+                assert_eq!(bracket.kind, Type::RBracket);
+                assert!(matches!(value.as_ref(), Expr::Map(_, entries) if entries.len() == 2));
+            }
+            other => panic!("unexpected program {:?}", other),
+        }
+
+        let tokens: Vec<Token> = Lexer::new("var m = {\"k\" 1};").collect();
+        let errors = Parser::new(tokens).parse_program().unwrap_err();
+        assert_eq!(errors[0].to_string(), "Expect ':' after map key.");
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let errors = |input: &str| -> Vec<String> {
+            let tokens: Vec<Token> = Lexer::new(input).collect();
+            match Parser::new(tokens).parse_program() {
+                Ok(_) => Vec::new(),
+                Err(errors) => errors.iter().map(|err| err.to_string()).collect(),
+            }
+        };
+        let parens = |depth| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        assert!(errors(&parens(MAX_NESTING - 2)).is_empty());
+        let too_deep = vec!["Can't nest more than 256 levels deep.".to_string()];
+        assert_eq!(errors(&parens(20_000)), too_deep);
+        assert_eq!(errors(&format!("print {}1;", "-".repeat(20_000))), too_deep);
+        assert_eq!(
+            errors(&format!("{}a = 1;", "a = ".repeat(20_000))),
+            too_deep
+        );
+        let blocks = format!("{}{}", "{".repeat(300), "}".repeat(300));
+        assert_eq!(errors(&blocks)[0], too_deep[0]);
+    }
 }
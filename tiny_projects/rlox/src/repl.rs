@@ -0,0 +1,244 @@
+use crate::app::Lox;
+use rlox::grammar::Stmt;
+use rlox::lexer::{Lexer, Token, Type};
+use rlox::parser::Parser;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+
+const PROMPT: &str = "> ";
+/// Shown while an input is still missing a closing brace or parenthesis
+const CONTINUE_PROMPT: &str = "... ";
+
+const HELP: &str = "\
+:ast <code>     print the syntax tree of <code>
+:tokens <code>  print the tokens of <code>
+:load <path>    run a file in this session
+:reset          forget every global defined so far
+:help           show this message
+:quit           leave, as does Ctrl-D";
+
+/// Interactive prompt around a `Lox` session. Globals survive from one
+/// input to the next and a bare expression prints its value.
+pub struct Repl {
+    lox: Lox,
+}
+
+impl Repl {
+    pub fn new(lox: Lox) -> Self {
+        Self { lox }
+    }
+
+    pub fn run(&mut self) {
+        let mut editor = match DefaultEditor::new() {
+            Ok(editor) => editor,
+            Err(err) => {
+                eprintln!("Cannot start the line editor: {}", err);
+                return;
+            }
+        };
+        let history = history_path();
+        if let Some(path) = &history {
+            // there is no history the first time
+            let _ = editor.load_history(path);
+        }
+
+        let mut input = String::new();
+        loop {
+            let prompt = if input.is_empty() {
+                PROMPT
+            } else {
+                CONTINUE_PROMPT
+            };
+            let line = match editor.readline(prompt) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => {
+                    input.clear();
+                    continue;
+                }
+                Err(ReadlineError::Eof) => {
+                    println!("Bye!");
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
+                    break;
+                }
+            };
+            if input.is_empty() && line.trim_start().starts_with(':') {
+                let _ = editor.add_history_entry(line.trim());
+                if !self.command(line.trim()) {
+                    break;
+                }
+                continue;
+            }
+            input.push_str(&line);
+            input.push('\n');
+            if !is_complete(&input) {
+                continue;
+            }
+            let entry = mem::take(&mut input);
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let _ = editor.add_history_entry(entry);
+            self.eval(entry);
+        }
+
+        if let Some(path) = &history {
+            if let Err(err) = editor.save_history(path) {
+                eprintln!("Cannot save history to {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    /// Runs a meta-command, returns false when the REPL should stop
+    fn command(&mut self, line: &str) -> bool {
+        let (name, arg) = match line.find(char::is_whitespace) {
+            Some(idx) => (&line[..idx], line[idx..].trim()),
+            None => (line, ""),
+        };
+        match name {
+            ":quit" | ":q" => return false,
+            ":help" => println!("{}", HELP),
+            ":reset" => {
+                self.lox = Lox::with_options(self.lox.options());
+                println!("Session reset.");
+            }
+            ":load" => match fs::read_to_string(arg) {
+                Ok(source) => self.lox.run(&source),
+                Err(err) => eprintln!("Cannot read '{}': {}", arg, err),
+            },
+            ":ast" => match parse_lenient(arg) {
+                Some((_, program)) => {
+                    for stmt in program.iter() {
+                        println!("{}", stmt);
+                    }
+                }
+                // parse again to report the errors against what was typed
+                None => {
+                    self.lox.parse(arg);
+                }
+            },
+            ":tokens" => {
+                for token in Lexer::new(arg) {
+                    println!(
+                        "{:4} {:?} '{}'",
+                        token.line + 1,
+                        token.kind,
+                        token.lexeme(arg)
+                    );
+                }
+            }
+            _ => eprintln!("Unknown command {}, try :help", name),
+        }
+        self.lox.clear_errors();
+        true
+    }
+
+    fn eval(&mut self, source: &str) {
+        match parse_input(source) {
+            Some((source, program)) => self.lox.execute(&source, &program),
+            // parse again to report the errors against what was typed
+            None => {
+                self.lox.parse(source);
+            }
+        }
+        self.lox.clear_errors();
+    }
+}
+
+/// Whether `source` can be run as it is, rather than waiting for more lines
+/// to close a brace, parenthesis, string or comment
+pub fn is_complete(source: &str) -> bool {
+    let mut depth = 0;
+    for token in Lexer::new(source) {
+        match token.kind {
+            Type::LParen | Type::LBrace | Type::LBracket => depth += 1,
+            Type::RParen | Type::RBrace | Type::RBracket => depth -= 1,
+            Type::UnterminatedString { .. } | Type::UnterminatedComment => return false,
+            _ => {}
+        }
+    }
+    // too many closing brackets is an error that should be reported now
+    depth <= 0
+}
+
+fn parse_quietly(source: &str) -> Option<Vec<Stmt>> {
+    let tokens: Vec<Token> = Lexer::new(source).collect();
+    Parser::new(tokens).parse_program().ok()
+}
+
+/// Parses a REPL input, accepting an expression without its `;`. Also
+/// returns the source that was parsed, for error snippets.
+fn parse_lenient(source: &str) -> Option<(String, Vec<Stmt>)> {
+    match parse_quietly(source) {
+        Some(program) => Some((source.to_string(), program)),
+        None => {
+            let source = format!("{};", source);
+            match parse_quietly(&source) {
+                Some(program) if matches!(program[..], [Stmt::Expression(_)]) => {
+                    Some((source, program))
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Parses a REPL input like `parse_lenient`. An input that is a single
+/// expression statement is turned into a `print` of it.
+fn parse_input(source: &str) -> Option<(String, Vec<Stmt>)> {
+    let (source, mut program) = parse_lenient(source)?;
+    if let [Stmt::Expression(_)] = program[..] {
+        if let Some(Stmt::Expression(expr)) = program.pop() {
+            program.push(Stmt::Print(expr));
+        }
+    }
+    Some((source, program))
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".rlox_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete("print 1;"));
+        assert!(is_complete("fun f() {\n  return 1;\n}\n"));
+        assert!(is_complete("print 1);"));
+        assert!(!is_complete("fun f() {\n"));
+        assert!(!is_complete("print (1 +\n"));
+        assert!(!is_complete("var l = [1,\n"));
+        assert!(!is_complete("print \"abc\n"));
+        assert!(!is_complete("/* note\n"));
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let (source, program) = parse_input("1 + 2").unwrap();
+        assert_eq!(source, "1 + 2;");
+        assert!(matches!(program[..], [Stmt::Print(_)]));
+
+        let (_, program) = parse_input("x = 1;").unwrap();
+        assert!(matches!(program[..], [Stmt::Print(_)]));
+
+        let (_, program) = parse_input("var x = 1; x;").unwrap();
+        assert!(matches!(program[..], [Stmt::Var(..), Stmt::Expression(_)]));
+
+        assert!(parse_input("var x = 1").is_none());
+        assert!(parse_input("1 +").is_none());
+
+        // what `:ast` shows, without the `print`
+        let (_, program) = parse_lenient("1 + 2").unwrap();
+        assert_eq!(program[0].to_string(), "(; (+ 1 2))");
+    }
+}
@@ -0,0 +1,475 @@
+use crate::error::{ResolveError, Warning};
+use crate::grammar::*;
+use crate::lexer::Token;
+use std::collections::HashMap;
+
+/// Everything the resolver found out about a program
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// How many scopes out from its use each local variable was declared.
+    /// Variables that are not in here are globals.
+    pub locals: HashMap<ExprId, usize>,
+    pub errors: Vec<ResolveError>,
+    pub warnings: Vec<Warning>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+struct Local {
+    token: Token,
+    defined: bool,
+    used: bool,
+    /// Parameters are part of a function's signature and are not expected
+    /// to always be used
+    is_param: bool,
+}
+
+/// Static pass between the parser and the interpreter. Works out which
+/// declaration every variable refers to and reports scoping mistakes that
+/// can be caught without running the program.
+pub struct Resolver {
+    scopes: Vec<HashMap<String, Local>>,
+    function: FunctionType,
+    class: ClassType,
+    resolution: Resolution,
+}
+
+pub fn resolve(program: &[Stmt]) -> Resolution {
+    let mut resolver = Resolver {
+        scopes: Vec::new(),
+        function: FunctionType::None,
+        class: ClassType::None,
+        resolution: Resolution::default(),
+    };
+    crate::ensure_stack(|| resolver.resolve_stmts(program));
+    resolver.resolution
+}
+
+impl Resolver {
+    fn resolve_stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts.iter() {
+            self.resolve_stmt(stmt);
+        }
+    }
+
+    fn resolve_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.resolve_expr(expr),
+            Stmt::Var(name, _, initializer) => {
+                self.declare(name, false);
+                if let Some(expr) = initializer {
+                    self.resolve_expr(expr);
+                }
+                self.define(name);
+            }
+            Stmt::Block(stmts) => {
+                self.begin_scope();
+                self.resolve_stmts(stmts);
+                self.end_scope();
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_stmt(else_branch);
+                }
+            }
+            Stmt::While(condition, body, _) => {
+                self.resolve_expr(condition);
+                self.resolve_stmt(body);
+            }
+            Stmt::Function(decl) => {
+                // defined straight away so the function can recurse
+                self.declare(&decl.name, false);
+                self.define(&decl.name);
+                self.resolve_function(decl, FunctionType::Function);
+            }
+            Stmt::Return(keyword, value) => {
+                if self.function == FunctionType::None {
+                    self.resolution
+                        .errors
+                        .push(ResolveError::ReturnFromTopLevel(keyword.clone()));
+                }
+                if let Some(value) = value {
+                    if self.function == FunctionType::Initializer {
+                        self.resolution
+                            .errors
+                            .push(ResolveError::ReturnFromInitializer(keyword.clone()));
+                    }
+                    self.resolve_expr(value);
+                }
+            }
+            Stmt::Throw(_, value) => self.resolve_expr(value),
+            Stmt::Try(_, body, name, handler) => {
+                self.begin_scope();
+                self.resolve_stmts(body);
+                self.end_scope();
+                // like a parameter, the error is there whether it is used or not
+                self.begin_scope();
+                self.declare(name, true);
+                self.define(name);
+                self.resolve_stmts(handler);
+                self.end_scope();
+            }
+            Stmt::Class(decl) => self.resolve_class(decl),
+            Stmt::Import(path, _, _) => {
+                // modules are loaded before anything runs, so only a file's
+                // own top level can ask for one
+                if !self.scopes.is_empty() {
+                    self.resolution
+                        .errors
+                        .push(ResolveError::ImportNotTopLevel(path.clone()));
+                }
+            }
+        }
+    }
+
+    fn resolve_class(&mut self, decl: &ClassDecl) {
+        let enclosing = self.class;
+        self.class = ClassType::Class;
+        self.declare(&decl.name, false);
+        self.define(&decl.name);
+        self.mark_used(&decl.name);
+
+        if let Some(superclass) = &decl.superclass {
+            if let Expr::Variable(name, _) = superclass {
+                if name.name() == decl.name.name() {
+                    self.resolution
+                        .errors
+                        .push(ResolveError::InheritFromSelf(name.clone()));
+                }
+            }
+            self.class = ClassType::Subclass;
+            self.resolve_expr(superclass);
+            self.begin_scope();
+            self.define_implicit("super", &decl.name);
+        }
+
+        self.begin_scope();
+        self.define_implicit("this", &decl.name);
+        for method in decl.methods.iter() {
+            let kind = if method.name.name() == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            self.resolve_function(method, kind);
+        }
+        self.end_scope();
+
+        if decl.superclass.is_some() {
+            self.end_scope();
+        }
+        self.class = enclosing;
+    }
+
+    fn resolve_function(&mut self, decl: &FunctionDecl, kind: FunctionType) {
+        let enclosing = self.function;
+        self.function = kind;
+
+        self.begin_scope();
+        for param in decl.params.iter() {
+            self.declare(param, true);
+            self.define(param);
+        }
+        self.resolve_stmts(&decl.body);
+        self.end_scope();
+
+        self.function = enclosing;
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(..) => {}
+            Expr::UnaryExpr(_, _, expr) | Expr::Grouping(expr) => self.resolve_expr(expr),
+            Expr::BinaryExpr(left, _, _, right) | Expr::Logical(left, _, right) => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            Expr::Variable(name, id) => {
+                let in_initializer = self
+                    .scopes
+                    .last()
+                    .and_then(|scope| scope.get(name.name()))
+                    .is_some_and(|local| !local.defined);
+                if in_initializer {
+                    self.resolution
+                        .errors
+                        .push(ResolveError::ReadInOwnInitializer(name.clone()));
+                }
+                self.resolve_local(name.name(), *id, true);
+            }
+            Expr::Assign(name, value, id) => {
+                self.resolve_expr(value);
+                // storing to a variable is not using it
+                self.resolve_local(name.name(), *id, false);
+            }
+            Expr::Get(object, _) => self.resolve_expr(object),
+            Expr::Set(object, _, value) => {
+                self.resolve_expr(value);
+                self.resolve_expr(object);
+            }
+            Expr::This(keyword, id) => {
+                if self.class == ClassType::None {
+                    self.resolution
+                        .errors
+                        .push(ResolveError::ThisOutsideClass(keyword.clone()));
+                }
+                self.resolve_local("this", *id, true);
+            }
+            Expr::Super(keyword, _, id) => match self.class {
+                ClassType::None => self
+                    .resolution
+                    .errors
+                    .push(ResolveError::SuperOutsideClass(keyword.clone())),
+                ClassType::Class => self
+                    .resolution
+                    .errors
+                    .push(ResolveError::SuperWithoutSuperclass(keyword.clone())),
+                ClassType::Subclass => self.resolve_local("super", *id, true),
+            },
+            Expr::Call(callee, _, args) => {
+                self.resolve_expr(callee);
+                for arg in args.iter() {
+                    self.resolve_expr(arg);
+                }
+            }
+            Expr::List(_, elements) => {
+                for element in elements.iter() {
+                    self.resolve_expr(element);
+                }
+            }
+            Expr::Map(_, entries) => {
+                for (key, value) in entries.iter() {
+                    self.resolve_expr(key);
+                    self.resolve_expr(value);
+                }
+            }
+            Expr::Index(object, _, index) => {
+                self.resolve_expr(object);
+                self.resolve_expr(index);
+            }
+            Expr::SetIndex(object, _, index, value) => {
+                self.resolve_expr(object);
+                self.resolve_expr(index);
+                self.resolve_expr(value);
+            }
+        }
+    }
+
+    /// Binds a use of `name`, which counts towards the local being used
+    /// when it is a `read`
+    fn resolve_local(&mut self, name: &str, id: ExprId, read: bool) {
+        for (depth, scope) in self.scopes.iter_mut().rev().enumerate() {
+            if let Some(local) = scope.get_mut(name) {
+                local.used |= read;
+                self.resolution.locals.insert(id, depth);
+                return;
+            }
+        }
+        // not found in any scope, so it has to be a global
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        if let Some(scope) = self.scopes.pop() {
+            let mut unused: Vec<&Local> = scope
+                .values()
+                .filter(|local| {
+                    !local.used && !local.is_param && !local.token.name().starts_with('_')
+                })
+                .collect();
+            unused.sort_by_key(|local| local.token.span.start);
+            for local in unused {
+                self.resolution
+                    .warnings
+                    .push(Warning::UnusedLocal(local.token.clone()));
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &Token, is_param: bool) {
+        if let Some(scope) = self.scopes.last_mut() {
+            if scope.contains_key(name.name()) {
+                self.resolution
+                    .errors
+                    .push(ResolveError::AlreadyDeclared(name.clone()));
+            }
+            scope.insert(
+                name.name().to_string(),
+                Local {
+                    token: name.clone(),
+                    defined: false,
+                    used: false,
+                    is_param,
+                },
+            );
+        }
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(local) = self
+            .scopes
+            .last_mut()
+            .and_then(|scope| scope.get_mut(name.name()))
+        {
+            local.defined = true;
+        }
+    }
+
+    /// Binds a name the language provides, like `this`, in the innermost
+    /// scope. `token` is only there to point diagnostics somewhere.
+    fn define_implicit(&mut self, name: &str, token: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(
+                name.to_string(),
+                Local {
+                    token: token.clone(),
+                    defined: true,
+                    used: true,
+                    is_param: false,
+                },
+            );
+        }
+    }
+
+    fn mark_used(&mut self, name: &Token) {
+        if let Some(local) = self
+            .scopes
+            .last_mut()
+            .and_then(|scope| scope.get_mut(name.name()))
+        {
+            local.used = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn resolve_source(input: &str) -> Resolution {
+        let tokens: Vec<Token> = Lexer::new(input).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        resolve(&program)
+    }
+
+    fn messages(input: &str) -> Vec<String> {
+        resolve_source(input)
+            .errors
+            .iter()
+            .map(|err| format!("{} {}", err.token().name(), err))
+            .collect()
+    }
+
+    #[test]
+    fn test_depths() {
+        let resolution = resolve_source("var a = 1; { var b = a; { print b; } }");
+        let mut depths: Vec<usize> = resolution.locals.values().cloned().collect();
+        depths.sort();
+        // `a` is global and `b` is read one scope out
+        assert_eq!(depths, vec![1]);
+        assert!(resolution.errors.is_empty());
+        assert!(resolution.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            messages("{ var a = a; }"),
+            vec!["a Can't read local variable in its own initializer."]
+        );
+        assert_eq!(
+            messages("{ var a = 1; var a = 2; print a; }"),
+            vec!["a Already a variable with this name in this scope."]
+        );
+        assert_eq!(
+            messages("fun f(a, a) {}"),
+            vec!["a Already a variable with this name in this scope."]
+        );
+        assert_eq!(messages("return 1;").len(), 1);
+        assert_eq!(
+            messages("fun f() { import \"a.lox\" as a; }"),
+            vec![" Can only import at the top level of a file."]
+        );
+        assert!(messages("import \"a.lox\" as a; print a.b;").is_empty());
+        assert!(messages("fun f() { return 1; } var a = 1; var a = a;").is_empty());
+    }
+
+    #[test]
+    fn test_class_errors() {
+        let tests = [
+            ("print this;", "Can't use 'this' outside of a class."),
+            (
+                "fun f() { super.g(); }",
+                "Can't use 'super' outside of a class.",
+            ),
+            (
+                "class A { f() { super.f(); } }",
+                "Can't use 'super' in a class with no superclass.",
+            ),
+            ("class A < A {}", "A class can't inherit from itself."),
+            (
+                "class A { init() { return 1; } }",
+                "Can't return a value from an initializer.",
+            ),
+        ];
+        for (input, expected) in tests.iter() {
+            let errors: Vec<String> = resolve_source(input)
+                .errors
+                .iter()
+                .map(|err| err.to_string())
+                .collect();
+            assert_eq!(errors, vec![expected.to_string()], "{}", input);
+        }
+        let resolution = resolve_source(
+            "class A { init() { return; } } class B < A { f() { return super.f; } }",
+        );
+        assert!(resolution.errors.is_empty());
+    }
+
+    #[test]
+    fn test_unused_locals() {
+        let warnings: Vec<String> = resolve_source(
+            "fun f(unused_param) { var x = 1; var _y = 2; var z = 3; print z; } var g = 1;",
+        )
+        .warnings
+        .iter()
+        .map(|warning| warning.to_string())
+        .collect();
+        assert_eq!(warnings, vec!["Local variable 'x' is never used."]);
+
+        let warnings: Vec<String> = resolve_source(
+            "{ var a; a = 1; fun unused() {} fun called() {} called(); var b = 0; b = b + 1; }",
+        )
+        .warnings
+        .iter()
+        .map(|warning| warning.to_string())
+        .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "Local variable 'a' is never used.",
+                "Local variable 'unused' is never used."
+            ]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+//! What scripts get without defining it themselves: methods on strings,
+//! lists and maps, and the `math` and `io` modules
+
+use crate::interpreter::ImportPolicy;
+use crate::value::{whole_number, Key, Module, NativeFunction, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Built-in types that have methods
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Receiver {
+    Str,
+    List,
+    Map,
+}
+
+/// A method of a built-in type. Calling one goes through the backend,
+/// which owns the receiver.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Len,
+    Substring,
+    Split,
+    Find,
+    Push,
+    Pop,
+    Keys,
+    Values,
+    Has,
+    Remove,
+}
+
+impl Method {
+    pub fn lookup(receiver: Receiver, name: &str) -> Option<Method> {
+        let methods: &[Method] = match receiver {
+            Receiver::Str => &[Method::Len, Method::Substring, Method::Split, Method::Find],
+            Receiver::List => &[Method::Len, Method::Push, Method::Pop],
+            Receiver::Map => &[
+                Method::Len,
+                Method::Keys,
+                Method::Values,
+                Method::Has,
+                Method::Remove,
+            ],
+        };
+        methods.iter().copied().find(|method| method.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Len => "len",
+            Method::Substring => "substring",
+            Method::Split => "split",
+            Method::Find => "find",
+            Method::Push => "push",
+            Method::Pop => "pop",
+            Method::Keys => "keys",
+            Method::Values => "values",
+            Method::Has => "has",
+            Method::Remove => "remove",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Method::Len | Method::Pop | Method::Keys | Method::Values => 0,
+            Method::Split | Method::Find | Method::Push | Method::Has | Method::Remove => 1,
+            Method::Substring => 2,
+        }
+    }
+}
+
+/// The message for an argument of the wrong type, `idx` counting from zero
+pub fn arg_error(name: &str, idx: usize, expected: &str) -> String {
+    format!("Argument {} of '{}' must be {}.", idx + 1, name, expected)
+}
+
+pub const EMPTY_LIST: &str = "Can't pop from an empty list.";
+
+/// What `arg_error` says a map key argument must be
+pub const KEY_TYPES: &str = "a string, number, boolean or nil";
+
+fn int_arg(name: &str, args: &[Value], idx: usize) -> Result<i64, String> {
+    args[idx]
+        .as_int()
+        .ok_or_else(|| arg_error(name, idx, "an integer"))
+}
+
+fn number_arg(name: &str, args: &[Value], idx: usize) -> Result<f64, String> {
+    args[idx]
+        .as_float()
+        .ok_or_else(|| arg_error(name, idx, "a number"))
+}
+
+fn str_arg<'a>(name: &str, args: &'a [Value], idx: usize) -> Result<&'a str, String> {
+    match &args[idx] {
+        Value::Str(val) => Ok(val),
+        _ => Err(arg_error(name, idx, "a string")),
+    }
+}
+
+fn key_arg(name: &str, args: &[Value], idx: usize) -> Result<Key, String> {
+    Key::from_value(&args[idx]).ok_or_else(|| arg_error(name, idx, KEY_TYPES))
+}
+
+/// Number of characters, which is what string indices count
+pub fn char_len(val: &str) -> usize {
+    val.chars().count()
+}
+
+/// The character at `idx` as a string of its own
+pub fn char_at(val: &str, idx: i64) -> Option<String> {
+    if idx < 0 {
+        return None;
+    }
+    val.chars().nth(idx as usize).map(String::from)
+}
+
+/// Calls `method` on a receiver the host can hold. The VM only uses this
+/// for strings, its lists and maps live in its own heap.
+pub fn call_method(receiver: &Value, method: Method, args: &[Value]) -> Result<Value, String> {
+    let name = method.name();
+    match (receiver, method) {
+        (Value::Str(val), Method::Len) => Ok(Value::Int(char_len(val) as i64)),
+        (Value::Str(val), Method::Substring) => {
+            let start = int_arg(name, args, 0)?;
+            let end = int_arg(name, args, 1)?;
+            if start < 0 || end < start || end as usize > char_len(val) {
+                return Err(format!(
+                    "Substring range {}..{} is out of bounds for a string of length {}.",
+                    start,
+                    end,
+                    char_len(val)
+                ));
+            }
+            Ok(Value::Str(
+                val.chars()
+                    .skip(start as usize)
+                    .take((end - start) as usize)
+                    .collect(),
+            ))
+        }
+        (Value::Str(val), Method::Split) => {
+            let separator = str_arg(name, args, 0)?;
+            let parts = if separator.is_empty() {
+                val.chars().map(|ch| Value::Str(ch.to_string())).collect()
+            } else {
+                val.split(separator)
+                    .map(|part| Value::Str(part.to_string()))
+                    .collect()
+            };
+            Ok(Value::list(parts))
+        }
+        (Value::Str(val), Method::Find) => {
+            let needle = str_arg(name, args, 0)?;
+            Ok(Value::Int(match val.find(needle) {
+                Some(byte) => char_len(&val[..byte]) as i64,
+                None => -1,
+            }))
+        }
+        (Value::List(list), Method::Len) => Ok(Value::Int(list.borrow().len() as i64)),
+        (Value::List(list), Method::Push) => {
+            list.borrow_mut().push(args[0].clone());
+            Ok(Value::Nil)
+        }
+        (Value::List(list), Method::Pop) => list
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| EMPTY_LIST.to_string()),
+        (Value::Map(map), Method::Len) => Ok(Value::Int(map.borrow().len() as i64)),
+        (Value::Map(map), Method::Keys) => Ok(Value::list(
+            map.borrow().iter().map(|(key, _)| key.to_value()).collect(),
+        )),
+        (Value::Map(map), Method::Values) => Ok(Value::list(
+            map.borrow()
+                .iter()
+                .map(|(_, value)| value.clone())
+                .collect(),
+        )),
+        (Value::Map(map), Method::Has) => {
+            let key = key_arg(name, args, 0)?;
+            Ok(Value::Bool(map.borrow().contains_key(&key)))
+        }
+        (Value::Map(map), Method::Remove) => {
+            let key = key_arg(name, args, 0)?;
+            Ok(map.borrow_mut().remove(&key).unwrap_or(Value::Nil))
+        }
+        _ => unreachable!("methods are looked up for their receiver"),
+    }
+}
+
+fn native<F>(name: &str, arity: usize, func: F) -> (String, Value)
+where
+    F: Fn(&[Value]) -> Result<Value, String> + 'static,
+{
+    let native = NativeFunction {
+        name: name.to_string(),
+        arity,
+        func: Box::new(func),
+    };
+    (name.to_string(), Value::Native(Rc::new(native)))
+}
+
+/// Whole results come back as ints, so `math.floor(x)` can index a list
+fn number(val: f64) -> Value {
+    match whole_number(val) {
+        Some(val) => Value::Int(val),
+        None => Value::Float(val),
+    }
+}
+
+fn math() -> Module {
+    let unary = |name: &'static str, func: fn(f64) -> f64| {
+        native(name, 1, move |args| {
+            Ok(number(func(number_arg(name, args, 0)?)))
+        })
+    };
+    let mut members: HashMap<String, Value> = vec![
+        unary("sqrt", f64::sqrt),
+        unary("floor", f64::floor),
+        unary("ceil", f64::ceil),
+        unary("round", f64::round),
+        native("abs", 1, |args| match &args[0] {
+            Value::Int(val) => Ok(val
+                .checked_abs()
+                .map_or(Value::Float(-(*val as f64)), Value::Int)),
+            _ => Ok(Value::Float(number_arg("abs", args, 0)?.abs())),
+        }),
+        native("pow", 2, |args| {
+            let base = number_arg("pow", args, 0)?;
+            let exponent = number_arg("pow", args, 1)?;
+            Ok(number(base.powf(exponent)))
+        }),
+        native("min", 2, |args| {
+            let (a, b) = (number_arg("min", args, 0)?, number_arg("min", args, 1)?);
+            Ok(args[if b < a { 1 } else { 0 }].clone())
+        }),
+        native("max", 2, |args| {
+            let (a, b) = (number_arg("max", args, 0)?, number_arg("max", args, 1)?);
+            Ok(args[if b > a { 1 } else { 0 }].clone())
+        }),
+    ]
+    .into_iter()
+    .collect();
+    members.insert("pi".to_string(), Value::Float(std::f64::consts::PI));
+    Module {
+        name: "math".to_string(),
+        members,
+    }
+}
+
+/// `readFile` only reads what `policy` allows at the time of the call
+fn io(policy: Rc<RefCell<ImportPolicy>>) -> Module {
+    let members = vec![
+        native("readFile", 1, move |args| {
+            let path = str_arg("readFile", args, 0)?;
+            if !policy.borrow().allows(Path::new(path)) {
+                return Err(format!("Not allowed to read file '{}'.", path));
+            }
+            fs::read_to_string(path)
+                .map(Value::Str)
+                .map_err(|err| format!("Cannot read file '{}': {}.", path, err))
+        }),
+        // nil once stdin is exhausted
+        native("readLine", 0, |_| {
+            let mut line = String::new();
+            match io::stdin().lock().read_line(&mut line) {
+                Ok(0) => Ok(Value::Nil),
+                Ok(_) => {
+                    let trimmed = line.trim_end_matches(&['\n', '\r'][..]);
+                    Ok(Value::Str(trimmed.to_string()))
+                }
+                Err(err) => Err(format!("Cannot read from stdin: {}.", err)),
+            }
+        }),
+    ]
+    .into_iter()
+    .collect();
+    Module {
+        name: "io".to_string(),
+        members,
+    }
+}
+
+/// The modules defined as globals in every session
+pub fn modules(policy: Rc<RefCell<ImportPolicy>>) -> Vec<Module> {
+    vec![math(), io(policy)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(receiver: Value, name: &str, args: &[Value]) -> Result<Value, String> {
+        let kind = match receiver {
+            Value::Str(_) => Receiver::Str,
+            Value::List(_) => Receiver::List,
+            _ => Receiver::Map,
+        };
+        let method = Method::lookup(kind, name).expect("method exists");
+        assert_eq!(method.arity(), args.len());
+        call_method(&receiver, method, args)
+    }
+
+    fn string(val: &str) -> Value {
+        Value::Str(val.to_string())
+    }
+
+    #[test]
+    fn test_string_methods() {
+        assert_eq!(call(string("héllo"), "len", &[]), Ok(Value::Int(5)));
+        assert_eq!(
+            call(
+                string("héllo"),
+                "substring",
+                &[Value::Int(1), Value::Float(3.0)]
+            ),
+            Ok(string("él"))
+        );
+        assert_eq!(
+            call(string("abc"), "substring", &[Value::Int(2), Value::Int(4)]),
+            Err("Substring range 2..4 is out of bounds for a string of length 3.".to_string())
+        );
+        assert_eq!(
+            call(
+                string("abc"),
+                "substring",
+                &[Value::Int(0), Value::Float(0.5)]
+            ),
+            Err("Argument 2 of 'substring' must be an integer.".to_string())
+        );
+        assert_eq!(
+            call(string("a,b,,c"), "split", &[string(",")]).map(|parts| parts.to_string()),
+            Ok("[\"a\", \"b\", \"\", \"c\"]".to_string())
+        );
+        assert_eq!(
+            call(string("héllo"), "find", &[string("l")]),
+            Ok(Value::Int(2))
+        );
+        assert_eq!(
+            call(string("abc"), "find", &[string("x")]),
+            Ok(Value::Int(-1))
+        );
+        assert_eq!(Method::lookup(Receiver::Str, "push"), None);
+    }
+
+    #[test]
+    fn test_collection_methods() {
+        let list = Value::list(vec![Value::Int(1)]);
+        assert_eq!(call(list.clone(), "push", &[string("a")]), Ok(Value::Nil));
+        assert_eq!(list.to_string(), "[1, \"a\"]");
+        assert_eq!(call(list.clone(), "pop", &[]), Ok(string("a")));
+        assert_eq!(call(list.clone(), "pop", &[]), Ok(Value::Int(1)));
+        assert_eq!(call(list, "pop", &[]), Err(EMPTY_LIST.to_string()));
+
+        let mut entries = crate::value::OrderedMap::new();
+        entries.insert(Key::Str("b".to_string()), Value::Int(1));
+        entries.insert(Key::Int(2), Value::Nil);
+        let map = Value::Map(Rc::new(std::cell::RefCell::new(entries)));
+        assert_eq!(map.to_string(), "{\"b\": 1, 2: nil}");
+        assert_eq!(
+            call(map.clone(), "has", &[Value::Float(2.0)]),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            call(map.clone(), "remove", &[string("b")]),
+            Ok(Value::Int(1))
+        );
+        assert_eq!(
+            call(map.clone(), "keys", &[]).map(|keys| keys.to_string()),
+            Ok("[2]".to_string())
+        );
+        assert_eq!(
+            call(map, "has", &[Value::list(Vec::new())]),
+            Err("Argument 1 of 'has' must be a string, number, boolean or nil.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_math() {
+        let math = math();
+        let call = |name: &str, args: &[Value]| match &math.members[name] {
+            Value::Native(native) => (native.func)(args),
+            _ => panic!("{} is not a function", name),
+        };
+        assert_eq!(call("sqrt", &[Value::Int(16)]), Ok(Value::Int(4)));
+        assert_eq!(
+            call("sqrt", &[Value::Int(2)]),
+            Ok(Value::Float(2f64.sqrt()))
+        );
+        assert_eq!(call("floor", &[Value::Float(-1.5)]), Ok(Value::Int(-2)));
+        assert_eq!(call("abs", &[Value::Int(-3)]), Ok(Value::Int(3)));
+        assert_eq!(
+            call("max", &[Value::Int(1), Value::Float(1.5)]),
+            Ok(Value::Float(1.5))
+        );
+        assert_eq!(
+            call("pow", &[Value::Int(2), Value::Nil]),
+            Err("Argument 2 of 'pow' must be a number.".to_string())
+        );
+    }
+}
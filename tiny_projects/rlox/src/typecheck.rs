@@ -0,0 +1,589 @@
+//! `--check`, which finds type mistakes without running the program
+//!
+//! Typing is gradual. Variables, parameters and function results can be
+//! annotated, as in `var x: number = 1;` or `fun f(a: string): bool`, and
+//! anything without an annotation is `any`, which every type fits. Literals
+//! and operators always have a type, so `"a" - 1` is caught with no
+//! annotations at all.
+
+use crate::error::TypeError;
+use crate::grammar::*;
+use crate::lexer::{Token, Type};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxType {
+    Any,
+    Nil,
+    Bool,
+    Number,
+    String,
+    List,
+    Map,
+    Function,
+    Class,
+    /// An instance of the named class
+    Instance(String),
+}
+
+impl LoxType {
+    /// Whether a value of type `other` fits where `self` is expected
+    fn accepts(&self, other: &LoxType) -> bool {
+        *self == LoxType::Any || *other == LoxType::Any || self == other
+    }
+}
+
+impl fmt::Display for LoxType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LoxType::Any => "any",
+            LoxType::Nil => "nil",
+            LoxType::Bool => "bool",
+            LoxType::Number => "number",
+            LoxType::String => "string",
+            LoxType::List => "list",
+            LoxType::Map => "map",
+            LoxType::Function => "function",
+            LoxType::Class => "class",
+            LoxType::Instance(class) => class,
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What calling a function or class takes and gives back
+#[derive(Debug, Clone)]
+struct Signature {
+    params: Vec<LoxType>,
+    result: LoxType,
+}
+
+#[derive(Debug, Clone)]
+struct Binding {
+    kind: LoxType,
+    /// Only annotated variables are checked when they are assigned
+    annotated: bool,
+    signature: Option<Signature>,
+}
+
+/// Checks a parsed program, which should also resolve without errors
+pub fn check(program: &[Stmt]) -> Vec<TypeError> {
+    let mut classes = HashSet::new();
+    class_names(program, &mut classes);
+    let mut checker = Checker {
+        scopes: vec![HashMap::new()],
+        classes,
+        results: Vec::new(),
+        errors: Vec::new(),
+    };
+    crate::ensure_stack(|| checker.stmts(program));
+    checker.errors
+}
+
+/// Every class can be named in an annotation, wherever it is declared
+fn class_names(stmts: &[Stmt], classes: &mut HashSet<String>) {
+    for stmt in stmts.iter() {
+        match stmt {
+            Stmt::Class(decl) => {
+                classes.insert(decl.name.name().to_string());
+                for method in decl.methods.iter() {
+                    class_names(&method.body, classes);
+                }
+            }
+            Stmt::Function(decl) => class_names(&decl.body, classes),
+            Stmt::Block(stmts) => class_names(stmts, classes),
+            Stmt::Try(_, body, _, handler) => {
+                class_names(body, classes);
+                class_names(handler, classes);
+            }
+            Stmt::If(_, then_branch, else_branch) => {
+                class_names(std::slice::from_ref(then_branch.as_ref()), classes);
+                if let Some(else_branch) = else_branch {
+                    class_names(std::slice::from_ref(else_branch.as_ref()), classes);
+                }
+            }
+            Stmt::While(_, body, _) => class_names(std::slice::from_ref(body.as_ref()), classes),
+            _ => {}
+        }
+    }
+}
+
+struct Checker {
+    scopes: Vec<HashMap<String, Binding>>,
+    classes: HashSet<String>,
+    /// The declared result of each function being checked, innermost last
+    results: Vec<LoxType>,
+    errors: Vec<TypeError>,
+}
+
+impl Checker {
+    fn define(&mut self, name: &Token, binding: Binding) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.name().to_string(), binding);
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    /// The type an annotation names, `any` when there is none
+    fn annotation(&mut self, annotation: &Option<Token>) -> LoxType {
+        let token = match annotation {
+            Some(token) => token,
+            None => return LoxType::Any,
+        };
+        if token.kind == Type::Nil {
+            return LoxType::Nil;
+        }
+        match token.name() {
+            "any" => LoxType::Any,
+            "bool" => LoxType::Bool,
+            "number" => LoxType::Number,
+            "string" => LoxType::String,
+            "list" => LoxType::List,
+            "map" => LoxType::Map,
+            "function" => LoxType::Function,
+            name if self.classes.contains(name) => LoxType::Instance(name.to_string()),
+            _ => {
+                self.errors.push(TypeError::UnknownType(token.clone()));
+                LoxType::Any
+            }
+        }
+    }
+
+    fn signature(&mut self, decl: &FunctionDecl) -> Signature {
+        Signature {
+            params: decl
+                .param_types
+                .iter()
+                .map(|annotation| self.annotation(annotation))
+                .collect(),
+            result: self.annotation(&decl.return_type),
+        }
+    }
+
+    /// Reports `found` at `expr` unless it fits `expected`
+    fn expect(&mut self, expected: &LoxType, found: LoxType, expr: &Expr) {
+        if !expected.accepts(&found) {
+            self.errors.push(TypeError::Mismatch {
+                token: expr.first_token().clone(),
+                expected: expected.clone(),
+                found,
+            });
+        }
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts.iter() {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => {
+                self.expr(expr);
+            }
+            Stmt::Var(name, annotation, initializer) => {
+                let kind = self.annotation(annotation);
+                if let Some(initializer) = initializer {
+                    let found = self.expr(initializer);
+                    self.expect(&kind, found, initializer);
+                }
+                let binding = Binding {
+                    kind,
+                    annotated: annotation.is_some(),
+                    signature: None,
+                };
+                self.define(name, binding);
+            }
+            Stmt::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                self.stmts(stmts);
+                self.scopes.pop();
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expr(condition);
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch);
+                }
+            }
+            Stmt::While(condition, body, _) => {
+                self.expr(condition);
+                self.stmt(body);
+            }
+            Stmt::Function(decl) => {
+                let signature = self.signature(decl);
+                // bound before the body so it can call itself
+                let binding = Binding {
+                    kind: LoxType::Function,
+                    annotated: false,
+                    signature: Some(signature.clone()),
+                };
+                self.define(&decl.name, binding);
+                self.function(decl, signature);
+            }
+            Stmt::Return(keyword, value) => {
+                let found = match value {
+                    Some(expr) => self.expr(expr),
+                    None => LoxType::Nil,
+                };
+                let expected = match self.results.last() {
+                    Some(expected) => expected.clone(),
+                    None => return,
+                };
+                if !expected.accepts(&found) {
+                    let token = value.as_ref().map_or(keyword, |expr| expr.first_token());
+                    self.errors.push(TypeError::Mismatch {
+                        token: token.clone(),
+                        expected,
+                        found,
+                    });
+                }
+            }
+            Stmt::Throw(_, value) => {
+                self.expr(value);
+            }
+            Stmt::Try(_, body, name, handler) => {
+                self.scopes.push(HashMap::new());
+                self.stmts(body);
+                self.scopes.pop();
+                // anything can be thrown
+                let mut scope = HashMap::new();
+                let error = Binding {
+                    kind: LoxType::Any,
+                    annotated: false,
+                    signature: None,
+                };
+                scope.insert(name.name().to_string(), error);
+                self.scopes.push(scope);
+                self.stmts(handler);
+                self.scopes.pop();
+            }
+            Stmt::Class(decl) => self.class(decl),
+            Stmt::Import(_, name, _) => {
+                // modules are not checked, so nothing is known of their members
+                let module = Binding {
+                    kind: LoxType::Any,
+                    annotated: false,
+                    signature: None,
+                };
+                self.define(name, module);
+            }
+        }
+    }
+
+    fn function(&mut self, decl: &FunctionDecl, signature: Signature) {
+        let mut scope = HashMap::new();
+        for ((param, kind), annotation) in decl
+            .params
+            .iter()
+            .zip(signature.params)
+            .zip(decl.param_types.iter())
+        {
+            let binding = Binding {
+                kind,
+                annotated: annotation.is_some(),
+                signature: None,
+            };
+            scope.insert(param.name().to_string(), binding);
+        }
+        self.scopes.push(scope);
+        self.results.push(signature.result);
+        self.stmts(&decl.body);
+        self.results.pop();
+        self.scopes.pop();
+    }
+
+    fn class(&mut self, decl: &ClassDecl) {
+        if let Some(superclass) = &decl.superclass {
+            self.expr(superclass);
+        }
+        let instance = LoxType::Instance(decl.name.name().to_string());
+        let mut methods = Vec::new();
+        for method in decl.methods.iter() {
+            methods.push((method, self.signature(method)));
+        }
+        // calling the class runs `init`, which may be inherited from a
+        // superclass this pass knows nothing about
+        let init = methods
+            .iter()
+            .find(|(method, _)| method.name.name() == "init")
+            .map(|(_, signature)| signature.params.clone());
+        let params = match (init, &decl.superclass) {
+            (Some(params), _) => Some(params),
+            (None, None) => Some(Vec::new()),
+            (None, Some(_)) => None,
+        };
+        let binding = Binding {
+            kind: LoxType::Class,
+            annotated: false,
+            signature: params.map(|params| Signature {
+                params,
+                result: instance.clone(),
+            }),
+        };
+        self.define(&decl.name, binding);
+
+        let mut scope = HashMap::new();
+        let this = Binding {
+            kind: instance,
+            annotated: false,
+            signature: None,
+        };
+        scope.insert("this".to_string(), this);
+        self.scopes.push(scope);
+        for (method, signature) in methods {
+            self.function(method, signature);
+        }
+        self.scopes.pop();
+    }
+
+    fn expr(&mut self, expr: &Expr) -> LoxType {
+        match expr {
+            Expr::Literal(literal, _) => match literal {
+                Literal::String(_) => LoxType::String,
+                Literal::Float(_) | Literal::Int(_) => LoxType::Number,
+                Literal::True | Literal::False => LoxType::Bool,
+                Literal::Nil => LoxType::Nil,
+            },
+            Expr::UnaryExpr(op, token, operand) => {
+                let found = self.expr(operand);
+                match op {
+                    UnaryOp::Negative => {
+                        if !LoxType::Number.accepts(&found) {
+                            self.errors.push(TypeError::Operand {
+                                token: token.clone(),
+                                found,
+                            });
+                        }
+                        LoxType::Number
+                    }
+                    UnaryOp::Not => LoxType::Bool,
+                }
+            }
+            Expr::BinaryExpr(left, op, token, right) => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                self.binary(*op, token, left, right)
+            }
+            Expr::Grouping(inner) => self.expr(inner),
+            Expr::Variable(name, _) => self
+                .lookup(name.name())
+                .map_or(LoxType::Any, |binding| binding.kind.clone()),
+            Expr::Assign(name, value, _) => {
+                let found = self.expr(value);
+                if let Some(binding) = self.lookup(name.name()) {
+                    if binding.annotated {
+                        let expected = binding.kind.clone();
+                        self.expect(&expected, found.clone(), value);
+                    }
+                }
+                found
+            }
+            Expr::Logical(left, _, right) => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                // either operand can be the result
+                if left == right {
+                    left
+                } else {
+                    LoxType::Any
+                }
+            }
+            Expr::Call(callee, paren, args) => self.call(callee, paren, args),
+            Expr::Get(object, _) => {
+                self.expr(object);
+                LoxType::Any
+            }
+            Expr::Set(object, _, value) => {
+                self.expr(object);
+                self.expr(value)
+            }
+            Expr::This(..) => self
+                .lookup("this")
+                .map_or(LoxType::Any, |binding| binding.kind.clone()),
+            Expr::Super(..) => LoxType::Any,
+            Expr::List(_, elements) => {
+                for element in elements.iter() {
+                    self.expr(element);
+                }
+                LoxType::List
+            }
+            Expr::Map(_, entries) => {
+                for (key, value) in entries.iter() {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                LoxType::Map
+            }
+            Expr::Index(object, _, index) => {
+                self.expr(object);
+                self.expr(index);
+                LoxType::Any
+            }
+            Expr::SetIndex(object, _, index, value) => {
+                self.expr(object);
+                self.expr(index);
+                self.expr(value)
+            }
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, token: &Token, left: LoxType, right: LoxType) -> LoxType {
+        let number = LoxType::Number;
+        let (fits, result) = match op {
+            BinaryOp::Eq | BinaryOp::Ne => return LoxType::Bool,
+            BinaryOp::Plus => match (&left, &right) {
+                (LoxType::Any, LoxType::Any) => (true, LoxType::Any),
+                (LoxType::Number, LoxType::Number)
+                | (LoxType::Number, LoxType::Any)
+                | (LoxType::Any, LoxType::Number) => (true, LoxType::Number),
+                (LoxType::String, LoxType::String)
+                | (LoxType::String, LoxType::Any)
+                | (LoxType::Any, LoxType::String) => (true, LoxType::String),
+                _ => (false, LoxType::Any),
+            },
+            BinaryOp::Minus | BinaryOp::Times | BinaryOp::Divide => {
+                (number.accepts(&left) && number.accepts(&right), number)
+            }
+            BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Lt | BinaryOp::Le => (
+                number.accepts(&left) && number.accepts(&right),
+                LoxType::Bool,
+            ),
+        };
+        if !fits {
+            self.errors.push(TypeError::Operands {
+                token: token.clone(),
+                left,
+                right,
+            });
+        }
+        result
+    }
+
+    fn call(&mut self, callee: &Expr, paren: &Token, args: &[Expr]) -> LoxType {
+        let signature = match callee {
+            Expr::Variable(name, _) => self
+                .lookup(name.name())
+                .and_then(|binding| binding.signature.clone()),
+            _ => {
+                self.expr(callee);
+                None
+            }
+        };
+        let found: Vec<LoxType> = args.iter().map(|arg| self.expr(arg)).collect();
+        let signature = match signature {
+            Some(signature) => signature,
+            None => return LoxType::Any,
+        };
+        if signature.params.len() != args.len() {
+            self.errors.push(TypeError::WrongArity {
+                token: paren.clone(),
+                expected: signature.params.len(),
+                got: args.len(),
+            });
+        } else {
+            for ((expected, found), arg) in signature.params.iter().zip(found).zip(args) {
+                self.expect(expected, found, arg);
+            }
+        }
+        signature.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Interpreter;
+
+    /// The messages for the errors in `source`, with their lines
+    fn check_source(source: &str) -> Vec<String> {
+        let program = Interpreter::new().parse(source).unwrap();
+        check(&program)
+            .iter()
+            .map(|err| format!("{} {}", err.token().line + 1, err))
+            .collect()
+    }
+
+    #[test]
+    fn test_operators() {
+        let errors = check_source(
+            "
+print \"a\" - 1;
+print -\"a\";
+print \"a\" + 1;
+print \"a\" + \"b\" + \"c\";
+print 1 < true;
+print !\"a\" == nil;
+var untyped;
+print untyped - 1 + untyped;
+",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "2 Operands must be numbers, not string and number.",
+                "3 Operand must be a number, not string.",
+                "4 Operands must be two numbers or two strings, not string and number.",
+                "6 Operands must be numbers, not number and bool.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_annotations() {
+        let errors = check_source(
+            "
+var count: number = \"none\";
+var name: string = \"lox\";
+name = 1;
+fun greet(who: string, times: number): string {
+  if (times < 1) return nil;
+  return who;
+}
+greet(name, 2);
+greet(2, name);
+greet(name);
+var n: number = greet(name, 1);
+var shape: Shape;
+class Point { init(x: number) {} }
+var p: Point = Point(1);
+p = Point(\"a\");
+",
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "2 Expected number but found string.",
+                "4 Expected string but found number.",
+                "6 Expected string but found nil.",
+                "10 Expected string but found number.",
+                "10 Expected number but found string.",
+                "11 Expected 2 arguments but got 1.",
+                "12 Expected number but found string.",
+                "13 Unknown type 'Shape'.",
+                "16 Expected number but found string.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gradual() {
+        // nothing is known about unannotated names, so nothing is flagged
+        let errors = check_source(
+            "
+fun id(x) { return x; }
+var a = id(\"a\") - 1;
+var b: number = id(\"b\");
+var c: bool = clock() > 1;
+var d: number = 1 and 2;
+var e: number = 1 or \"a\";
+",
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+}
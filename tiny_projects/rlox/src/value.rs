@@ -0,0 +1,397 @@
+use crate::evaluation::Environment;
+use crate::grammar::FunctionDecl;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::rc::Rc;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Function(Rc<LoxFunction>),
+    Native(Rc<NativeFunction>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<RefCell<LoxInstance>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<OrderedMap<Key, Value>>>),
+    Module(Rc<Module>),
+}
+
+impl Value {
+    /// Lox follows Ruby's rule: `false` and `nil` are falsey, everything
+    /// else is truthy
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Lox equality never errors and never coerces across types, except
+    /// that ints and floats compare by numeric value
+    pub fn is_equal(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+            (Value::Module(a), Value::Module(b)) => Rc::ptr_eq(a, b),
+            (a, b) => match (a.as_float(), b.as_float()) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+
+    /// Numeric view of the value, promoting ints to floats
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Int(val) => Some(*val as f64),
+            Value::Float(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    /// The value as a list index or substring bound. Floats count when
+    /// they are whole numbers.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(val) => Some(*val),
+            Value::Float(val) => whole_number(*val),
+            _ => None,
+        }
+    }
+
+    /// A new list holding `items`
+    pub fn list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+
+    /// How the value shows inside a list or map, where strings are quoted
+    pub fn repr(&self) -> String {
+        match self {
+            Value::Str(val) => format!("{:?}", val),
+            other => other.to_string(),
+        }
+    }
+}
+
+/// `val` as an int, if it is a whole number that fits in one
+pub fn whole_number(val: f64) -> Option<i64> {
+    // i64::MAX rounds up to 2^63 as a float, which does not fit
+    if val.fract() == 0.0 && val >= i64::MIN as f64 && val < i64::MAX as f64 {
+        Some(val as i64)
+    } else {
+        None
+    }
+}
+
+// Unlike `is_equal` this keeps ints and floats apart, which is what tests
+// comparing values want
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Int(_), _) | (Value::Float(_), _) => false,
+            (a, b) => a.is_equal(b),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &mut Vec::new())
+    }
+}
+
+impl Value {
+    /// Writes the value as `print` shows it. Strings inside lists and maps
+    /// are quoted, and `seen` holds the collections being written so one
+    /// that contains itself prints as `[...]` or `{...}`.
+    fn write(&self, f: &mut fmt::Formatter<'_>, seen: &mut Vec<*const ()>) -> fmt::Result {
+        let nested =
+            |value: &Value, f: &mut fmt::Formatter<'_>, seen: &mut Vec<*const ()>| match value {
+                Value::Str(val) => write!(f, "{:?}", val),
+                other => other.write(f, seen),
+            };
+        match self {
+            Value::List(list) => {
+                let ptr = Rc::as_ptr(list) as *const ();
+                if seen.contains(&ptr) {
+                    return write!(f, "[...]");
+                }
+                seen.push(ptr);
+                write!(f, "[")?;
+                for (idx, item) in list.borrow().iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    nested(item, f, seen)?;
+                }
+                seen.pop();
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                let ptr = Rc::as_ptr(map) as *const ();
+                if seen.contains(&ptr) {
+                    return write!(f, "{{...}}");
+                }
+                seen.push(ptr);
+                write!(f, "{{")?;
+                for (idx, (key, value)) in map.borrow().iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    nested(&key.to_value(), f, seen)?;
+                    write!(f, ": ")?;
+                    nested(value, f, seen)?;
+                }
+                seen.pop();
+                write!(f, "}}")
+            }
+            other => write!(f, "{}", Plain(other)),
+        }
+    }
+}
+
+/// Display of the values that do not contain other values
+struct Plain<'a>(&'a Value);
+
+impl fmt::Display for Plain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(val) => write!(f, "{}", val),
+            Value::Int(val) => write!(f, "{}", val),
+            // f64's Display already drops the trailing `.0` of whole numbers
+            Value::Float(val) => write!(f, "{}", val),
+            Value::Str(val) => write!(f, "{}", val),
+            Value::Function(fun) => write!(f, "<fn {}>", fun.decl.name.name()),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+            Value::Module(module) => write!(f, "<module {}>", module.name),
+            Value::List(_) | Value::Map(_) => {
+                unreachable!("collections are written by `Value::write`")
+            }
+        }
+    }
+}
+
+/// A map key. Keys compare the way `==` compares values, so `1` and `1.0`
+/// are the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    /// The bits of a float that is not a whole number
+    Float(u64),
+    Str(String),
+}
+
+impl Key {
+    /// Only values with equality by content can be keys
+    pub fn from_value(value: &Value) -> Option<Key> {
+        Some(match value {
+            Value::Nil => Key::Nil,
+            Value::Bool(val) => Key::Bool(*val),
+            Value::Int(val) => Key::Int(*val),
+            Value::Float(val) => match whole_number(*val) {
+                Some(val) => Key::Int(val),
+                None => Key::Float(val.to_bits()),
+            },
+            Value::Str(val) => Key::Str(val.clone()),
+            _ => return None,
+        })
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            Key::Nil => Value::Nil,
+            Key::Bool(val) => Value::Bool(*val),
+            Key::Int(val) => Value::Int(*val),
+            Key::Float(bits) => Value::Float(f64::from_bits(*bits)),
+            Key::Str(val) => Value::Str(val.clone()),
+        }
+    }
+}
+
+/// Hash map that remembers insertion order, so maps print and iterate the
+/// same way every run and on both backends
+#[derive(Debug, Clone)]
+pub struct OrderedMap<K, V> {
+    entries: Vec<(K, V)>,
+    /// Position of each key in `entries`
+    index: HashMap<K, usize>,
+}
+
+impl<K, V> Default for OrderedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> OrderedMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.index.get(key).map(|idx| &self.entries[*idx].1)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Replacing the value of a key keeps it where it was
+    pub fn insert(&mut self, key: K, value: V) {
+        match self.index.get(&key) {
+            Some(idx) => self.entries[*idx].1 = value,
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(idx);
+        for (key, _) in self.entries[idx..].iter() {
+            if let Some(later) = self.index.get_mut(key) {
+                *later -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+}
+
+/// A namespace of built-in values, such as `math`
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub members: HashMap<String, Value>,
+}
+
+/// A function declared in Lox together with the environment it closes over
+pub struct LoxFunction {
+    pub decl: Rc<FunctionDecl>,
+    pub closure: Rc<RefCell<Environment>>,
+    /// The globals of the file the function was declared in
+    pub globals: Rc<RefCell<Environment>>,
+    /// `init` methods always return `this`
+    pub is_initializer: bool,
+}
+
+impl LoxFunction {
+    /// A copy of the method whose closure has `this` bound to `instance`
+    pub fn bind(&self, instance: Rc<RefCell<LoxInstance>>) -> LoxFunction {
+        let mut env = Environment::new_enclosed(self.closure.clone());
+        env.define("this", Value::Instance(instance));
+        LoxFunction {
+            decl: self.decl.clone(),
+            closure: Rc::new(RefCell::new(env)),
+            globals: self.globals.clone(),
+            is_initializer: self.is_initializer,
+        }
+    }
+}
+
+impl fmt::Debug for LoxFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoxFunction({})", self.decl.name.name())
+    }
+}
+
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+/// A function implemented by the host. An error message returned by `func`
+/// is raised as a runtime error at the call site.
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: usize,
+    pub func: Box<NativeFn>,
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NativeFunction({})", self.name)
+    }
+}
+
+pub struct LoxClass {
+    pub name: String,
+    pub superclass: Option<Rc<LoxClass>>,
+    pub methods: HashMap<String, Rc<LoxFunction>>,
+}
+
+impl LoxClass {
+    /// Looks for `name` on this class and then up the inheritance chain
+    pub fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        match self.methods.get(name) {
+            Some(method) => Some(method.clone()),
+            None => self
+                .superclass
+                .as_ref()
+                .and_then(|superclass| superclass.find_method(name)),
+        }
+    }
+
+    /// Calling a class takes the arguments of its initializer, if it has one
+    pub fn arity(&self) -> usize {
+        self.find_method("init")
+            .map_or(0, |init| init.decl.params.len())
+    }
+}
+
+impl fmt::Debug for LoxClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoxClass({})", self.name)
+    }
+}
+
+pub struct LoxInstance {
+    pub class: Rc<LoxClass>,
+    pub fields: HashMap<String, Value>,
+}
+
+impl LoxInstance {
+    pub fn new(class: Rc<LoxClass>) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl fmt::Debug for LoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoxInstance({})", self.class.name)
+    }
+}
@@ -0,0 +1,1578 @@
+pub mod chunk;
+pub mod compiler;
+pub mod debug;
+pub mod object;
+pub mod table;
+pub mod value;
+
+use crate::error::{CompileError, HostError, RuntimeError, TraceFrame};
+use crate::evaluation::{arithmetic, check_index, negate, MAX_CALL_DEPTH};
+use crate::grammar::{BinaryOp, ExprId, Stmt};
+use crate::lexer::{Token, Type};
+use crate::stdlib::{self, Method, Receiver};
+use crate::value::{NativeFunction, OrderedMap};
+use chunk::{Chunk, OpCode};
+use object::{
+    BoundMethod, BuiltinMethod, Class, Closure, GcStats, Heap, Instance, Module, Obj, ObjRef,
+    Upvalue,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use table::Table;
+use value::Value;
+
+struct CallFrame {
+    closure: ObjRef,
+    /// Shared with the function object so the frame can read code without
+    /// going through the heap
+    chunk: Rc<Chunk>,
+    ip: usize,
+    /// Stack index of the callee, arguments and locals follow it
+    slots: usize,
+    /// The closure's globals, see `Closure::globals`
+    globals: Option<ObjRef>,
+}
+
+/// A `try` whose body is running
+struct Handler {
+    /// How many frames were running, the handler belongs to the last one
+    frames: usize,
+    /// Stack height to go back to before the error is pushed
+    stack: usize,
+    /// Where the `catch` block starts
+    ip: usize,
+}
+
+/// Stack based virtual machine running code from `compiler::compile`
+pub struct Vm {
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: Table,
+    /// What the host defined, the globals every module starts out with
+    prelude: Table,
+    /// The interned "init", looked up on every class call
+    init_string: ObjRef,
+    /// Upvalues still pointing into the stack, sorted by stack slot
+    open_upvalues: Vec<ObjRef>,
+    /// Print the stack and each instruction as it executes
+    trace: bool,
+    /// Report every collection on stderr
+    log_gc: bool,
+    /// Where `print` writes
+    out: Box<dyn Write>,
+    /// Instructions left before the program is stopped
+    steps_left: Option<u64>,
+    /// Innermost last
+    handlers: Vec<Handler>,
+    /// Where the last uncaught error was raised, innermost call first
+    stack_trace: Vec<TraceFrame>,
+    /// The globals of every module `run_module` is running or has run
+    namespaces: Vec<ObjRef>,
+    /// Every module `run_module` made, by the index it returned
+    modules: Vec<ObjRef>,
+    /// The module each `import` compiled from now on binds
+    imports: HashMap<ExprId, ObjRef>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        Self {
+            heap,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: Table::new(),
+            prelude: Table::new(),
+            init_string,
+            open_upvalues: Vec::new(),
+            trace: false,
+            log_gc: false,
+            out: Box::new(io::stdout()),
+            steps_left: None,
+            handlers: Vec::new(),
+            stack_trace: Vec::new(),
+            namespaces: Vec::new(),
+            modules: Vec::new(),
+            imports: HashMap::new(),
+        }
+    }
+
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Allows `limit` more instructions, or any number with `None`
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.steps_left = limit;
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// `stress` collects before every allocation, `log` reports each
+    /// collection
+    pub fn set_gc_options(&mut self, stress: bool, log: bool) {
+        self.heap.stress = stress;
+        self.log_gc = log;
+    }
+
+    /// Bytecode listing of a function produced by `compile` and everything
+    /// nested in it
+    pub fn disassemble(&self, function: ObjRef) -> String {
+        debug::disassemble(&self.heap, function)
+    }
+
+    /// Makes `value` visible to scripts as a global. Only values that do
+    /// not belong to the tree-walker (numbers, strings, booleans, nil,
+    /// natives, lists, maps and modules) can be shared. Lists and maps are
+    /// copied. Modules loaded later start out with it too.
+    pub fn define_global(
+        &mut self,
+        name: &str,
+        value: crate::value::Value,
+    ) -> Result<(), HostError> {
+        let value = self
+            .host_value(value)
+            .ok_or_else(|| HostError::Unsupported(name.to_string()))?;
+        // keep the value reachable while the name is interned
+        self.stack.push(value);
+        let name = self.intern(name);
+        self.stack.pop();
+        let hash = self.heap.string_hash(name);
+        self.globals.set(name, hash, value);
+        self.prelude.set(name, hash, value);
+        Ok(())
+    }
+
+    /// The global `name` as a host value, if it is a number, string,
+    /// boolean, nil or a list or map of those. Lists and maps are copied.
+    pub fn global(&mut self, name: &str) -> Option<crate::value::Value> {
+        let name = self.heap.intern(name);
+        let value = self.globals.get(name, self.heap.string_hash(name))?;
+        self.to_host(value)
+    }
+
+    pub fn compile(&mut self, program: &[Stmt]) -> Result<ObjRef, Vec<CompileError>> {
+        compiler::compile(program, &mut self.heap, &self.imports)
+    }
+
+    /// Takes on the modules that `import` statements compiled later bind,
+    /// as indices returned by `run_module`
+    pub fn add_imports(&mut self, imports: HashMap<ExprId, usize>) {
+        for (id, module) in imports.into_iter() {
+            self.imports.insert(id, self.modules[module]);
+        }
+    }
+
+    /// Runs a function produced by `compile`. Globals persist between runs.
+    pub fn run(&mut self, function: ObjRef) -> Result<(), RuntimeError> {
+        self.run_in(function, None)
+    }
+
+    /// Runs a function produced by `compile` with globals of its own, which
+    /// start out as everything `define_global` defined. Afterwards the ones
+    /// named in `exports` are collected into a module called `name`, and its
+    /// index is returned.
+    pub fn run_module(
+        &mut self,
+        function: ObjRef,
+        name: &str,
+        exports: &[String],
+    ) -> Result<usize, RuntimeError> {
+        // everything made here stays on the stack until a module holds it
+        let base = self.stack.len();
+        self.stack.push(Value::Obj(function));
+        let globals = self.new_module(name);
+        self.stack.push(Value::Obj(globals));
+        let prelude: Vec<(ObjRef, u32, Value)> = self
+            .prelude
+            .iter()
+            .map(|(member, value)| (member, self.heap.string_hash(member), value))
+            .collect();
+        if let Obj::Module(module) = self.heap.get_mut(globals) {
+            for (member, hash, value) in prelude.into_iter() {
+                module.members.set(member, hash, value);
+            }
+        }
+        self.heap.resize(globals);
+        self.namespaces.push(globals);
+        if let Err(err) = self.run_in(function, Some(globals)) {
+            self.namespaces.pop();
+            return Err(err);
+        }
+        let module = self.new_module(name);
+        self.stack.push(Value::Obj(module));
+        for member in exports.iter() {
+            let key = self.intern(member);
+            if let Some(value) = self.member(globals, key) {
+                self.set_member(module, member, value);
+            }
+        }
+        self.stack.truncate(base);
+        self.modules.push(module);
+        Ok(self.modules.len() - 1)
+    }
+
+    fn new_module(&mut self, name: &str) -> ObjRef {
+        self.alloc(Obj::Module(Module {
+            name: name.to_string(),
+            members: Table::new(),
+        }))
+    }
+
+    fn member(&self, module: ObjRef, name: ObjRef) -> Option<Value> {
+        match self.heap.get(module) {
+            Obj::Module(module) => module.members.get(name, self.heap.string_hash(name)),
+            other => panic!("expected a module, found {:?}", other),
+        }
+    }
+
+    /// Sets a member of `module`, which has to be reachable
+    fn set_member(&mut self, module: ObjRef, name: &str, value: Value) {
+        // keep the value reachable while the name is interned
+        self.stack.push(value);
+        let name = self.intern(name);
+        self.stack.pop();
+        let hash = self.heap.string_hash(name);
+        match self.heap.get_mut(module) {
+            Obj::Module(module) => module.members.set(name, hash, value),
+            other => panic!("expected a module, found {:?}", other),
+        };
+        self.heap.resize(module);
+    }
+
+    fn run_in(&mut self, function: ObjRef, globals: Option<ObjRef>) -> Result<(), RuntimeError> {
+        // keep the function reachable while its closure is allocated
+        self.stack.push(Value::Obj(function));
+        let closure = self.alloc(Obj::Closure(Closure {
+            function,
+            upvalues: Vec::new(),
+            globals,
+        }));
+        self.stack.pop();
+        self.stack.push(Value::Obj(closure));
+        let result = self
+            .call(closure, 0, &Token::new(Type::Eof, 0))
+            .and_then(|()| self.execute());
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+            self.handlers.clear();
+        }
+        result
+    }
+
+    /// The calls the last error from `run` was raised in, innermost first
+    pub fn take_trace(&mut self) -> Vec<TraceFrame> {
+        std::mem::take(&mut self.stack_trace)
+    }
+
+    /// Every allocation made while running goes through here so the
+    /// collector gets a chance to run first. The compiler allocates straight
+    /// from the heap and never triggers a collection, so it does not have to
+    /// root the objects it is building.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    /// `Heap::intern` with the same chance to collect first as `alloc`
+    fn intern(&mut self, value: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(value)
+    }
+
+    /// Recounts `obj` after a script made it grow. Nothing is allocated
+    /// when that happens, so this is where a collection can become due;
+    /// whatever the caller still needs has to be reachable.
+    fn resized(&mut self, obj: ObjRef) {
+        self.heap.resize(obj);
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let before = self.heap.bytes_allocated();
+
+        for value in self.stack.iter() {
+            self.heap.mark_value(*value);
+        }
+        for frame in self.frames.iter() {
+            self.heap.mark_object(frame.closure);
+        }
+        for upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(*upvalue);
+        }
+        for module in self.namespaces.iter().chain(self.modules.iter()) {
+            self.heap.mark_object(*module);
+        }
+        for (name, value) in self.globals.iter().chain(self.prelude.iter()) {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        self.heap.mark_object(self.init_string);
+        self.heap.trace_references();
+        let (objects, bytes) = self.heap.sweep();
+
+        if self.log_gc {
+            eprintln!(
+                "-- gc: freed {} objects ({} bytes), {} -> {} bytes, {} objects live",
+                objects,
+                bytes,
+                before,
+                self.heap.bytes_allocated(),
+                self.heap.live_objects()
+            );
+        }
+    }
+
+    /// Collection totals so far, for `--log-gc`
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats
+    }
+
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("always running a frame")
+    }
+
+    /// The globals of the module the running function belongs to
+    fn globals(&mut self) -> &mut Table {
+        match self.frames.last().and_then(|frame| frame.globals) {
+            Some(module) => match self.heap.get_mut(module) {
+                Obj::Module(module) => &mut module.members,
+                other => panic!("expected a module, found {:?}", other),
+            },
+            None => &mut self.globals,
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame();
+        let byte = frame.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_short(&mut self) -> usize {
+        let high = self.read_byte() as usize;
+        let low = self.read_byte() as usize;
+        (high << 8) | low
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let idx = self.read_byte() as usize;
+        self.frame().chunk.constants[idx]
+    }
+
+    /// Reads a name operand, an interned string constant
+    fn read_string(&mut self) -> ObjRef {
+        self.read_constant()
+            .as_obj()
+            .expect("name operands are string constants")
+    }
+
+    /// The token the byte at `offset` in the current function came from
+    fn token_at(&self, offset: usize) -> Token {
+        self.frames
+            .last()
+            .expect("always running a frame")
+            .chunk
+            .token(offset)
+            .clone()
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    /// Runs the current frame until the script returns, handing every
+    /// error to the innermost `try` that is running
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            match self.dispatch() {
+                Ok(()) => return Ok(()),
+                Err(err) => self.catch(err)?,
+            }
+        }
+    }
+
+    /// Unwinds to the innermost handler with the message of `err` as the
+    /// caught value. Without one, the error is given back and the calls it
+    /// was raised in are kept for `take_trace`.
+    fn catch(&mut self, err: RuntimeError) -> Result<(), RuntimeError> {
+        if self.handlers.is_empty() || !err.is_catchable() {
+            self.stack_trace = self.trace_frames(&err);
+            return Err(err);
+        }
+        let message = self.intern(&err.to_string());
+        self.unwind(Value::Obj(message));
+        Ok(())
+    }
+
+    /// Drops everything the innermost `try` body left on the stack and
+    /// continues in its `catch` block with `value`
+    fn unwind(&mut self, value: Value) {
+        let handler = self.handlers.pop().expect("unwinding to a handler");
+        self.close_upvalues(handler.stack);
+        self.frames.truncate(handler.frames);
+        self.stack.truncate(handler.stack);
+        self.stack.push(value);
+        self.frame().ip = handler.ip;
+    }
+
+    fn trace_frames(&self, err: &RuntimeError) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(idx, frame)| {
+                // callers are stopped just after their call instruction
+                let line = if idx == 0 {
+                    err.token().line
+                } else {
+                    frame.chunk.token(frame.ip - 1).line
+                };
+                let function = self.heap.closure(frame.closure).function;
+                let module = frame
+                    .globals
+                    .and_then(|globals| self.namespaces.iter().position(|known| *known == globals));
+                TraceFrame {
+                    function: self.heap.function(function).name.clone(),
+                    line,
+                    module,
+                }
+            })
+            .collect()
+    }
+
+    fn dispatch(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            let start = self.frame().ip;
+            if self.trace {
+                let chunk = self.frame().chunk.clone();
+                let mut line = debug::format_stack(&self.heap, &self.stack);
+                line.push('\n');
+                debug::disassemble_instruction(&self.heap, &chunk, start, &mut line);
+                print!("{}", line);
+            }
+            if let Some(steps) = &mut self.steps_left {
+                if *steps == 0 {
+                    return Err(RuntimeError::StepLimitExceeded(self.token_at(start)));
+                }
+                *steps -= 1;
+            }
+            let byte = self.read_byte();
+            let op = OpCode::from_byte(byte).expect("compiler only emits valid opcodes");
+            match op {
+                OpCode::Constant => {
+                    let constant = self.read_constant();
+                    self.stack.push(constant);
+                }
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Bool(true)),
+                OpCode::False => self.stack.push(Value::Bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frame().slots;
+                    self.stack.push(self.stack[base + slot]);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let base = self.frame().slots;
+                    self.stack[base + slot] = self.peek(0);
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    let hash = self.heap.string_hash(name);
+                    match self.globals().get(name, hash) {
+                        Some(value) => self.stack.push(value),
+                        None => {
+                            return Err(RuntimeError::UndefinedVariable(self.token_at(start)));
+                        }
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    let value = self.pop();
+                    let hash = self.heap.string_hash(name);
+                    self.globals().set(name, hash, value);
+                    if let Some(module) = self.frames.last().and_then(|frame| frame.globals) {
+                        self.resized(module);
+                    }
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    let hash = self.heap.string_hash(name);
+                    let value = self.peek(0);
+                    if self.globals().set(name, hash, value) {
+                        // assignment never creates a global
+                        self.globals().delete(name, hash);
+                        return Err(RuntimeError::UndefinedVariable(self.token_at(start)));
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let idx = self.read_byte() as usize;
+                    let upvalue = self.upvalue(idx);
+                    let value = match self.heap.get(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Obj::Upvalue(Upvalue::Closed(value)) => *value,
+                        other => panic!("expected an upvalue, found {:?}", other),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let idx = self.read_byte() as usize;
+                    let upvalue = self.upvalue(idx);
+                    let value = self.peek(0);
+                    match self.heap.get_mut(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => {
+                            let slot = *slot;
+                            self.stack[slot] = value;
+                        }
+                        Obj::Upvalue(closed) => *closed = Upvalue::Closed(value),
+                        other => panic!("expected an upvalue, found {:?}", other),
+                    }
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    let receiver = self.peek(0);
+                    match receiver.as_obj().map(|obj| self.heap.get(obj)) {
+                        Some(Obj::Instance(instance)) => {
+                            let hash = self.heap.string_hash(name);
+                            if let Some(value) = instance.fields.get(name, hash) {
+                                self.pop();
+                                self.stack.push(value);
+                            } else {
+                                let class = instance.class;
+                                self.bind_method(class, name, start)?;
+                            }
+                        }
+                        Some(Obj::Module(module)) => {
+                            match module.members.get(name, self.heap.string_hash(name)) {
+                                Some(value) => {
+                                    self.pop();
+                                    self.stack.push(value);
+                                }
+                                None => {
+                                    return Err(RuntimeError::UndefinedProperty(
+                                        self.token_at(start),
+                                    ))
+                                }
+                            }
+                        }
+                        _ => {
+                            let method = match self.builtin_method(receiver, name) {
+                                Some(method) => method,
+                                None => {
+                                    return Err(RuntimeError::OnlyInstancesHaveProperties(
+                                        self.token_at(start),
+                                    ))
+                                }
+                            };
+                            // the receiver stays on the stack until the method holds it
+                            let bound =
+                                self.alloc(Obj::BuiltinMethod(BuiltinMethod { receiver, method }));
+                            self.pop();
+                            self.stack.push(Value::Obj(bound));
+                        }
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    let hash = self.heap.string_hash(name);
+                    let value = self.peek(0);
+                    match self.peek(1) {
+                        Value::Obj(obj) => match self.heap.get_mut(obj) {
+                            Obj::Instance(instance) => {
+                                instance.fields.set(name, hash, value);
+                                self.resized(obj);
+                            }
+                            _ => {
+                                return Err(RuntimeError::OnlyInstancesHaveFields(
+                                    self.token_at(start),
+                                ))
+                            }
+                        },
+                        _ => {
+                            return Err(RuntimeError::OnlyInstancesHaveFields(self.token_at(start)))
+                        }
+                    }
+                    self.pop();
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let superclass = self.pop().as_obj().expect("`super` is always a class");
+                    self.bind_method(superclass, name, start)?;
+                }
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.stack.push(Value::Bool(self.heap.values_equal(a, b)));
+                }
+                OpCode::Greater | OpCode::GreaterEqual | OpCode::Less | OpCode::LessEqual => {
+                    let (a, b) = match (self.peek(1).as_float(), self.peek(0).as_float()) {
+                        (Some(a), Some(b)) => (a, b),
+                        _ => return Err(RuntimeError::OperandsMustBeNumbers(self.token_at(start))),
+                    };
+                    self.pop();
+                    self.pop();
+                    self.stack.push(Value::Bool(match op {
+                        OpCode::Greater => a > b,
+                        OpCode::GreaterEqual => a >= b,
+                        OpCode::Less => a < b,
+                        _ => a <= b,
+                    }));
+                }
+                OpCode::Add => {
+                    let (a, b) = (self.peek(1), self.peek(0));
+                    let strings = match (a, b) {
+                        (Value::Obj(a), Value::Obj(b)) => {
+                            match (self.heap.string(a), self.heap.string(b)) {
+                                (Some(a), Some(b)) => Some(format!("{}{}", a, b)),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    let result = match strings {
+                        Some(joined) => Value::Obj(self.intern(&joined)),
+                        None => match self.arithmetic(&BinaryOp::Plus, a, b) {
+                            Some(result) => result,
+                            None => {
+                                return Err(RuntimeError::OperandsMustBeNumbersOrStrings(
+                                    self.token_at(start),
+                                ))
+                            }
+                        },
+                    };
+                    self.pop();
+                    self.pop();
+                    self.stack.push(result);
+                }
+                OpCode::Subtract | OpCode::Multiply | OpCode::Divide => {
+                    let op = match op {
+                        OpCode::Subtract => BinaryOp::Minus,
+                        OpCode::Multiply => BinaryOp::Times,
+                        _ => BinaryOp::Divide,
+                    };
+                    match self.arithmetic(&op, self.peek(1), self.peek(0)) {
+                        Some(_)
+                            if op == BinaryOp::Divide && self.peek(0).as_float() == Some(0.0) =>
+                        {
+                            return Err(RuntimeError::DivisionByZero(self.token_at(start)))
+                        }
+                        Some(result) => {
+                            self.pop();
+                            self.pop();
+                            self.stack.push(result);
+                        }
+                        None => {
+                            return Err(RuntimeError::OperandsMustBeNumbers(self.token_at(start)))
+                        }
+                    }
+                }
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Bool(!value.is_truthy()));
+                }
+                OpCode::Negate => {
+                    match self
+                        .peek(0)
+                        .to_number()
+                        .and_then(negate)
+                        .and_then(Value::from_number)
+                    {
+                        Some(result) => {
+                            self.pop();
+                            self.stack.push(result);
+                        }
+                        None => {
+                            return Err(RuntimeError::OperandMustBeNumber(self.token_at(start)))
+                        }
+                    }
+                }
+                OpCode::Print => {
+                    let value = self.pop();
+                    let _ = writeln!(self.out, "{}", self.heap.format(value));
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.frame().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if !self.peek(0).is_truthy() {
+                        self.frame().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.frame().ip -= offset;
+                }
+                OpCode::Call => {
+                    let argc = self.read_byte() as usize;
+                    let token = self.token_at(start);
+                    self.call_value(self.peek(argc), argc, &token)?;
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let argc = self.read_byte() as usize;
+                    self.invoke(name, argc, start)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let argc = self.read_byte() as usize;
+                    let superclass = self.pop().as_obj().expect("`super` is always a class");
+                    self.invoke_from_class(superclass, name, argc, start)?;
+                }
+                OpCode::Closure => {
+                    let function = self
+                        .read_constant()
+                        .as_obj()
+                        .expect("closures are made from function constants");
+                    let count = self.heap.function(function).upvalue_count;
+                    let mut upvalues = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.upvalue(index));
+                        }
+                    }
+                    let globals = self.frame().globals;
+                    let closure = self.alloc(Obj::Closure(Closure {
+                        function,
+                        upvalues,
+                        globals,
+                    }));
+                    self.stack.push(Value::Obj(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("always running a frame");
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    // a `return` inside a `try` leaves its handler behind
+                    let running = self.frames.len();
+                    while self
+                        .handlers
+                        .last()
+                        .is_some_and(|handler| handler.frames > running)
+                    {
+                        self.handlers.pop();
+                    }
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    self.stack.push(result);
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    let name = self.heap.string(name).unwrap_or_default().to_string();
+                    let class = self.alloc(Obj::Class(Class {
+                        name,
+                        methods: Table::new(),
+                    }));
+                    self.stack.push(Value::Obj(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Obj(obj) => match self.heap.get(obj) {
+                            Obj::Class(class) => Some(class.methods.clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let methods = match superclass {
+                        Some(methods) => methods,
+                        None => {
+                            return Err(RuntimeError::SuperclassMustBeClass(self.token_at(start)))
+                        }
+                    };
+                    let subclass = self.pop().as_obj().expect("inheriting into a class");
+                    if let Obj::Class(class) = self.heap.get_mut(subclass) {
+                        class.methods.add_all(&methods);
+                    }
+                    self.heap.resize(subclass);
+                }
+                OpCode::Method => {
+                    let name = self.read_string();
+                    let hash = self.heap.string_hash(name);
+                    let method = self.pop();
+                    let class = self.peek(0).as_obj().expect("methods belong to a class");
+                    if let Obj::Class(class) = self.heap.get_mut(class) {
+                        class.methods.set(name, hash, method);
+                    }
+                    self.resized(class);
+                }
+                OpCode::BuildList => {
+                    let count = self.read_byte() as usize;
+                    let first = self.stack.len() - count;
+                    // the elements stay on the stack until the list holds them
+                    let items = self.stack[first..].to_vec();
+                    let list = self.alloc(Obj::List(items));
+                    self.stack.truncate(first);
+                    self.stack.push(Value::Obj(list));
+                }
+                OpCode::BuildMap => {
+                    let count = self.read_byte() as usize;
+                    let first = self.stack.len() - count * 2;
+                    let mut map = OrderedMap::new();
+                    for entry in self.stack[first..].chunks(2) {
+                        match self.heap.map_key(entry[0]) {
+                            Some(key) => map.insert(key, entry[1]),
+                            None => return Err(RuntimeError::InvalidKey(self.token_at(start))),
+                        }
+                    }
+                    let map = self.alloc(Obj::Map(map));
+                    self.stack.truncate(first);
+                    self.stack.push(Value::Obj(map));
+                }
+                OpCode::GetIndex => {
+                    let value = self.get_index(self.peek(1), self.peek(0), start)?;
+                    self.pop();
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::SetIndex => {
+                    let value = self.peek(0);
+                    self.set_index(self.peek(2), self.peek(1), value, start)?;
+                    self.stack.truncate(self.stack.len() - 3);
+                    self.stack.push(value);
+                }
+                OpCode::Throw => {
+                    let value = self.peek(0);
+                    if self.handlers.is_empty() {
+                        return Err(RuntimeError::Thrown {
+                            token: self.token_at(start),
+                            value: self.heap.format(value),
+                        });
+                    }
+                    self.pop();
+                    self.unwind(value);
+                }
+                OpCode::PushHandler => {
+                    let offset = self.read_short();
+                    let handler = Handler {
+                        frames: self.frames.len(),
+                        stack: self.stack.len(),
+                        ip: self.frame().ip + offset,
+                    };
+                    self.handlers.push(handler);
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+            }
+        }
+    }
+
+    /// `object[index]`. Indexing a string interns the character, so the
+    /// operands have to still be on the stack.
+    fn get_index(
+        &mut self,
+        object: Value,
+        index: Value,
+        start: usize,
+    ) -> Result<Value, RuntimeError> {
+        let error = match object.as_obj().map(|obj| self.heap.get(obj)) {
+            Some(Obj::List(items)) => match check_index(index.as_int(), items.len()) {
+                Ok(idx) => return Ok(items[idx]),
+                Err(error) => error,
+            },
+            Some(Obj::Str(string)) => {
+                match check_index(index.as_int(), stdlib::char_len(&string.value)) {
+                    Ok(idx) => {
+                        let ch =
+                            stdlib::char_at(&string.value, idx as i64).expect("index was checked");
+                        return Ok(Value::Obj(self.intern(&ch)));
+                    }
+                    Err(error) => error,
+                }
+            }
+            Some(Obj::Map(map)) => match self.heap.map_key(index) {
+                Some(key) => match map.get(&key) {
+                    Some(value) => return Ok(*value),
+                    None => {
+                        return Err(RuntimeError::UndefinedKey {
+                            token: self.token_at(start),
+                            key: self.heap.repr(index),
+                        })
+                    }
+                },
+                None => RuntimeError::InvalidKey,
+            },
+            _ => RuntimeError::NotIndexable,
+        };
+        Err(error(self.token_at(start)))
+    }
+
+    /// `object[index] = value`
+    fn set_index(
+        &mut self,
+        object: Value,
+        index: Value,
+        value: Value,
+        start: usize,
+    ) -> Result<(), RuntimeError> {
+        let key = self.heap.map_key(index);
+        let error = match object.as_obj().map(|obj| self.heap.get_mut(obj)) {
+            Some(Obj::List(items)) => match check_index(index.as_int(), items.len()) {
+                Ok(idx) => {
+                    items[idx] = value;
+                    return Ok(());
+                }
+                Err(error) => error,
+            },
+            Some(Obj::Map(map)) => match key {
+                Some(key) => {
+                    map.insert(key, value);
+                    self.resized(object.as_obj().expect("maps are objects"));
+                    return Ok(());
+                }
+                None => RuntimeError::InvalidKey,
+            },
+            _ => RuntimeError::NotIndexAssignable,
+        };
+        Err(error(self.token_at(start)))
+    }
+
+    /// The method `name` of a string, list or map
+    fn builtin_method(&self, receiver: Value, name: ObjRef) -> Option<Method> {
+        let kind = match self.heap.get(receiver.as_obj()?) {
+            Obj::Str(_) => Receiver::Str,
+            Obj::List(_) => Receiver::List,
+            Obj::Map(_) => Receiver::Map,
+            _ => return None,
+        };
+        Method::lookup(kind, self.heap.string(name)?)
+    }
+
+    fn arithmetic(&self, op: &BinaryOp, a: Value, b: Value) -> Option<Value> {
+        arithmetic(op, &a.to_number()?, &b.to_number()?).and_then(Value::from_number)
+    }
+
+    fn upvalue(&mut self, idx: usize) -> ObjRef {
+        let closure = self.frame().closure;
+        self.heap.closure(closure).upvalues[idx]
+    }
+
+    /// Reuses the open upvalue for `slot` if there is one, so closures
+    /// capturing the same variable share it
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let heap = &self.heap;
+        let found =
+            self.open_upvalues
+                .binary_search_by_key(&slot, |upvalue| match heap.get(*upvalue) {
+                    Obj::Upvalue(Upvalue::Open(slot)) => *slot,
+                    other => panic!("expected an open upvalue, found {:?}", other),
+                });
+        match found {
+            Ok(idx) => self.open_upvalues[idx],
+            Err(idx) => {
+                let upvalue = self.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+                self.open_upvalues.insert(idx, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    /// Moves every variable from stack slot `last` upwards off the stack and
+    /// into its upvalue
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last().copied() {
+            let slot = match self.heap.get(upvalue) {
+                Obj::Upvalue(Upvalue::Open(slot)) => *slot,
+                other => panic!("expected an open upvalue, found {:?}", other),
+            };
+            if slot < last {
+                break;
+            }
+            *self.heap.get_mut(upvalue) = Obj::Upvalue(Upvalue::Closed(self.stack[slot]));
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn call_value(
+        &mut self,
+        callee: Value,
+        argc: usize,
+        token: &Token,
+    ) -> Result<(), RuntimeError> {
+        let obj = match callee {
+            Value::Obj(obj) => obj,
+            _ => return Err(RuntimeError::NotCallable(token.clone())),
+        };
+        match self.heap.get(obj) {
+            Obj::Closure(_) => self.call(obj, argc, token),
+            Obj::BoundMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                let callee_slot = self.stack.len() - argc - 1;
+                self.stack[callee_slot] = receiver;
+                self.call(method, argc, token)
+            }
+            Obj::Class(class) => {
+                let init = class
+                    .methods
+                    .get(self.init_string, self.heap.string_hash(self.init_string))
+                    .and_then(|method| method.as_obj());
+                let instance = self.alloc(Obj::Instance(Instance {
+                    class: obj,
+                    fields: Table::new(),
+                }));
+                let callee_slot = self.stack.len() - argc - 1;
+                self.stack[callee_slot] = Value::Obj(instance);
+                match init {
+                    Some(init) => self.call(init, argc, token),
+                    None if argc != 0 => Err(RuntimeError::ArityMismatch {
+                        token: token.clone(),
+                        expected: 0,
+                        got: argc,
+                    }),
+                    None => Ok(()),
+                }
+            }
+            Obj::Native(native) => {
+                let native = native.clone();
+                self.call_native(&native, argc, token)
+            }
+            Obj::BuiltinMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                self.call_builtin(receiver, method, argc, token)
+            }
+            _ => Err(RuntimeError::NotCallable(token.clone())),
+        }
+    }
+
+    fn call(&mut self, closure: ObjRef, argc: usize, token: &Token) -> Result<(), RuntimeError> {
+        let globals = self.heap.closure(closure).globals;
+        let function = self.heap.function(self.heap.closure(closure).function);
+        if argc != function.arity {
+            return Err(RuntimeError::ArityMismatch {
+                token: token.clone(),
+                expected: function.arity,
+                got: argc,
+            });
+        }
+        if self.frames.len() > MAX_CALL_DEPTH {
+            return Err(RuntimeError::StackOverflow(token.clone()));
+        }
+        let chunk = function.chunk.clone();
+        self.frames.push(CallFrame {
+            closure,
+            chunk,
+            ip: 0,
+            slots: self.stack.len() - argc - 1,
+            globals,
+        });
+        Ok(())
+    }
+
+    fn call_native(
+        &mut self,
+        native: &NativeFunction,
+        argc: usize,
+        token: &Token,
+    ) -> Result<(), RuntimeError> {
+        if argc != native.arity {
+            return Err(RuntimeError::ArityMismatch {
+                token: token.clone(),
+                expected: native.arity,
+                got: argc,
+            });
+        }
+        let native_error = |msg: String| RuntimeError::Native {
+            token: token.clone(),
+            msg,
+        };
+        let args = self.stack[self.stack.len() - argc..]
+            .iter()
+            .map(|arg| self.to_host(*arg))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| {
+                native_error(
+                    "Native functions only take numbers, strings, booleans, nil, lists and maps."
+                        .to_string(),
+                )
+            })?;
+        let result = (native.func)(&args).map_err(native_error)?;
+        let result = self.host_value(result).ok_or_else(|| {
+            native_error("Native function returned a value the VM cannot hold.".to_string())
+        })?;
+        self.stack.truncate(self.stack.len() - argc - 1);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// Calls a method of a string, list or map. The receiver and the
+    /// arguments stay on the stack until the result replaces them.
+    fn call_builtin(
+        &mut self,
+        receiver: Value,
+        method: Method,
+        argc: usize,
+        token: &Token,
+    ) -> Result<(), RuntimeError> {
+        if argc != method.arity() {
+            return Err(RuntimeError::ArityMismatch {
+                token: token.clone(),
+                expected: method.arity(),
+                got: argc,
+            });
+        }
+        let args = self.stack[self.stack.len() - argc..].to_vec();
+        let result = self
+            .builtin_result(receiver, method, &args)
+            .map_err(|msg| RuntimeError::Native {
+                token: token.clone(),
+                msg,
+            })?;
+        self.stack.truncate(self.stack.len() - argc - 1);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// String methods are shared with the tree-walker, list and map methods
+    /// work on the VM's own objects
+    fn builtin_result(
+        &mut self,
+        receiver: Value,
+        method: Method,
+        args: &[Value],
+    ) -> Result<Value, String> {
+        use crate::value::Value as Host;
+        let obj = receiver
+            .as_obj()
+            .expect("builtin methods have object receivers");
+        if let Some(string) = self.heap.string(obj) {
+            let receiver = Host::Str(string.to_string());
+            // an argument the host cannot hold is the wrong type either way
+            let args: Vec<Host> = args
+                .iter()
+                .map(|arg| self.to_host(*arg).unwrap_or(Host::Nil))
+                .collect();
+            let result = stdlib::call_method(&receiver, method, &args)?;
+            return Ok(self
+                .host_value(result)
+                .expect("string methods return plain values"));
+        }
+        let name = method.name();
+        let key = args.first().and_then(|arg| self.heap.map_key(*arg));
+        let values = match (self.heap.get_mut(obj), method) {
+            (Obj::List(items), Method::Len) => return Ok(Value::Int(items.len() as i64)),
+            (Obj::List(items), Method::Push) => {
+                items.push(args[0]);
+                self.resized(obj);
+                return Ok(Value::Nil);
+            }
+            (Obj::List(items), Method::Pop) => {
+                return items.pop().ok_or_else(|| stdlib::EMPTY_LIST.to_string())
+            }
+            (Obj::Map(map), Method::Len) => return Ok(Value::Int(map.len() as i64)),
+            (Obj::Map(map), Method::Has) => {
+                let key = key.ok_or_else(|| stdlib::arg_error(name, 0, stdlib::KEY_TYPES))?;
+                return Ok(Value::Bool(map.contains_key(&key)));
+            }
+            (Obj::Map(map), Method::Remove) => {
+                let key = key.ok_or_else(|| stdlib::arg_error(name, 0, stdlib::KEY_TYPES))?;
+                let removed = map.remove(&key).unwrap_or(Value::Nil);
+                self.heap.resize(obj);
+                return Ok(removed);
+            }
+            (Obj::Map(map), Method::Keys) => map.iter().map(|(key, _)| key.to_value()).collect(),
+            (Obj::Map(map), Method::Values) => map.iter().map(|(_, value)| *value).collect(),
+            _ => unreachable!("methods are looked up for their receiver"),
+        };
+        // everything in `values` is still reachable through the receiver
+        Ok(Value::Obj(self.alloc(Obj::List(values))))
+    }
+
+    fn invoke(&mut self, name: ObjRef, argc: usize, start: usize) -> Result<(), RuntimeError> {
+        let receiver = self.peek(argc);
+        let instance = match receiver.as_obj().map(|obj| self.heap.get(obj)) {
+            Some(Obj::Instance(instance)) => instance,
+            Some(Obj::Module(module)) => {
+                let member = match module.members.get(name, self.heap.string_hash(name)) {
+                    Some(member) => member,
+                    None => return Err(RuntimeError::UndefinedProperty(self.token_at(start))),
+                };
+                let callee_slot = self.stack.len() - argc - 1;
+                self.stack[callee_slot] = member;
+                let token = self.token_at(start + 2);
+                return self.call_value(member, argc, &token);
+            }
+            _ => match self.builtin_method(receiver, name) {
+                Some(method) => {
+                    let token = self.token_at(start + 2);
+                    return self.call_builtin(receiver, method, argc, &token);
+                }
+                None => {
+                    return Err(RuntimeError::OnlyInstancesHaveProperties(
+                        self.token_at(start),
+                    ))
+                }
+            },
+        };
+        // a field holding a function shadows a method of the same name
+        if let Some(field) = instance.fields.get(name, self.heap.string_hash(name)) {
+            let callee_slot = self.stack.len() - argc - 1;
+            self.stack[callee_slot] = field;
+            let token = self.token_at(start + 2);
+            return self.call_value(field, argc, &token);
+        }
+        let class = instance.class;
+        self.invoke_from_class(class, name, argc, start)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        argc: usize,
+        start: usize,
+    ) -> Result<(), RuntimeError> {
+        let method = match self.heap.get(class) {
+            Obj::Class(class) => class
+                .methods
+                .get(name, self.heap.string_hash(name))
+                .and_then(|method| method.as_obj()),
+            _ => None,
+        };
+        match method {
+            Some(method) => {
+                let token = self.token_at(start + 2);
+                self.call(method, argc, &token)
+            }
+            None => Err(RuntimeError::UndefinedProperty(self.token_at(start))),
+        }
+    }
+
+    /// Replaces the instance on top of the stack with its method `name`
+    /// bound to it
+    fn bind_method(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        start: usize,
+    ) -> Result<(), RuntimeError> {
+        let method = match self.heap.get(class) {
+            Obj::Class(class) => class
+                .methods
+                .get(name, self.heap.string_hash(name))
+                .and_then(|method| method.as_obj()),
+            _ => None,
+        };
+        let method = match method {
+            Some(method) => method,
+            None => return Err(RuntimeError::UndefinedProperty(self.token_at(start))),
+        };
+        // the receiver stays on the stack until the bound method holds it
+        let receiver = self.peek(0);
+        let bound = self.alloc(Obj::BoundMethod(BoundMethod { receiver, method }));
+        self.pop();
+        self.stack.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    fn to_host(&self, value: Value) -> Option<crate::value::Value> {
+        self.copy_to_host(value, &mut Vec::new())
+    }
+
+    /// Lists and maps are copied. `seen` holds the ones being copied, one
+    /// that contains itself cannot be.
+    fn copy_to_host(&self, value: Value, seen: &mut Vec<ObjRef>) -> Option<crate::value::Value> {
+        use crate::value::{Key, Value as Host};
+        Some(match value {
+            Value::Nil => Host::Nil,
+            Value::Bool(val) => Host::Bool(val),
+            Value::Int(val) => Host::Int(val),
+            Value::Float(val) => Host::Float(val),
+            Value::Obj(obj) if seen.contains(&obj) => return None,
+            Value::Obj(obj) => match self.heap.get(obj) {
+                Obj::Str(string) => Host::Str(string.value.clone()),
+                Obj::List(items) => {
+                    seen.push(obj);
+                    let items = items
+                        .iter()
+                        .map(|item| self.copy_to_host(*item, seen))
+                        .collect::<Option<Vec<_>>>();
+                    seen.pop();
+                    Host::list(items?)
+                }
+                Obj::Map(map) => {
+                    seen.push(obj);
+                    let mut copy = OrderedMap::new();
+                    for (key, value) in map.iter() {
+                        let key = Key::from_value(&self.copy_to_host(key.to_value(), seen)?)?;
+                        copy.insert(key, self.copy_to_host(*value, seen)?);
+                    }
+                    seen.pop();
+                    Host::Map(Rc::new(RefCell::new(copy)))
+                }
+                _ => return None,
+            },
+        })
+    }
+
+    fn host_value(&mut self, value: crate::value::Value) -> Option<Value> {
+        use crate::value::Value as Host;
+        Some(match value {
+            Host::Nil => Value::Nil,
+            Host::Bool(val) => Value::Bool(val),
+            Host::Int(val) => Value::Int(val),
+            Host::Float(val) => Value::Float(val),
+            Host::Str(val) => Value::Obj(self.intern(&val)),
+            Host::Native(native) => Value::Obj(self.alloc(Obj::Native(native))),
+            Host::List(items) => {
+                let entries = items
+                    .borrow()
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, item)| (Host::Int(idx as i64), item.clone()))
+                    .collect();
+                return self.build_object(Obj::List(Vec::new()), entries);
+            }
+            Host::Map(map) => {
+                let entries = map
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| (key.to_value(), value.clone()))
+                    .collect();
+                return self.build_object(Obj::Map(OrderedMap::new()), entries);
+            }
+            Host::Module(module) => {
+                let entries = module
+                    .members
+                    .iter()
+                    .map(|(name, value)| (Host::Str(name.clone()), value.clone()))
+                    .collect();
+                let module = Module {
+                    name: module.name.clone(),
+                    members: Table::new(),
+                };
+                return self.build_object(Obj::Module(module), entries);
+            }
+            Host::Function(_) | Host::Class(_) | Host::Instance(_) => return None,
+        })
+    }
+
+    /// Allocates an empty list, map or module and fills it with `entries`
+    /// converted from the host. Lists ignore the keys, which are their
+    /// indices. Whatever is converted stays on the stack until the object
+    /// holds it.
+    fn build_object(
+        &mut self,
+        obj: Obj,
+        entries: Vec<(crate::value::Value, crate::value::Value)>,
+    ) -> Option<Value> {
+        let obj = self.alloc(obj);
+        self.stack.push(Value::Obj(obj));
+        let base = self.stack.len();
+        let mut filled = Some(Value::Obj(obj));
+        for (key, value) in entries.into_iter() {
+            let converted = self.host_value(key).and_then(|key| {
+                self.stack.push(key);
+                self.host_value(value).map(|value| (key, value))
+            });
+            self.stack.truncate(base);
+            let (key, value) = match converted {
+                Some(entry) => entry,
+                None => {
+                    filled = None;
+                    break;
+                }
+            };
+            let map_key = self.heap.map_key(key);
+            let hash = key.as_obj().map(|name| self.heap.string_hash(name));
+            match self.heap.get_mut(obj) {
+                Obj::List(items) => items.push(value),
+                Obj::Map(map) => map.insert(map_key.expect("host keys are valid keys"), value),
+                Obj::Module(module) => {
+                    let name = key.as_obj().expect("module members are named");
+                    module
+                        .members
+                        .set(name, hash.expect("names are strings"), value);
+                }
+                other => panic!("expected a list, map or module, found {:?}", other),
+            }
+            self.heap.resize(obj);
+        }
+        self.stack.pop();
+        filled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    /// Runs `program` and renders the global `name` afterwards
+    fn run_and_get(program: &str, name: &str) -> Result<String, String> {
+        let tokens: Vec<Token> = Lexer::new(program).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        let mut vm = Vm::new();
+        let function = vm.compile(&program).unwrap();
+        vm.run(function)
+            .map_err(|err| format!("{} [line {}]", err, err.token().line + 1))?;
+        Ok(global(&mut vm, name))
+    }
+
+    /// The global `name` rendered the way `print` shows it
+    fn global(vm: &mut Vm, name: &str) -> String {
+        let name = vm.heap.intern(name);
+        let hash = vm.heap.string_hash(name);
+        vm.heap
+            .format(vm.globals.get(name, hash).expect("global is defined"))
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let tests = [
+            ("var x = 1 + 2;", "3"),
+            ("var x = 3 / 2;", "1.5"),
+            ("var x = 2147483647 + 1;", "2147483648"),
+            ("var x = -0 == 0;", "true"),
+            ("var x = \"a\" + \"b\" == \"ab\";", "true"),
+            ("var x = 1 >= 1.0 and 2 <= 1;", "false"),
+            ("var x = nil or \"default\";", "default"),
+        ];
+        for (program, expected) in tests.iter() {
+            assert_eq!(run_and_get(program, "x"), Ok(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_closures() {
+        let program = "
+            fun makeCounter() {
+                var i = 0;
+                fun count() { i = i + 1; return i; }
+                return count;
+            }
+            var counter = makeCounter();
+            counter();
+            var x = counter();
+        ";
+        assert_eq!(run_and_get(program, "x"), Ok("2".to_string()));
+
+        // both closures see the same variable once it leaves the stack
+        let program = "
+            var get; var set;
+            { var a = 1; fun g() { return a; } fun s(v) { a = v; } get = g; set = s; }
+            set(5);
+            var x = get();
+        ";
+        assert_eq!(run_and_get(program, "x"), Ok("5".to_string()));
+    }
+
+    #[test]
+    fn test_classes() {
+        let program = "
+            class A {
+                init(n) { this.n = n; }
+                get() { return this.n; }
+            }
+            class B < A {
+                init(n) { super.init(n * 2); }
+                get() { return super.get() + 1; }
+            }
+            var b = B(5);
+            var method = b.get;
+            var x = method() + b.get();
+        ";
+        assert_eq!(run_and_get(program, "x"), Ok("22".to_string()));
+        assert_eq!(run_and_get(program, "b"), Ok("B instance".to_string()));
+        assert_eq!(run_and_get(program, "method"), Ok("<fn get>".to_string()));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let tests = [
+            ("var x = -\"a\";", "Operand must be a number. [line 1]"),
+            ("var x = 1;\nx = y;", "Undefined variable 'y'. [line 2]"),
+            ("class A {}\nA().f();", "Undefined property 'f'. [line 2]"),
+            (
+                "fun f(a) {}\nf();",
+                "Expected 1 arguments but got 0. [line 2]",
+            ),
+            (
+                "var x = 1;\nx.y = 2;",
+                "Only instances have fields. [line 2]",
+            ),
+            ("fun f() { f(); }\nf();", "Stack overflow. [line 1]"),
+        ];
+        for (program, expected) in tests.iter() {
+            assert_eq!(run_and_get(program, "x"), Err(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_gc() {
+        let program = "
+            class Node { init(next) { this.next = next; this.self = this; } }
+            fun churn() {
+                var list = nil;
+                for (var i = 0; i < 100; i = i + 1) list = Node(list);
+                var s = \"\";
+                for (var i = 0; i < 10; i = i + 1) s = s + \"x\";
+                return s;
+            }
+            var x;
+            for (var i = 0; i < 10; i = i + 1) x = churn();
+        ";
+        let tokens: Vec<Token> = Lexer::new(program).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        let mut vm = Vm::new();
+        vm.set_gc_options(true, false);
+        let function = vm.compile(&program).unwrap();
+        vm.run(function).unwrap();
+        assert_eq!(global(&mut vm, "x"), "xxxxxxxxxx");
+        assert!(vm.gc_stats().collections > 1000);
+
+        // only the globals and what they reach survive, cycles included, so
+        // running more code leaves the heap the same size
+        vm.collect_garbage();
+        let live = vm.heap.live_objects();
+        let tokens: Vec<Token> = Lexer::new("x = churn();").collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        let function = vm.compile(&program).unwrap();
+        vm.run(function).unwrap();
+        vm.collect_garbage();
+        assert_eq!(vm.heap.live_objects(), live);
+    }
+}
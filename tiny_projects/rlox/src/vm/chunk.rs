@@ -0,0 +1,224 @@
+use super::value::Value;
+use crate::lexer::Token;
+
+/// One byte instructions, some followed by operands. Operand layouts are
+/// noted next to each opcode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum OpCode {
+    /// constant index
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    /// stack slot
+    GetLocal,
+    /// stack slot
+    SetLocal,
+    /// name constant
+    GetGlobal,
+    /// name constant
+    DefineGlobal,
+    /// name constant
+    SetGlobal,
+    /// upvalue index
+    GetUpvalue,
+    /// upvalue index
+    SetUpvalue,
+    /// name constant
+    GetProperty,
+    /// name constant
+    SetProperty,
+    /// name constant
+    GetSuper,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    /// 16 bit forward offset
+    Jump,
+    /// 16 bit forward offset
+    JumpIfFalse,
+    /// 16 bit backward offset
+    Loop,
+    /// argument count
+    Call,
+    /// name constant, argument count
+    Invoke,
+    /// name constant, argument count
+    SuperInvoke,
+    /// function constant, then an (is_local, index) pair per upvalue
+    Closure,
+    CloseUpvalue,
+    Return,
+    /// name constant
+    Class,
+    Inherit,
+    /// name constant
+    Method,
+    /// element count
+    BuildList,
+    /// entry count, the keys and values are interleaved on the stack
+    BuildMap,
+    GetIndex,
+    SetIndex,
+    Throw,
+    /// 16 bit forward offset to the handler
+    PushHandler,
+    PopHandler,
+}
+
+impl OpCode {
+    const ALL: [OpCode; 46] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Invoke,
+        OpCode::SuperInvoke,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+        OpCode::BuildList,
+        OpCode::BuildMap,
+        OpCode::GetIndex,
+        OpCode::SetIndex,
+        OpCode::Throw,
+        OpCode::PushHandler,
+        OpCode::PopHandler,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        Self::ALL.get(byte as usize).copied()
+    }
+}
+
+/// A compiled function body: the bytecode, the constants it refers to and
+/// where in the source every byte came from
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Value>,
+    /// Run-length encoded: each entry is the offset of the first byte
+    /// compiled from that token
+    tokens: Vec<(usize, Token)>,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, byte: u8, token: &Token) {
+        let same_token = matches!(
+            self.tokens.last(),
+            Some((_, last)) if last.span == token.span && last.line == token.line
+        );
+        if !same_token {
+            self.tokens.push((self.code.len(), token.clone()));
+        }
+        self.code.push(byte);
+    }
+
+    pub fn write_op(&mut self, op: OpCode, token: &Token) {
+        self.write(op as u8, token);
+    }
+
+    /// Adds `value` to the constant pool and returns its index. Constants
+    /// are not deduplicated.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    /// The token the byte at `offset` was compiled from
+    pub fn token(&self, offset: usize) -> &Token {
+        let idx = self.tokens.partition_point(|(start, _)| *start <= offset);
+        &self.tokens[idx.saturating_sub(1)].1
+    }
+
+    pub fn line(&self, offset: usize) -> i32 {
+        self.token(offset).line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Span, Type};
+
+    #[test]
+    fn test_opcode_round_trip() {
+        for (byte, op) in OpCode::ALL.iter().enumerate() {
+            assert_eq!(*op as u8 as usize, byte);
+            assert_eq!(OpCode::from_byte(byte as u8), Some(*op));
+        }
+        assert_eq!(OpCode::from_byte(OpCode::ALL.len() as u8), None);
+    }
+
+    #[test]
+    fn test_token_table() {
+        let mut first = Token::new(Type::Nil, 0);
+        first.span = Span {
+            start: 0,
+            end: 3,
+            column: 0,
+        };
+        let mut second = Token::new(Type::Print, 1);
+        second.span = Span {
+            start: 4,
+            end: 9,
+            column: 0,
+        };
+
+        let mut chunk = Chunk::new();
+        chunk.write_op(OpCode::Nil, &first);
+        chunk.write_op(OpCode::Pop, &first);
+        chunk.write_op(OpCode::Nil, &second);
+        chunk.write_op(OpCode::Print, &second);
+        assert_eq!(chunk.tokens.len(), 2);
+        assert_eq!(
+            (0..4).map(|offset| chunk.line(offset)).collect::<Vec<_>>(),
+            vec![0, 0, 1, 1]
+        );
+    }
+}
@@ -0,0 +1,755 @@
+use super::chunk::{Chunk, OpCode};
+use super::object::{Function, Heap, Obj, ObjRef};
+use super::value::Value;
+use crate::error::CompileError;
+use crate::grammar::*;
+use crate::lexer::{Token, Type};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Operands are a single byte, so this is also the limit on constants,
+/// locals and upvalues per function
+const MAX_OPERAND: usize = u8::MAX as usize + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    is_captured: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct UpvalueRef {
+    index: u8,
+    is_local: bool,
+}
+
+/// Book keeping for the function currently being compiled
+struct FunctionState {
+    kind: FunctionKind,
+    name: Option<String>,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(kind: FunctionKind, name: Option<String>) -> Self {
+        // slot zero holds the callee, or `this` inside methods
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            _ => "",
+        };
+        Self {
+            kind,
+            name,
+            arity: 0,
+            chunk: Chunk::new(),
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: 0,
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<u8> {
+        self.locals
+            .iter()
+            .rposition(|local| local.name == name)
+            .map(|slot| slot as u8)
+    }
+}
+
+/// Turns a resolved syntax tree into bytecode. Programs given to the
+/// compiler must have passed the resolver, so scoping mistakes are not
+/// checked again here.
+pub struct Compiler<'h> {
+    heap: &'h mut Heap,
+    /// The module each `import` loaded
+    imports: &'h HashMap<ExprId, ObjRef>,
+    /// Innermost function last
+    functions: Vec<FunctionState>,
+    errors: Vec<CompileError>,
+    /// The token instructions are currently attributed to
+    token: Token,
+}
+
+pub fn compile(
+    program: &[Stmt],
+    heap: &mut Heap,
+    imports: &HashMap<ExprId, ObjRef>,
+) -> Result<ObjRef, Vec<CompileError>> {
+    let mut compiler = Compiler {
+        heap,
+        imports,
+        functions: vec![FunctionState::new(FunctionKind::Script, None)],
+        errors: Vec::new(),
+        token: Token::new(Type::Eof, 0),
+    };
+    crate::ensure_stack(|| {
+        for stmt in program.iter() {
+            compiler.statement(stmt);
+        }
+    });
+    let function = compiler.end_function();
+    if compiler.errors.is_empty() {
+        Ok(compiler.heap.alloc(Obj::Function(function)))
+    } else {
+        Err(compiler.errors)
+    }
+}
+
+impl Compiler<'_> {
+    fn state(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("always compiling a function")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().chunk
+    }
+
+    fn emit(&mut self, byte: u8) {
+        let token = self.token.clone();
+        self.chunk().write(byte, &token);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit(op as u8);
+    }
+
+    fn emit_op_arg(&mut self, op: OpCode, arg: u8) {
+        self.emit_op(op);
+        self.emit(arg);
+    }
+
+    /// A loop that is too large also overflows the jump out of it, only the
+    /// first error at a token is worth reporting
+    fn error(&mut self, err: CompileError) {
+        let repeated = matches!(
+            self.errors.last(),
+            Some(last) if last.token().span == err.token().span
+        );
+        if !repeated {
+            self.errors.push(err);
+        }
+    }
+
+    fn make_constant(&mut self, value: Value) -> u8 {
+        let idx = self.chunk().add_constant(value);
+        if idx >= MAX_OPERAND {
+            self.error(CompileError::TooManyConstants(self.token.clone()));
+            return 0;
+        }
+        idx as u8
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> u8 {
+        let name = self.heap.intern(name);
+        self.make_constant(Value::Obj(name))
+    }
+
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op(op);
+        self.emit(0xff);
+        self.emit(0xff);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to skip over the jump offset itself
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error(CompileError::JumpTooLarge(self.token.clone()));
+        }
+        let code = &mut self.chunk().code;
+        code[offset] = (jump >> 8) as u8;
+        code[offset + 1] = jump as u8;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error(CompileError::LoopTooLarge(self.token.clone()));
+        }
+        self.emit((offset >> 8) as u8);
+        self.emit(offset as u8);
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_op_arg(OpCode::GetLocal, 0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
+    }
+
+    fn end_function(&mut self) -> Function {
+        self.emit_return();
+        let state = self.functions.pop().expect("always compiling a function");
+        Function {
+            name: state.name,
+            arity: state.arity,
+            upvalue_count: state.upvalues.len(),
+            chunk: Rc::new(state.chunk),
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+        while let Some(local) = self.state().locals.last() {
+            if local.depth <= depth {
+                break;
+            }
+            let op = if local.is_captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            self.emit_op(op);
+            self.state().locals.pop();
+        }
+    }
+
+    fn add_local(&mut self, name: &Token) {
+        if self.state().locals.len() >= MAX_OPERAND {
+            self.error(CompileError::TooManyLocals(name.clone()));
+            return;
+        }
+        let state = self.state();
+        let depth = state.scope_depth;
+        state.locals.push(Local {
+            name: name.name().to_string(),
+            depth,
+            is_captured: false,
+        });
+    }
+
+    /// Emits the code that binds the value on top of the stack to `name`.
+    /// Locals just stay where they are on the stack.
+    fn define_variable(&mut self, name: &Token) {
+        if self.state().scope_depth > 0 {
+            self.add_local(name);
+        } else {
+            let global = self.identifier_constant(name.name());
+            self.emit_op_arg(OpCode::DefineGlobal, global);
+        }
+    }
+
+    fn resolve_upvalue(&mut self, function: usize, name: &str) -> Option<u8> {
+        if function == 0 {
+            return None;
+        }
+        if let Some(local) = self.functions[function - 1].resolve_local(name) {
+            self.functions[function - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(function, local, true));
+        }
+        let upvalue = self.resolve_upvalue(function - 1, name)?;
+        Some(self.add_upvalue(function, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, function: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = UpvalueRef { index, is_local };
+        let upvalues = &mut self.functions[function].upvalues;
+        if let Some(existing) = upvalues.iter().position(|known| *known == upvalue) {
+            return existing as u8;
+        }
+        if upvalues.len() >= MAX_OPERAND {
+            self.error(CompileError::TooManyUpvalues(self.token.clone()));
+            return 0;
+        }
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    /// Loads or, when `assign` is set, stores the variable called `name`
+    fn named_variable(&mut self, name: &str, assign: bool) {
+        let innermost = self.functions.len() - 1;
+        let (get, set, arg) = if let Some(slot) = self.state().resolve_local(name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(upvalue) = self.resolve_upvalue(innermost, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, upvalue)
+        } else {
+            let global = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, global)
+        };
+        self.emit_op_arg(if assign { set } else { get }, arg);
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                self.emit_op(OpCode::Pop);
+            }
+            Stmt::Print(expr) => {
+                self.expression(expr);
+                self.emit_op(OpCode::Print);
+            }
+            Stmt::Var(name, _, initializer) => {
+                match initializer {
+                    Some(expr) => self.expression(expr),
+                    None => {
+                        self.token = name.clone();
+                        self.emit_op(OpCode::Nil);
+                    }
+                }
+                self.token = name.clone();
+                self.define_variable(name);
+            }
+            Stmt::Block(stmts) => {
+                self.begin_scope();
+                for stmt in stmts.iter() {
+                    self.statement(stmt);
+                }
+                self.end_scope();
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch);
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit_op(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            Stmt::While(condition, body, end) => {
+                let loop_start = self.chunk().code.len();
+                self.expression(condition);
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(body);
+                self.token = end.clone();
+                self.emit_loop(loop_start);
+                self.patch_jump(exit_jump);
+                self.emit_op(OpCode::Pop);
+            }
+            Stmt::Function(decl) => {
+                self.token = decl.name.clone();
+                // bound before the body so the function can call itself
+                if self.state().scope_depth > 0 {
+                    self.add_local(&decl.name);
+                }
+                self.function(decl, FunctionKind::Function);
+                if self.state().scope_depth == 0 {
+                    self.define_variable(&decl.name);
+                }
+            }
+            Stmt::Return(keyword, value) => {
+                self.token = keyword.clone();
+                match value {
+                    Some(expr) => {
+                        self.expression(expr);
+                        self.token = keyword.clone();
+                        self.emit_op(OpCode::Return);
+                    }
+                    None => self.emit_return(),
+                }
+            }
+            Stmt::Throw(keyword, value) => {
+                self.expression(value);
+                self.token = keyword.clone();
+                self.emit_op(OpCode::Throw);
+            }
+            Stmt::Try(keyword, body, name, handler) => {
+                self.token = keyword.clone();
+                let handler_jump = self.emit_jump(OpCode::PushHandler);
+                self.begin_scope();
+                for stmt in body.iter() {
+                    self.statement(stmt);
+                }
+                self.end_scope();
+                self.token = keyword.clone();
+                self.emit_op(OpCode::PopHandler);
+                let end_jump = self.emit_jump(OpCode::Jump);
+
+                // the VM leaves the error on top of the stack, right where
+                // the local for it goes
+                self.patch_jump(handler_jump);
+                self.begin_scope();
+                self.token = name.clone();
+                self.add_local(name);
+                for stmt in handler.iter() {
+                    self.statement(stmt);
+                }
+                self.end_scope();
+                self.patch_jump(end_jump);
+            }
+            Stmt::Class(decl) => self.class(decl),
+            Stmt::Import(_, name, id) => {
+                self.token = name.clone();
+                let module = self.imports[id];
+                let constant = self.make_constant(Value::Obj(module));
+                self.emit_op_arg(OpCode::Constant, constant);
+                self.define_variable(name);
+            }
+        }
+    }
+
+    fn function(&mut self, decl: &FunctionDecl, kind: FunctionKind) {
+        self.functions
+            .push(FunctionState::new(kind, Some(decl.name.name().to_string())));
+        self.begin_scope();
+        for param in decl.params.iter() {
+            self.state().arity += 1;
+            self.token = param.clone();
+            self.add_local(param);
+        }
+        for stmt in decl.body.iter() {
+            self.statement(stmt);
+        }
+        // no need to end the scope, returning discards the whole frame
+        let upvalues = self
+            .functions
+            .last()
+            .map_or(Vec::new(), |state| state.upvalues.clone());
+        let function = self.end_function();
+
+        self.token = decl.name.clone();
+        let function = Value::Obj(self.heap.alloc(Obj::Function(function)));
+        let constant = self.make_constant(function);
+        self.emit_op_arg(OpCode::Closure, constant);
+        for upvalue in upvalues {
+            self.emit(upvalue.is_local as u8);
+            self.emit(upvalue.index);
+        }
+    }
+
+    fn class(&mut self, decl: &ClassDecl) {
+        self.token = decl.name.clone();
+        let name = self.identifier_constant(decl.name.name());
+        if self.state().scope_depth > 0 {
+            self.add_local(&decl.name);
+        }
+        self.emit_op_arg(OpCode::Class, name);
+        if self.state().scope_depth == 0 {
+            self.define_variable(&decl.name);
+        }
+
+        if let Some(superclass) = &decl.superclass {
+            self.expression(superclass);
+            // methods find the superclass through a local called `super`
+            self.begin_scope();
+            self.add_local(&Token::new(
+                Type::Identifier {
+                    name: "super".to_string(),
+                },
+                decl.name.line,
+            ));
+            self.token = decl.name.clone();
+            self.named_variable(decl.name.name(), false);
+            if let Expr::Variable(token, _) = superclass {
+                self.token = token.clone();
+            }
+            self.emit_op(OpCode::Inherit);
+        }
+
+        self.token = decl.name.clone();
+        self.named_variable(decl.name.name(), false);
+        for method in decl.methods.iter() {
+            let kind = if method.name.name() == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.function(method, kind);
+            let name = self.identifier_constant(method.name.name());
+            self.emit_op_arg(OpCode::Method, name);
+        }
+        self.emit_op(OpCode::Pop);
+
+        if decl.superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(lit, token) => {
+                self.token = token.clone();
+                match lit {
+                    Literal::Nil => self.emit_op(OpCode::Nil),
+                    Literal::True => self.emit_op(OpCode::True),
+                    Literal::False => self.emit_op(OpCode::False),
+                    Literal::Int(val) => self.constant(Value::Int(*val)),
+                    Literal::Float(val) => self.constant(Value::Float(*val)),
+                    Literal::String(val) => {
+                        let string = self.heap.intern(val);
+                        self.constant(Value::Obj(string));
+                    }
+                }
+            }
+            Expr::UnaryExpr(op, token, operand) => {
+                self.expression(operand);
+                self.token = token.clone();
+                self.emit_op(match op {
+                    UnaryOp::Negative => OpCode::Negate,
+                    UnaryOp::Not => OpCode::Not,
+                });
+            }
+            Expr::BinaryExpr(left, op, token, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.token = token.clone();
+                match op {
+                    BinaryOp::Eq => self.emit_op(OpCode::Equal),
+                    BinaryOp::Ne => {
+                        self.emit_op(OpCode::Equal);
+                        self.emit_op(OpCode::Not);
+                    }
+                    BinaryOp::Gt => self.emit_op(OpCode::Greater),
+                    BinaryOp::Ge => self.emit_op(OpCode::GreaterEqual),
+                    BinaryOp::Lt => self.emit_op(OpCode::Less),
+                    BinaryOp::Le => self.emit_op(OpCode::LessEqual),
+                    BinaryOp::Plus => self.emit_op(OpCode::Add),
+                    BinaryOp::Minus => self.emit_op(OpCode::Subtract),
+                    BinaryOp::Times => self.emit_op(OpCode::Multiply),
+                    BinaryOp::Divide => self.emit_op(OpCode::Divide),
+                }
+            }
+            Expr::Grouping(inner) => self.expression(inner),
+            Expr::Variable(name, _) => {
+                self.token = name.clone();
+                self.named_variable(name.name(), false);
+            }
+            Expr::Assign(name, value, _) => {
+                self.expression(value);
+                self.token = name.clone();
+                self.named_variable(name.name(), true);
+            }
+            Expr::Logical(left, op, right) => {
+                self.expression(left);
+                match op {
+                    LogicalOp::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.emit_op(OpCode::Pop);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    }
+                    LogicalOp::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        let end_jump = self.emit_jump(OpCode::Jump);
+                        self.patch_jump(else_jump);
+                        self.emit_op(OpCode::Pop);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    }
+                }
+            }
+            Expr::Call(callee, paren, args) => self.call(callee, paren, args),
+            Expr::Get(object, name) => {
+                self.expression(object);
+                self.token = name.clone();
+                let name = self.identifier_constant(name.name());
+                self.emit_op_arg(OpCode::GetProperty, name);
+            }
+            Expr::Set(object, name, value) => {
+                self.expression(object);
+                self.expression(value);
+                self.token = name.clone();
+                let name = self.identifier_constant(name.name());
+                self.emit_op_arg(OpCode::SetProperty, name);
+            }
+            Expr::This(keyword, _) => {
+                self.token = keyword.clone();
+                self.named_variable("this", false);
+            }
+            Expr::Super(keyword, method, _) => {
+                self.token = keyword.clone();
+                self.named_variable("this", false);
+                self.named_variable("super", false);
+                self.token = method.clone();
+                let name = self.identifier_constant(method.name());
+                self.emit_op_arg(OpCode::GetSuper, name);
+            }
+            Expr::List(bracket, elements) => {
+                for element in elements.iter() {
+                    self.expression(element);
+                }
+                self.token = bracket.clone();
+                self.emit_op_arg(OpCode::BuildList, elements.len() as u8);
+            }
+            Expr::Map(brace, entries) => {
+                for (key, value) in entries.iter() {
+                    self.expression(key);
+                    self.expression(value);
+                }
+                self.token = brace.clone();
+                self.emit_op_arg(OpCode::BuildMap, entries.len() as u8);
+            }
+            Expr::Index(object, bracket, index) => {
+                self.expression(object);
+                self.expression(index);
+                self.token = bracket.clone();
+                self.emit_op(OpCode::GetIndex);
+            }
+            Expr::SetIndex(object, bracket, index, value) => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+                self.token = bracket.clone();
+                self.emit_op(OpCode::SetIndex);
+            }
+        }
+    }
+
+    fn constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_op_arg(OpCode::Constant, constant);
+    }
+
+    /// Method calls skip creating a bound method by invoking the method
+    /// straight off the receiver. The opcode is attributed to the method
+    /// name and the argument count to the closing parenthesis, so errors
+    /// point at the same tokens the tree-walker reports.
+    fn call(&mut self, callee: &Expr, paren: &Token, args: &[Expr]) {
+        let invoke = match callee {
+            Expr::Get(object, name) => {
+                self.expression(object);
+                Some((OpCode::Invoke, name))
+            }
+            Expr::Super(keyword, method, _) => {
+                self.token = keyword.clone();
+                self.named_variable("this", false);
+                Some((OpCode::SuperInvoke, method))
+            }
+            _ => {
+                self.expression(callee);
+                None
+            }
+        };
+        for arg in args.iter() {
+            self.expression(arg);
+        }
+        let argc = args.len() as u8;
+        match invoke {
+            Some((op, name)) => {
+                if op == OpCode::SuperInvoke {
+                    self.token = name.clone();
+                    self.named_variable("super", false);
+                }
+                self.token = name.clone();
+                let name = self.identifier_constant(name.name());
+                self.emit_op_arg(op, name);
+                self.token = paren.clone();
+                self.emit(argc);
+            }
+            None => {
+                self.token = paren.clone();
+                self.emit_op_arg(OpCode::Call, argc);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn compile_source(input: &str) -> (Heap, Result<ObjRef, Vec<CompileError>>) {
+        let tokens: Vec<Token> = Lexer::new(input).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        let mut heap = Heap::new();
+        let result = compile(&program, &mut heap, &HashMap::new());
+        (heap, result)
+    }
+
+    fn ops(input: &str) -> Vec<u8> {
+        let (heap, result) = compile_source(input);
+        heap.function(result.unwrap()).chunk.code.clone()
+    }
+
+    #[test]
+    fn test_expression_statement() {
+        assert_eq!(
+            ops("print 1 + 2 * 3;"),
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::Constant as u8,
+                1,
+                OpCode::Constant as u8,
+                2,
+                OpCode::Multiply as u8,
+                OpCode::Add as u8,
+                OpCode::Print as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locals_and_jumps() {
+        assert_eq!(
+            ops("{ var a = true; if (a) a = nil; }"),
+            vec![
+                OpCode::True as u8,
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::JumpIfFalse as u8,
+                0,
+                8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::SetLocal as u8,
+                1,
+                OpCode::Pop as u8,
+                OpCode::Jump as u8,
+                0,
+                1,
+                OpCode::Pop as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let locals: String = (0..256).map(|i| format!("var v{} = nil; ", i)).collect();
+        let (_, result) = compile_source(&format!("fun f() {{ {} }}", locals));
+        let errors = result.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].token().name(), "v255");
+        assert_eq!(
+            errors[0].to_string(),
+            "Too many local variables in function."
+        );
+
+        let constants: String = (0..257).map(|i| format!("{}; ", i)).collect();
+        let (_, result) = compile_source(&constants);
+        assert_eq!(
+            result.unwrap_err()[0].to_string(),
+            "Too many constants in one chunk."
+        );
+    }
+}
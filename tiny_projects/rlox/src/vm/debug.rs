@@ -0,0 +1,193 @@
+use super::chunk::{Chunk, OpCode};
+use super::object::{Heap, Obj, ObjRef};
+use super::value::Value;
+use std::fmt::Write;
+
+/// Listing of `function` followed by every function compiled inside it
+pub fn disassemble(heap: &Heap, function: ObjRef) -> String {
+    let mut out = String::new();
+    let mut pending = vec![function];
+    while let Some(function) = pending.pop() {
+        let function = heap.function(function);
+        let name = match &function.name {
+            Some(name) => name.as_str(),
+            None => "<script>",
+        };
+        out.push_str(&disassemble_chunk(heap, &function.chunk, name));
+        // nested functions are listed in the order they appear
+        for constant in function.chunk.constants.iter().rev() {
+            if let Value::Obj(obj) = constant {
+                if let Obj::Function(_) = heap.get(*obj) {
+                    pending.push(*obj);
+                }
+            }
+        }
+    }
+    out
+}
+
+pub fn disassemble_chunk(heap: &Heap, chunk: &Chunk, name: &str) -> String {
+    let mut out = format!("== {} ==\n", name);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(heap, chunk, offset, &mut out);
+    }
+    out
+}
+
+/// Appends one line describing the instruction at `offset` to `out` and
+/// returns the offset of the next instruction
+pub fn disassemble_instruction(
+    heap: &Heap,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut String,
+) -> usize {
+    let _ = write!(out, "{:04} ", offset);
+    let line = chunk.line(offset);
+    if offset > 0 && chunk.line(offset - 1) == line {
+        out.push_str("   | ");
+    } else {
+        let _ = write!(out, "{:4} ", line + 1);
+    }
+
+    let op = match OpCode::from_byte(chunk.code[offset]) {
+        Some(op) => op,
+        None => {
+            let _ = writeln!(out, "Unknown opcode {}", chunk.code[offset]);
+            return offset + 1;
+        }
+    };
+    let name = format!("{:?}", op);
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method => {
+            let constant = chunk.code[offset + 1];
+            let _ = writeln!(
+                out,
+                "{:<16} {:4} '{}'",
+                name,
+                constant,
+                heap.format(chunk.constants[constant as usize])
+            );
+            offset + 2
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call
+        | OpCode::BuildList
+        | OpCode::BuildMap => {
+            let _ = writeln!(out, "{:<16} {:4}", name, chunk.code[offset + 1]);
+            offset + 2
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler => {
+            let jump = ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize;
+            let target = if op == OpCode::Loop {
+                offset + 3 - jump
+            } else {
+                offset + 3 + jump
+            };
+            let _ = writeln!(out, "{:<16} {:4} -> {}", name, offset, target);
+            offset + 3
+        }
+        OpCode::Invoke | OpCode::SuperInvoke => {
+            let constant = chunk.code[offset + 1];
+            let argc = chunk.code[offset + 2];
+            let _ = writeln!(
+                out,
+                "{:<16} ({} args) {:4} '{}'",
+                name,
+                argc,
+                constant,
+                heap.format(chunk.constants[constant as usize])
+            );
+            offset + 3
+        }
+        OpCode::Closure => {
+            let constant = chunk.code[offset + 1];
+            let function = chunk.constants[constant as usize];
+            let _ = writeln!(out, "{:<16} {:4} {}", name, constant, heap.format(function));
+            let upvalue_count = function
+                .as_obj()
+                .map_or(0, |function| heap.function(function).upvalue_count);
+            let mut offset = offset + 2;
+            for _ in 0..upvalue_count {
+                let kind = if chunk.code[offset] == 1 {
+                    "local"
+                } else {
+                    "upvalue"
+                };
+                let _ = writeln!(
+                    out,
+                    "{:04}    |                     {} {}",
+                    offset,
+                    kind,
+                    chunk.code[offset + 1]
+                );
+                offset += 2;
+            }
+            offset
+        }
+        _ => {
+            let _ = writeln!(out, "{}", name);
+            offset + 1
+        }
+    }
+}
+
+/// The VM stack as `[ a ][ b ]`, printed before each traced instruction
+pub fn format_stack(heap: &Heap, stack: &[Value]) -> String {
+    let mut out = String::from("          ");
+    for value in stack.iter() {
+        let _ = write!(out, "[ {} ]", heap.format(*value));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::compiler::compile;
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+    use crate::parser::Parser;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_disassemble() {
+        let source = "fun f(a) {\n  return a + 1;\n}\nprint f(2);";
+        let tokens: Vec<Token> = Lexer::new(source).collect();
+        let program = Parser::new(tokens).parse_program().unwrap();
+        let mut heap = Heap::new();
+        let script = compile(&program, &mut heap, &HashMap::new()).unwrap();
+        assert_eq!(
+            disassemble(&heap, script),
+            "\
+== <script> ==
+0000    1 Closure             0 <fn f>
+0002    | DefineGlobal        1 'f'
+0004    4 GetGlobal           2 'f'
+0006    | Constant            3 '2'
+0008    | Call                1
+0010    | Print
+0011    | Nil
+0012    | Return
+== f ==
+0000    2 GetLocal            1
+0002    | Constant            0 '1'
+0004    | Add
+0005    | Return
+0006    | Nil
+0007    | Return
+"
+        );
+    }
+}
@@ -0,0 +1,599 @@
+use super::chunk::Chunk;
+use super::table::Table;
+use super::value::{Key, Value};
+use crate::stdlib::Method;
+use crate::value::{whole_number, NativeFunction, OrderedMap};
+use std::mem;
+use std::rc::Rc;
+
+/// Handle to an object in the `Heap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+#[derive(Debug)]
+pub enum Obj {
+    Str(LoxString),
+    Function(Function),
+    Native(Rc<NativeFunction>),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    List(Vec<Value>),
+    Map(OrderedMap<Key, Value>),
+    Module(Module),
+    BuiltinMethod(BuiltinMethod),
+}
+
+impl Obj {
+    /// Rough number of bytes the object keeps alive, used to pace the
+    /// collector
+    fn size(&self) -> usize {
+        let value = mem::size_of::<Value>();
+        mem::size_of::<Obj>()
+            + match self {
+                Obj::Str(string) => string.value.capacity(),
+                Obj::Function(function) => {
+                    function.chunk.code.capacity() + function.chunk.constants.capacity() * value
+                }
+                Obj::Native(_) | Obj::Upvalue(_) | Obj::BoundMethod(_) | Obj::BuiltinMethod(_) => 0,
+                Obj::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+                Obj::Class(class) => class.methods.size(),
+                Obj::Instance(instance) => instance.fields.size(),
+                Obj::List(items) => items.capacity() * value,
+                Obj::Map(map) => map.len() * (mem::size_of::<Key>() + value) * 2,
+                Obj::Module(module) => module.members.size(),
+            }
+    }
+}
+
+/// An interned string. There is never more than one with the same
+/// contents, so strings are equal exactly when their handles are.
+#[derive(Debug)]
+pub struct LoxString {
+    pub value: String,
+    pub hash: u32,
+}
+
+/// FNV-1a, the hash strings are interned and looked up with
+pub fn hash_string(value: &str) -> u32 {
+    value.bytes().fold(2_166_136_261, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(16_777_619)
+    })
+}
+
+#[derive(Debug)]
+pub struct Function {
+    /// `None` for the top level script
+    pub name: Option<String>,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Rc<Chunk>,
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+    /// The module whose members are this function's globals, `None` for
+    /// the VM's own globals
+    pub globals: Option<ObjRef>,
+}
+
+/// A variable captured by a closure. It points into the stack while the
+/// variable is in scope and holds the value itself once it is not.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    /// Method name to closure
+    pub methods: Table,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: Table,
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// A namespace of values, such as `math` or a file loaded by `import`
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub members: Table,
+}
+
+/// A method of a string, list or map together with its receiver
+#[derive(Debug)]
+pub struct BuiltinMethod {
+    pub receiver: Value,
+    pub method: Method,
+}
+
+/// The first collection happens once this many bytes are live
+const FIRST_GC: usize = 1024 * 1024;
+/// After a collection the next one is due when the heap has grown by this
+/// factor
+const GC_GROWTH: usize = 2;
+
+struct Entry {
+    obj: Obj,
+    marked: bool,
+    /// What `Obj::size` was when the object was last counted, so freeing
+    /// subtracts exactly what was added
+    size: usize,
+}
+
+/// Running totals for `--log-gc`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+}
+
+/// Owns every object the VM allocates and reclaims the unreachable ones
+/// with a mark-sweep collector. The heap only finds garbage, it is up to
+/// the VM to mark the roots.
+pub struct Heap {
+    objects: Vec<Option<Entry>>,
+    /// Slots of freed objects, reused by later allocations
+    free: Vec<usize>,
+    /// Marked objects whose references have not been traced yet
+    gray: Vec<ObjRef>,
+    /// Every live string, keyed by itself. Entries do not keep strings
+    /// alive, a sweep drops the ones nothing else reaches.
+    strings: Table,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Collect before every allocation and never reuse slots, so a handle
+    /// to a freed object fails loudly instead of seeing a new object
+    pub stress: bool,
+    pub stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            strings: Table::new(),
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_allocated += size;
+        let entry = Some(Entry {
+            obj,
+            marked: false,
+            size,
+        });
+        match self.free.pop() {
+            Some(idx) => {
+                self.objects[idx] = entry;
+                ObjRef(idx)
+            }
+            None => {
+                self.objects.push(entry);
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// Counts `obj` again after it grew or shrank in place, like a list
+    /// that was pushed to, so collections keep up with what is really live
+    pub fn resize(&mut self, obj: ObjRef) {
+        let entry = match &mut self.objects[obj.0] {
+            Some(entry) => entry,
+            None => panic!("resizing freed object {:?}", obj),
+        };
+        let size = entry.obj.size();
+        self.bytes_allocated = self.bytes_allocated - entry.size + size;
+        entry.size = size;
+    }
+
+    /// Whether enough has been allocated since the last collection that
+    /// another one is due
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn live_objects(&self) -> usize {
+        self.objects.iter().filter(|entry| entry.is_some()).count()
+    }
+
+    fn entry(&self, obj: ObjRef) -> &Entry {
+        match &self.objects[obj.0] {
+            Some(entry) => entry,
+            None => panic!("use of freed object {:?}", obj),
+        }
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        let entry = match &mut self.objects[obj.0] {
+            Some(entry) => entry,
+            None => panic!("marking freed object {:?}", obj),
+        };
+        if !entry.marked {
+            entry.marked = true;
+            self.gray.push(obj);
+        }
+    }
+
+    /// Blackens gray objects until every object reachable from the roots is
+    /// marked
+    pub fn trace_references(&mut self) {
+        let mut children = Vec::new();
+        while let Some(obj) = self.gray.pop() {
+            match &self.entry(obj).obj {
+                Obj::Str(_) | Obj::Native(_) => {}
+                Obj::Function(function) => {
+                    children.extend(function.chunk.constants.iter().copied())
+                }
+                Obj::Closure(closure) => {
+                    children.push(Value::Obj(closure.function));
+                    children.extend(closure.upvalues.iter().map(|upvalue| Value::Obj(*upvalue)));
+                    children.extend(closure.globals.map(Value::Obj));
+                }
+                Obj::Upvalue(Upvalue::Closed(value)) => children.push(*value),
+                Obj::Upvalue(Upvalue::Open(_)) => {}
+                Obj::Class(class) => {
+                    children.extend(
+                        class
+                            .methods
+                            .iter()
+                            .flat_map(|(name, method)| [Value::Obj(name), method]),
+                    );
+                }
+                Obj::Instance(instance) => {
+                    children.push(Value::Obj(instance.class));
+                    children.extend(
+                        instance
+                            .fields
+                            .iter()
+                            .flat_map(|(name, value)| [Value::Obj(name), value]),
+                    );
+                }
+                Obj::BoundMethod(bound) => {
+                    children.push(bound.receiver);
+                    children.push(Value::Obj(bound.method));
+                }
+                Obj::List(items) => children.extend(items.iter().copied()),
+                Obj::Map(map) => {
+                    children.extend(map.iter().flat_map(|(key, value)| [key.to_value(), *value]));
+                }
+                Obj::Module(module) => {
+                    children.extend(
+                        module
+                            .members
+                            .iter()
+                            .flat_map(|(name, value)| [Value::Obj(name), value]),
+                    );
+                }
+                Obj::BuiltinMethod(bound) => children.push(bound.receiver),
+            }
+            for child in children.drain(..) {
+                self.mark_value(child);
+            }
+        }
+    }
+
+    /// Frees every unmarked object and clears the marks on the rest.
+    /// Returns how many objects and bytes were freed.
+    pub fn sweep(&mut self) -> (usize, usize) {
+        let objects = &self.objects;
+        self.strings
+            .retain(|string| matches!(&objects[string.0], Some(entry) if entry.marked));
+
+        let (mut objects, mut bytes) = (0, 0);
+        for (idx, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(entry) if entry.marked => entry.marked = false,
+                Some(entry) => {
+                    objects += 1;
+                    bytes += entry.size;
+                    *slot = None;
+                    if !self.stress {
+                        self.free.push(idx);
+                    }
+                }
+                None => {}
+            }
+        }
+        self.bytes_allocated -= bytes;
+        self.next_gc = (self.bytes_allocated * GC_GROWTH).max(FIRST_GC);
+        self.stats.collections += 1;
+        self.stats.objects_freed += objects;
+        self.stats.bytes_freed += bytes;
+        (objects, bytes)
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        &self.entry(obj).obj
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        match &mut self.objects[obj.0] {
+            Some(entry) => &mut entry.obj,
+            None => panic!("use of freed object {:?}", obj),
+        }
+    }
+
+    /// The string object holding `value`, allocating it only if no string
+    /// with these contents exists yet
+    pub fn intern(&mut self, value: &str) -> ObjRef {
+        let hash = hash_string(value);
+        let objects = &self.objects;
+        let found = self.strings.find_key(hash, |string| {
+            matches!(&objects[string.0], Some(Entry { obj: Obj::Str(string), .. }) if string.value == value)
+        });
+        if let Some(string) = found {
+            return string;
+        }
+        let string = self.alloc(Obj::Str(LoxString {
+            value: value.to_string(),
+            hash,
+        }));
+        self.strings.set(string, hash, Value::Nil);
+        string
+    }
+
+    pub fn string(&self, obj: ObjRef) -> Option<&str> {
+        match self.get(obj) {
+            Obj::Str(string) => Some(&string.value),
+            _ => None,
+        }
+    }
+
+    /// Hash of the interned string `obj`, what tables keyed by it need
+    pub fn string_hash(&self, obj: ObjRef) -> u32 {
+        match self.get(obj) {
+            Obj::Str(string) => string.hash,
+            other => panic!("expected a string, found {:?}", other),
+        }
+    }
+
+    pub fn closure(&self, obj: ObjRef) -> &Closure {
+        match self.get(obj) {
+            Obj::Closure(closure) => closure,
+            other => panic!("expected a closure, found {:?}", other),
+        }
+    }
+
+    pub fn function(&self, obj: ObjRef) -> &Function {
+        match self.get(obj) {
+            Obj::Function(function) => function,
+            other => panic!("expected a function, found {:?}", other),
+        }
+    }
+
+    /// `value` as a map key, if it is a kind of value that can be one
+    pub fn map_key(&self, value: Value) -> Option<Key> {
+        Some(match value {
+            Value::Nil => Key::Nil,
+            Value::Bool(val) => Key::Bool(val),
+            Value::Int(val) => Key::Int(val),
+            Value::Float(val) => match whole_number(val) {
+                Some(val) => Key::Int(val),
+                None => Key::Float(val.to_bits()),
+            },
+            Value::Obj(obj) => {
+                self.string(obj)?;
+                Key::Str(obj)
+            }
+        })
+    }
+
+    /// Same rules as `value::Value::is_equal`. Strings are interned, so
+    /// comparing handles compares their contents too.
+    pub fn values_equal(&self, a: Value, b: Value) -> bool {
+        match (a, b) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Obj(a), Value::Obj(b)) => a == b,
+            (a, b) => match (a.as_float(), b.as_float()) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+
+    /// Renders a value the way `print` shows it
+    pub fn format(&self, value: Value) -> String {
+        let mut out = String::new();
+        self.write(value, &mut out, &mut Vec::new());
+        out
+    }
+
+    /// How the value shows inside a list or map, where strings are quoted
+    pub fn repr(&self, value: Value) -> String {
+        let mut out = String::new();
+        self.write_nested(value, &mut out, &mut Vec::new());
+        out
+    }
+
+    fn write_nested(&self, value: Value, out: &mut String, seen: &mut Vec<ObjRef>) {
+        match value.as_obj().and_then(|obj| self.string(obj)) {
+            Some(string) => out.push_str(&format!("{:?}", string)),
+            None => self.write(value, out, seen),
+        }
+    }
+
+    /// Same output as `Display` for `value::Value`. `seen` holds the
+    /// collections being written so one that contains itself prints as
+    /// `[...]` or `{...}`.
+    fn write(&self, value: Value, out: &mut String, seen: &mut Vec<ObjRef>) {
+        let obj = match value {
+            Value::Obj(obj) => obj,
+            other => return out.push_str(&self.format_plain(other)),
+        };
+        match self.get(obj) {
+            Obj::List(items) => {
+                if seen.contains(&obj) {
+                    return out.push_str("[...]");
+                }
+                seen.push(obj);
+                out.push('[');
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        out.push_str(", ");
+                    }
+                    self.write_nested(*item, out, seen);
+                }
+                seen.pop();
+                out.push(']');
+            }
+            Obj::Map(map) => {
+                if seen.contains(&obj) {
+                    return out.push_str("{...}");
+                }
+                seen.push(obj);
+                out.push('{');
+                for (idx, (key, value)) in map.iter().enumerate() {
+                    if idx > 0 {
+                        out.push_str(", ");
+                    }
+                    self.write_nested(key.to_value(), out, seen);
+                    out.push_str(": ");
+                    self.write_nested(*value, out, seen);
+                }
+                seen.pop();
+                out.push('}');
+            }
+            _ => out.push_str(&self.format_plain(value)),
+        }
+    }
+
+    /// Display of the values that do not contain other values
+    fn format_plain(&self, value: Value) -> String {
+        match value {
+            Value::Nil => "nil".to_string(),
+            Value::Bool(val) => val.to_string(),
+            Value::Int(val) => val.to_string(),
+            Value::Float(val) => val.to_string(),
+            Value::Obj(obj) => match self.get(obj) {
+                Obj::Str(string) => string.value.clone(),
+                Obj::Function(function) => match &function.name {
+                    Some(name) => format!("<fn {}>", name),
+                    None => "<script>".to_string(),
+                },
+                Obj::Native(_) => "<native fn>".to_string(),
+                Obj::Closure(closure) => self.format(Value::Obj(closure.function)),
+                Obj::Upvalue(_) => "upvalue".to_string(),
+                Obj::Class(class) => class.name.clone(),
+                Obj::Instance(instance) => match self.get(instance.class) {
+                    Obj::Class(class) => format!("{} instance", class.name),
+                    _ => "instance".to_string(),
+                },
+                Obj::BoundMethod(bound) => self.format(Value::Obj(bound.method)),
+                Obj::Module(module) => format!("<module {}>", module.name),
+                Obj::BuiltinMethod(_) => "<native fn>".to_string(),
+                Obj::List(_) | Obj::Map(_) => unreachable!("collections are written by `write`"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_cycle() {
+        let mut heap = Heap::new();
+        let class = heap.alloc(Obj::Class(Class {
+            name: "A".to_string(),
+            methods: Table::new(),
+        }));
+        let a = heap.alloc(Obj::Instance(Instance {
+            class,
+            fields: Table::new(),
+        }));
+        let b = heap.alloc(Obj::Instance(Instance {
+            class,
+            fields: Table::new(),
+        }));
+        // a and b point at each other
+        let other = heap.intern("other");
+        for (from, to) in [(a, b), (b, a)].iter() {
+            if let Obj::Instance(instance) = heap.get_mut(*from) {
+                instance
+                    .fields
+                    .set(other, hash_string("other"), Value::Obj(*to));
+            }
+        }
+        let kept = heap.intern("kept");
+
+        heap.mark_object(kept);
+        heap.trace_references();
+        assert_eq!(heap.sweep().0, 4);
+        assert_eq!(heap.live_objects(), 1);
+        assert_eq!(heap.string(kept), Some("kept"));
+
+        // freed strings are no longer interned and freed slots are reused
+        let reused = heap.intern("other");
+        assert_ne!(reused, kept);
+        assert_eq!(heap.live_objects(), 2);
+    }
+
+    #[test]
+    fn test_intern() {
+        let mut heap = Heap::new();
+        let a = heap.intern("a");
+        assert_eq!(heap.intern("a"), a);
+        assert_ne!(heap.intern("b"), a);
+        let ab = heap.intern(&["a", "b"].concat());
+        assert_eq!(heap.intern("ab"), ab);
+        assert_eq!(heap.string(ab), Some("ab"));
+        assert_eq!(heap.string_hash(ab), hash_string("ab"));
+        let again = heap.intern(&String::from("ab"));
+        assert!(heap.values_equal(Value::Obj(ab), Value::Obj(again)));
+    }
+
+    #[test]
+    #[should_panic(expected = "use of freed object")]
+    fn test_stress_catches_use_after_free() {
+        let mut heap = Heap::new();
+        heap.stress = true;
+        let dangling = heap.intern("gone");
+        heap.trace_references();
+        heap.sweep();
+        heap.intern("new");
+        heap.get(dangling);
+    }
+}
@@ -0,0 +1,283 @@
+use super::object::ObjRef;
+use super::value::Value;
+
+/// Grow once this fraction of buckets is in use, tombstones included
+const MAX_LOAD: f64 = 0.75;
+const MIN_CAPACITY: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+enum Bucket {
+    Empty,
+    /// A deleted entry. Probing has to continue past it, but inserts can
+    /// reuse it.
+    Tombstone,
+    Full {
+        key: ObjRef,
+        hash: u32,
+        value: Value,
+    },
+}
+
+/// Hash table from interned strings to values, using open addressing with
+/// linear probing. Keys are compared by handle, so callers pass the hash
+/// the string was interned with.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    buckets: Vec<Bucket>,
+    /// Full buckets and tombstones, what the load factor is measured on
+    used: usize,
+    len: usize,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes taken by the buckets, for pacing the collector
+    pub fn size(&self) -> usize {
+        self.buckets.capacity() * std::mem::size_of::<Bucket>()
+    }
+
+    /// Index of the bucket holding `key`, or of the bucket it should be
+    /// inserted in if it is missing
+    fn find_bucket(buckets: &[Bucket], key: ObjRef, hash: u32) -> usize {
+        let mask = buckets.len() - 1;
+        let mut idx = hash as usize & mask;
+        let mut tombstone = None;
+        loop {
+            match buckets[idx] {
+                Bucket::Empty => return tombstone.unwrap_or(idx),
+                Bucket::Tombstone => {
+                    tombstone.get_or_insert(idx);
+                }
+                Bucket::Full { key: found, .. } if found == key => return idx,
+                Bucket::Full { .. } => {}
+            }
+            idx = (idx + 1) & mask;
+        }
+    }
+
+    pub fn get(&self, key: ObjRef, hash: u32) -> Option<Value> {
+        if self.len == 0 {
+            return None;
+        }
+        match self.buckets[Self::find_bucket(&self.buckets, key, hash)] {
+            Bucket::Full { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Stores `value` under `key` and returns whether the key is new
+    pub fn set(&mut self, key: ObjRef, hash: u32, value: Value) -> bool {
+        if (self.used + 1) as f64 > self.buckets.len() as f64 * MAX_LOAD {
+            self.grow();
+        }
+        let idx = Self::find_bucket(&self.buckets, key, hash);
+        let bucket = &mut self.buckets[idx];
+        let is_new = !matches!(bucket, Bucket::Full { .. });
+        if is_new {
+            self.len += 1;
+            // a reused tombstone was already counted
+            if let Bucket::Empty = bucket {
+                self.used += 1;
+            }
+        }
+        *bucket = Bucket::Full { key, hash, value };
+        is_new
+    }
+
+    pub fn delete(&mut self, key: ObjRef, hash: u32) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        let idx = Self::find_bucket(&self.buckets, key, hash);
+        match self.buckets[idx] {
+            Bucket::Full { .. } => {
+                self.buckets[idx] = Bucket::Tombstone;
+                self.len -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Copies every entry of `from` into this table, used for inheritance
+    pub fn add_all(&mut self, from: &Table) {
+        for bucket in from.buckets.iter() {
+            if let Bucket::Full { key, hash, value } = *bucket {
+                self.set(key, hash, value);
+            }
+        }
+    }
+
+    /// Looks for a key by content rather than handle, which is how the
+    /// string interner finds out whether it has seen a string before
+    pub fn find_key(&self, hash: u32, mut matches: impl FnMut(ObjRef) -> bool) -> Option<ObjRef> {
+        if self.len == 0 {
+            return None;
+        }
+        let mask = self.buckets.len() - 1;
+        let mut idx = hash as usize & mask;
+        loop {
+            match self.buckets[idx] {
+                Bucket::Empty => return None,
+                Bucket::Full {
+                    key, hash: found, ..
+                } if found == hash && matches(key) => return Some(key),
+                _ => {}
+            }
+            idx = (idx + 1) & mask;
+        }
+    }
+
+    /// Deletes every entry whose key `keep` rejects
+    pub fn retain(&mut self, mut keep: impl FnMut(ObjRef) -> bool) {
+        for bucket in self.buckets.iter_mut() {
+            if let Bucket::Full { key, .. } = *bucket {
+                if !keep(key) {
+                    *bucket = Bucket::Tombstone;
+                    self.len -= 1;
+                }
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ObjRef, Value)> + '_ {
+        self.buckets.iter().filter_map(|bucket| match *bucket {
+            Bucket::Full { key, value, .. } => Some((key, value)),
+            _ => None,
+        })
+    }
+
+    /// Doubles the capacity and rehashes, which also drops tombstones
+    fn grow(&mut self) {
+        let capacity = (self.buckets.len() * 2).max(MIN_CAPACITY);
+        let old = std::mem::replace(&mut self.buckets, vec![Bucket::Empty; capacity]);
+        self.used = self.len;
+        for bucket in old.into_iter() {
+            if let Bucket::Full { key, hash, value } = bucket {
+                let idx = Self::find_bucket(&self.buckets, key, hash);
+                self.buckets[idx] = Bucket::Full { key, hash, value };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::object::{hash_string, Heap};
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    fn keys(heap: &mut Heap, count: usize) -> Vec<(ObjRef, u32)> {
+        (0..count)
+            .map(|i| {
+                let key = heap.intern(&format!("key{}", i));
+                (key, hash_string(&format!("key{}", i)))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_set_get_delete() {
+        let mut heap = Heap::new();
+        let keys = keys(&mut heap, 100);
+        let mut table = Table::new();
+        for (i, (key, hash)) in keys.iter().enumerate() {
+            assert!(table.set(*key, *hash, Value::Int(i as i64)));
+        }
+        assert!(!table.set(keys[0].0, keys[0].1, Value::Nil));
+        assert_eq!(table.len(), 100);
+        assert_eq!(table.get(keys[0].0, keys[0].1), Some(Value::Nil));
+        assert_eq!(table.get(keys[99].0, keys[99].1), Some(Value::Int(99)));
+
+        for (key, hash) in keys.iter().step_by(2) {
+            assert!(table.delete(*key, *hash));
+        }
+        assert!(!table.delete(keys[0].0, keys[0].1));
+        assert_eq!(table.len(), 50);
+        // lookups probe past tombstones
+        for (i, (key, hash)) in keys.iter().enumerate() {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some(Value::Int(i as i64))
+            };
+            assert_eq!(table.get(*key, *hash), expected);
+        }
+    }
+
+    #[test]
+    fn test_tombstones_are_reused() {
+        let mut heap = Heap::new();
+        let keys = keys(&mut heap, 4);
+        let mut table = Table::new();
+        for _ in 0..1000 {
+            for (key, hash) in keys.iter() {
+                table.set(*key, *hash, Value::Nil);
+            }
+            for (key, hash) in keys.iter() {
+                table.delete(*key, *hash);
+            }
+        }
+        assert!(table.is_empty());
+        assert_eq!(table.buckets.len(), MIN_CAPACITY);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_against_hashmap() {
+        const KEYS: usize = 1000;
+        const ROUNDS: usize = 2000;
+        let mut heap = Heap::new();
+        let keys = keys(&mut heap, KEYS);
+        let names: Vec<String> = (0..KEYS).map(|i| format!("key{}", i)).collect();
+
+        let start = Instant::now();
+        let mut table = Table::new();
+        for round in 0..ROUNDS {
+            for (key, hash) in keys.iter() {
+                table.set(*key, *hash, Value::Int(round as i64));
+            }
+            for (key, hash) in keys.iter() {
+                assert_eq!(table.get(*key, *hash), Some(Value::Int(round as i64)));
+            }
+        }
+        println!("Table (interned keys):       {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let mut map = HashMap::new();
+        for round in 0..ROUNDS {
+            for (key, _) in keys.iter() {
+                map.insert(*key, Value::Int(round as i64));
+            }
+            for (key, _) in keys.iter() {
+                assert_eq!(map.get(key), Some(&Value::Int(round as i64)));
+            }
+        }
+        println!("HashMap<ObjRef, Value>:      {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let mut map = HashMap::new();
+        for round in 0..ROUNDS {
+            for name in names.iter() {
+                map.insert(name.clone(), Value::Int(round as i64));
+            }
+            for name in names.iter() {
+                assert_eq!(map.get(name), Some(&Value::Int(round as i64)));
+            }
+        }
+        println!("HashMap<String, Value>:      {:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,85 @@
+use super::object::ObjRef;
+
+/// What the VM keeps on its stack. Anything bigger than a number lives in
+/// the heap and is referred to by handle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Numbers as the tree-walker sees them, so both backends share one
+    /// implementation of arithmetic
+    pub fn to_number(self) -> Option<crate::value::Value> {
+        match self {
+            Value::Int(val) => Some(crate::value::Value::Int(val)),
+            Value::Float(val) => Some(crate::value::Value::Float(val)),
+            _ => None,
+        }
+    }
+
+    pub fn from_number(number: crate::value::Value) -> Option<Value> {
+        match number {
+            crate::value::Value::Int(val) => Some(Value::Int(val)),
+            crate::value::Value::Float(val) => Some(Value::Float(val)),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Int(val) => Some(*val as f64),
+            Value::Float(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    /// The value as a list index, see `value::Value::as_int`
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(val) => Some(*val),
+            Value::Float(val) => crate::value::whole_number(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<ObjRef> {
+        match self {
+            Value::Obj(obj) => Some(*obj),
+            _ => None,
+        }
+    }
+}
+
+/// A map key. Strings are interned, so comparing their handles compares
+/// their contents, and whole floats are stored as ints so `1` and `1.0`
+/// are the same key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    /// The bits of a float that is not a whole number
+    Float(u64),
+    Str(ObjRef),
+}
+
+impl Key {
+    pub fn to_value(self) -> Value {
+        match self {
+            Key::Nil => Value::Nil,
+            Key::Bool(val) => Value::Bool(val),
+            Key::Int(val) => Value::Int(val),
+            Key::Float(bits) => Value::Float(f64::from_bits(bits)),
+            Key::Str(string) => Value::Obj(string),
+        }
+    }
+}
@@ -0,0 +1,664 @@
+//! `rlox build --target wasm`, which lowers a resolved program to a
+//! WebAssembly module
+//!
+//! Only part of the language has a lowering: numbers, booleans and nil,
+//! arithmetic and comparisons, globals and locals, control flow, and
+//! functions declared at the top level, which may call each other and
+//! themselves. Anything else is a compile error.
+//!
+//! The module imports two host functions from `env`:
+//!
+//! - `print(value: f64)` prints a value, see `display`
+//! - `error(message: i32, line: i32)` reports a runtime error, where
+//!   `message` is the offset of a NUL-terminated string in the exported
+//!   `memory`, see `message`. The module traps straight after.
+//!
+//! and exports `main`, which runs the script.
+
+use crate::error::{CompileError, RuntimeError};
+use crate::evaluation::MAX_CALL_DEPTH;
+use crate::grammar::*;
+use crate::lexer::Token;
+use crate::stdlib;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::mem;
+
+/// Every value is an f64. Numbers are themselves, and nil, the booleans and
+/// globals that have not been assigned yet are NaNs with payloads that
+/// arithmetic never produces.
+pub const NIL: u64 = 0x7ffc_0000_0000_0001;
+pub const FALSE: u64 = 0x7ffc_0000_0000_0002;
+pub const TRUE: u64 = 0x7ffc_0000_0000_0003;
+const UNDEFINED: u64 = 0x7ffc_0000_0000_0000;
+
+/// Helpers the generated code calls, written out ahead of it. Calls are
+/// counted so deep recursion fails the way it does in the interpreters,
+/// before the engine runs out of stack.
+const RUNTIME: &str = r#"
+  (func $rt.boxed (param $value f64) (result i32)
+    (i64.eq
+      (i64.and (i64.reinterpret_f64 (local.get $value)) (i64.const 0xfffffffffffffffc))
+      (i64.const 0x7ffc000000000000)))
+
+  (func $rt.truthy (param $value f64) (result i32)
+    (i32.and
+      (i64.ne (i64.reinterpret_f64 (local.get $value)) (i64.reinterpret_f64 (global.get $rt.nil)))
+      (i64.ne (i64.reinterpret_f64 (local.get $value)) (i64.reinterpret_f64 (global.get $rt.false)))))
+
+  (func $rt.bool (param $condition i32) (result f64)
+    (select (global.get $rt.true) (global.get $rt.false) (local.get $condition)))
+
+  (func $rt.equal (param $a f64) (param $b f64) (result i32)
+    (if (result i32) (i32.or (call $rt.boxed (local.get $a)) (call $rt.boxed (local.get $b)))
+      (then (i64.eq (i64.reinterpret_f64 (local.get $a)) (i64.reinterpret_f64 (local.get $b))))
+      (else (f64.eq (local.get $a) (local.get $b)))))
+
+  (func $rt.number (param $value f64) (param $message i32) (param $line i32) (result f64)
+    (if (call $rt.boxed (local.get $value))
+      (then (call $host.error (local.get $message) (local.get $line)) (unreachable)))
+    (local.get $value))
+
+  (func $rt.numbers (param $a f64) (param $b f64) (param $message i32) (param $line i32) (result f64 f64)
+    (if (i32.or (call $rt.boxed (local.get $a)) (call $rt.boxed (local.get $b)))
+      (then (call $host.error (local.get $message) (local.get $line)) (unreachable)))
+    (local.get $a)
+    (local.get $b))
+
+  (func $rt.divisor (param $value f64) (param $message i32) (param $line i32) (result f64)
+    (if (f64.eq (local.get $value) (f64.const 0))
+      (then (call $host.error (local.get $message) (local.get $line)) (unreachable)))
+    (local.get $value))
+
+  (func $rt.defined (param $value f64) (param $message i32) (param $line i32) (result f64)
+    (if (i64.eq (i64.reinterpret_f64 (local.get $value)) (i64.reinterpret_f64 (global.get $rt.undefined)))
+      (then (call $host.error (local.get $message) (local.get $line)) (unreachable)))
+    (local.get $value))
+
+  (func $rt.enter (param $message i32) (param $line i32)
+    (if (i32.ge_u (global.get $rt.depth) (i32.const MAX_CALL_DEPTH))
+      (then (call $host.error (local.get $message) (local.get $line)) (unreachable)))
+    (global.set $rt.depth (i32.add (global.get $rt.depth) (i32.const 1))))
+
+  (func $rt.leave
+    (global.set $rt.depth (i32.sub (global.get $rt.depth) (i32.const 1))))
+"#;
+
+/// How `print` shows a value, the same way the interpreters do
+pub fn display(value: f64) -> String {
+    match value.to_bits() {
+        NIL => "nil".to_string(),
+        FALSE => "false".to_string(),
+        TRUE => "true".to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// The message `error` was called with, from the module's memory
+pub fn message(memory: &[u8], offset: usize) -> String {
+    let text = memory.get(offset..).unwrap_or(&[]);
+    let end = text
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(text.len());
+    String::from_utf8_lossy(&text[..end]).into_owned()
+}
+
+/// The binary encoding of a module `compile` produced
+pub fn assemble(text: &str) -> Vec<u8> {
+    wat::parse_str(text).expect("Generated an invalid module")
+}
+
+/// Lowers `program`, which must already be resolved, to a module in the
+/// WebAssembly text format
+pub fn compile(program: &[Stmt]) -> Result<String, Vec<CompileError>> {
+    let mut wasm = Wasm::default();
+    wasm.declare(program);
+    wasm.stmts(program);
+    let main = wasm.finish_function("(export \"main\")", "", "");
+    if !wasm.errors.is_empty() {
+        return Err(wasm.errors);
+    }
+
+    let mut out = String::from("(module\n");
+    out.push_str("  (import \"env\" \"print\" (func $host.print (param f64)))\n");
+    out.push_str("  (import \"env\" \"error\" (func $host.error (param i32 i32)))\n");
+    let pages = wasm.data.len() / 65536 + 1;
+    let _ = writeln!(out, "  (memory (export \"memory\") {})", pages);
+    let _ = writeln!(out, "  (data (i32.const 0) \"{}\")", escape(&wasm.data));
+    for (name, bits) in [
+        ("nil", NIL),
+        ("false", FALSE),
+        ("true", TRUE),
+        ("undefined", UNDEFINED),
+    ] {
+        let _ = writeln!(out, "  (global $rt.{} f64 {})", name, nan(bits));
+    }
+    out.push_str("  (global $rt.depth (mut i32) (i32.const 0))\n");
+    for name in wasm.globals.iter() {
+        let _ = writeln!(out, "  (global ${} (mut f64) {})", name, nan(UNDEFINED));
+    }
+    out.push_str(&RUNTIME.replace("MAX_CALL_DEPTH", &MAX_CALL_DEPTH.to_string()));
+    for function in wasm.functions_code.iter().chain(Some(&main)) {
+        out.push('\n');
+        out.push_str(function);
+    }
+    out.push_str(")\n");
+    Ok(out)
+}
+
+/// Names the standard library defines, none of which exist in a module
+fn is_native(name: &str) -> bool {
+    name == "clock"
+        || stdlib::modules(Default::default())
+            .iter()
+            .any(|module| module.name == name)
+}
+
+/// An `f64.const` for one of the boxed values
+fn nan(bits: u64) -> String {
+    format!("(f64.const nan:{:#x})", bits & ((1 << 52) - 1))
+}
+
+fn float(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Bytes as the contents of a string in the text format
+fn escape(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for &byte in bytes.iter() {
+        match byte {
+            b'"' | b'\\' => {
+                let _ = write!(out, "\\{:02x}", byte);
+            }
+            b' '..=b'~' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\{:02x}", byte);
+            }
+        }
+    }
+    out
+}
+
+/// The function being generated
+#[derive(Default)]
+struct Body {
+    code: String,
+    indent: usize,
+    /// Every local and parameter name used so far, as written in the module
+    names: Vec<String>,
+    /// The locals that aren't parameters
+    locals: Vec<String>,
+    /// Lox names in scope, innermost last, with their names in the module.
+    /// Empty at the top level of the script, where variables are globals.
+    scopes: Vec<Vec<(String, String)>>,
+}
+
+#[derive(Default)]
+struct Wasm {
+    /// Functions declared at the top level, with their arity
+    functions: HashMap<String, usize>,
+    /// Variables declared at the top level
+    globals: BTreeSet<String>,
+    /// Every message `error` can be called with
+    data: Vec<u8>,
+    messages: HashMap<String, usize>,
+    functions_code: Vec<String>,
+    body: Body,
+    errors: Vec<CompileError>,
+}
+
+impl Wasm {
+    /// Collects the top-level names up front, since functions can call
+    /// each other and use globals declared after them
+    fn declare(&mut self, program: &[Stmt]) {
+        for stmt in program.iter() {
+            match stmt {
+                Stmt::Var(name, _, _) if self.functions.contains_key(name.name()) => {
+                    self.unsupported(name, "a variable with a function's name")
+                }
+                Stmt::Var(name, _, _) => {
+                    self.globals.insert(name.name().to_string());
+                }
+                Stmt::Function(decl)
+                    if self.functions.contains_key(decl.name.name())
+                        || self.globals.contains(decl.name.name()) =>
+                {
+                    self.unsupported(&decl.name, "redefining a function")
+                }
+                Stmt::Function(decl) => {
+                    let name = decl.name.name().to_string();
+                    self.functions.insert(name, decl.params.len());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn unsupported(&mut self, token: &Token, what: &'static str) {
+        self.errors
+            .push(CompileError::Unsupported(token.clone(), what));
+    }
+
+    fn emit<S: AsRef<str>>(&mut self, instruction: S) {
+        let indent = "  ".repeat(self.body.indent + 2);
+        let _ = writeln!(self.body.code, "{}{}", indent, instruction.as_ref());
+    }
+
+    /// Starts a `block`, `loop` or `if`
+    fn open<S: AsRef<str>>(&mut self, instruction: S) {
+        self.emit(instruction);
+        self.body.indent += 1;
+    }
+
+    fn close(&mut self) {
+        self.body.indent -= 1;
+        self.emit("end");
+    }
+
+    /// The offset of `text` in memory
+    fn message(&mut self, text: String) -> usize {
+        if let Some(&offset) = self.messages.get(&text) {
+            return offset;
+        }
+        let offset = self.data.len();
+        self.data.extend_from_slice(text.as_bytes());
+        self.data.push(0);
+        self.messages.insert(text, offset);
+        offset
+    }
+
+    /// Pushes the arguments the runtime checks take after the value: the
+    /// message for `err` and its line
+    fn error_args(&mut self, err: RuntimeError) {
+        let line = err.token().line + 1;
+        let offset = self.message(err.to_string());
+        self.emit(format!("i32.const {}", offset));
+        self.emit(format!("i32.const {}", line));
+    }
+
+    /// Fails with `err` unconditionally
+    fn fail(&mut self, err: RuntimeError) {
+        self.error_args(err);
+        self.emit("call $host.error");
+        self.emit("unreachable");
+    }
+
+    /// Declares a local for `name` in the innermost scope, returning its
+    /// name in the module
+    fn local(&mut self, name: &str) -> String {
+        let mut local = format!("${}", name);
+        let mut suffix = 1;
+        while self.body.names.contains(&local) {
+            suffix += 1;
+            local = format!("${}.{}", name, suffix);
+        }
+        self.body.names.push(local.clone());
+        if let Some(scope) = self.body.scopes.last_mut() {
+            scope.push((name.to_string(), local.clone()));
+        }
+        local
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        self.body
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(lox, _)| lox == name)
+            .map(|(_, local)| local.clone())
+    }
+
+    /// Wraps the body generated so far in a function
+    fn finish_function(&mut self, name: &str, params: &str, result: &str) -> String {
+        let body = mem::take(&mut self.body);
+        let mut out = format!("  (func {}{}{}\n", name, params, result);
+        for local in body.locals.iter() {
+            let _ = writeln!(out, "    (local {} f64)", local);
+        }
+        out.push_str("    (local $rt.scratch f64)\n");
+        out.push_str(&body.code);
+        out.push_str("  )\n");
+        out
+    }
+
+    fn function(&mut self, decl: &FunctionDecl) {
+        let outer = mem::take(&mut self.body);
+        self.body.scopes.push(Vec::new());
+        let mut params = String::new();
+        for param in decl.params.iter() {
+            let local = self.local(param.name());
+            let _ = write!(params, " (param {} f64)", local);
+        }
+        self.stmts(&decl.body);
+        self.emit("global.get $rt.nil");
+        let name = format!("${}", decl.name.name());
+        let code = self.finish_function(&name, &params, " (result f64)");
+        self.functions_code.push(code);
+        self.body = outer;
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts.iter() {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.expr(expr);
+                self.emit("drop");
+            }
+            Stmt::Print(expr) => {
+                self.expr(expr);
+                self.emit("call $host.print");
+            }
+            Stmt::Var(name, _, initializer) => {
+                match initializer {
+                    Some(expr) => self.expr(expr),
+                    None => self.emit("global.get $rt.nil"),
+                }
+                if self.body.scopes.is_empty() {
+                    self.emit(format!("global.set ${}", name.name()));
+                } else {
+                    let local = self.local(name.name());
+                    self.body.locals.push(local.clone());
+                    self.emit(format!("local.set {}", local));
+                }
+            }
+            Stmt::Block(stmts) => {
+                self.body.scopes.push(Vec::new());
+                self.stmts(stmts);
+                self.body.scopes.pop();
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                self.expr(condition);
+                self.emit("call $rt.truthy");
+                self.open("if");
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.body.indent -= 1;
+                    self.emit("else");
+                    self.body.indent += 1;
+                    self.stmt(else_branch);
+                }
+                self.close();
+            }
+            Stmt::While(condition, body, _) => {
+                self.open("block");
+                self.open("loop");
+                self.expr(condition);
+                self.emit("call $rt.truthy");
+                self.emit("i32.eqz");
+                self.emit("br_if 1");
+                self.stmt(body);
+                self.emit("br 0");
+                self.close();
+                self.close();
+            }
+            // closures would need the enclosing locals to live somewhere
+            // other than the wasm stack
+            Stmt::Function(decl) if !self.body.scopes.is_empty() => {
+                self.unsupported(&decl.name, "functions declared inside functions or blocks")
+            }
+            Stmt::Function(decl) => self.function(decl),
+            Stmt::Return(_, value) => {
+                match value {
+                    Some(expr) => self.expr(expr),
+                    None => self.emit("global.get $rt.nil"),
+                }
+                self.emit("return");
+            }
+            Stmt::Throw(keyword, _) | Stmt::Try(keyword, ..) => {
+                self.unsupported(keyword, "exceptions")
+            }
+            Stmt::Class(decl) => self.unsupported(&decl.name, "classes"),
+            Stmt::Import(path, ..) => self.unsupported(path, "imports"),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(literal, token) => match literal {
+                Literal::Float(value) => self.emit(format!("f64.const {}", float(*value))),
+                Literal::Int(value) => self.emit(format!("f64.const {}", value)),
+                Literal::True => self.emit("global.get $rt.true"),
+                Literal::False => self.emit("global.get $rt.false"),
+                Literal::Nil => self.emit("global.get $rt.nil"),
+                Literal::String(_) => self.unsupported(token, "strings"),
+            },
+            Expr::UnaryExpr(op, token, operand) => {
+                self.expr(operand);
+                match op {
+                    UnaryOp::Negative => {
+                        self.error_args(RuntimeError::OperandMustBeNumber(token.clone()));
+                        self.emit("call $rt.number");
+                        self.emit("f64.neg");
+                    }
+                    UnaryOp::Not => {
+                        self.emit("call $rt.truthy");
+                        self.emit("i32.eqz");
+                        self.emit("call $rt.bool");
+                    }
+                }
+            }
+            Expr::BinaryExpr(left, op, token, right) => {
+                self.expr(left);
+                self.expr(right);
+                self.binary(*op, token);
+            }
+            Expr::Grouping(inner) => self.expr(inner),
+            Expr::Variable(name, _) => self.variable(name),
+            Expr::Assign(name, value, _) => {
+                self.expr(value);
+                self.assign(name);
+            }
+            Expr::Logical(left, op, right) => {
+                // the scratch local keeps the left operand in case it is
+                // the result
+                self.expr(left);
+                self.emit("local.tee $rt.scratch");
+                self.emit("call $rt.truthy");
+                self.open("if (result f64)");
+                match op {
+                    LogicalOp::And => self.expr(right),
+                    LogicalOp::Or => self.emit("local.get $rt.scratch"),
+                }
+                self.body.indent -= 1;
+                self.emit("else");
+                self.body.indent += 1;
+                match op {
+                    LogicalOp::And => self.emit("local.get $rt.scratch"),
+                    LogicalOp::Or => self.expr(right),
+                }
+                self.close();
+            }
+            Expr::Call(callee, paren, args) => self.call(callee, paren, args),
+            Expr::Get(_, name) | Expr::Set(_, name, _) => self.unsupported(name, "properties"),
+            Expr::This(keyword, _) | Expr::Super(keyword, _, _) => {
+                self.unsupported(keyword, "classes")
+            }
+            Expr::List(bracket, _) => self.unsupported(bracket, "lists"),
+            // the receiver could be a list or a map
+            Expr::Index(_, bracket, _) | Expr::SetIndex(_, bracket, _, _) => {
+                self.unsupported(bracket, "indexing")
+            }
+            Expr::Map(brace, _) => self.unsupported(brace, "maps"),
+        }
+    }
+
+    /// With both operands on the stack
+    fn binary(&mut self, op: BinaryOp, token: &Token) {
+        let instruction = match op {
+            BinaryOp::Eq | BinaryOp::Ne => {
+                self.emit("call $rt.equal");
+                if op == BinaryOp::Ne {
+                    self.emit("i32.eqz");
+                }
+                self.emit("call $rt.bool");
+                return;
+            }
+            BinaryOp::Gt => "f64.gt",
+            BinaryOp::Ge => "f64.ge",
+            BinaryOp::Lt => "f64.lt",
+            BinaryOp::Le => "f64.le",
+            BinaryOp::Plus => "f64.add",
+            BinaryOp::Minus => "f64.sub",
+            BinaryOp::Times => "f64.mul",
+            BinaryOp::Divide => "f64.div",
+        };
+        let err = match op {
+            BinaryOp::Plus => RuntimeError::OperandsMustBeNumbersOrStrings(token.clone()),
+            _ => RuntimeError::OperandsMustBeNumbers(token.clone()),
+        };
+        self.error_args(err);
+        self.emit("call $rt.numbers");
+        if op == BinaryOp::Divide {
+            self.error_args(RuntimeError::DivisionByZero(token.clone()));
+            self.emit("call $rt.divisor");
+        }
+        self.emit(instruction);
+        if matches!(
+            op,
+            BinaryOp::Gt | BinaryOp::Ge | BinaryOp::Lt | BinaryOp::Le
+        ) {
+            self.emit("call $rt.bool");
+        }
+    }
+
+    fn variable(&mut self, name: &Token) {
+        if let Some(local) = self.lookup(name.name()) {
+            self.emit(format!("local.get {}", local));
+        } else if self.functions.contains_key(name.name()) {
+            self.unsupported(name, "functions used as values");
+        } else if self.globals.contains(name.name()) {
+            self.emit(format!("global.get ${}", name.name()));
+            self.error_args(RuntimeError::UndefinedVariable(name.clone()));
+            self.emit("call $rt.defined");
+        } else if is_native(name.name()) {
+            self.unsupported(name, "the standard library");
+        } else {
+            self.fail(RuntimeError::UndefinedVariable(name.clone()));
+        }
+    }
+
+    /// With the new value on the stack, which stays there
+    fn assign(&mut self, name: &Token) {
+        if let Some(local) = self.lookup(name.name()) {
+            self.emit(format!("local.tee {}", local));
+        } else if self.functions.contains_key(name.name()) {
+            self.unsupported(name, "assigning to a function");
+        } else if self.globals.contains(name.name()) {
+            // like the interpreters, only a defined global can be assigned
+            self.variable(name);
+            self.emit("drop");
+            self.emit(format!("global.set ${}", name.name()));
+            self.emit(format!("global.get ${}", name.name()));
+        } else {
+            self.variable(name);
+        }
+    }
+
+    fn call(&mut self, callee: &Expr, paren: &Token, args: &[Expr]) {
+        let name = match callee {
+            Expr::Variable(name, _) if self.lookup(name.name()).is_none() => name,
+            _ => return self.unsupported(paren, "calls to anything but functions"),
+        };
+        let arity = match self.functions.get(name.name()) {
+            Some(&arity) => arity,
+            None if is_native(name.name()) => {
+                return self.unsupported(name, "the standard library")
+            }
+            None => return self.unsupported(paren, "calls to anything but functions"),
+        };
+        if arity != args.len() {
+            return self.errors.push(CompileError::WrongArity {
+                token: paren.clone(),
+                expected: arity,
+                got: args.len(),
+            });
+        }
+        for arg in args.iter() {
+            self.expr(arg);
+        }
+        self.error_args(RuntimeError::StackOverflow(paren.clone()));
+        self.emit("call $rt.enter");
+        self.emit(format!("call ${}", name.name()));
+        self.emit("call $rt.leave");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Interpreter;
+
+    fn compile_source(source: &str) -> Result<String, Vec<String>> {
+        let program = Interpreter::new().parse(source).unwrap();
+        compile(&program).map_err(|errors| errors.iter().map(|err| err.to_string()).collect())
+    }
+
+    #[test]
+    fn test_compile() {
+        let text = compile_source(
+            "
+fun add(a, b) { return a + b; }
+var total = add(1, 2.5);
+{ var a = total; { var a = -a; print a; } }
+",
+        )
+        .unwrap();
+        assert!(text.contains("(func $add (param $a f64) (param $b f64) (result f64)"));
+        assert!(text.contains("(global $total (mut f64)"));
+        assert!(text.contains("(local $a.2 f64)"));
+        assert!(text.contains("f64.const 2.5"));
+        // the messages runtime errors report are in memory
+        assert!(text.contains("Operands must be two numbers or two strings.\\00"));
+        assert!(!assemble(&text).is_empty());
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let errors = compile_source(
+            "
+class A {}
+print \"hi\";
+fun f(a) { fun g() {} return a; }
+f();
+print clock();
+var m = 1;
+m[0] = m[1];
+",
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "Can't compile classes to wasm.",
+                "Can't compile strings to wasm.",
+                "Can't compile functions declared inside functions or blocks to wasm.",
+                "Expected 1 arguments but got 0.",
+                "Can't compile the standard library to wasm.",
+                "Can't compile indexing to wasm.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(display(f64::from_bits(NIL)), "nil");
+        assert_eq!(display(f64::from_bits(TRUE)), "true");
+        assert_eq!(display(2.5), "2.5");
+        assert_eq!(display(3.0), "3");
+        assert_eq!(message(b"one\0two\0", 4), "two");
+    }
+}
@@ -0,0 +1,478 @@
+//! Runs every script under `tests/end-to-end` through the `rlox` binary and
+//! compares what it prints with the expectations written in its comments:
+//!
+//! - `// expect: <line>` is a line the script prints to stdout
+//! - `// expect runtime error: <message>` fails with exit code 70 on that line
+//! - `// Error at '<lexeme>': <message>` is a compile error on that line, and
+//!   `// [line N] Error ...` one reported on line N; both exit with 65
+//!
+//! Warnings, the source snippets under each error and the stack trace
+//! after a runtime error are not compared.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Directories that are not whole programs, or too slow to run on every test.
+/// `modules` holds files that scripts import.
+const SKIPPED_DIRS: &[&str] = &["benchmark", "expressions", "modules", "scanning"];
+
+/// Limits of the bytecode format, which the tree-walker does not have
+const VM_ONLY: &[&str] = &[
+    "limit/loop_too_large.lox",
+    "limit/no_reuse_constants.lox",
+    "limit/too_many_constants.lox",
+    "limit/too_many_locals.lox",
+    "limit/too_many_upvalues.lox",
+];
+
+/// Scripts the optimizer changes on purpose: this one needs a loop body
+/// that does not fold away
+const NOT_OPTIMIZED: &[&str] = &["limit/loop_too_large.lox"];
+
+#[derive(Debug, Default, PartialEq)]
+struct Outcome {
+    stdout: Vec<String>,
+    stderr: Vec<String>,
+    exit_code: i32,
+}
+
+impl Outcome {
+    fn expected(source: &str) -> Self {
+        let mut expected = Self::default();
+        for (idx, line) in source.lines().enumerate() {
+            let line_no = idx + 1;
+            if let Some(output) = after(line, "// expect:") {
+                let output = output.strip_prefix(' ').unwrap_or(output);
+                expected.stdout.push(output.to_string());
+            } else if let Some(message) = after(line, "// expect runtime error: ") {
+                expected.stderr.push(message.to_string());
+                expected.stderr.push(format!("[line {}]", line_no));
+                expected.exit_code = 70;
+            } else if let Some(error) = after(line, "// Error") {
+                expected
+                    .stderr
+                    .push(format!("[line {}] Error{}", line_no, error));
+                expected.exit_code = 65;
+            } else if let Some(error) =
+                after(line, "// [line ").or_else(|| after(line, "// [java line "))
+            {
+                expected.stderr.push(format!("[line {}", error));
+                expected.exit_code = 65;
+            }
+        }
+        expected
+    }
+
+    fn actual(path: &Path, flags: &[&str]) -> Self {
+        // scripts that read files name them relative to the crate
+        let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(flags)
+            .arg(path)
+            .output()
+            .expect("Cannot run rlox");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Self {
+            stdout: stdout.lines().map(str::to_string).collect(),
+            stderr: stderr
+                .lines()
+                .filter(|line| !is_snippet(line) && !is_trace(line) && !line.contains("] Warning"))
+                .map(str::to_string)
+                .collect(),
+            exit_code: output.status.code().unwrap_or(-1),
+        }
+    }
+}
+
+/// The text after `marker`, where it appears in `line`
+fn after<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    line.find(marker).map(|idx| &line[idx + marker.len()..])
+}
+
+/// Lines like `12 | print x;` and `   |       ^` that point into the source
+fn is_snippet(line: &str) -> bool {
+    line.trim_start()
+        .trim_start_matches(|c: char| c.is_ascii_digit())
+        .trim_start()
+        .starts_with('|')
+}
+
+/// Lines like `[line 3] in f()` and `[... 12 more calls]` that list the
+/// calls a runtime error unwound
+fn is_trace(line: &str) -> bool {
+    (line.starts_with("[line ") && line.contains("] in ")) || line.starts_with("[... ")
+}
+
+fn scripts(dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("Cannot read test directory") {
+        let path = entry.expect("Cannot read test directory").path();
+        if path.is_dir() {
+            let name = path.file_name().and_then(|name| name.to_str());
+            if !SKIPPED_DIRS.iter().any(|dir| Some(*dir) == name) {
+                scripts(&path, found);
+            }
+        } else if path.extension() == Some("lox".as_ref()) {
+            found.push(path);
+        }
+    }
+}
+
+fn diff(label: &str, expected: &[String], actual: &[String]) -> String {
+    if expected == actual {
+        return String::new();
+    }
+    let mut out = format!("  {}:\n", label);
+    for line in expected.iter().filter(|line| !actual.contains(line)) {
+        out.push_str(&format!("    - {}\n", line));
+    }
+    for line in actual.iter().filter(|line| !expected.contains(line)) {
+        out.push_str(&format!("    + {}\n", line));
+    }
+    if expected.iter().all(|line| actual.contains(line)) && actual.len() == expected.len() {
+        out.push_str("    (same lines in a different order)\n");
+    }
+    out
+}
+
+/// Runs the whole suite with `flags` and fails with a report of every
+/// script that did not do what its comments say
+fn run_suite(flags: &[&str], skip: &[&str]) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/end-to-end");
+    let mut paths = Vec::new();
+    scripts(&root, &mut paths);
+    paths.sort();
+
+    let mut failures = Vec::new();
+    let mut passed = 0;
+    for path in paths.iter() {
+        let name = path
+            .strip_prefix(&root)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        if skip.contains(&name.as_str()) {
+            continue;
+        }
+        let source = fs::read_to_string(path).expect("Cannot read test script");
+        let expected = Outcome::expected(&source);
+        let actual = Outcome::actual(path, flags);
+        if expected == actual {
+            passed += 1;
+            continue;
+        }
+        let mut report = format!("{}\n", name);
+        report.push_str(&diff("stdout", &expected.stdout, &actual.stdout));
+        report.push_str(&diff("stderr", &expected.stderr, &actual.stderr));
+        if expected.exit_code != actual.exit_code {
+            report.push_str(&format!(
+                "  exit code: expected {}, got {}\n",
+                expected.exit_code, actual.exit_code
+            ));
+        }
+        failures.push(report);
+    }
+    assert!(
+        failures.is_empty(),
+        "{} passed, {} failed with {:?}:\n{}",
+        passed,
+        failures.len(),
+        flags,
+        failures.concat()
+    );
+    assert!(passed > 200, "only found {} test scripts", passed);
+}
+
+#[test]
+fn tree_walker() {
+    run_suite(&[], VM_ONLY);
+}
+
+#[test]
+fn vm() {
+    run_suite(&["--vm"], &[]);
+}
+
+#[test]
+fn vm_stress_gc() {
+    run_suite(&["--vm", "--stress-gc"], &[]);
+}
+
+#[test]
+fn vm_optimized() {
+    run_suite(&["-O", "--vm"], NOT_OPTIMIZED);
+}
+
+#[test]
+fn test_expected() {
+    let source = "\
+print 1; // expect: 1
+var a = ; // Error at ';': Expect expression.
+// [line 7] Error at end: Expect '}' after block.
+nil(); // expect runtime error: Can only call functions and classes.
+";
+    let expected = Outcome::expected(source);
+    assert_eq!(expected.stdout, vec!["1"]);
+    assert_eq!(
+        expected.stderr,
+        vec![
+            "[line 2] Error at ';': Expect expression.",
+            "[line 7] Error at end: Expect '}' after block.",
+            "Can only call functions and classes.",
+            "[line 4]",
+        ]
+    );
+    assert_eq!(expected.exit_code, 70);
+    assert!(is_snippet("12 | print x;"));
+    assert!(is_snippet("   |       ^"));
+    assert!(!is_snippet("[line 1]"));
+}
+
+/// `--check` runs nothing, and finds no type errors in scripts that run
+/// cleanly. One that goes wrong at runtime is reported before it starts.
+#[test]
+fn type_check() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/end-to-end");
+    let mut paths = Vec::new();
+    scripts(&root, &mut paths);
+    let mut checked = 0;
+    for path in paths.iter() {
+        let source = fs::read_to_string(path).expect("Cannot read test script");
+        if Outcome::expected(&source).exit_code != 0 {
+            continue;
+        }
+        let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .arg("--check")
+            .arg(path)
+            .output()
+            .unwrap();
+        assert!(output.stdout.is_empty(), "{} printed", path.display());
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        checked += 1;
+    }
+    assert!(checked > 100, "only checked {} scripts", checked);
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("check");
+    fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("script.lox");
+    fs::write(&script, "print 1;\nvar a: number = \"a\" + \"b\";\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("--check")
+        .arg(&script)
+        .output()
+        .unwrap();
+    assert!(output.stdout.is_empty());
+    assert_eq!(output.status.code(), Some(65));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .starts_with("[line 2] Error at '\"a\"': Expected number but found string."));
+}
+
+/// The stack trace the other tests skip, the same from both backends
+#[test]
+fn stack_trace() {
+    let cases: [(&str, &[&str]); 2] = [
+        (
+            "uncaught.lox",
+            &[
+                "Uncaught error: boom",
+                "[line 2]",
+                "[line 2] in fail()",
+                "[line 4] in script",
+            ],
+        ),
+        (
+            "uncaught_division_by_zero.lox",
+            &[
+                "Division by zero.",
+                "[line 2]",
+                "[line 2] in average()",
+                "[line 6] in report()",
+                "[line 9] in script",
+            ],
+        ),
+    ];
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/end-to-end/exceptions");
+    for (script, expected) in cases.iter() {
+        for flags in [&[][..], &["--vm"][..]] {
+            let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+                .args(flags)
+                .arg(dir.join(script))
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let lines: Vec<&str> = stderr.lines().filter(|line| !is_snippet(line)).collect();
+            assert_eq!(lines, *expected, "{} {:?}", script, flags);
+            assert_eq!(output.status.code(), Some(70));
+        }
+    }
+}
+
+/// Errors in imported files name the file, which the other tests can't
+/// check as the paths depend on where the crate is
+#[test]
+fn import_errors() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("import");
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    let files = [
+        ("cycle.lox", "import \"lib/a.lox\" as a;\n"),
+        ("lib/a.lox", "import \"b.lox\" as b;\n"),
+        ("lib/b.lox", "import \"a.lox\" as a;\n"),
+        ("runtime.lox", "import \"lib/c.lox\" as c;\nc.f();\n"),
+        ("lib/c.lox", "print \"c\";\nfun f() { return missing; }\n"),
+        ("missing.lox", "import \"nope.lox\" as nope;\n"),
+    ];
+    for (path, source) in files.iter() {
+        fs::write(dir.join(path), source).unwrap();
+    }
+    let run = |flags: &[&str], script: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .current_dir(&dir)
+            .args(flags)
+            .arg(script)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines: Vec<String> = stderr
+            .lines()
+            .filter(|line| !is_snippet(line))
+            .map(str::to_string)
+            .collect();
+        (lines, output.status.code())
+    };
+    for flags in [&[][..], &["--vm"][..]] {
+        let (lines, code) = run(flags, "cycle.lox");
+        assert_eq!(
+            lines,
+            vec![
+                "In lib/b.lox:",
+                "[line 1] Error at '\"a.lox\"': Import cycle: cycle.lox -> lib/a.lox -> lib/b.lox -> lib/a.lox.",
+            ],
+            "{:?}",
+            flags
+        );
+        assert_eq!(code, Some(65));
+
+        let (lines, code) = run(flags, "runtime.lox");
+        assert_eq!(
+            lines,
+            vec![
+                "In lib/c.lox:",
+                "Undefined variable 'missing'.",
+                "[line 2]",
+                "[line 2] in f()",
+                "[line 2] in script",
+            ],
+            "{:?}",
+            flags
+        );
+        assert_eq!(code, Some(70));
+
+        let (lines, code) = run(flags, "missing.lox");
+        assert!(
+            lines[0]
+                .starts_with("[line 1] Error at '\"nope.lox\"': Can't read module 'nope.lox': "),
+            "{:?}",
+            lines
+        );
+        assert_eq!(code, Some(65));
+    }
+}
+
+/// Growing a container counts towards the next collection, even when the
+/// loop doing it allocates nothing else
+#[test]
+fn gc_log() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("gc");
+    fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("grow.lox");
+    fs::write(
+        &script,
+        "var items = [];\nfor (var i = 0; i < 200000; i = i + 1) items.push(i);\nprint items.len();\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(["--vm", "--log-gc"])
+        .arg(&script)
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "200000\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.lines().any(|line| line.starts_with("-- gc: ")),
+        "{}",
+        stderr
+    );
+    assert!(
+        !stderr.contains("-- gc summary: 0 collections"),
+        "{}",
+        stderr
+    );
+}
+
+/// A script that has the name of a subcommand can still be run
+#[test]
+fn scripts_named_like_subcommands() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("subcommands");
+    fs::create_dir_all(&dir).unwrap();
+    for name in ["build", "debug", "fmt", "lsp"].iter() {
+        fs::write(dir.join(name), format!("print \"{}\";\n", name)).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .current_dir(&dir)
+            .arg(name)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0), "{}", name);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{}\n", name)
+        );
+    }
+}
+
+/// A command line that makes no sense exits with 64, before running anything
+#[test]
+fn usage_errors() {
+    let invocations: [&[&str]; 6] = [
+        &["--bogus"],
+        &["one.lox", "two.lox"],
+        &["--check"],
+        &["fmt"],
+        &["debug", "--bogus", "x.lox"],
+        &["build", "--target", "jvm", "x.lox"],
+    ];
+    for args in invocations.iter() {
+        let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .args(*args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(64), "{:?}", args);
+        assert!(output.stdout.is_empty(), "{:?}", args);
+    }
+}
+
+#[test]
+fn missing_scripts() {
+    let invocations: [&[&str]; 5] = [
+        &["missing.lox"],
+        &["--check", "missing.lox"],
+        &["fmt", "missing.lox"],
+        &["debug", "missing.lox"],
+        &["build", "--target", "wasm", "missing.lox"],
+    ];
+    for args in invocations.iter() {
+        let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .args(*args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(66), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.starts_with("Cannot read missing.lox"), "{}", stderr);
+    }
+}
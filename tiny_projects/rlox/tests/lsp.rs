@@ -0,0 +1,240 @@
+//! Drives `rlox lsp` the way an editor would: a whole session of JSON-RPC
+//! messages is piped through stdin and the replies are read back from
+//! stdout once the server has exited.
+
+use serde_json::{json, Value};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const URI: &str = "file:///test.lox";
+
+const SOURCE: &str = "\
+fun add(a, b) {
+  return a + b;
+}
+class Point {
+  init(x) { this.x = x; }
+}
+var total = add(1, 2);
+print total; // sum
+{ var unused; }
+";
+
+/// Runs one session and returns every message the server sent
+fn session(messages: &[Value]) -> Vec<Value> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Cannot run rlox");
+    let mut stdin = child.stdin.take().unwrap();
+    for msg in messages.iter() {
+        let body = msg.to_string();
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let mut stdout = String::from_utf8(output.stdout).unwrap();
+    let mut replies = Vec::new();
+    while let Some(header_end) = stdout.find("\r\n\r\n") {
+        let length: usize = stdout[..header_end]
+            .trim_start_matches("Content-Length: ")
+            .parse()
+            .unwrap();
+        let body_start = header_end + 4;
+        replies.push(serde_json::from_str(&stdout[body_start..body_start + length]).unwrap());
+        stdout.drain(..body_start + length);
+    }
+    replies
+}
+
+fn request(id: i32, method: &str, params: Value) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({"jsonrpc": "2.0", "method": method, "params": params})
+}
+
+fn at(line: u32, character: u32) -> Value {
+    json!({"textDocument": {"uri": URI}, "position": {"line": line, "character": character}})
+}
+
+fn result(replies: &[Value], id: i32) -> &Value {
+    &replies
+        .iter()
+        .find(|reply| reply["id"] == id)
+        .unwrap_or_else(|| panic!("No reply to request {}", id))["result"]
+}
+
+fn range(start: (u32, u32), end: (u32, u32)) -> Value {
+    json!({
+        "start": {"line": start.0, "character": start.1},
+        "end": {"line": end.0, "character": end.1}
+    })
+}
+
+fn run_with_document(requests: &[Value]) -> Vec<Value> {
+    let mut messages = vec![
+        request(0, "initialize", json!({"capabilities": {}})),
+        notification("initialized", json!({})),
+        notification(
+            "textDocument/didOpen",
+            json!({"textDocument": {"uri": URI, "languageId": "lox", "version": 1, "text": SOURCE}}),
+        ),
+    ];
+    messages.extend(requests.iter().cloned());
+    messages.push(request(99, "shutdown", Value::Null));
+    messages.push(notification("exit", Value::Null));
+    session(&messages)
+}
+
+#[test]
+fn diagnostics() {
+    let replies = run_with_document(&[
+        notification(
+            "textDocument/didChange",
+            json!({
+                "textDocument": {"uri": URI, "version": 2},
+                "contentChanges": [{"text": "print 1\nprint @;"}]
+            }),
+        ),
+        notification(
+            "textDocument/didClose",
+            json!({"textDocument": {"uri": URI}}),
+        ),
+    ]);
+    let published: Vec<&Value> = replies
+        .iter()
+        .filter(|reply| reply["method"] == "textDocument/publishDiagnostics")
+        .map(|reply| &reply["params"]["diagnostics"])
+        .collect();
+    assert_eq!(published.len(), 3);
+
+    assert_eq!(published[0].as_array().unwrap().len(), 1);
+    assert_eq!(
+        published[0][0]["message"],
+        "Local variable 'unused' is never used."
+    );
+    assert_eq!(published[0][0]["severity"], 2);
+    assert_eq!(published[0][0]["range"], range((8, 6), (8, 12)));
+
+    let messages: Vec<&Value> = published[1]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|diagnostic| &diagnostic["message"])
+        .collect();
+    assert_eq!(
+        messages,
+        vec!["Expect ';' after value.", "Unexpected character."]
+    );
+    assert_eq!(published[1][0]["severity"], 1);
+    // closing the file clears its diagnostics
+    assert_eq!(published[2], &json!([]));
+}
+
+#[test]
+fn navigation() {
+    let mut references = at(0, 4);
+    references["context"] = json!({"includeDeclaration": true});
+    let replies = run_with_document(&[
+        request(1, "textDocument/definition", at(6, 13)),
+        request(2, "textDocument/references", references),
+        request(3, "textDocument/hover", at(1, 9)),
+        request(4, "textDocument/hover", at(7, 8)),
+        request(5, "textDocument/definition", at(7, 2)),
+        request(6, "textDocument/unknown", json!({})),
+    ]);
+
+    assert_eq!(
+        result(&replies, 1),
+        &json!({"uri": URI, "range": range((0, 4), (0, 7))})
+    );
+    assert_eq!(
+        result(&replies, 2),
+        &json!([
+            {"uri": URI, "range": range((0, 4), (0, 7))},
+            {"uri": URI, "range": range((6, 12), (6, 15))}
+        ])
+    );
+    assert_eq!(
+        result(&replies, 3)["contents"]["value"],
+        "```lox\nfun add(a, b)\n```\nParameter declared on line 1"
+    );
+    assert_eq!(result(&replies, 3)["range"], range((1, 9), (1, 10)));
+    assert_eq!(
+        result(&replies, 4)["contents"]["value"],
+        "```lox\nvar total\n```\nVariable declared on line 7"
+    );
+    // nothing to find on a keyword
+    assert_eq!(result(&replies, 5), &Value::Null);
+    let unknown = replies.iter().find(|reply| reply["id"] == 6).unwrap();
+    assert_eq!(unknown["error"]["code"], -32601);
+}
+
+#[test]
+fn symbols_and_highlighting() {
+    let replies = run_with_document(&[
+        request(
+            1,
+            "textDocument/documentSymbol",
+            json!({"textDocument": {"uri": URI}}),
+        ),
+        request(
+            2,
+            "textDocument/semanticTokens/full",
+            json!({"textDocument": {"uri": URI}}),
+        ),
+    ]);
+
+    let symbols = result(&replies, 1);
+    assert_eq!(symbols[0]["name"], "add");
+    assert_eq!(symbols[0]["kind"], 12);
+    assert_eq!(symbols[0]["range"], range((0, 0), (2, 1)));
+    assert_eq!(symbols[0]["selectionRange"], range((0, 4), (0, 7)));
+    assert_eq!(symbols[1]["name"], "Point");
+    assert_eq!(symbols[1]["kind"], 5);
+    assert_eq!(symbols[1]["children"][0]["name"], "init");
+    assert_eq!(symbols[1]["children"][0]["kind"], 6);
+    assert_eq!(symbols.as_array().unwrap().len(), 2);
+
+    let legend = replies.iter().find(|reply| reply["id"] == 0).unwrap()["result"]["capabilities"]
+        ["semanticTokensProvider"]["legend"]["tokenTypes"]
+        .clone();
+    let legend: Vec<String> = serde_json::from_value(legend).unwrap();
+    let data = result(&replies, 2)["data"].as_array().unwrap();
+    // decode the first line: `fun add(a, b) {`
+    let first_line: Vec<(u64, u64, &str)> = data
+        .chunks(5)
+        .take_while(|token| token[0] == 0)
+        .scan(0, |column, token| {
+            *column += token[1].as_u64().unwrap();
+            Some((
+                *column,
+                token[2].as_u64().unwrap(),
+                legend[token[3].as_u64().unwrap() as usize].as_str(),
+            ))
+        })
+        .collect();
+    assert_eq!(
+        first_line,
+        vec![
+            (0, 3, "keyword"),
+            (4, 3, "function"),
+            (8, 1, "parameter"),
+            (11, 1, "parameter")
+        ]
+    );
+    let types: Vec<&str> = data
+        .chunks(5)
+        .map(|token| legend[token[3].as_u64().unwrap() as usize].as_str())
+        .collect();
+    assert!(types.contains(&"comment"));
+    assert!(types.contains(&"class"));
+    assert!(types.contains(&"method"));
+    assert!(types.contains(&"property"));
+}
@@ -0,0 +1,232 @@
+//! Runs the modules `rlox build --target wasm` produces on wasmi, an
+//! interpreter for WebAssembly, with host functions that print the way
+//! `rlox` does. Every end-to-end script the backend can compile has to
+//! print what its comments expect.
+
+use rlox::{wasm, Interpreter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use wasmi::{Caller, Engine, Linker, Module, Store};
+
+/// Directories that are not whole programs, as in the end-to-end runner
+const SKIPPED_DIRS: &[&str] = &["benchmark", "expressions", "scanning"];
+
+/// What a module printed, and the runtime error that stopped it
+#[derive(Debug, Default, PartialEq)]
+struct Output {
+    stdout: Vec<String>,
+    error: Option<(String, i32)>,
+}
+
+fn run_module(bytes: &[u8]) -> Output {
+    let engine = Engine::default();
+    let module = Module::new(&engine, bytes).expect("Invalid module");
+    let mut store = Store::new(&engine, Output::default());
+    let mut linker = <Linker<Output>>::new(&engine);
+    linker
+        .func_wrap(
+            "env",
+            "print",
+            |mut caller: Caller<'_, Output>, value: f64| {
+                caller.data_mut().stdout.push(wasm::display(value));
+            },
+        )
+        .unwrap();
+    linker
+        .func_wrap(
+            "env",
+            "error",
+            |mut caller: Caller<'_, Output>, message: i32, line: i32| {
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(|export| export.into_memory())
+                    .expect("No memory export");
+                let message = wasm::message(memory.data(&caller), message as usize);
+                caller.data_mut().error = Some((message, line));
+            },
+        )
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .and_then(|pre| pre.start(&mut store))
+        .expect("Cannot instantiate module");
+    let main = instance
+        .get_typed_func::<(), ()>(&store, "main")
+        .expect("No main export");
+    // a runtime error traps once the host has been told about it
+    let trapped = main.call(&mut store, ());
+    let output = store.into_data();
+    assert_eq!(trapped.is_err(), output.error.is_some(), "{:?}", trapped);
+    output
+}
+
+/// Compiles `source` the way `rlox build` does
+fn compile(source: &str) -> Result<Vec<u8>, String> {
+    let mut lox = Interpreter::new();
+    let program = lox.parse(source).map_err(|err| err.to_string())?;
+    let text = lox.compile_wasm(&program).map_err(|err| err.to_string())?;
+    Ok(wasm::assemble(&text))
+}
+
+fn run(source: &str) -> Output {
+    run_module(&compile(source).unwrap())
+}
+
+fn lines(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|line| line.to_string()).collect()
+}
+
+#[test]
+fn functions_and_control_flow() {
+    let output = run("
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+fun isEven(n) { if (n == 0) return true; return isOdd(n - 1); }
+fun isOdd(n) { if (n == 0) return false; return isEven(n - 1); }
+fun nothing() {}
+
+var total = 0;
+for (var i = 0; i < 10; i = i + 1) {
+  total = total + fib(i);
+}
+print total;
+print isEven(10);
+print nothing();
+print 7 / 2;
+print -(1 - 4);
+print 1 == 1 and nil or 2 >= 3;
+{
+  var a = 1;
+  { var a = 2; print a; }
+  print a;
+}
+");
+    assert_eq!(
+        output.stdout,
+        lines(&["88", "true", "nil", "3.5", "3", "false", "2", "1"])
+    );
+    assert_eq!(output.error, None);
+}
+
+#[test]
+fn runtime_errors() {
+    let output = run("print 1;\nprint -true;\nprint 2;");
+    assert_eq!(output.stdout, lines(&["1"]));
+    assert_eq!(
+        output.error,
+        Some(("Operand must be a number.".to_string(), 2))
+    );
+
+    let output = run("var zero = 0;\nprint 1 / zero;");
+    assert_eq!(output.error, Some(("Division by zero.".to_string(), 2)));
+
+    let output = run("fun f() { return later; }\nprint f();\nvar later = 1;");
+    assert_eq!(
+        output.error,
+        Some(("Undefined variable 'later'.".to_string(), 1))
+    );
+
+    assert_eq!(
+        compile("class A {}\nprint \"a\";").unwrap_err(),
+        "[line 1] Can't compile classes to wasm.\n[line 2] Can't compile strings to wasm."
+    );
+}
+
+#[test]
+fn command_line() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("wasm");
+    fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("script.lox");
+    fs::write(&script, "var a = 20;\nprint a + 22;\n").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(["build", "--target", "wasm"])
+        .arg(&script)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let bytes = fs::read(dir.join("script.wasm")).unwrap();
+    assert_eq!(run_module(&bytes).stdout, lines(&["42"]));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(["build", "--wat", "--target", "wasm"])
+        .arg(&script)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let text = fs::read_to_string(dir.join("script.wat")).unwrap();
+    assert_eq!(wasm::assemble(&text), bytes);
+
+    fs::write(&script, "print \"a\";\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(["build", "--target", "wasm"])
+        .arg(&script)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(65));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .starts_with("[line 1] Error at '\"a\"': Can't compile strings to wasm."));
+}
+
+/// What an end-to-end script's comments say it prints, if it is meant to
+/// compile
+fn expected(source: &str) -> Option<Output> {
+    let mut expected = Output::default();
+    for (line_no, line) in (1..).zip(source.lines()) {
+        if let Some(idx) = line.find("// expect: ") {
+            expected.stdout.push(line[idx + 11..].to_string());
+        } else if let Some(idx) = line.find("// expect runtime error: ") {
+            expected.error = Some((line[idx + 25..].to_string(), line_no));
+        } else if line.contains("// Error") || line.contains("// [line ") {
+            return None;
+        }
+    }
+    Some(expected)
+}
+
+fn scripts(dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            let name = path.file_name().and_then(|name| name.to_str());
+            if !SKIPPED_DIRS.iter().any(|dir| Some(*dir) == name) {
+                scripts(&path, found);
+            }
+        } else if path.extension() == Some("lox".as_ref()) {
+            found.push(path);
+        }
+    }
+}
+
+#[test]
+fn end_to_end_scripts() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/end-to-end");
+    let mut paths = Vec::new();
+    scripts(&root, &mut paths);
+    paths.sort();
+
+    let mut ran = 0;
+    let mut failures = Vec::new();
+    for path in paths.iter() {
+        let source = fs::read_to_string(path).unwrap();
+        let (expected, bytes) = match (expected(&source), compile(&source)) {
+            (Some(expected), Ok(bytes)) => (expected, bytes),
+            _ => continue,
+        };
+        ran += 1;
+        let actual = run_module(&bytes);
+        if actual != expected {
+            failures.push(format!(
+                "{}\n  expected {:?}\n  got      {:?}",
+                path.strip_prefix(&root).unwrap().display(),
+                expected,
+                actual
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+    assert!(ran >= 25, "only {} scripts compiled to wasm", ran);
+}